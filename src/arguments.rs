@@ -1,25 +1,99 @@
 use std::path::PathBuf;
+use std::time::Duration;
 
 use clap;
 
+use uuid::Uuid;
+
 pub struct IndexCommand {
 	pub paths: Vec <PathBuf>,
 	pub index: PathBuf,
+	pub fs_uuid: Option <[u8; 16]>,
+	pub node_size: Option <u32>,
+	pub sector_size: Option <u32>,
+	pub csum_type: Option <u16>,
+	pub threads: Option <usize>,
+	pub resume: bool,
+	pub checkpoint_interval: Option <Duration>,
+	pub mapfiles: Vec <PathBuf>,
+	pub offsets: Vec <u64>,
+	pub start: Option <u64>,
+	pub end: Option <u64>,
+	pub metadata_only: bool,
+	pub filter: NodeFilter,
 }
 
 pub struct ScanCommand {
 	pub paths: Vec <PathBuf>,
+	pub mapfiles: Vec <PathBuf>,
+	pub offsets: Vec <u64>,
+	pub index: Option <PathBuf>,
+	pub include_corrupt: bool,
 }
 
 pub struct RestoreCommand {
 	pub paths: Vec <PathBuf>,
+	pub mapfiles: Vec <PathBuf>,
+	pub offsets: Vec <u64>,
 	pub subvolume_id: u64,
 	pub source: PathBuf,
 	pub target: PathBuf,
+	pub index: Option <PathBuf>,
+	pub root: Option <u64>,
+	pub max_generation: Option <u64>,
+	pub include_corrupt: bool,
+	pub from_index: bool,
+}
+
+pub struct IndexInfoCommand {
+	pub index: PathBuf,
+	pub filter: NodeFilter,
+	pub device_id: Option <u64>,
+	pub corrupt: bool,
+	pub print_nodes: bool,
+	pub histogram_buckets: usize,
+}
+
+pub struct IndexMergeCommand {
+	pub inputs: Vec <PathBuf>,
+	pub index: PathBuf,
+}
+
+pub struct IndexDiffCommand {
+	pub old_index: PathBuf,
+	pub new_index: PathBuf,
+}
+
+pub struct FindRootCommand {
+	pub paths: Vec <PathBuf>,
+	pub mapfiles: Vec <PathBuf>,
+	pub offsets: Vec <u64>,
+	pub index: PathBuf,
+}
+
+pub struct FindSuperblockCommand {
+	pub paths: Vec <PathBuf>,
+	pub start: Option <u64>,
+	pub end: Option <u64>,
+}
+
+#[ derive (Clone, Debug) ]
+pub struct NodeFilter {
+	pub tree_ids: Vec <u64>,
+	pub levels: Vec <u8>,
+	pub min_generation: Option <u64>,
+	pub max_generation: Option <u64>,
+	pub object_ids: Vec <u64>,
+	pub item_types: Vec <u8>,
 }
 
 pub enum Command {
 	Index (IndexCommand),
+	IndexInfo (IndexInfoCommand),
+	IndexMerge (IndexMergeCommand),
+	IndexDiff (IndexDiffCommand),
+	FindRoot (FindRootCommand),
+	FindSuperblock (FindSuperblockCommand),
 	Scan (ScanCommand),
 	Restore (RestoreCommand),
 }
@@ -40,6 +114,56 @@ pub fn parse_arguments (
 		);
 	}
 
+	if let Some (index_info_matches) = (
+		matches.subcommand_matches (
+			"index-info")
+	) {
+		return Some (
+			index_info_command (
+				index_info_matches)
+		);
+	}
+
+	if let Some (index_merge_matches) = (
+		matches.subcommand_matches (
+			"index-merge")
+	) {
+		return Some (
+			index_merge_command (
+				index_merge_matches)
+		);
+	}
+
+	if let Some (index_diff_matches) = (
+		matches.subcommand_matches (
+			"index-diff")
+	) {
+		return Some (
+			index_diff_command (
+				index_diff_matches)
+		);
+	}
+
+	if let Some (find_root_matches) = (
+		matches.subcommand_matches (
+			"find-root")
+	) {
+		return Some (
+			find_root_command (
+				find_root_matches)
+		);
+	}
+
+	if let Some (find_superblock_matches) = (
+		matches.subcommand_matches (
+			"find-superblock")
+	) {
+		return Some (
+			find_superblock_command (
+				find_superblock_matches)
+		);
+	}
+
 	if let Some (restore_matches) = (
 		matches.subcommand_matches (
 			"restore")
@@ -85,10 +209,348 @@ fn index_command (
 
 		).collect ();
 
+	let fs_uuid =
+		index_matches.value_of (
+			"fs-uuid",
+		).map (
+			|value| {
+
+			let mut fs_uuid: [u8; 16] = [0; 16];
+
+			fs_uuid.copy_from_slice (
+				Uuid::parse_str (
+					value,
+				).unwrap ().as_bytes ());
+
+			fs_uuid
+
+		});
+
+	let node_size =
+		index_matches.value_of (
+			"node-size",
+		).map (
+			|value|
+
+			parse_block_size (
+				"node size",
+				value,
+			).unwrap ()
+
+		);
+
+	let sector_size =
+		index_matches.value_of (
+			"sector-size",
+		).map (
+			|value|
+
+			parse_block_size (
+				"sector size",
+				value,
+			).unwrap ()
+
+		);
+
+	let csum_type =
+		index_matches.value_of (
+			"csum-type",
+		).map (
+			|value|
+
+			parse_csum_type (
+				value,
+			).unwrap ()
+
+		);
+
+	let threads =
+		index_matches.value_of (
+			"threads",
+		).map (
+			|value|
+
+			usize::from_str_radix (
+				value,
+				10,
+			).unwrap ()
+
+		);
+
+	let resume =
+		index_matches.is_present (
+			"resume");
+
+	let checkpoint_interval =
+		u64::from_str_radix (
+			index_matches.value_of (
+				"checkpoint-interval",
+			).unwrap (),
+			10,
+		).unwrap ();
+
+	let checkpoint_interval =
+		if checkpoint_interval > 0 {
+			Some (Duration::from_secs (checkpoint_interval))
+		} else {
+			None
+		};
+
+	let mapfiles =
+		mapfile_paths (
+			index_matches);
+
+	let offsets =
+		offset_values (
+			index_matches);
+
+	let start =
+		index_matches.value_of (
+			"start",
+		).map (
+			|value|
+
+			parse_size (
+				value,
+			).unwrap ()
+
+		);
+
+	let end =
+		index_matches.value_of (
+			"end",
+		).map (
+			|value|
+
+			parse_size (
+				value,
+			).unwrap ()
+
+		);
+
+	let metadata_only =
+		index_matches.is_present (
+			"metadata-only");
+
+	let filter =
+		node_filter (
+			index_matches);
+
 	Command::Index (
 		IndexCommand {
 			paths: paths,
 			index: index,
+			fs_uuid: fs_uuid,
+			node_size: node_size,
+			sector_size: sector_size,
+			csum_type: csum_type,
+			threads: threads,
+			resume: resume,
+			checkpoint_interval: checkpoint_interval,
+			mapfiles: mapfiles,
+			offsets: offsets,
+			start: start,
+			end: end,
+			metadata_only: metadata_only,
+			filter: filter,
+		}
+	)
+
+}
+
+fn index_info_command (
+	index_info_matches: & clap::ArgMatches,
+) -> Command {
+
+	let index =
+		PathBuf::from (
+			index_info_matches.value_of_os (
+				"index",
+			).unwrap ());
+
+	let filter =
+		node_filter (
+			index_info_matches);
+
+	let device_id =
+		index_info_matches.value_of (
+			"device-id",
+		).map (
+			|value|
+
+			parse_number (
+				value,
+			).unwrap ()
+
+		);
+
+	let corrupt =
+		index_info_matches.is_present (
+			"corrupt");
+
+	let print_nodes =
+		index_info_matches.is_present (
+			"nodes");
+
+	let histogram_buckets =
+		parse_number (
+			index_info_matches.value_of (
+				"histogram-buckets",
+			).unwrap (),
+		).unwrap () as usize;
+
+	Command::IndexInfo (
+		IndexInfoCommand {
+			index: index,
+			filter: filter,
+			device_id: device_id,
+			corrupt: corrupt,
+			print_nodes: print_nodes,
+			histogram_buckets: histogram_buckets,
+		}
+	)
+
+}
+
+fn index_merge_command (
+	index_merge_matches: & clap::ArgMatches,
+) -> Command {
+
+	let inputs =
+		index_merge_matches.values_of_os (
+			"input",
+		).unwrap ().map (
+			|os_value|
+
+			PathBuf::from (
+				os_value)
+
+		).collect ();
+
+	let index =
+		PathBuf::from (
+			index_merge_matches.value_of_os (
+				"index",
+			).unwrap ());
+
+	Command::IndexMerge (
+		IndexMergeCommand {
+			inputs: inputs,
+			index: index,
+		}
+	)
+
+}
+
+fn index_diff_command (
+	index_diff_matches: & clap::ArgMatches,
+) -> Command {
+
+	let old_index =
+		PathBuf::from (
+			index_diff_matches.value_of_os (
+				"old-index",
+			).unwrap ());
+
+	let new_index =
+		PathBuf::from (
+			index_diff_matches.value_of_os (
+				"new-index",
+			).unwrap ());
+
+	Command::IndexDiff (
+		IndexDiffCommand {
+			old_index: old_index,
+			new_index: new_index,
+		}
+	)
+
+}
+
+fn find_root_command (
+	find_root_matches: & clap::ArgMatches,
+) -> Command {
+
+	let paths =
+		find_root_matches.values_of_os (
+			"path",
+		).unwrap ().map (
+			|os_value|
+
+			PathBuf::from (
+				os_value)
+
+		).collect ();
+
+	let mapfiles =
+		mapfile_paths (
+			find_root_matches);
+
+	let offsets =
+		offset_values (
+			find_root_matches);
+
+	let index =
+		PathBuf::from (
+			find_root_matches.value_of_os (
+				"index",
+			).unwrap ());
+
+	Command::FindRoot (
+		FindRootCommand {
+			paths: paths,
+			mapfiles: mapfiles,
+			offsets: offsets,
+			index: index,
+		}
+	)
+
+}
+
+fn find_superblock_command (
+	find_superblock_matches: & clap::ArgMatches,
+) -> Command {
+
+	let paths =
+		find_superblock_matches.values_of_os (
+			"path",
+		).unwrap ().map (
+			|os_value|
+
+			PathBuf::from (
+				os_value)
+
+		).collect ();
+
+	let start =
+		find_superblock_matches.value_of (
+			"start",
+		).map (
+			|value|
+
+			parse_size (
+				value,
+			).unwrap ()
+
+		);
+
+	let end =
+		find_superblock_matches.value_of (
+			"end",
+		).map (
+			|value|
+
+			parse_size (
+				value,
+			).unwrap ()
+
+		);
+
+	Command::FindSuperblock (
+		FindSuperblockCommand {
+			paths: paths,
+			start: start,
+			end: end,
 		}
 	)
 
@@ -129,12 +591,70 @@ fn restore_command (
 				"target",
 			).unwrap ());
 
+	let mapfiles =
+		mapfile_paths (
+			restore_matches);
+
+	let offsets =
+		offset_values (
+			restore_matches);
+
+	let index =
+		restore_matches.value_of_os (
+			"index",
+		).map (
+			|os_value|
+
+			PathBuf::from (
+				os_value)
+
+		);
+
+	let root =
+		restore_matches.value_of (
+			"root",
+		).map (
+			|value|
+
+			parse_size (
+				value,
+			).unwrap ()
+
+		);
+
+	let max_generation =
+		restore_matches.value_of (
+			"max-generation",
+		).map (
+			|value|
+
+			parse_number (
+				value,
+			).unwrap ()
+
+		);
+
+	let include_corrupt =
+		restore_matches.is_present (
+			"include-corrupt");
+
+	let from_index =
+		restore_matches.is_present (
+			"from-index");
+
 	Command::Restore (
 		RestoreCommand {
 			paths: paths,
+			mapfiles: mapfiles,
+			offsets: offsets,
 			subvolume_id: subvolume_id,
 			source: source,
 			target: target,
+			index: index,
+			root: root,
+			max_generation: max_generation,
+			include_corrupt: include_corrupt,
+			from_index: from_index,
 		}
 	)
 
@@ -155,9 +675,36 @@ fn scan_command (
 
 		).collect ();
 
+	let mapfiles =
+		mapfile_paths (
+			scan_matches);
+
+	let offsets =
+		offset_values (
+			scan_matches);
+
+	let index =
+		scan_matches.value_of_os (
+			"index",
+		).map (
+			|os_value|
+
+			PathBuf::from (
+				os_value)
+
+		);
+
+	let include_corrupt =
+		scan_matches.is_present (
+			"include-corrupt");
+
 	Command::Scan (
 		ScanCommand {
 			paths: paths,
+			mapfiles: mapfiles,
+			offsets: offsets,
+			index: index,
+			include_corrupt: include_corrupt,
 		}
 	)
 
@@ -176,6 +723,24 @@ fn application <'a, 'b> (
 			clap::SubCommand::with_name ("index")
 
 			.arg (index_argument ())
+			.arg (fs_uuid_argument ())
+			.arg (node_size_argument ())
+			.arg (sector_size_argument ())
+			.arg (csum_type_argument ())
+			.arg (threads_argument ())
+			.arg (resume_argument ())
+			.arg (checkpoint_interval_argument ())
+			.arg (mapfile_argument ())
+			.arg (offset_argument ())
+			.arg (start_argument ())
+			.arg (end_argument ())
+			.arg (metadata_only_argument ())
+			.arg (tree_id_argument ())
+			.arg (level_argument ())
+			.arg (min_generation_argument ())
+			.arg (max_generation_argument ())
+			.arg (object_id_argument ())
+			.arg (item_type_argument ())
 			.arg (path_argument ())
 
 			.about ("builds an index of btrfs nodes")
@@ -183,41 +748,667 @@ fn application <'a, 'b> (
 		)
 
 		.subcommand (
-			clap::SubCommand::with_name ("restore")
+			clap::SubCommand::with_name ("index-info")
+
+			.arg (index_argument ().required (true))
+			.arg (tree_id_argument ())
+			.arg (level_argument ())
+			.arg (min_generation_argument ())
+			.arg (max_generation_argument ())
+			.arg (object_id_argument ())
+			.arg (item_type_argument ())
+			.arg (device_id_argument ())
+			.arg (corrupt_argument ())
+			.arg (nodes_argument ())
+			.arg (histogram_buckets_argument ())
+
+			.about ("Prints statistics and nodes from an index")
 
-			.arg (subvolume_id_argument ())
-			.arg (source_argument ())
-			.arg (target_argument ())
-			.arg (path_argument ())
+		)
 
-			.about ("Restores files")
+		.subcommand (
+			clap::SubCommand::with_name ("index-merge")
+
+			.arg (index_argument ().required (true))
+			.arg (input_argument ())
+
+			.about ("Merges indexes of the same filesystem")
 
 		)
 
 		.subcommand (
-			clap::SubCommand::with_name ("scan")
+			clap::SubCommand::with_name ("index-diff")
 
-			.arg (path_argument ())
+			.arg (old_index_argument ())
+			.arg (new_index_argument ())
 
-			.about ("Scans a filesystem")
+			.about ("Shows nodes added, removed or changed between indexes")
 
 		)
 
-}
+		.subcommand (
+			clap::SubCommand::with_name ("find-root")
 
-fn index_argument <'a, 'b> (
-) -> clap::Arg <'a, 'b> {
+			.arg (index_argument ().required (true))
+			.arg (mapfile_argument ())
+			.arg (offset_argument ())
+			.arg (path_argument ())
 
-	clap::Arg::with_name ("index")
+			.about ("Lists root tree nodes from an index, ranked by how much \
+				of the filesystem each can reach")
 
-		.long ("index")
-		.value_name ("INDEX")
-		.required (false)
+		)
 
-		.help ("Index file")
+		.subcommand (
+			clap::SubCommand::with_name ("find-superblock")
 
+			.arg (start_argument ())
+			.arg (end_argument ())
+			.arg (path_argument ())
 
-}
+			.about ("Searches every sector for superblocks, to find where a \
+				filesystem starts")
+
+		)
+
+		.subcommand (
+			clap::SubCommand::with_name ("restore")
+
+			.arg (subvolume_id_argument ())
+			.arg (source_argument ())
+			.arg (target_argument ())
+			.arg (index_argument ().help (
+				"Index to restore from if the filesystem trees can't be read, \
+				and to find chunks from if the chunk tree is damaged"))
+			.arg (from_index_argument ())
+			.arg (root_argument ())
+			.arg (max_generation_argument ().requires ("index").help (
+				"Restore files as they were at this generation, from the \
+				most recent copy of each item no newer than it"))
+			.arg (include_corrupt_argument ())
+			.arg (mapfile_argument ())
+			.arg (offset_argument ())
+			.arg (path_argument ())
+
+			.about ("Restores files")
+
+		)
+
+		.subcommand (
+			clap::SubCommand::with_name ("scan")
+
+			.arg (index_argument ().help (
+				"Index to find chunks from if the chunk tree is damaged"))
+			.arg (include_corrupt_argument ())
+			.arg (mapfile_argument ())
+			.arg (offset_argument ())
+			.arg (path_argument ())
+
+			.about ("Scans a filesystem")
+
+		)
+
+}
+
+fn index_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("index")
+
+		.long ("index")
+		.value_name ("INDEX")
+		.required (false)
+
+		.help ("Index file")
+
+
+}
+
+fn fs_uuid_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("fs-uuid")
+
+		.long ("fs-uuid")
+		.value_name ("UUID")
+		.required (false)
+		.validator (
+			|value|
+
+			Uuid::parse_str (
+				& value,
+			).map (
+				|_| (),
+			).map_err (
+				|_| format! ("Invalid UUID: {}", value),
+			)
+
+		)
+
+		.help ("Filesystem UUID, if the superblock is unusable, or to index \
+			the nodes of an earlier filesystem replaced by mkfs")
+
+}
+
+fn node_size_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("node-size")
+
+		.long ("node-size")
+		.value_name ("SIZE")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_block_size (
+				"node size",
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Node size, if the superblock is unusable")
+
+}
+
+fn sector_size_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("sector-size")
+
+		.long ("sector-size")
+		.value_name ("SIZE")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_block_size (
+				"sector size",
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Sector size, if the superblock is unusable")
+
+}
+
+fn csum_type_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("csum-type")
+
+		.long ("csum-type")
+		.value_name ("TYPE")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_csum_type (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Checksum type (crc32c, xxhash64, sha256, blake2b), if the \
+			superblock is unusable")
+
+}
+
+fn threads_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("threads")
+
+		.long ("threads")
+		.value_name ("THREADS")
+		.required (false)
+		.validator (
+			|value|
+
+			match usize::from_str_radix (& value, 10) {
+				Ok (threads) if threads > 0 => Ok (()),
+				_ => Err (format! ("Invalid thread count: {}", value)),
+			}
+
+		)
+
+		.help ("Number of scanning threads, defaults to the number of CPUs")
+
+}
+
+fn resume_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("resume")
+
+		.long ("resume")
+
+		.help ("Resume an interrupted scan from its checkpoint")
+
+}
+
+fn checkpoint_interval_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("checkpoint-interval")
+
+		.long ("checkpoint-interval")
+		.value_name ("SECONDS")
+		.required (false)
+		.default_value ("300")
+		.validator (
+			|value|
+
+			u64::from_str_radix (
+				& value,
+				10,
+			).map (
+				|_| (),
+			).map_err (
+				|_| format! ("Invalid checkpoint interval: {}", value),
+			)
+
+		)
+
+		.help ("Seconds between checkpoints, or 0 to disable them")
+
+}
+
+fn mapfile_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("mapfile")
+
+		.long ("mapfile")
+		.value_name ("MAPFILE")
+		.required (false)
+		.multiple (true)
+		.number_of_values (1)
+
+		.help ("GNU ddrescue mapfile for an image, given once for each path \
+			in the same order")
+
+}
+
+fn offset_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("offset")
+
+		.long ("offset")
+		.value_name ("OFFSET")
+		.required (false)
+		.multiple (true)
+		.number_of_values (1)
+		.validator (
+			|value|
+
+			parse_size (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Position of the filesystem in each path, given once for all \
+			paths or once for each, otherwise partition tables and bcache \
+			or md headers are used to find it")
+
+}
+
+fn start_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("start")
+
+		.long ("start")
+		.value_name ("POSITION")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_size (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Position on each device to start scanning from")
+
+}
+
+fn end_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("end")
+
+		.long ("end")
+		.value_name ("POSITION")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_size (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Position on each device to stop scanning at")
+
+}
+
+fn metadata_only_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("metadata-only")
+
+		.long ("metadata-only")
+
+		.help ("Only scan metadata and system chunks, if the chunk tree can \
+			be read")
+
+}
+
+fn input_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("input")
+
+		.value_name ("INPUT")
+		.required (true)
+		.multiple (true)
+
+		.help ("Index files to merge")
+
+}
+
+fn old_index_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("old-index")
+
+		.value_name ("OLD-INDEX")
+		.required (true)
+		.index (1)
+
+		.help ("Index to compare from")
+
+}
+
+fn new_index_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("new-index")
+
+		.value_name ("NEW-INDEX")
+		.required (true)
+		.index (2)
+
+		.help ("Index to compare to")
+
+}
+
+fn tree_id_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("tree-id")
+
+		.long ("tree-id")
+		.value_name ("TREE-ID")
+		.required (false)
+		.multiple (true)
+		.number_of_values (1)
+		.validator (
+			|value|
+
+			parse_number (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Only include nodes from this tree, may be repeated")
+
+}
+
+fn level_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("level")
+
+		.long ("level")
+		.value_name ("LEVEL")
+		.required (false)
+		.multiple (true)
+		.number_of_values (1)
+		.validator (
+			|value|
+
+			match parse_number (& value) {
+				Ok (level) if level < 8 => Ok (()),
+				_ => Err (format! ("Invalid level: {}", value)),
+			}
+
+		)
+
+		.help ("Only include nodes at this level, may be repeated")
+
+}
+
+fn min_generation_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("min-generation")
+
+		.long ("min-generation")
+		.value_name ("GENERATION")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_number (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Only include nodes from this generation or later")
+
+}
+
+fn max_generation_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("max-generation")
+
+		.long ("max-generation")
+		.value_name ("GENERATION")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_number (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Only include nodes from this generation or earlier")
+
+}
+
+fn object_id_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("object-id")
+
+		.long ("object-id")
+		.value_name ("OBJECT-ID")
+		.required (false)
+		.multiple (true)
+		.number_of_values (1)
+		.validator (
+			|value|
+
+			parse_number (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Only include nodes whose keys may cover this object id, may \
+			be repeated")
+
+}
+
+fn item_type_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("item-type")
+
+		.long ("item-type")
+		.value_name ("ITEM-TYPE")
+		.required (false)
+		.multiple (true)
+		.number_of_values (1)
+		.validator (
+			|value|
+
+			parse_number (
+				& value,
+			).and_then (
+				|value|
+
+				if value < 0x100 {
+					Ok (())
+				} else {
+					Err (format! ("Invalid item type: {}", value))
+				}
+
+			)
+
+		)
+
+		.help ("Only include nodes with keys of this item type, may be \
+			repeated")
+
+}
+
+fn device_id_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("device-id")
+
+		.long ("device-id")
+		.value_name ("DEVICE-ID")
+		.required (false)
+		.validator (
+			|value|
+
+			parse_number (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Only include nodes from this device")
+
+}
+
+fn corrupt_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("corrupt")
+
+		.long ("corrupt")
+
+		.help ("Only include nodes with bad checksums or broken structure")
+
+}
+
+fn from_index_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("from-index")
+
+		.long ("from-index")
+		.requires ("index")
+
+		.help ("Restore from the index without trying the filesystem trees \
+			first")
+
+}
+
+fn include_corrupt_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("include-corrupt")
+
+		.long ("include-corrupt")
+
+		.help ("Use nodes with bad checksums when there is no good copy, \
+			which may recover more but can return wrong data")
+
+}
+
+fn nodes_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("nodes")
+
+		.long ("nodes")
+
+		.help ("Print every matching node")
+
+}
+
+fn histogram_buckets_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("histogram-buckets")
+
+		.long ("histogram-buckets")
+		.value_name ("BUCKETS")
+		.required (false)
+		.default_value ("32")
+		.validator (
+			|value|
+
+			match parse_number (& value) {
+				Ok (buckets) if buckets > 0 => Ok (()),
+				_ => Err (format! ("Invalid bucket count: {}", value)),
+			}
+
+		)
+
+		.help ("Number of buckets in the generation histogram for each \
+			device")
+
+}
 
 fn subvolume_id_argument <'a, 'b> (
 ) -> clap::Arg <'a, 'b> {
@@ -233,6 +1424,31 @@ fn subvolume_id_argument <'a, 'b> (
 
 }
 
+fn root_argument <'a, 'b> (
+) -> clap::Arg <'a, 'b> {
+
+	clap::Arg::with_name ("root")
+
+		.long ("root")
+		.value_name ("BYTENR")
+		.required (false)
+		.requires ("index")
+		.validator (
+			|value|
+
+			parse_size (
+				& value,
+			).map (
+				|_| (),
+			)
+
+		)
+
+		.help ("Root tree node to find the subvolume from, as listed by \
+			find-root, instead of the one in the superblock")
+
+}
+
 fn path_argument <'a, 'b> (
 ) -> clap::Arg <'a, 'b> {
 
@@ -242,7 +1458,9 @@ fn path_argument <'a, 'b> (
 		.required (true)
 		.multiple (true)
 
-		.help ("Path to the BTRFS image(s) to recover")
+		.help ("Path to the BTRFS image(s) to recover, which may be raw or \
+			qcow2, VMDK or VHDX virtual machine disks, or btrfs-image metadata \
+			dumps")
 
 
 }
@@ -273,4 +1491,290 @@ fn target_argument <'a, 'b> (
 
 }
 
+fn mapfile_paths (
+	matches: & clap::ArgMatches,
+) -> Vec <PathBuf> {
+
+	matches.values_of_os (
+		"mapfile",
+	).map (
+		|os_values|
+
+		os_values.map (
+			|os_value|
+
+			PathBuf::from (
+				os_value)
+
+		).collect ()
+
+	).unwrap_or_else (
+		Vec::new)
+
+}
+
+fn offset_values (
+	matches: & clap::ArgMatches,
+) -> Vec <u64> {
+
+	matches.values_of (
+		"offset",
+	).map (
+		|values|
+
+		values.map (
+			|value|
+
+			parse_size (
+				value,
+			).unwrap ()
+
+		).collect ()
+
+	).unwrap_or_else (
+		Vec::new)
+
+}
+
+fn node_filter (
+	matches: & clap::ArgMatches,
+) -> NodeFilter {
+
+	let tree_ids =
+		matches.values_of (
+			"tree-id",
+		).map (
+			|values|
+
+			values.map (
+				|value|
+
+				parse_number (
+					value,
+				).unwrap ()
+
+			).collect ()
+
+		).unwrap_or_else (
+			Vec::new);
+
+	let levels =
+		matches.values_of (
+			"level",
+		).map (
+			|values|
+
+			values.map (
+				|value|
+
+				parse_number (
+					value,
+				).unwrap () as u8
+
+			).collect ()
+
+		).unwrap_or_else (
+			Vec::new);
+
+	let min_generation =
+		matches.value_of (
+			"min-generation",
+		).map (
+			|value|
+
+			parse_number (
+				value,
+			).unwrap ()
+
+		);
+
+	let max_generation =
+		matches.value_of (
+			"max-generation",
+		).map (
+			|value|
+
+			parse_number (
+				value,
+			).unwrap ()
+
+		);
+
+	let object_ids =
+		matches.values_of (
+			"object-id",
+		).map (
+			|values|
+
+			values.map (
+				|value|
+
+				parse_number (
+					value,
+				).unwrap ()
+
+			).collect ()
+
+		).unwrap_or_else (
+			Vec::new);
+
+	let item_types =
+		matches.values_of (
+			"item-type",
+		).map (
+			|values|
+
+			values.map (
+				|value|
+
+				parse_number (
+					value,
+				).unwrap () as u8
+
+			).collect ()
+
+		).unwrap_or_else (
+			Vec::new);
+
+	NodeFilter {
+		tree_ids: tree_ids,
+		levels: levels,
+		min_generation: min_generation,
+		max_generation: max_generation,
+		object_ids: object_ids,
+		item_types: item_types,
+	}
+
+}
+
+// parses a number, in decimal or hex
+
+fn parse_number (
+	value: & str,
+) -> Result <u64, String> {
+
+	if value.starts_with ("0x") {
+
+		u64::from_str_radix (
+			& value [2 ..],
+			16)
+
+	} else {
+
+		u64::from_str_radix (
+			value,
+			10)
+
+	}.map_err (
+		|_| format! ("Invalid number: {}", value),
+	)
+
+}
+
+// parses a size, in decimal or hex, with an optional k/m/g suffix
+
+fn parse_size (
+	value: & str,
+) -> Result <u64, String> {
+
+	let lower_value =
+		value.to_lowercase ();
+
+	let (number_part, multiplier) =
+		if lower_value.ends_with ("k") {
+			(& lower_value [0 .. lower_value.len () - 1], 0x400)
+		} else if lower_value.ends_with ("m") {
+			(& lower_value [0 .. lower_value.len () - 1], 0x10_0000)
+		} else if lower_value.ends_with ("g") {
+			(& lower_value [0 .. lower_value.len () - 1], 0x4000_0000)
+		} else if lower_value.ends_with ("t") {
+			(& lower_value [0 .. lower_value.len () - 1], 0x100_0000_0000)
+		} else {
+			(& lower_value [..], 1)
+		};
+
+	let number =
+		if number_part.starts_with ("0x") {
+
+		u64::from_str_radix (
+			& number_part [2 ..],
+			16)
+
+	} else {
+
+		u64::from_str_radix (
+			number_part,
+			10)
+
+	}.map_err (
+		|_| format! ("Invalid size: {}", value),
+	) ?;
+
+	number.checked_mul (
+		multiplier,
+	).ok_or_else (
+		|| format! ("Invalid size: {}", value),
+	)
+
+}
+
+// node and sector sizes are powers of two from 4K to 64K. a node can't be
+// smaller than a sector, but that can only be checked once both are known.
+
+pub const MIN_BLOCK_SIZE: u32 = 0x1000;
+pub const MAX_BLOCK_SIZE: u32 = 0x1_0000;
+
+pub fn check_block_size (
+	name: & str,
+	size: u64,
+	min_size: u32,
+) -> Result <u32, String> {
+
+	if (
+		! size.is_power_of_two ()
+		|| size < min_size as u64
+		|| size > MAX_BLOCK_SIZE as u64
+	) {
+
+		return Err (
+			format! (
+				"Invalid {}: 0x{:x}, must be a power of two from 0x{:x} to \
+				0x{:x}",
+				name,
+				size,
+				min_size,
+				MAX_BLOCK_SIZE));
+
+	}
+
+	Ok (size as u32)
+
+}
+
+fn parse_block_size (
+	name: & str,
+	value: & str,
+) -> Result <u32, String> {
+
+	check_block_size (
+		name,
+		parse_size (value) ?,
+		MIN_BLOCK_SIZE)
+
+}
+
+fn parse_csum_type (
+	value: & str,
+) -> Result <u16, String> {
+
+	match value {
+		"crc32c" | "0" => Ok (0),
+		"xxhash64" | "1" => Ok (1),
+		"sha256" | "2" => Ok (2),
+		"blake2b" | "3" => Ok (3),
+		_ => Err (format! ("Invalid checksum type: {}", value)),
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,96 @@
+pub fn le_u16 (
+	bytes: & [u8],
+	offset: usize,
+) -> u16 {
+
+	bytes [offset] as u16
+	| (bytes [offset + 1] as u16) << 8
+
+}
+
+pub fn le_u32 (
+	bytes: & [u8],
+	offset: usize,
+) -> u32 {
+
+	bytes [offset] as u32
+	| (bytes [offset + 1] as u32) << 8
+	| (bytes [offset + 2] as u32) << 16
+	| (bytes [offset + 3] as u32) << 24
+
+}
+
+pub fn le_u64 (
+	bytes: & [u8],
+	offset: usize,
+) -> u64 {
+
+	le_u32 (bytes, offset) as u64
+	| (le_u32 (bytes, offset + 4) as u64) << 32
+
+}
+
+pub fn be_u16 (
+	bytes: & [u8],
+	offset: usize,
+) -> u16 {
+
+	(bytes [offset] as u16) << 8
+	| bytes [offset + 1] as u16
+
+}
+
+pub fn be_u32 (
+	bytes: & [u8],
+	offset: usize,
+) -> u32 {
+
+	(be_u16 (bytes, offset) as u32) << 16
+	| be_u16 (bytes, offset + 2) as u32
+
+}
+
+pub fn be_u64 (
+	bytes: & [u8],
+	offset: usize,
+) -> u64 {
+
+	(be_u32 (bytes, offset) as u64) << 32
+	| be_u32 (bytes, offset + 4) as u64
+
+}
+
+pub fn set_le_u16 (
+	bytes: & mut [u8],
+	offset: usize,
+	value: u16,
+) {
+
+	bytes [offset] = value as u8;
+	bytes [offset + 1] = (value >> 8) as u8;
+
+}
+
+pub fn set_le_u32 (
+	bytes: & mut [u8],
+	offset: usize,
+	value: u32,
+) {
+
+	set_le_u16 (bytes, offset, value as u16);
+	set_le_u16 (bytes, offset + 2, (value >> 16) as u16);
+
+}
+
+pub fn set_le_u64 (
+	bytes: & mut [u8],
+	offset: usize,
+	value: u64,
+) {
+
+	set_le_u32 (bytes, offset, value as u32);
+	set_le_u32 (bytes, offset + 4, (value >> 32) as u32);
+
+}
+
+// ex: noet ts=4 filetype=rust
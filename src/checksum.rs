@@ -0,0 +1,103 @@
+use crc::crc32;
+
+pub const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+
+pub const BTRFS_CSUM_SIZE: usize = 0x20;
+
+pub fn checksum_type_name (
+	csum_type: u16,
+) -> & 'static str {
+
+	match csum_type {
+		0 => "crc32c",
+		1 => "xxhash64",
+		2 => "sha256",
+		3 => "blake2b",
+		_ => "unknown",
+	}
+
+}
+
+pub fn checksum_supported (
+	csum_type: u16,
+) -> bool {
+
+	csum_type == BTRFS_CSUM_TYPE_CRC32
+
+}
+
+// verifies the checksum at the start of a node or superblock, which covers
+// everything after the checksum field. returns none if the checksum type is
+// not supported.
+
+pub fn checksum_verify (
+	csum_type: u16,
+	bytes: & [u8],
+) -> Option <bool> {
+
+	if bytes.len () < BTRFS_CSUM_SIZE {
+		return Some (false);
+	}
+
+	match csum_type {
+
+		BTRFS_CSUM_TYPE_CRC32 => {
+
+			let checksum =
+				crc32::checksum_castagnoli (
+					& bytes [BTRFS_CSUM_SIZE .. ]);
+
+			let stored_checksum =
+				bytes [0] as u32
+				| (bytes [1] as u32) << 8
+				| (bytes [2] as u32) << 16
+				| (bytes [3] as u32) << 24;
+
+			Some (checksum == stored_checksum)
+
+		},
+
+		_ => None,
+
+	}
+
+}
+
+// writes the checksum at the start of a node or superblock after changing
+// it, returning false if the checksum type is not supported
+
+pub fn checksum_update (
+	csum_type: u16,
+	bytes: & mut [u8],
+) -> bool {
+
+	match csum_type {
+
+		BTRFS_CSUM_TYPE_CRC32 => {
+
+			let checksum =
+				crc32::checksum_castagnoli (
+					& bytes [BTRFS_CSUM_SIZE .. ]);
+
+			for (index, byte) in bytes [0 .. BTRFS_CSUM_SIZE].iter_mut ().enumerate () {
+
+				* byte =
+					if index < 4 {
+						(checksum >> (index * 8)) as u8
+					} else {
+						0
+					};
+
+			}
+
+			true
+
+		},
+
+		_ => false,
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
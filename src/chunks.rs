@@ -0,0 +1,1120 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use bytes::*;
+use checksum::*;
+use devices::*;
+use index::*;
+
+// raw chunk tree parsing. we do this ourselves, rather than through the
+// btrfs crate, so we can read the chunk tree one device at a time, while
+// scanning, and from nodes we found in an index.
+
+pub const BTRFS_SUPERBLOCK_CHUNK_ROOT_OFFSET: usize = 0x58;
+pub const BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_SIZE_OFFSET: usize = 0xa0;
+pub const BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET: usize = 0x32b;
+pub const BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_MAX: usize = 0x800;
+
+pub const BTRFS_DISK_KEY_SIZE: usize = 0x11;
+pub const BTRFS_CHUNK_ITEM_TYPE: u8 = 228;
+pub const BTRFS_CHUNK_ITEM_SIZE: usize = 0x30;
+pub const BTRFS_CHUNK_STRIPE_SIZE: usize = 0x20;
+
+pub const BTRFS_DEV_EXTENT_ITEM_TYPE: u8 = 204;
+pub const BTRFS_DEV_EXTENT_ITEM_SIZE: usize = 0x30;
+
+pub const BTRFS_BLOCK_GROUP_SYSTEM: u64 = 1 << 1;
+pub const BTRFS_BLOCK_GROUP_METADATA: u64 = 1 << 2;
+pub const BTRFS_BLOCK_GROUP_RAID0: u64 = 1 << 3;
+pub const BTRFS_BLOCK_GROUP_RAID10: u64 = 1 << 6;
+pub const BTRFS_BLOCK_GROUP_RAID5: u64 = 1 << 7;
+pub const BTRFS_BLOCK_GROUP_RAID6: u64 = 1 << 8;
+
+#[ derive (Clone, Debug) ]
+pub struct Chunk {
+	pub logical_address: u64,
+	pub length: u64,
+	pub stripe_length: u64,
+	pub chunk_type: u64,
+	pub sub_stripes: u16,
+	pub stripes: Vec <ChunkStripe>,
+}
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct ChunkStripe {
+	pub device_id: u64,
+	pub offset: u64,
+}
+
+impl Chunk {
+
+	pub fn is_metadata (
+		& self,
+	) -> bool {
+
+		self.chunk_type & (
+			BTRFS_BLOCK_GROUP_METADATA | BTRFS_BLOCK_GROUP_SYSTEM
+		) != 0
+
+	}
+
+	// number of stripes which hold data, rather than copies or parity
+
+	fn data_stripes (
+		& self,
+	) -> u64 {
+
+		let num_stripes =
+			self.stripes.len () as u64;
+
+		if self.chunk_type & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+			num_stripes
+		} else if self.chunk_type & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+			num_stripes / self.sub_stripes as u64
+		} else if self.chunk_type & BTRFS_BLOCK_GROUP_RAID5 != 0 {
+			num_stripes.saturating_sub (1)
+		} else if self.chunk_type & BTRFS_BLOCK_GROUP_RAID6 != 0 {
+			num_stripes.saturating_sub (2)
+		} else {
+			1
+		}
+
+	}
+
+	// the amount of space the chunk takes up on each device
+
+	pub fn device_length (
+		& self,
+	) -> u64 {
+
+		self.length / self.data_stripes ()
+
+	}
+
+	// returns every copy of a logical address within the chunk as a device
+	// id and physical position
+
+	pub fn map (
+		& self,
+		logical_address: u64,
+	) -> Vec <(u64, u64)> {
+
+		let offset =
+			logical_address - self.logical_address;
+
+		let num_stripes =
+			self.stripes.len () as u64;
+
+		let stripe_number =
+			offset / self.stripe_length;
+
+		let stripe_offset =
+			offset % self.stripe_length;
+
+		let (stripe_indexes, stripe_number): (Vec <u64>, u64) =
+			if self.chunk_type & BTRFS_BLOCK_GROUP_RAID0 != 0 {
+
+			(
+				vec! [ stripe_number % num_stripes ],
+				stripe_number / num_stripes,
+			)
+
+		} else if self.chunk_type & BTRFS_BLOCK_GROUP_RAID10 != 0 {
+
+			let sub_stripes =
+				self.sub_stripes as u64;
+
+			let factor =
+				num_stripes / sub_stripes;
+
+			let first_index =
+				(stripe_number % factor) * sub_stripes;
+
+			(
+				(first_index .. first_index + sub_stripes).collect (),
+				stripe_number / factor,
+			)
+
+		} else if self.chunk_type & (
+			BTRFS_BLOCK_GROUP_RAID5 | BTRFS_BLOCK_GROUP_RAID6
+		) != 0 {
+
+			// parity rotates through the stripes
+
+			let data_stripes =
+				self.data_stripes ();
+
+			let full_stripe_number =
+				stripe_number / data_stripes;
+
+			(
+				vec! [
+					(full_stripe_number + stripe_number % data_stripes)
+						% num_stripes,
+				],
+				full_stripe_number,
+			)
+
+		} else {
+
+			// single, dup and mirrored profiles
+
+			(
+				(0 .. num_stripes).collect (),
+				stripe_number,
+			)
+
+		};
+
+		stripe_indexes.into_iter ().map (
+			|stripe_index| {
+
+			let stripe =
+				& self.stripes [stripe_index as usize];
+
+			(
+				stripe.device_id,
+				stripe.offset
+					+ stripe_number * self.stripe_length
+					+ stripe_offset,
+			)
+
+		}).collect ()
+
+	}
+
+}
+
+// chunks by logical address
+
+#[ derive (Clone, Debug, Default) ]
+pub struct ChunkMap {
+	chunks: BTreeMap <u64, Chunk>,
+}
+
+impl ChunkMap {
+
+	pub fn new (
+	) -> ChunkMap {
+
+		Default::default ()
+
+	}
+
+	pub fn insert (
+		& mut self,
+		chunk: Chunk,
+	) {
+
+		self.chunks.insert (
+			chunk.logical_address,
+			chunk);
+
+	}
+
+	pub fn len (
+		& self,
+	) -> usize {
+
+		self.chunks.len ()
+
+	}
+
+	pub fn chunks (
+		& self,
+	) -> Vec <& Chunk> {
+
+		self.chunks.values ().collect ()
+
+	}
+
+	// whether any chunk covers part of a logical range
+
+	pub fn overlaps (
+		& self,
+		logical_address: u64,
+		length: u64,
+	) -> bool {
+
+		self.chunks.range (
+			.. logical_address.saturating_add (length),
+		).next_back ().map_or (
+			false,
+			|(_, chunk)|
+
+			logical_address < chunk.logical_address + chunk.length
+
+		)
+
+	}
+
+	pub fn chunk (
+		& self,
+		logical_address: u64,
+	) -> Option <& Chunk> {
+
+		self.chunks.range (
+			.. logical_address + 1,
+		).next_back ().and_then (
+			|(_, chunk)|
+
+			if logical_address < chunk.logical_address + chunk.length {
+				Some (chunk)
+			} else {
+				None
+			}
+
+		)
+
+	}
+
+	pub fn map (
+		& self,
+		logical_address: u64,
+	) -> Vec <(u64, u64)> {
+
+		self.chunk (
+			logical_address,
+		).map_or (
+			Vec::new (),
+			|chunk| chunk.map (logical_address),
+		)
+
+	}
+
+	// the physical ranges on a device used by metadata and system chunks
+
+	pub fn metadata_ranges (
+		& self,
+		device_id: u64,
+	) -> Vec <DeviceRange> {
+
+		let mut ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		for chunk in self.chunks.values () {
+
+			if ! chunk.is_metadata () {
+				continue;
+			}
+
+			for stripe in chunk.stripes.iter () {
+
+				if stripe.device_id != device_id {
+					continue;
+				}
+
+				ranges.push (
+					DeviceRange {
+						start: stripe.offset,
+						end: stripe.offset + chunk.device_length (),
+					});
+
+			}
+
+		}
+
+		ranges.sort_by_key (
+			|range| range.start);
+
+		ranges
+
+	}
+
+}
+
+// parses a chunk item, returning none if it doesn't make sense
+
+pub fn chunk_parse (
+	logical_address: u64,
+	bytes: & [u8],
+) -> Option <Chunk> {
+
+	if bytes.len () < BTRFS_CHUNK_ITEM_SIZE {
+		return None;
+	}
+
+	let num_stripes =
+		le_u16 (bytes, 0x2c) as usize;
+
+	if (
+		num_stripes == 0
+		|| bytes.len ()
+			< BTRFS_CHUNK_ITEM_SIZE + num_stripes * BTRFS_CHUNK_STRIPE_SIZE
+	) {
+		return None;
+	}
+
+	let stripes: Vec <ChunkStripe> =
+		(0 .. num_stripes).map (
+			|stripe_index| {
+
+			let stripe_start =
+				BTRFS_CHUNK_ITEM_SIZE
+					+ stripe_index * BTRFS_CHUNK_STRIPE_SIZE;
+
+			ChunkStripe {
+				device_id: le_u64 (bytes, stripe_start),
+				offset: le_u64 (bytes, stripe_start + 8),
+			}
+
+		}).collect ();
+
+	let chunk = Chunk {
+		logical_address: logical_address,
+		length: le_u64 (bytes, 0x00),
+		stripe_length: le_u64 (bytes, 0x10),
+		chunk_type: le_u64 (bytes, 0x18),
+		sub_stripes: le_u16 (bytes, 0x2e),
+		stripes: stripes,
+	};
+
+	if (
+		chunk.length == 0
+		|| chunk.stripe_length == 0
+		|| (chunk.chunk_type & BTRFS_BLOCK_GROUP_RAID10 != 0
+			&& chunk.sub_stripes == 0)
+		|| chunk.data_stripes () == 0
+	) {
+		return None;
+	}
+
+	Some (chunk)
+
+}
+
+// reads the system chunks from the array in the superblock, which are
+// enough to find the chunk tree
+
+pub fn chunks_from_superblock (
+	superblock_bytes: & [u8],
+) -> Result <ChunkMap, String> {
+
+	let mut chunk_map =
+		ChunkMap::new ();
+
+	let array_size =
+		le_u32 (
+			superblock_bytes,
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_SIZE_OFFSET,
+		) as usize;
+
+	if array_size > BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_MAX {
+
+		return Err (
+			format! (
+				"Invalid system chunk array size {}",
+				array_size));
+
+	}
+
+	let array_bytes =
+		& superblock_bytes [
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET
+		..
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET + array_size
+		];
+
+	let mut position: usize = 0;
+
+	while position + BTRFS_DISK_KEY_SIZE <= array_bytes.len () {
+
+		let key_type =
+			array_bytes [position + 8];
+
+		let logical_address =
+			le_u64 (array_bytes, position + 9);
+
+		if key_type != BTRFS_CHUNK_ITEM_TYPE {
+
+			return Err (
+				format! (
+					"Invalid item type {} in system chunk array",
+					key_type));
+
+		}
+
+		position += BTRFS_DISK_KEY_SIZE;
+
+		let chunk =
+			chunk_parse (
+				logical_address,
+				& array_bytes [position ..],
+			).ok_or_else (
+				|| "Invalid chunk in system chunk array".to_owned (),
+			) ?;
+
+		position +=
+			BTRFS_CHUNK_ITEM_SIZE
+				+ chunk.stripes.len () * BTRFS_CHUNK_STRIPE_SIZE;
+
+		chunk_map.insert (
+			chunk);
+
+	}
+
+	Ok (chunk_map)
+
+}
+
+// reads the chunk tree from a single device, starting from the system
+// chunks in the superblock. only copies of nodes on this device can be read.
+
+pub fn chunks_read_tree (
+	reader: & DeviceReader,
+	device_id: u64,
+	node_size: u32,
+	csum_type: u16,
+	superblock_bytes: & [u8],
+) -> Result <ChunkMap, String> {
+
+	chunks_walk_tree (
+		superblock_bytes,
+		|chunk_map, logical_address|
+
+		chunks_read_node (
+			reader,
+			device_id,
+			node_size,
+			csum_type,
+			chunk_map,
+			logical_address)
+
+	)
+
+}
+
+// walks the chunk tree, given a function which reads a node using the
+// chunks found so far
+
+pub fn chunks_walk_tree <ReadNode> (
+	superblock_bytes: & [u8],
+	mut read_node: ReadNode,
+) -> Result <ChunkMap, String>
+where ReadNode: FnMut (& ChunkMap, u64) -> Result <Vec <u8>, String> {
+
+	let mut chunk_map =
+		chunks_from_superblock (
+			superblock_bytes,
+		) ?;
+
+	let chunk_root =
+		le_u64 (
+			superblock_bytes,
+			BTRFS_SUPERBLOCK_CHUNK_ROOT_OFFSET);
+
+	let mut pending: Vec <u64> =
+		vec! [ chunk_root ];
+
+	let mut num_nodes: u64 = 0;
+
+	while let Some (logical_address) = pending.pop () {
+
+		num_nodes += 1;
+
+		if num_nodes > 0x10000 {
+
+			return Err (
+				"Too many nodes in chunk tree".to_owned ());
+
+		}
+
+		let node_bytes =
+			read_node (
+				& chunk_map,
+				logical_address,
+			) ?;
+
+		let level =
+			node_bytes [0x64];
+
+		let num_items =
+			le_u32 (& node_bytes, 0x60) as usize;
+
+		let item_size =
+			if level == 0 {
+				BTRFS_LEAF_ITEM_SIZE
+			} else {
+				BTRFS_INTERNAL_ITEM_SIZE
+			};
+
+		if BTRFS_NODE_HEADER_SIZE + num_items * item_size > node_bytes.len () {
+
+			return Err (
+				format! (
+					"Too many items in chunk tree node at 0x{:x}",
+					logical_address));
+
+		}
+
+		if level > 0 {
+
+			for item_index in 0 .. num_items {
+
+				pending.push (
+					le_u64 (
+						& node_bytes,
+						BTRFS_NODE_HEADER_SIZE + item_index * item_size
+							+ BTRFS_DISK_KEY_SIZE));
+
+			}
+
+			continue;
+
+		}
+
+		for (key, data) in chunks_leaf_items (& node_bytes) {
+
+			if key.item_type != BTRFS_CHUNK_ITEM_TYPE {
+				continue;
+			}
+
+			if let Some (chunk) =
+				chunk_parse (
+					key.offset,
+					data) {
+
+				chunk_map.insert (
+					chunk);
+
+			}
+
+		}
+
+	}
+
+	Ok (chunk_map)
+
+}
+
+fn chunks_read_node (
+	reader: & DeviceReader,
+	device_id: u64,
+	node_size: u32,
+	csum_type: u16,
+	chunk_map: & ChunkMap,
+	logical_address: u64,
+) -> Result <Vec <u8>, String> {
+
+	let mut node_bytes: Vec <u8> =
+		vec! [0; node_size as usize];
+
+	for (stripe_device_id, position) in chunk_map.map (logical_address) {
+
+		if stripe_device_id != device_id {
+			continue;
+		}
+
+		if reader.read_at (position, & mut node_bytes).is_err () {
+			continue;
+		}
+
+		if (
+			le_u64 (& node_bytes, 0x30) == logical_address
+			&& checksum_verify (csum_type, & node_bytes) != Some (false)
+		) {
+			return Ok (node_bytes);
+		}
+
+	}
+
+	Err (
+		format! (
+			"Unable to read chunk tree node at 0x{:x} from device {}",
+			logical_address,
+			device_id))
+
+}
+
+// returns the key and data of each item in a leaf, skipping any whose data
+// falls outside the node
+
+pub fn chunks_leaf_items (
+	node_bytes: & [u8],
+) -> Vec <(IndexKey, & [u8])> {
+
+	let mut items: Vec <(IndexKey, & [u8])> =
+		Vec::new ();
+
+	if node_bytes.len () < BTRFS_NODE_HEADER_SIZE {
+		return items;
+	}
+
+	let num_items =
+		cmp::min (
+			le_u32 (node_bytes, 0x60) as usize,
+			(node_bytes.len () - BTRFS_NODE_HEADER_SIZE)
+				/ BTRFS_LEAF_ITEM_SIZE);
+
+	for item_index in 0 .. num_items {
+
+		let item_start =
+			BTRFS_NODE_HEADER_SIZE + item_index * BTRFS_LEAF_ITEM_SIZE;
+
+		let data_start =
+			BTRFS_NODE_HEADER_SIZE
+				+ le_u32 (node_bytes, item_start + 0x11) as usize;
+
+		let data_size =
+			le_u32 (node_bytes, item_start + 0x15) as usize;
+
+		if data_start + data_size > node_bytes.len () {
+			continue;
+		}
+
+		items.push ((
+			IndexKey::from_bytes (
+				node_bytes,
+				item_start),
+			& node_bytes [data_start .. data_start + data_size],
+		));
+
+	}
+
+	items
+
+}
+
+// a device extent records which chunk owns a range of a device. they live in
+// the device tree, separately from the chunk items, so each can be used to
+// check the other.
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct DevExtent {
+	pub device_id: u64,
+	pub offset: u64,
+	pub length: u64,
+	pub chunk_logical_address: u64,
+}
+
+pub fn dev_extent_parse (
+	key: & IndexKey,
+	bytes: & [u8],
+) -> Option <DevExtent> {
+
+	if bytes.len () < BTRFS_DEV_EXTENT_ITEM_SIZE {
+		return None;
+	}
+
+	let dev_extent = DevExtent {
+		device_id: key.object_id,
+		offset: key.offset,
+		length: le_u64 (bytes, 0x18),
+		chunk_logical_address: le_u64 (bytes, 0x10),
+	};
+
+	if dev_extent.length == 0 {
+		return None;
+	}
+
+	Some (dev_extent)
+
+}
+
+// rebuilds a chunk map from chunk and device extent items found in leaves
+// anywhere on the devices, for when the system chunk array or the chunk tree
+// can't be read. every copy of every item is collected, from current and
+// stale nodes alike, so duplicates are resolved when the map is built.
+
+#[ derive (Clone, Debug, Default) ]
+pub struct ChunkRebuild {
+	chunks: Vec <ChunkRebuildItem <Chunk>>,
+	dev_extents: Vec <ChunkRebuildItem <DevExtent>>,
+}
+
+#[ derive (Clone, Debug) ]
+struct ChunkRebuildItem <Item> {
+	item: Item,
+	generation: u64,
+	valid: bool,
+}
+
+#[ derive (Clone, Debug, Default) ]
+pub struct ChunkRebuildResult {
+	pub chunk_map: ChunkMap,
+	pub num_chunk_items: u64,
+	pub num_dev_extent_items: u64,
+	pub num_confirmed: u64,
+	pub num_unconfirmed: u64,
+	pub num_contradicted: u64,
+	pub num_discarded: u64,
+}
+
+#[ derive (Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd) ]
+enum ChunkRebuildStatus {
+	Contradicted,
+	Unconfirmed,
+	Confirmed,
+}
+
+impl ChunkRebuild {
+
+	pub fn new (
+	) -> ChunkRebuild {
+
+		Default::default ()
+
+	}
+
+	// whether an indexed node is a leaf which may hold items we collect
+
+	pub fn wants_node (
+		index_node: & IndexNode,
+	) -> bool {
+
+		index_node.level == 0
+		&& index_node.validity != IndexNodeValidity::Broken
+		&& (
+			index_node.may_contain_item_type (
+				BTRFS_CHUNK_ITEM_TYPE)
+			|| index_node.may_contain_item_type (
+				BTRFS_DEV_EXTENT_ITEM_TYPE)
+		)
+
+	}
+
+	// collects the chunk and device extent items from a leaf, given the
+	// generation from its header and whether its checksum matched
+
+	pub fn add_leaf (
+		& mut self,
+		node_bytes: & [u8],
+		generation: u64,
+		valid: bool,
+	) {
+
+		for (key, data) in chunks_leaf_items (node_bytes) {
+
+			if key.item_type == BTRFS_CHUNK_ITEM_TYPE {
+
+				if let Some (chunk) =
+					chunk_parse (
+						key.offset,
+						data) {
+
+					self.chunks.push (
+						ChunkRebuildItem {
+							item: chunk,
+							generation: generation,
+							valid: valid,
+						});
+
+				}
+
+			} else if key.item_type == BTRFS_DEV_EXTENT_ITEM_TYPE {
+
+				if let Some (dev_extent) =
+					dev_extent_parse (
+						& key,
+						data) {
+
+					self.dev_extents.push (
+						ChunkRebuildItem {
+							item: dev_extent,
+							generation: generation,
+							valid: valid,
+						});
+
+				}
+
+			}
+
+		}
+
+	}
+
+	// picks one chunk for each part of the logical address space. chunks
+	// whose stripes all agree with the device extents are preferred, then
+	// those from leaves with good checksums, then the most recent. anything
+	// overlapping a chunk already picked is discarded.
+
+	pub fn build (
+		& self,
+	) -> ChunkRebuildResult {
+
+		let mut result =
+			ChunkRebuildResult {
+				num_chunk_items: self.chunks.len () as u64,
+				num_dev_extent_items: self.dev_extents.len () as u64,
+				.. Default::default ()
+			};
+
+		// pick the best copy of each device extent
+
+		let mut dev_extents: HashMap <(u64, u64), & ChunkRebuildItem <DevExtent>> =
+			HashMap::new ();
+
+		for dev_extent in self.dev_extents.iter () {
+
+			let best_dev_extent =
+				dev_extents.entry (
+					(dev_extent.item.device_id, dev_extent.item.offset),
+				).or_insert (
+					dev_extent);
+
+			if (dev_extent.valid, dev_extent.generation)
+				> (best_dev_extent.valid, best_dev_extent.generation) {
+
+				* best_dev_extent =
+					dev_extent;
+
+			}
+
+		}
+
+		// rank the chunks
+
+		let mut chunks: Vec <(ChunkRebuildStatus, & ChunkRebuildItem <Chunk>)> =
+			self.chunks.iter ().map (
+				|chunk|
+
+				(
+					Self::chunk_status (
+						& dev_extents,
+						& chunk.item),
+					chunk,
+				)
+
+			).collect ();
+
+		chunks.sort_by (
+			|& (left_status, left), & (right_status, right)|
+
+			(right_status, right.valid, right.generation).cmp (
+				& (left_status, left.valid, left.generation))
+
+		);
+
+		// pick them in order
+
+		for (status, chunk) in chunks {
+
+			if result.chunk_map.overlaps (
+				chunk.item.logical_address,
+				chunk.item.length) {
+
+				result.num_discarded += 1;
+
+				continue;
+
+			}
+
+			match status {
+
+				ChunkRebuildStatus::Confirmed =>
+					result.num_confirmed += 1,
+
+				ChunkRebuildStatus::Unconfirmed =>
+					result.num_unconfirmed += 1,
+
+				ChunkRebuildStatus::Contradicted =>
+					result.num_contradicted += 1,
+
+			}
+
+			result.chunk_map.insert (
+				chunk.item.clone ());
+
+		}
+
+		result
+
+	}
+
+	// a chunk is contradicted if any of its stripes is claimed by a device
+	// extent for a different chunk, and confirmed if every stripe has a
+	// matching one
+
+	fn chunk_status (
+		dev_extents: & HashMap <(u64, u64), & ChunkRebuildItem <DevExtent>>,
+		chunk: & Chunk,
+	) -> ChunkRebuildStatus {
+
+		let mut status =
+			ChunkRebuildStatus::Confirmed;
+
+		for stripe in chunk.stripes.iter () {
+
+			match dev_extents.get (
+				& (stripe.device_id, stripe.offset)) {
+
+				Some (dev_extent) => {
+
+					if (
+						dev_extent.item.chunk_logical_address
+							!= chunk.logical_address
+						|| dev_extent.item.length != chunk.device_length ()
+					) {
+						return ChunkRebuildStatus::Contradicted;
+					}
+
+				},
+
+				None =>
+					status = ChunkRebuildStatus::Unconfirmed,
+
+			}
+
+		}
+
+		status
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	const STRIPE_LENGTH: u64 = 0x1_0000;
+
+	// a chunk at 1 GiB, with stripe n on device n, 16 MiB times n into it
+
+	fn chunk (
+		chunk_type: u64,
+		num_stripes: u64,
+		sub_stripes: u16,
+		length: u64,
+	) -> Chunk {
+
+		Chunk {
+			logical_address: 0x4000_0000,
+			length: length,
+			stripe_length: STRIPE_LENGTH,
+			chunk_type: chunk_type,
+			sub_stripes: sub_stripes,
+			stripes: (1 .. num_stripes + 1).map (
+				|device_id|
+
+				ChunkStripe {
+					device_id: device_id,
+					offset: device_id * 0x100_0000,
+				}
+
+			).collect (),
+		}
+
+	}
+
+	fn map (
+		chunk: & Chunk,
+		offset: u64,
+	) -> Vec <(u64, u64)> {
+
+		chunk.map (
+			chunk.logical_address + offset)
+
+	}
+
+	#[ test ]
+	fn single_and_dup () {
+
+		let single =
+			chunk (BTRFS_BLOCK_GROUP_METADATA, 1, 0, 0x100_0000);
+
+		assert_eq! (
+			map (& single, 0x2_1234),
+			vec! [ (1, 0x102_1234) ]);
+
+		let dup =
+			ChunkMap {
+				chunks: vec! [
+					(0x4000_0000, Chunk {
+						stripes: vec! [
+							ChunkStripe { device_id: 1, offset: 0x100_0000 },
+							ChunkStripe { device_id: 1, offset: 0x300_0000 },
+						],
+						.. single.clone ()
+					}),
+				].into_iter ().collect (),
+			};
+
+		assert_eq! (
+			dup.map (0x4000_5000),
+			vec! [ (1, 0x100_5000), (1, 0x300_5000) ]);
+
+		assert_eq! (dup.map (0x3fff_f000), vec! []);
+		assert_eq! (dup.map (0x4100_0000), vec! []);
+
+	}
+
+	#[ test ]
+	fn raid0 () {
+
+		let raid0 =
+			chunk (BTRFS_BLOCK_GROUP_RAID0, 3, 0, 0x30_0000);
+
+		assert_eq! (map (& raid0, 0x1234), vec! [ (1, 0x100_1234) ]);
+		assert_eq! (map (& raid0, 0x1_1234), vec! [ (2, 0x200_1234) ]);
+		assert_eq! (map (& raid0, 0x2_1234), vec! [ (3, 0x300_1234) ]);
+		assert_eq! (map (& raid0, 0x3_1234), vec! [ (1, 0x101_1234) ]);
+		assert_eq! (map (& raid0, 0x4_0000), vec! [ (2, 0x201_0000) ]);
+
+		assert_eq! (raid0.device_length (), 0x10_0000);
+
+	}
+
+	#[ test ]
+	fn raid10 () {
+
+		let raid10 =
+			chunk (BTRFS_BLOCK_GROUP_RAID10, 4, 2, 0x20_0000);
+
+		assert_eq! (
+			map (& raid10, 0x1234),
+			vec! [ (1, 0x100_1234), (2, 0x200_1234) ]);
+
+		assert_eq! (
+			map (& raid10, 0x1_1234),
+			vec! [ (3, 0x300_1234), (4, 0x400_1234) ]);
+
+		assert_eq! (
+			map (& raid10, 0x2_1234),
+			vec! [ (1, 0x101_1234), (2, 0x201_1234) ]);
+
+		assert_eq! (raid10.device_length (), 0x10_0000);
+
+	}
+
+	#[ test ]
+	fn raid5_rotates_parity () {
+
+		let raid5 =
+			chunk (BTRFS_BLOCK_GROUP_RAID5, 3, 0, 0x20_0000);
+
+		// parity on device 3, then device 1, then device 2
+
+		assert_eq! (map (& raid5, 0x0_1234), vec! [ (1, 0x100_1234) ]);
+		assert_eq! (map (& raid5, 0x1_1234), vec! [ (2, 0x200_1234) ]);
+		assert_eq! (map (& raid5, 0x2_1234), vec! [ (2, 0x201_1234) ]);
+		assert_eq! (map (& raid5, 0x3_1234), vec! [ (3, 0x301_1234) ]);
+		assert_eq! (map (& raid5, 0x4_1234), vec! [ (3, 0x302_1234) ]);
+		assert_eq! (map (& raid5, 0x5_1234), vec! [ (1, 0x102_1234) ]);
+
+		assert_eq! (raid5.device_length (), 0x10_0000);
+
+	}
+
+	#[ test ]
+	fn raid6_rotates_parity () {
+
+		let raid6 =
+			chunk (BTRFS_BLOCK_GROUP_RAID6, 4, 0, 0x20_0000);
+
+		assert_eq! (map (& raid6, 0x0_1234), vec! [ (1, 0x100_1234) ]);
+		assert_eq! (map (& raid6, 0x1_1234), vec! [ (2, 0x200_1234) ]);
+		assert_eq! (map (& raid6, 0x2_1234), vec! [ (2, 0x201_1234) ]);
+		assert_eq! (map (& raid6, 0x3_1234), vec! [ (3, 0x301_1234) ]);
+		assert_eq! (map (& raid6, 0x4_1234), vec! [ (3, 0x302_1234) ]);
+		assert_eq! (map (& raid6, 0x5_1234), vec! [ (4, 0x402_1234) ]);
+
+		assert_eq! (raid6.device_length (), 0x10_0000);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
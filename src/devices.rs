@@ -0,0 +1,756 @@
+use std::cmp;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use libc;
+
+use images::*;
+use mapfile::*;
+
+// lseek whence values for finding data in sparse files, which this version
+// of libc doesn't define
+
+const SEEK_DATA: libc::c_int = 3;
+const SEEK_HOLE: libc::c_int = 4;
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub struct DeviceRange {
+	pub start: u64,
+	pub end: u64,
+}
+
+impl DeviceRange {
+
+	pub fn overlaps (
+		& self,
+		start: u64,
+		end: u64,
+	) -> bool {
+
+		self.start < end && start < self.end
+
+	}
+
+}
+
+// returns the parts of two sorted lists of ranges which are in both
+
+pub fn device_ranges_intersect (
+	left: & [DeviceRange],
+	right: & [DeviceRange],
+) -> Vec <DeviceRange> {
+
+	let mut ranges: Vec <DeviceRange> =
+		Vec::new ();
+
+	let mut left_index: usize = 0;
+	let mut right_index: usize = 0;
+
+	while left_index < left.len () && right_index < right.len () {
+
+		let left_range = left [left_index];
+		let right_range = right [right_index];
+
+		let start = cmp::max (left_range.start, right_range.start);
+		let end = cmp::min (left_range.end, right_range.end);
+
+		if start < end {
+
+			ranges.push (
+				DeviceRange {
+					start: start,
+					end: end,
+				});
+
+		}
+
+		if left_range.end < right_range.end {
+			left_index += 1;
+		} else {
+			right_index += 1;
+		}
+
+	}
+
+	ranges
+
+}
+
+// pread based access to a device or image. unlike an mmap, a read error
+// comes back as an error rather than killing the process with SIGBUS. the
+// filesystem can start at an offset, for partitions in a whole disk image,
+// and all positions are relative to that. virtual machine disk images are
+// read through the image, and positions are on the virtual disk.
+
+pub struct DeviceReader {
+	path: PathBuf,
+	file: File,
+	image: Option <Box <Image>>,
+	offset: u64,
+	size: u64,
+}
+
+impl DeviceReader {
+
+	pub fn open (
+		path: & Path,
+	) -> Result <DeviceReader, String> {
+
+		Self::open_at (
+			path,
+			0)
+
+	}
+
+	pub fn open_at (
+		path: & Path,
+		offset: u64,
+	) -> Result <DeviceReader, String> {
+
+		let mut file =
+			File::open (
+				path,
+			).map_err (
+				|error|
+
+				format! (
+					"Error opening {}: {}",
+					path.to_string_lossy (),
+					error.description ())
+
+			) ?;
+
+		let image =
+			image_open (
+				path,
+				& file,
+				& [],
+			) ?;
+
+		// seek to find the size, since metadata reports zero for block
+		// devices
+
+		let size =
+			match image {
+
+			Some (ref image) =>
+				image.size (),
+
+			None =>
+				file.seek (
+					SeekFrom::End (0),
+				).map_err (
+					|error|
+
+					format! (
+						"Error finding size of {}: {}",
+						path.to_string_lossy (),
+						error.description ())
+
+				) ?,
+
+		};
+
+		if offset >= size {
+
+			return Err (
+				format! (
+					"Offset 0x{:x} is beyond the end of {}",
+					offset,
+					path.to_string_lossy ()));
+
+		}
+
+		Ok (DeviceReader {
+			path: path.to_owned (),
+			file: file,
+			image: image,
+			offset: offset,
+			size: size - offset,
+		})
+
+	}
+
+	pub fn path (& self) -> & Path {
+		& self.path
+	}
+
+	pub fn size (& self) -> u64 {
+		self.size
+	}
+
+	// the image, if this is a virtual machine disk image rather than raw
+
+	pub fn image (& self) -> Option <& (Image + 'static)> {
+		self.image.as_ref ().map (|image| & ** image)
+	}
+
+	pub fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		if let Some (ref image) = self.image {
+
+			return image.read_at (
+				self.offset + offset,
+				buffer);
+
+		}
+
+		file_read_at (
+			& self.file,
+			self.offset + offset,
+			buffer)
+
+	}
+
+	// finds the parts of a range which contain data, using SEEK_DATA and
+	// SEEK_HOLE, or the allocation tables of an image. if the file system or
+	// device doesn't support them, the whole range is returned.
+
+	pub fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> Result <Vec <DeviceRange>, String> {
+
+		if let Some (ref image) = self.image {
+
+			return image.data_ranges (
+				self.offset + start,
+				self.offset + end,
+			).map (
+				|image_ranges|
+
+				image_ranges.into_iter ().map (
+					|image_range|
+
+					DeviceRange {
+						start: image_range.start - self.offset,
+						end: image_range.end - self.offset,
+					}
+
+				).collect ()
+
+			).map_err (
+				|error|
+
+				format! (
+					"Error finding data in {}: {}",
+					self.path.to_string_lossy (),
+					error.description ())
+
+			);
+
+		}
+
+		let mut data_ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		let mut position = start;
+
+		while position < end {
+
+			let data_start =
+				match self.seek (position, SEEK_DATA) {
+
+				Ok (data_start) =>
+					data_start,
+
+				// no more data
+
+				Err (ref error)
+				if error.raw_os_error () == Some (libc::ENXIO) =>
+					break,
+
+				// not supported
+
+				Err (ref error)
+				if error.raw_os_error () == Some (libc::EINVAL)
+					&& data_ranges.is_empty () =>
+					return Ok (vec! [
+						DeviceRange {
+							start: start,
+							end: end,
+						},
+					]),
+
+				Err (error) =>
+					return Err (
+						format! (
+							"Error finding data in {} at 0x{:x}: {}",
+							self.path.to_string_lossy (),
+							position,
+							error.description ())),
+
+			};
+
+			if data_start >= end {
+				break;
+			}
+
+			let data_end =
+				match self.seek (data_start, SEEK_HOLE) {
+
+				Ok (data_end) =>
+					cmp::min (data_end, end),
+
+				Err (error) =>
+					return Err (
+						format! (
+							"Error finding hole in {} at 0x{:x}: {}",
+							self.path.to_string_lossy (),
+							data_start,
+							error.description ())),
+
+			};
+
+			data_ranges.push (
+				DeviceRange {
+					start: data_start,
+					end: data_end,
+				});
+
+			position = data_end;
+
+		}
+
+		Ok (data_ranges)
+
+	}
+
+	fn seek (
+		& self,
+		offset: u64,
+		whence: libc::c_int,
+	) -> io::Result <u64> {
+
+		let result = unsafe {
+			libc::lseek64 (
+				self.file.as_raw_fd (),
+				(self.offset + offset) as libc::off64_t,
+				whence)
+		};
+
+		if result < 0 {
+			Err (io::Error::last_os_error ())
+		} else {
+			Ok (result as u64 - self.offset)
+		}
+
+	}
+
+	// an I/O error from the device, or data in an image which can't be
+	// decoded, such as a corrupt compressed cluster. either way only that
+	// part is lost, and the rest of the device can still be read.
+
+	fn unreadable_error (
+		& self,
+		error: & io::Error,
+	) -> bool {
+
+		error.raw_os_error () == Some (libc::EIO)
+			|| (self.image.is_some ()
+				&& error.kind () == io::ErrorKind::InvalidData)
+
+	}
+
+	// reads a block, falling back to reading one sector at a time if the
+	// device reports an I/O error. unreadable sectors are zero filled and
+	// returned as a list of ranges.
+
+	pub fn read_tolerant (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+		sector_size: usize,
+	) -> Result <Vec <DeviceRange>, String> {
+
+		match self.read_at (offset, buffer) {
+
+			Ok (()) =>
+				return Ok (Vec::new ()),
+
+			Err (ref error)
+			if self.unreadable_error (error) =>
+				(),
+
+			Err (error) =>
+				return Err (
+					format! (
+						"Error reading {} at 0x{:x}: {}",
+						self.path.to_string_lossy (),
+						offset,
+						error.description ())),
+
+		}
+
+		let mut bad_ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		let mut sector_start: usize = 0;
+
+		while sector_start < buffer.len () {
+
+			let sector_end =
+				cmp::min (
+					sector_start + sector_size,
+					buffer.len ());
+
+			match self.read_at (
+				offset + sector_start as u64,
+				& mut buffer [sector_start .. sector_end],
+			) {
+
+				Ok (()) =>
+					(),
+
+				Err (ref error)
+				if self.unreadable_error (error) => {
+
+					for byte in buffer [sector_start .. sector_end].iter_mut () {
+						* byte = 0;
+					}
+
+					let range_start = offset + sector_start as u64;
+					let range_end = offset + sector_end as u64;
+
+					let extended =
+						match bad_ranges.last_mut () {
+
+						Some (last_range) =>
+							if last_range.end == range_start {
+								last_range.end = range_end;
+								true
+							} else {
+								false
+							},
+
+						None => false,
+
+					};
+
+					if ! extended {
+
+						bad_ranges.push (
+							DeviceRange {
+								start: range_start,
+								end: range_end,
+							});
+
+					}
+
+				},
+
+				Err (error) =>
+					return Err (
+						format! (
+							"Error reading {} at 0x{:x}: {}",
+							self.path.to_string_lossy (),
+							offset + sector_start as u64,
+							error.description ())),
+
+			}
+
+			sector_start = sector_end;
+
+		}
+
+		Ok (bad_ranges)
+
+	}
+
+}
+
+// a set of devices, read with pread through their readers. each read is into
+// a new buffer, owned by the caller, so nothing is kept here but the ranges
+// which couldn't be read. unreadable sectors are zero filled and recorded, so
+// they can be reported against whatever was being restored when they were
+// read.
+
+pub struct DeviceSet {
+	devices: Vec <Device>,
+	unreadable_ranges: Mutex <Vec <DeviceUnreadableRange>>,
+}
+
+struct Device {
+	reader: DeviceReader,
+	mapfile: Option <Mapfile>,
+}
+
+// the granularity at which unreadable parts of a block are found
+
+const DEVICE_SECTOR_SIZE: usize = 0x200;
+
+#[ derive (Clone, Debug) ]
+pub struct DeviceUnreadableRange {
+	pub path: PathBuf,
+	pub range: DeviceRange,
+}
+
+impl DeviceSet {
+
+	pub fn open (
+		paths: & [PathBuf],
+		offsets: & [u64],
+		mapfile_paths: & [PathBuf],
+	) -> Result <DeviceSet, String> {
+
+		let mapfiles =
+			mapfiles_load (
+				paths,
+				mapfile_paths,
+			) ?;
+
+		let mut devices: Vec <Device> =
+			Vec::new ();
+
+		for ((path, offset), mapfile)
+		in paths.iter ().zip (offsets.iter ()).zip (mapfiles.into_iter ()) {
+
+			let reader =
+				DeviceReader::open_at (
+					path,
+					* offset,
+				) ?;
+
+			if let Some (image) = reader.image () {
+
+				if mapfile.is_some () {
+
+					return Err (
+						format! (
+							"Can't use a mapfile with {}, which is a {}",
+							path.to_string_lossy (),
+							image.description ()));
+
+				}
+
+			}
+
+			devices.push (
+				Device {
+					reader: reader,
+					mapfile: mapfile.map (
+						|mapfile| mapfile.rebase (* offset)),
+				});
+
+		}
+
+		Ok (DeviceSet {
+			devices: devices,
+			unreadable_ranges: Mutex::new (Vec::new ()),
+		})
+
+	}
+
+	pub fn readers (
+		& self,
+	) -> Vec <& DeviceReader> {
+
+		self.devices.iter ().map (
+			|device|
+
+			& device.reader
+
+		).collect ()
+
+	}
+
+	pub fn device_size (
+		& self,
+		device_index: usize,
+	) -> u64 {
+
+		self.devices [device_index].reader.size ()
+
+	}
+
+	pub fn mapfiles (
+		& self,
+	) -> Vec <& Mapfile> {
+
+		self.devices.iter ().filter_map (
+			|device|
+
+			device.mapfile.as_ref ()
+
+		).collect ()
+
+	}
+
+	// reads a block from a device, in the same order as readers, into a new
+	// buffer
+
+	pub fn read (
+		& self,
+		device_index: usize,
+		offset: u64,
+		size: usize,
+	) -> Result <Vec <u8>, String> {
+
+		let reader =
+			& self.devices [device_index].reader;
+
+		if offset.checked_add (size as u64).map_or (
+			true,
+			|end| end > reader.size (),
+		) {
+
+			return Err (
+				format! (
+					"Read of 0x{:x} bytes at 0x{:x} is beyond the end of {}",
+					size,
+					offset,
+					reader.path ().to_string_lossy ()));
+
+		}
+
+		let mut buffer: Vec <u8> =
+			vec! [0; size];
+
+		let bad_ranges =
+			reader.read_tolerant (
+				offset,
+				& mut buffer,
+				DEVICE_SECTOR_SIZE,
+			) ?;
+
+		if ! bad_ranges.is_empty () {
+
+			let mut unreadable_ranges =
+				self.unreadable_ranges.lock ().unwrap ();
+
+			for bad_range in bad_ranges {
+
+				unreadable_ranges.push (
+					DeviceUnreadableRange {
+						path: reader.path ().to_owned (),
+						range: bad_range,
+					});
+
+			}
+
+		}
+
+		Ok (buffer)
+
+	}
+
+	// returns the parts of a range of a device which ddrescue has not
+	// rescued, according to its mapfile
+
+	pub fn unrescued_ranges (
+		& self,
+		device_index: usize,
+		start: u64,
+		end: u64,
+	) -> Vec <DeviceUnreadableRange> {
+
+		let device =
+			& self.devices [device_index];
+
+		let mapfile =
+			match device.mapfile {
+				Some (ref mapfile) => mapfile,
+				None => return Vec::new (),
+			};
+
+		mapfile.unrescued_in (
+			start,
+			end,
+		).into_iter ().map (
+			|range|
+
+			DeviceUnreadableRange {
+				path: device.reader.path ().to_owned (),
+				range: range,
+			}
+
+		).collect ()
+
+	}
+
+	// returns ranges which couldn't be read since the last call
+
+	pub fn take_unreadable_ranges (
+		& self,
+	) -> Vec <DeviceUnreadableRange> {
+
+		mem::replace (
+			& mut * self.unreadable_ranges.lock ().unwrap (),
+			Vec::new ())
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::io::Write;
+	use std::process;
+
+	use super::*;
+
+	#[ test ]
+	fn reads_are_bounded_by_the_device () {
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"btrfs-fuse-devices-{}",
+					process::id ()));
+
+		{
+
+			let mut file =
+				File::create (
+					& path,
+				).unwrap ();
+
+			file.write_all (
+				& (0 .. 0x2000).map (|index| index as u8).collect::<Vec <u8>> (),
+			).unwrap ();
+
+		}
+
+		let device_set =
+			DeviceSet::open (
+				& [ path.clone () ],
+				& [ 0x1000 ],
+				& [],
+			).unwrap ();
+
+		assert_eq! (device_set.device_size (0), 0x1000);
+
+		assert_eq! (
+			device_set.read (0, 0xff0, 0x10).unwrap (),
+			(0x1ff0 .. 0x2000).map (|index| index as u8).collect::<Vec <u8>> ());
+
+		assert! (device_set.read (0, 0xff0, 0x11).is_err ());
+		assert! (device_set.read (0, u64::max_value () - 0x10, 0x20).is_err ());
+
+		assert! (device_set.take_unreadable_ranges ().is_empty ());
+
+		fs::remove_file (
+			& path,
+		).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
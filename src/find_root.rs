@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use output::Output;
+
+use arguments::*;
+use devices::*;
+use index::*;
+use indexed_filesystem::*;
+use partitions::*;
+use trees::*;
+
+// lists the root tree nodes in an index which aren't referenced by any other,
+// and so were once the top of the root tree. each is ranked by how much of
+// the filesystem can still be reached from it, since the most recent root is
+// often too damaged to use.
+
+struct RootCandidate {
+	tree_root: TreeRoot,
+	validity: IndexNodeValidity,
+	num_subvolumes: u64,
+	num_subvolumes_reached: u64,
+	counts: TreeCounts,
+}
+
+pub fn find_root (
+	output: & Output,
+	command: FindRootCommand,
+) -> Result <(), String> {
+
+	let offsets =
+		device_offsets (
+			output,
+			& command.paths,
+			& command.offsets,
+		) ?;
+
+	let device_set =
+		DeviceSet::open (
+			& command.paths,
+			& offsets,
+			& command.mapfiles,
+		) ?;
+
+	let (indexed_filesystem, indexed_nodes) =
+		IndexedFilesystem::open (
+			output,
+			& device_set,
+			& command.index,
+			true,
+			None,
+		) ?;
+
+	let indexed_filesystem =
+		indexed_filesystem.load_nodes (
+			output,
+			& indexed_nodes);
+
+	let tree_roots =
+		find_root_tree_roots (
+			& indexed_filesystem);
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Following {} root tree candidates",
+			tree_roots.len ());
+
+	let mut tree_counter =
+		TreeCounter::new (
+			|logical_address, generation|
+
+			indexed_filesystem.read_node (
+				logical_address,
+				Some (generation)));
+
+	let mut root_candidates: Vec <RootCandidate> =
+		Vec::new ();
+
+	let num_tree_roots =
+		tree_roots.len () as u64;
+
+	for (tree_root, validity) in tree_roots {
+
+		output_job.progress (
+			root_candidates.len () as u64,
+			num_tree_roots);
+
+		root_candidates.push (
+			find_root_follow (
+				& indexed_filesystem,
+				& mut tree_counter,
+				tree_root,
+				validity));
+
+	}
+
+	output_job.complete ();
+
+	find_root_sort (
+		& mut root_candidates);
+
+	find_root_report (
+		output,
+		& command,
+		& root_candidates);
+
+	Ok (())
+
+}
+
+// returns the root tree nodes which no other root tree node points to, with
+// one copy of each, preferring one with a good checksum
+
+fn find_root_tree_roots (
+	indexed_filesystem: & IndexedFilesystem,
+) -> Vec <(TreeRoot, IndexNodeValidity)> {
+
+	let mut referenced: HashSet <(u64, u64)> =
+		HashSet::new ();
+
+	let mut tree_roots: HashMap <TreeRoot, IndexNodeValidity> =
+		HashMap::new ();
+
+	for index_node in indexed_filesystem.root_tree_nodes.iter () {
+
+		let node_bytes =
+			match indexed_filesystem.index_node_bytes (
+				index_node) {
+
+			Ok ((_, node_bytes)) => node_bytes,
+			Err (_) => continue,
+
+		};
+
+		let tree_root =
+			tree_root_from_node (
+				& node_bytes);
+
+		if tree_root.level > 0 {
+
+			referenced.extend (
+				tree_node_children (
+					& node_bytes));
+
+		}
+
+		let validity =
+			tree_roots.entry (
+				tree_root,
+			).or_insert (
+				index_node.validity);
+
+		if index_node.validity == IndexNodeValidity::Valid {
+			* validity = IndexNodeValidity::Valid;
+		}
+
+	}
+
+	let mut tree_roots: Vec <(TreeRoot, IndexNodeValidity)> =
+		tree_roots.into_iter ().filter (
+			|& (ref tree_root, _)|
+
+			! referenced.contains (& (
+				tree_root.logical_address,
+				tree_root.generation,
+			))
+
+		).collect ();
+
+	tree_roots.sort_by (
+		|& (ref left, _), & (ref right, _)|
+
+		right.generation.cmp (
+			& left.generation)
+
+	);
+
+	tree_roots
+
+}
+
+// walks the root tree from a candidate, and counts what can be reached in
+// the most recent version of each subvolume it names
+
+fn find_root_follow <'a, ReadNode> (
+	indexed_filesystem: & IndexedFilesystem <'a>,
+	tree_counter: & mut TreeCounter <ReadNode>,
+	tree_root: TreeRoot,
+	validity: IndexNodeValidity,
+) -> RootCandidate
+where ReadNode: FnMut (u64, u64) -> Result <Vec <u8>, String> {
+
+	let mut subvolume_roots: HashMap <u64, TreeRoot> =
+		HashMap::new ();
+
+	tree_walk (
+		& tree_root,
+		|logical_address, generation|
+
+		indexed_filesystem.read_node (
+			logical_address,
+			Some (generation)),
+
+		|leaf_bytes| {
+
+			for root_item in tree_root_items (leaf_bytes) {
+
+				if ! tree_is_subvolume (root_item.tree_id) {
+					continue;
+				}
+
+				let subvolume_root =
+					subvolume_roots.entry (
+						root_item.tree_id,
+					).or_insert (
+						root_item);
+
+				if root_item.generation > subvolume_root.generation {
+					* subvolume_root = root_item;
+				}
+
+			}
+
+		},
+	);
+
+	let mut root_candidate =
+		RootCandidate {
+			tree_root: tree_root,
+			validity: validity,
+			num_subvolumes: subvolume_roots.len () as u64,
+			num_subvolumes_reached: 0,
+			counts: Default::default (),
+		};
+
+	for subvolume_root in subvolume_roots.values () {
+
+		let subvolume_counts =
+			tree_counter.count (
+				subvolume_root);
+
+		if subvolume_counts.num_nodes > 0 {
+			root_candidate.num_subvolumes_reached += 1;
+		}
+
+		root_candidate.counts.add (
+			& subvolume_counts);
+
+	}
+
+	root_candidate
+
+}
+
+// candidates which reach more subvolumes come first, then more files, then
+// more recent ones
+
+fn find_root_rank (
+	root_candidate: & RootCandidate,
+) -> (u64, u64, u64, u64) {
+
+	(
+		root_candidate.num_subvolumes_reached,
+		root_candidate.counts.num_inodes,
+		root_candidate.counts.num_extent_datas,
+		root_candidate.tree_root.generation,
+	)
+
+}
+
+fn find_root_sort (
+	root_candidates: & mut [RootCandidate],
+) {
+
+	root_candidates.sort_by (
+		|left, right|
+
+		find_root_rank (right).cmp (
+			& find_root_rank (left))
+
+	);
+
+}
+
+fn find_root_report (
+	output: & Output,
+	command: & FindRootCommand,
+	root_candidates: & [RootCandidate],
+) {
+
+	if root_candidates.is_empty () {
+
+		output_message! (
+			output,
+			"No root tree candidates found in {}",
+			command.index.to_string_lossy ());
+
+		return;
+
+	}
+
+	output_message! (
+		output,
+		"Found {} root tree candidates, best first:",
+		root_candidates.len ());
+
+	for root_candidate in root_candidates.iter () {
+
+		output_message! (
+			output,
+			"  0x{:x} generation {} level {} {}: {} of {} subvolumes, {} \
+			inodes, {} extents, {} nodes missing",
+			root_candidate.tree_root.logical_address,
+			root_candidate.tree_root.generation,
+			root_candidate.tree_root.level,
+			root_candidate.validity.name (),
+			root_candidate.num_subvolumes_reached,
+			root_candidate.num_subvolumes,
+			root_candidate.counts.num_inodes,
+			root_candidate.counts.num_extent_datas,
+			root_candidate.counts.num_missing);
+
+	}
+
+	output_message! (
+		output,
+		"Pass one of these to restore with --index {} --root",
+		command.index.to_string_lossy ());
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn candidate (
+		generation: u64,
+		num_subvolumes_reached: u64,
+		num_inodes: u64,
+		num_extent_datas: u64,
+	) -> RootCandidate {
+
+		RootCandidate {
+			tree_root: TreeRoot {
+				tree_id: 1,
+				logical_address: generation * 0x4000,
+				generation: generation,
+				level: 1,
+			},
+			validity: IndexNodeValidity::Valid,
+			num_subvolumes: 3,
+			num_subvolumes_reached: num_subvolumes_reached,
+			counts: TreeCounts {
+				num_nodes: 0,
+				num_missing: 0,
+				num_inodes: num_inodes,
+				num_extent_datas: num_extent_datas,
+			},
+		}
+
+	}
+
+	fn sorted_generations (
+		mut root_candidates: Vec <RootCandidate>,
+	) -> Vec <u64> {
+
+		find_root_sort (
+			& mut root_candidates);
+
+		root_candidates.iter ().map (
+			|root_candidate| root_candidate.tree_root.generation,
+		).collect ()
+
+	}
+
+	#[ test ]
+	fn most_subvolumes_come_first () {
+
+		// the newest root has lost a subvolume, so an older one is better
+
+		assert_eq! (
+			sorted_generations (vec! [
+				candidate (100, 2, 1000, 1000),
+				candidate (99, 3, 10, 10),
+				candidate (98, 1, 5000, 5000),
+			]),
+			vec! [ 99, 100, 98 ]);
+
+	}
+
+	#[ test ]
+	fn most_inodes_then_extents_come_next () {
+
+		assert_eq! (
+			sorted_generations (vec! [
+				candidate (100, 3, 900, 2000),
+				candidate (99, 3, 1000, 500),
+				candidate (98, 3, 1000, 600),
+			]),
+			vec! [ 98, 99, 100 ]);
+
+	}
+
+	#[ test ]
+	fn newest_wins_a_tie () {
+
+		assert_eq! (
+			sorted_generations (vec! [
+				candidate (98, 3, 1000, 1000),
+				candidate (100, 3, 1000, 1000),
+				candidate (99, 3, 1000, 1000),
+			]),
+			vec! [ 100, 99, 98 ]);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,405 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use uuid::Uuid;
+
+use output::Output;
+
+use arguments::*;
+use bytes::*;
+use devices::*;
+use superblock::*;
+
+// searches a whole image for superblocks, at every sector, for when the
+// partition table and any container headers are gone. each superblock
+// records its own position on the device, so the start of the filesystem is
+// wherever that puts it.
+
+const SEARCH_BLOCK_SIZE: u64 = 0x100_0000;
+const SEARCH_ALIGNMENT: u64 = 0x200;
+
+const SUPERBLOCK_FSID_OFFSET: usize = 0x20;
+const SUPERBLOCK_BYTENR_OFFSET: usize = 0x30;
+const SUPERBLOCK_MAGIC_OFFSET: usize = 0x40;
+const SUPERBLOCK_GENERATION_OFFSET: usize = 0x48;
+
+const BTRFS_MAGIC_BYTES: & 'static [u8] = b"_BHRfS_M";
+
+struct FoundSuperblock {
+	position: u64,
+	fs_uuid: [u8; 16],
+	generation: u64,
+	filesystem_start: Option <u64>,
+	error: Option <String>,
+}
+
+pub fn find_superblock (
+	output: & Output,
+	command: FindSuperblockCommand,
+) -> Result <(), String> {
+
+	for path in command.paths.iter () {
+
+		let reader =
+			DeviceReader::open (
+				path,
+			) ?;
+
+		let found_superblocks =
+			find_superblock_device (
+				output,
+				& command,
+				& reader,
+			) ?;
+
+		find_superblock_report (
+			output,
+			& reader,
+			& found_superblocks);
+
+	}
+
+	Ok (())
+
+}
+
+fn find_superblock_device (
+	output: & Output,
+	command: & FindSuperblockCommand,
+	reader: & DeviceReader,
+) -> Result <Vec <FoundSuperblock>, String> {
+
+	let start_position =
+		command.start.unwrap_or (0)
+			/ SEARCH_ALIGNMENT * SEARCH_ALIGNMENT;
+
+	let end_position =
+		cmp::min (
+			command.end.unwrap_or (reader.size ()),
+			reader.size ());
+
+	let data_ranges =
+		reader.data_ranges (
+			start_position,
+			end_position,
+		) ?;
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Searching {} for superblocks from 0x{:x} to 0x{:x}",
+			reader.path ().to_string_lossy (),
+			start_position,
+			end_position);
+
+	let mut found_superblocks: Vec <FoundSuperblock> =
+		Vec::new ();
+
+	let mut num_unreadable: u64 = 0;
+
+	// read a little past the end of each block, so that a magic number
+	// which crosses the boundary is still seen
+
+	let mut buffer: Vec <u8> =
+		Vec::new ();
+
+	for data_range in data_ranges.iter () {
+
+		let mut block_start = data_range.start;
+
+		while block_start < data_range.end {
+
+			let block_end =
+				cmp::min (
+					block_start + SEARCH_BLOCK_SIZE,
+					data_range.end);
+
+			let read_end =
+				cmp::min (
+					block_end + SEARCH_ALIGNMENT,
+					reader.size ());
+
+			buffer.resize (
+				(read_end - block_start) as usize,
+				0);
+
+			let bad_ranges =
+				reader.read_tolerant (
+					block_start,
+					& mut buffer,
+					SEARCH_ALIGNMENT as usize,
+				) ?;
+
+			num_unreadable +=
+				bad_ranges.iter ().map (
+					|bad_range|
+
+					bad_range.end - bad_range.start
+
+				).sum::<u64> ();
+
+			let mut position =
+				(block_start + SEARCH_ALIGNMENT - 1)
+					/ SEARCH_ALIGNMENT * SEARCH_ALIGNMENT;
+
+			while position < block_end {
+
+				let magic_start =
+					(position - block_start) as usize
+						+ SUPERBLOCK_MAGIC_OFFSET;
+
+				if (
+					magic_start + BTRFS_MAGIC_BYTES.len () <= buffer.len ()
+					&& & buffer [
+						magic_start
+					..
+						magic_start + BTRFS_MAGIC_BYTES.len ()
+					] == BTRFS_MAGIC_BYTES
+				) {
+
+					if let Some (found_superblock) =
+						find_superblock_check (
+							reader,
+							position) {
+
+						output_message! (
+							output,
+							"Found superblock at 0x{:x}: {}",
+							position,
+							find_superblock_description (
+								& found_superblock));
+
+						found_superblocks.push (
+							found_superblock);
+
+					}
+
+				}
+
+				position += SEARCH_ALIGNMENT;
+
+			}
+
+			output_job_update! (
+				output_job,
+				"Searched 0x{:x} bytes ({}%)",
+				block_end - start_position,
+				(block_end - start_position) * 100 / cmp::max (
+					end_position - start_position,
+					1));
+
+			block_start = block_end;
+
+		}
+
+	}
+
+	output_job.complete ();
+
+	if num_unreadable > 0 {
+
+		output_message! (
+			output,
+			"Unable to read 0x{:x} bytes, superblocks there would be missed",
+			num_unreadable);
+
+	}
+
+	Ok (found_superblocks)
+
+}
+
+// reads a candidate in full and checks it. anything which has the magic
+// number is reported, even with a bad checksum, since that may still be
+// enough to find the filesystem.
+
+fn find_superblock_check (
+	reader: & DeviceReader,
+	position: u64,
+) -> Option <FoundSuperblock> {
+
+	let bytes =
+		match superblock_read (
+			reader,
+			position) {
+
+		Some (bytes) => bytes,
+		None => return None,
+
+	};
+
+	let error =
+		match superblock_at (
+			& bytes,
+			0) {
+
+		Some (superblock) =>
+			superblock_verify (
+				& bytes,
+				0,
+				superblock,
+			).err (),
+
+		None =>
+			return None,
+
+	};
+
+	let mut fs_uuid: [u8; 16] = [0; 16];
+
+	fs_uuid.copy_from_slice (
+		& bytes [
+			SUPERBLOCK_FSID_OFFSET
+		..
+			SUPERBLOCK_FSID_OFFSET + 16
+		]);
+
+	// only trust the recorded position if it is one of the places a
+	// superblock can be written
+
+	let bytenr =
+		le_u64 (
+			& bytes,
+			SUPERBLOCK_BYTENR_OFFSET);
+
+	let filesystem_start =
+		if (
+			BTRFS_SUPERBLOCK_OFFSETS.contains (& bytenr)
+			&& bytenr <= position
+		) {
+			Some (position - bytenr)
+		} else {
+			None
+		};
+
+	Some (FoundSuperblock {
+		position: position,
+		fs_uuid: fs_uuid,
+		generation: le_u64 (
+			& bytes,
+			SUPERBLOCK_GENERATION_OFFSET),
+		filesystem_start: filesystem_start,
+		error: error,
+	})
+
+}
+
+fn find_superblock_description (
+	found_superblock: & FoundSuperblock,
+) -> String {
+
+	format! (
+		"filesystem {}, generation {}, {}, {}",
+		find_superblock_uuid_string (
+			& found_superblock.fs_uuid),
+		found_superblock.generation,
+		match found_superblock.filesystem_start {
+			Some (filesystem_start) =>
+				format! ("filesystem starts at 0x{:x}", filesystem_start),
+			None =>
+				"unknown filesystem start".to_owned (),
+		},
+		match found_superblock.error {
+			Some (ref error) => format! ("invalid: {}", error),
+			None => "valid".to_owned (),
+		})
+
+}
+
+// groups the superblocks found by filesystem and start position, so the
+// right value for --offset is easy to pick out
+
+fn find_superblock_report (
+	output: & Output,
+	reader: & DeviceReader,
+	found_superblocks: & [FoundSuperblock],
+) {
+
+	if found_superblocks.is_empty () {
+
+		output_message! (
+			output,
+			"No superblocks found in {}",
+			reader.path ().to_string_lossy ());
+
+		return;
+
+	}
+
+	let mut filesystems: BTreeMap <(u64, [u8; 16]), (u64, u64, u64)> =
+		BTreeMap::new ();
+
+	for found_superblock in found_superblocks.iter () {
+
+		let filesystem_start =
+			match found_superblock.filesystem_start {
+				Some (filesystem_start) => filesystem_start,
+				None => continue,
+			};
+
+		let & mut (ref mut num_valid, ref mut num_invalid, ref mut generation) =
+			filesystems.entry (
+				(filesystem_start, found_superblock.fs_uuid),
+			).or_insert ((0, 0, 0));
+
+		if found_superblock.error.is_some () {
+			* num_invalid += 1;
+			continue;
+		}
+
+		* num_valid += 1;
+
+		if found_superblock.generation > * generation {
+			* generation = found_superblock.generation;
+		}
+
+	}
+
+	output_message! (
+		output,
+		"Found {} superblocks in {}, for {} filesystems:",
+		found_superblocks.len (),
+		reader.path ().to_string_lossy (),
+		filesystems.len ());
+
+	for (& (filesystem_start, fs_uuid), & (num_valid, num_invalid, generation))
+	in filesystems.iter () {
+
+		if num_valid > 0 {
+
+			output_message! (
+				output,
+				"  --offset 0x{:x}: filesystem {}, generation {}, {} valid and \
+				{} invalid superblocks",
+				filesystem_start,
+				find_superblock_uuid_string (& fs_uuid),
+				generation,
+				num_valid,
+				num_invalid);
+
+		} else {
+
+			output_message! (
+				output,
+				"  --offset 0x{:x}: filesystem {}, {} invalid superblocks",
+				filesystem_start,
+				find_superblock_uuid_string (& fs_uuid),
+				num_invalid);
+
+		}
+
+	}
+
+}
+
+fn find_superblock_uuid_string (
+	fs_uuid: & [u8; 16],
+) -> String {
+
+	Uuid::from_bytes (
+		fs_uuid,
+	).unwrap ().hyphenated ().to_string ()
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,1376 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use bytes::*;
+use checksum::*;
+use chunks::*;
+use devices::*;
+use images::*;
+use index::*;
+use superblock::*;
+
+// metadata dumps made by btrfs-image. these hold the superblock and every
+// tree block, by logical address, in clusters of items which may be zlib
+// compressed. to read one as a device, the metadata and system chunks are
+// laid out one after another, and the chunk items in the superblock and the
+// chunk tree are rewritten to match, with a single stripe each, much as
+// btrfs-image does when restoring. a dump of several devices becomes a
+// single device. data chunks all share one region of zeros at the end.
+
+pub const METADUMP_MAGIC: u64 = 0xbd5c_25e2_7295_668b;
+
+const METADUMP_CLUSTER_ALIGNMENT: u64 = 0x400;
+const METADUMP_HEADER_SIZE: usize = 0x15;
+const METADUMP_ITEM_SIZE: usize = 0xc;
+const METADUMP_COMPRESS_ZLIB: u8 = 1;
+
+// btrfs-image joins adjacent tree blocks into one item up to this size, so
+// anything which inflates to more is corrupt
+
+const METADUMP_MAX_ITEM_SIZE: u64 = 0x4_0000;
+
+const METADUMP_CHUNKS_START: u64 = 0x100_0000;
+const METADUMP_CHUNK_ALIGNMENT: u64 = 0x1_0000;
+
+const BTRFS_SUPERBLOCK_TOTAL_BYTES_OFFSET: usize = 0x70;
+const BTRFS_SUPERBLOCK_NUM_DEVICES_OFFSET: usize = 0x88;
+const BTRFS_SUPERBLOCK_SECTOR_SIZE_OFFSET: usize = 0x90;
+const BTRFS_SUPERBLOCK_NODE_SIZE_OFFSET: usize = 0x94;
+const BTRFS_SUPERBLOCK_CSUM_TYPE_OFFSET: usize = 0xc4;
+const BTRFS_SUPERBLOCK_DEV_ITEM_OFFSET: usize = 0xc9;
+
+const BTRFS_FIRST_CHUNK_TREE_OBJECTID: u64 = 0x100;
+const BTRFS_BLOCK_GROUP_PROFILE_MASK: u64 = 0x7f8;
+
+pub struct MetadumpImage {
+	file: File,
+	size: u64,
+	superblock: Vec <u8>,
+	items: BTreeMap <u64, MetadumpItem>,
+	lengths: MetadumpLengths,
+	chunk_leaves: BTreeMap <u64, Vec <u8>>,
+	placements: Vec <MetadumpPlacement>,
+}
+
+#[ derive (Clone, Copy) ]
+struct MetadumpItem {
+	file_offset: u64,
+	stored_size: u64,
+	compressed: bool,
+}
+
+// the length of a compressed item is only known once it has been inflated,
+// so it is noted by the first read which inflates it. an item which won't
+// inflate was a dumped tree block, so it is taken to be a node long.
+
+struct MetadumpLengths {
+	node_size: u64,
+	compressed: Mutex <HashMap <u64, u64>>,
+}
+
+// the device every chunk is rewritten to use
+
+struct MetadumpDevice {
+	device_id: u64,
+	device_uuid: Vec <u8>,
+	sector_size: u32,
+}
+
+#[ derive (Clone, Copy) ]
+struct MetadumpPlacement {
+	logical_address: u64,
+	length: u64,
+	physical_address: u64,
+}
+
+impl MetadumpImage {
+
+	pub fn open (
+		path: & Path,
+	) -> Result <MetadumpImage, String> {
+
+		let file =
+			image_file_open (
+				path,
+			) ?;
+
+		let (superblock_item, items) =
+			metadump_read_index (
+				path,
+				& file,
+			) ?;
+
+		let superblock_item =
+			superblock_item.ok_or_else (
+				|| image_invalid (
+					path,
+					"btrfs-image",
+					"No superblock in dump"),
+			) ?;
+
+		let mut superblock =
+			metadump_item_read (
+				& file,
+				& superblock_item,
+			).map_err (
+				|error|
+
+				format! (
+					"Error reading superblock from {}: {}",
+					path.to_string_lossy (),
+					error.description ())
+
+			) ?;
+
+		superblock.resize (
+			BTRFS_SUPERBLOCK_SIZE as usize,
+			0);
+
+		if superblock_at (& superblock, 0).is_none () {
+
+			return Err (
+				image_invalid (
+					path,
+					"btrfs-image",
+					"Invalid superblock in dump"));
+
+		}
+
+		let node_size =
+			le_u32 (
+				& superblock,
+				BTRFS_SUPERBLOCK_NODE_SIZE_OFFSET,
+			) as usize;
+
+		let csum_type =
+			le_u16 (
+				& superblock,
+				BTRFS_SUPERBLOCK_CSUM_TYPE_OFFSET);
+
+		if ! checksum_supported (csum_type) {
+
+			return Err (
+				image_invalid (
+					path,
+					"btrfs-image",
+					& format! (
+						"Can't rewrite chunks with {} checksums",
+						checksum_type_name (csum_type))));
+
+		}
+
+		if node_size < BTRFS_NODE_HEADER_SIZE {
+
+			return Err (
+				image_invalid (
+					path,
+					"btrfs-image",
+					& format! (
+						"Invalid node size 0x{:x}",
+						node_size)));
+
+		}
+
+		let lengths =
+			MetadumpLengths::new (
+				node_size as u64);
+
+		// read the chunk tree from the dump, keeping the nodes so the leaves
+		// can be rewritten
+
+		let mut chunk_nodes: Vec <(u64, Vec <u8>)> =
+			Vec::new ();
+
+		let chunk_map = {
+
+			let read_node =
+				|_: & ChunkMap, logical_address: u64| -> Result <Vec <u8>, String> {
+
+				let mut node_bytes: Vec <u8> =
+					vec! [0; node_size];
+
+				metadump_read_logical (
+					& file,
+					& items,
+					& lengths,
+					logical_address,
+					& mut node_bytes,
+				).map_err (
+					|error|
+
+					format! (
+						"Error reading chunk tree node at 0x{:x} from {}: {}",
+						logical_address,
+						path.to_string_lossy (),
+						error.description ())
+
+				) ?;
+
+				chunk_nodes.push (
+					(logical_address, node_bytes.clone ()));
+
+				Ok (node_bytes)
+
+			};
+
+			chunks_walk_tree (
+				& superblock,
+				read_node,
+			).map_err (
+				|error|
+
+				image_invalid (
+					path,
+					"btrfs-image",
+					& error)
+
+			) ?
+
+		};
+
+		// lay out the metadata chunks, keeping clear of superblock mirrors
+
+		let mut placements: Vec <MetadumpPlacement> =
+			Vec::new ();
+
+		let mut position = METADUMP_CHUNKS_START;
+		let mut data_length: u64 = 0;
+
+		for chunk in chunk_map.chunks () {
+
+			if ! chunk.is_metadata () {
+
+				data_length =
+					cmp::max (
+						data_length,
+						chunk.length);
+
+				continue;
+
+			}
+
+			position =
+				metadump_chunk_position (
+					position,
+					chunk.length);
+
+			placements.push (
+				MetadumpPlacement {
+					logical_address: chunk.logical_address,
+					length: chunk.length,
+					physical_address: position,
+				});
+
+			position += chunk.length;
+
+		}
+
+		let data_position =
+			metadump_align (
+				position,
+				METADUMP_CHUNK_ALIGNMENT);
+
+		let size =
+			data_position + data_length;
+
+		let physical_addresses: BTreeMap <u64, u64> =
+			placements.iter ().map (
+				|placement|
+
+				(placement.logical_address, placement.physical_address)
+
+			).collect ();
+
+		let physical_address =
+			|chunk: & Chunk|
+
+			physical_addresses.get (
+				& chunk.logical_address,
+			).cloned ().unwrap_or (
+				data_position);
+
+		let device = MetadumpDevice {
+
+			device_id: le_u64 (
+				& superblock,
+				BTRFS_SUPERBLOCK_DEV_ITEM_OFFSET),
+
+			device_uuid: superblock [
+				BTRFS_SUPERBLOCK_DEV_ITEM_OFFSET + 0x42
+			..
+				BTRFS_SUPERBLOCK_DEV_ITEM_OFFSET + 0x52
+			].to_vec (),
+
+			sector_size: le_u32 (
+				& superblock,
+				BTRFS_SUPERBLOCK_SECTOR_SIZE_OFFSET),
+
+		};
+
+		// rewrite the superblock
+
+		let mut array_bytes: Vec <u8> =
+			Vec::new ();
+
+		for chunk in chunks_from_superblock (& superblock) ?.chunks () {
+
+			let mut key_bytes: [u8; BTRFS_DISK_KEY_SIZE] =
+				[0; BTRFS_DISK_KEY_SIZE];
+
+			set_le_u64 (
+				& mut key_bytes,
+				0,
+				BTRFS_FIRST_CHUNK_TREE_OBJECTID);
+
+			key_bytes [8] = BTRFS_CHUNK_ITEM_TYPE;
+
+			set_le_u64 (
+				& mut key_bytes,
+				9,
+				chunk.logical_address);
+
+			array_bytes.extend_from_slice (
+				& key_bytes);
+
+			array_bytes.extend (
+				metadump_chunk_item (
+					chunk,
+					& device,
+					physical_address (chunk)));
+
+		}
+
+		for byte in superblock [
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET
+		..
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET
+				+ BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_MAX
+		].iter_mut () {
+			* byte = 0;
+		}
+
+		superblock [
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET
+		..
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_OFFSET + array_bytes.len ()
+		].copy_from_slice (
+			& array_bytes);
+
+		set_le_u32 (
+			& mut superblock,
+			BTRFS_SUPERBLOCK_SYS_CHUNK_ARRAY_SIZE_OFFSET,
+			array_bytes.len () as u32);
+
+		set_le_u64 (
+			& mut superblock,
+			BTRFS_SUPERBLOCK_TOTAL_BYTES_OFFSET,
+			size);
+
+		set_le_u64 (
+			& mut superblock,
+			BTRFS_SUPERBLOCK_NUM_DEVICES_OFFSET,
+			1);
+
+		set_le_u64 (
+			& mut superblock,
+			BTRFS_SUPERBLOCK_DEV_ITEM_OFFSET + 0x08,
+			size);
+
+		set_le_u64 (
+			& mut superblock,
+			BTRFS_SUPERBLOCK_DEV_ITEM_OFFSET + 0x10,
+			size);
+
+		checksum_update (
+			csum_type,
+			& mut superblock);
+
+		// rewrite the chunk tree leaves. interior nodes only hold logical
+		// addresses, which stay the same.
+
+		let mut chunk_leaves: BTreeMap <u64, Vec <u8>> =
+			BTreeMap::new ();
+
+		for & (logical_address, ref node_bytes) in chunk_nodes.iter () {
+
+			if node_bytes [0x64] != 0 {
+				continue;
+			}
+
+			if let Some (leaf_bytes) =
+				metadump_chunk_leaf (
+					node_bytes,
+					csum_type,
+					& device,
+					& physical_address) {
+
+				chunk_leaves.insert (
+					logical_address,
+					leaf_bytes);
+
+			}
+
+		}
+
+		Ok (MetadumpImage {
+			file: file,
+			size: size,
+			superblock: superblock,
+			items: items,
+			lengths: lengths,
+			chunk_leaves: chunk_leaves,
+			placements: placements,
+		})
+
+	}
+
+	fn read_logical (
+		& self,
+		logical_address: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		metadump_read_logical (
+			& self.file,
+			& self.items,
+			& self.lengths,
+			logical_address,
+			buffer,
+		) ?;
+
+		let end =
+			logical_address + buffer.len () as u64;
+
+		for (& leaf_address, leaf_bytes) in self.chunk_leaves.range (
+			.. end,
+		) {
+
+			metadump_overlay (
+				logical_address,
+				buffer,
+				leaf_address,
+				leaf_bytes);
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+impl Image for MetadumpImage {
+
+	fn description (& self) -> String {
+
+		format! (
+			"btrfs-image metadata dump, with {} metadata chunks and no file \
+			data",
+			self.placements.len ())
+
+	}
+
+	fn size (& self) -> u64 {
+		self.size
+	}
+
+	fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		let end =
+			offset + buffer.len () as u64;
+
+		if end > self.size {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::UnexpectedEof,
+					"Read past end of device"));
+
+		}
+
+		for byte in buffer.iter_mut () {
+			* byte = 0;
+		}
+
+		metadump_overlay (
+			offset,
+			buffer,
+			BTRFS_SUPERBLOCK_OFFSETS [0],
+			& self.superblock);
+
+		for placement in self.placements.iter () {
+
+			let start =
+				cmp::max (
+					offset,
+					placement.physical_address);
+
+			let placement_end =
+				cmp::min (
+					end,
+					placement.physical_address + placement.length);
+
+			if start >= placement_end {
+				continue;
+			}
+
+			self.read_logical (
+				placement.logical_address
+					+ (start - placement.physical_address),
+				& mut buffer [
+					(start - offset) as usize
+				..
+					(placement_end - offset) as usize
+				],
+			) ?;
+
+		}
+
+		Ok (())
+
+	}
+
+	fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> io::Result <Vec <DeviceRange>> {
+
+		let mut ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		image_add_range (
+			& mut ranges,
+			cmp::max (start, BTRFS_SUPERBLOCK_OFFSETS [0]),
+			cmp::min (end, BTRFS_SUPERBLOCK_OFFSETS [0] + BTRFS_SUPERBLOCK_SIZE));
+
+		for placement in self.placements.iter () {
+
+			if (
+				placement.physical_address >= end
+				|| placement.physical_address + placement.length <= start
+			) {
+				continue;
+			}
+
+			let logical_end =
+				placement.logical_address + placement.length;
+
+			for (& bytenr, item) in self.items.range (
+				placement.logical_address .. logical_end,
+			) {
+
+				// an item which hasn't been inflated yet is assumed to run up
+				// to the next one, which is harmless here
+
+				let length =
+					self.lengths.get (
+						bytenr,
+						item,
+					).unwrap_or_else (
+						||
+
+						self.items.range (
+							bytenr + 1 ..
+						).next ().map_or (
+							logical_end,
+							|(& next_bytenr, _)| next_bytenr,
+						).saturating_sub (
+							bytenr,
+						)
+
+					);
+
+				let item_start =
+					placement.physical_address
+						+ (bytenr - placement.logical_address);
+
+				let item_end =
+					placement.physical_address
+						+ (cmp::min (bytenr + length, logical_end)
+							- placement.logical_address);
+
+				image_add_range (
+					& mut ranges,
+					cmp::max (start, item_start),
+					cmp::min (end, item_end));
+
+			}
+
+		}
+
+		Ok (ranges)
+
+	}
+
+	fn has_file_data (& self) -> bool {
+		false
+	}
+
+}
+
+impl MetadumpLengths {
+
+	fn new (
+		node_size: u64,
+	) -> MetadumpLengths {
+
+		MetadumpLengths {
+			node_size: node_size,
+			compressed: Mutex::new (
+				HashMap::new ()),
+		}
+
+	}
+
+	fn get (
+		& self,
+		bytenr: u64,
+		item: & MetadumpItem,
+	) -> Option <u64> {
+
+		if ! item.compressed {
+			return Some (item.stored_size);
+		}
+
+		self.compressed.lock ().unwrap ().get (
+			& bytenr,
+		).cloned ()
+
+	}
+
+	fn insert (
+		& self,
+		bytenr: u64,
+		length: u64,
+	) {
+
+		self.compressed.lock ().unwrap ().insert (
+			bytenr,
+			length);
+
+	}
+
+}
+
+// reads the cluster headers, which list the position and stored size of
+// every item
+
+fn metadump_read_index (
+	path: & Path,
+	file: & File,
+) -> Result <(Option <MetadumpItem>, BTreeMap <u64, MetadumpItem>), String> {
+
+	let file_size =
+		image_file_size (
+			path,
+			file,
+		) ?;
+
+	let mut superblock_item: Option <MetadumpItem> = None;
+
+	let mut items: BTreeMap <u64, MetadumpItem> =
+		BTreeMap::new ();
+
+	let mut cluster_offset: u64 = 0;
+
+	while cluster_offset + METADUMP_CLUSTER_ALIGNMENT <= file_size {
+
+		let header =
+			image_file_read (
+				path,
+				file,
+				cluster_offset,
+				METADUMP_CLUSTER_ALIGNMENT as usize,
+			) ?;
+
+		if le_u64 (& header, 0) != METADUMP_MAGIC {
+
+			// the last cluster may be followed by padding
+
+			if cluster_offset == 0 {
+
+				return Err (
+					image_invalid (
+						path,
+						"btrfs-image",
+						"Bad magic number"));
+
+			}
+
+			break;
+
+		}
+
+		let num_items =
+			le_u32 (& header, 0x10) as usize;
+
+		let compressed =
+			match header [0x14] {
+
+			0 => false,
+			METADUMP_COMPRESS_ZLIB => true,
+
+			compress =>
+				return Err (
+					image_invalid (
+						path,
+						"btrfs-image",
+						& format! (
+							"Unsupported compression type {} at 0x{:x}",
+							compress,
+							cluster_offset))),
+
+		};
+
+		if (
+			METADUMP_HEADER_SIZE + num_items * METADUMP_ITEM_SIZE
+				> METADUMP_CLUSTER_ALIGNMENT as usize
+		) {
+
+			return Err (
+				image_invalid (
+					path,
+					"btrfs-image",
+					& format! (
+						"Too many items in cluster at 0x{:x}",
+						cluster_offset)));
+
+		}
+
+		let mut data_offset =
+			cluster_offset + METADUMP_CLUSTER_ALIGNMENT;
+
+		for item_index in 0 .. num_items {
+
+			let item_start =
+				METADUMP_HEADER_SIZE + item_index * METADUMP_ITEM_SIZE;
+
+			let bytenr =
+				le_u64 (& header, item_start);
+
+			let stored_size =
+				le_u32 (& header, item_start + 8) as u64;
+
+			if data_offset + stored_size > file_size {
+
+				return Err (
+					image_invalid (
+						path,
+						"btrfs-image",
+						& format! (
+							"Item for 0x{:x} extends past end of file",
+							bytenr)));
+
+			}
+
+			let item = MetadumpItem {
+				file_offset: data_offset,
+				stored_size: stored_size,
+				compressed: compressed,
+			};
+
+			if bytenr == BTRFS_SUPERBLOCK_OFFSETS [0] {
+				superblock_item = Some (item);
+			} else {
+				items.insert (bytenr, item);
+			}
+
+			data_offset += stored_size;
+
+		}
+
+		cluster_offset =
+			metadump_align (
+				data_offset,
+				METADUMP_CLUSTER_ALIGNMENT);
+
+	}
+
+	Ok ((superblock_item, items))
+
+}
+
+fn metadump_item_read (
+	file: & File,
+	item: & MetadumpItem,
+) -> io::Result <Vec <u8>> {
+
+	let mut stored_bytes: Vec <u8> =
+		vec! [0; item.stored_size as usize];
+
+	file_read_at (
+		file,
+		item.file_offset,
+		& mut stored_bytes,
+	) ?;
+
+	if item.compressed {
+		image_inflate_all (& stored_bytes, METADUMP_MAX_ITEM_SIZE)
+	} else {
+		Ok (stored_bytes)
+	}
+
+}
+
+// fills in the parts of a buffer covered by items, leaving the rest alone
+
+fn metadump_read_logical (
+	file: & File,
+	items: & BTreeMap <u64, MetadumpItem>,
+	lengths: & MetadumpLengths,
+	logical_address: u64,
+	buffer: & mut [u8],
+) -> io::Result <()> {
+
+	let end =
+		logical_address + buffer.len () as u64;
+
+	let first_bytenr =
+		items.range (
+			.. logical_address + 1,
+		).next_back ().map_or (
+			logical_address,
+			|(& bytenr, _)| bytenr);
+
+	for (& bytenr, item) in items.range (first_bytenr .. end) {
+
+		let start =
+			cmp::max (
+				logical_address,
+				bytenr);
+
+		// an item which is known to end before the buffer isn't inflated
+
+		if let Some (length) = lengths.get (bytenr, item) {
+
+			if bytenr + length <= start {
+				continue;
+			}
+
+		}
+
+		if ! item.compressed {
+
+			let item_end =
+				cmp::min (
+					end,
+					bytenr + item.stored_size);
+
+			file_read_at (
+				file,
+				item.file_offset + (start - bytenr),
+				& mut buffer [
+					(start - logical_address) as usize
+				..
+					(item_end - logical_address) as usize
+				],
+			) ?;
+
+			continue;
+
+		}
+
+		let item_bytes =
+			match metadump_item_read (file, item) {
+
+			Ok (item_bytes) =>
+				item_bytes,
+
+			Err (ref error)
+			if error.kind () == io::ErrorKind::InvalidData => {
+
+				lengths.insert (
+					bytenr,
+					lengths.node_size);
+
+				if bytenr + lengths.node_size <= start {
+					continue;
+				}
+
+				return Err (
+					io::Error::new (
+						io::ErrorKind::InvalidData,
+						format! (
+							"Corrupt compressed item for 0x{:x}",
+							bytenr)));
+
+			},
+
+			Err (error) =>
+				return Err (error),
+
+		};
+
+		lengths.insert (
+			bytenr,
+			item_bytes.len () as u64);
+
+		metadump_overlay (
+			logical_address,
+			buffer,
+			bytenr,
+			& item_bytes);
+
+	}
+
+	Ok (())
+
+}
+
+// copies the part of some bytes at a position which overlaps a buffer
+
+fn metadump_overlay (
+	buffer_position: u64,
+	buffer: & mut [u8],
+	position: u64,
+	bytes: & [u8],
+) {
+
+	let start =
+		cmp::max (
+			buffer_position,
+			position);
+
+	let end =
+		cmp::min (
+			buffer_position + buffer.len () as u64,
+			position + bytes.len () as u64);
+
+	if start >= end {
+		return;
+	}
+
+	buffer [
+		(start - buffer_position) as usize
+	..
+		(end - buffer_position) as usize
+	].copy_from_slice (
+		& bytes [
+			(start - position) as usize
+		..
+			(end - position) as usize
+		]);
+
+}
+
+// a chunk item with a single stripe, and no raid profile
+
+fn metadump_chunk_item (
+	chunk: & Chunk,
+	device: & MetadumpDevice,
+	physical_address: u64,
+) -> Vec <u8> {
+
+	let mut item_bytes: Vec <u8> =
+		vec! [0; BTRFS_CHUNK_ITEM_SIZE + BTRFS_CHUNK_STRIPE_SIZE];
+
+	set_le_u64 (& mut item_bytes, 0x00, chunk.length);
+	set_le_u64 (& mut item_bytes, 0x08, 2);
+	set_le_u64 (& mut item_bytes, 0x10, chunk.stripe_length);
+
+	set_le_u64 (
+		& mut item_bytes,
+		0x18,
+		chunk.chunk_type & ! BTRFS_BLOCK_GROUP_PROFILE_MASK);
+
+	set_le_u32 (& mut item_bytes, 0x20, chunk.stripe_length as u32);
+	set_le_u32 (& mut item_bytes, 0x24, chunk.stripe_length as u32);
+	set_le_u32 (& mut item_bytes, 0x28, device.sector_size);
+	set_le_u16 (& mut item_bytes, 0x2c, 1);
+	set_le_u16 (& mut item_bytes, 0x2e, 1);
+
+	set_le_u64 (& mut item_bytes, BTRFS_CHUNK_ITEM_SIZE, device.device_id);
+	set_le_u64 (& mut item_bytes, BTRFS_CHUNK_ITEM_SIZE + 8, physical_address);
+
+	item_bytes [
+		BTRFS_CHUNK_ITEM_SIZE + 0x10
+	..
+		BTRFS_CHUNK_ITEM_SIZE + 0x20
+	].copy_from_slice (
+		& device.device_uuid);
+
+	item_bytes
+
+}
+
+// rebuilds a chunk tree leaf with every chunk item rewritten. the new items
+// are never larger, so the data is simply packed again from the end.
+
+fn metadump_chunk_leaf <PhysicalAddress> (
+	node_bytes: & [u8],
+	csum_type: u16,
+	device: & MetadumpDevice,
+	physical_address: & PhysicalAddress,
+) -> Option <Vec <u8>>
+where PhysicalAddress: Fn (& Chunk) -> u64 {
+
+	let num_items =
+		le_u32 (node_bytes, 0x60) as usize;
+
+	if BTRFS_NODE_HEADER_SIZE + num_items * BTRFS_LEAF_ITEM_SIZE > node_bytes.len () {
+		return None;
+	}
+
+	let mut leaf_bytes: Vec <u8> =
+		vec! [0; node_bytes.len ()];
+
+	leaf_bytes [0 .. BTRFS_NODE_HEADER_SIZE].copy_from_slice (
+		& node_bytes [0 .. BTRFS_NODE_HEADER_SIZE]);
+
+	let mut data_end =
+		node_bytes.len () - BTRFS_NODE_HEADER_SIZE;
+
+	for item_index in 0 .. num_items {
+
+		let item_start =
+			BTRFS_NODE_HEADER_SIZE + item_index * BTRFS_LEAF_ITEM_SIZE;
+
+		let data_start =
+			BTRFS_NODE_HEADER_SIZE
+				+ le_u32 (node_bytes, item_start + 0x11) as usize;
+
+		let data_size =
+			le_u32 (node_bytes, item_start + 0x15) as usize;
+
+		if data_start + data_size > node_bytes.len () {
+			return None;
+		}
+
+		let data_bytes =
+			& node_bytes [data_start .. data_start + data_size];
+
+		let chunk =
+			if node_bytes [item_start + 8] == BTRFS_CHUNK_ITEM_TYPE {
+				chunk_parse (
+					le_u64 (node_bytes, item_start + 9),
+					data_bytes)
+			} else {
+				None
+			};
+
+		let new_data_bytes =
+			match chunk {
+
+			Some (ref chunk) =>
+				metadump_chunk_item (
+					chunk,
+					device,
+					physical_address (chunk)),
+
+			None =>
+				data_bytes.to_vec (),
+
+		};
+
+		if (
+			BTRFS_NODE_HEADER_SIZE + data_end
+				< BTRFS_NODE_HEADER_SIZE
+					+ num_items * BTRFS_LEAF_ITEM_SIZE
+					+ new_data_bytes.len ()
+		) {
+			return None;
+		}
+
+		data_end -= new_data_bytes.len ();
+
+		leaf_bytes [
+			item_start
+		..
+			item_start + BTRFS_DISK_KEY_SIZE
+		].copy_from_slice (
+			& node_bytes [item_start .. item_start + BTRFS_DISK_KEY_SIZE]);
+
+		set_le_u32 (
+			& mut leaf_bytes,
+			item_start + 0x11,
+			data_end as u32);
+
+		set_le_u32 (
+			& mut leaf_bytes,
+			item_start + 0x15,
+			new_data_bytes.len () as u32);
+
+		leaf_bytes [
+			BTRFS_NODE_HEADER_SIZE + data_end
+		..
+			BTRFS_NODE_HEADER_SIZE + data_end + new_data_bytes.len ()
+		].copy_from_slice (
+			& new_data_bytes);
+
+	}
+
+	checksum_update (
+		csum_type,
+		& mut leaf_bytes);
+
+	Some (leaf_bytes)
+
+}
+
+// the next aligned position where a chunk won't overwrite a superblock
+
+fn metadump_chunk_position (
+	position: u64,
+	length: u64,
+) -> u64 {
+
+	let mut position =
+		metadump_align (
+			position,
+			METADUMP_CHUNK_ALIGNMENT);
+
+	while superblock_overlaps (position, length) {
+
+		let overlapping_offset =
+			BTRFS_SUPERBLOCK_SKIP_OFFSETS.iter ().cloned ().filter (
+				|offset|
+
+				position < offset + BTRFS_SUPERBLOCK_SIZE
+				&& * offset < position + length
+
+			).max ().unwrap ();
+
+		position =
+			metadump_align (
+				overlapping_offset + BTRFS_SUPERBLOCK_SIZE,
+				METADUMP_CHUNK_ALIGNMENT);
+
+	}
+
+	position
+
+}
+
+fn metadump_align (
+	position: u64,
+	alignment: u64,
+) -> u64 {
+
+	(position + alignment - 1) / alignment * alignment
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::io::Write;
+	use std::path::PathBuf;
+	use std::process;
+
+	use super::*;
+
+	// a zlib stream of stored deflate blocks
+
+	fn zlib (
+		data: & [u8],
+	) -> Vec <u8> {
+
+		let mut bytes: Vec <u8> =
+			vec! [0x78, 0x01];
+
+		let mut blocks =
+			data.chunks (0xffff).peekable ();
+
+		while let Some (block) = blocks.next () {
+
+			let length = block.len () as u16;
+
+			bytes.push (if blocks.peek ().is_some () { 0 } else { 1 });
+			bytes.extend_from_slice (& [length as u8, (length >> 8) as u8]);
+			bytes.extend_from_slice (& [! length as u8, (! length >> 8) as u8]);
+			bytes.extend_from_slice (block);
+
+		}
+
+		let (mut sum_a, mut sum_b) = (1u32, 0u32);
+
+		for byte in data.iter () {
+			sum_a = (sum_a + * byte as u32) % 65521;
+			sum_b = (sum_b + sum_a) % 65521;
+		}
+
+		let adler = sum_b << 16 | sum_a;
+
+		for shift in [24, 16, 8, 0].iter () {
+			bytes.push ((adler >> * shift) as u8);
+		}
+
+		bytes
+
+	}
+
+	// a dump with one cluster of compressed items
+
+	fn write_dump (
+		path: & Path,
+		items: & [(u64, Vec <u8>)],
+	) {
+
+		let mut bytes: Vec <u8> =
+			vec! [0; METADUMP_CLUSTER_ALIGNMENT as usize];
+
+		set_le_u64 (& mut bytes, 0, METADUMP_MAGIC);
+		set_le_u32 (& mut bytes, 0x10, items.len () as u32);
+
+		bytes [0x14] = METADUMP_COMPRESS_ZLIB;
+
+		for (item_index, & (bytenr, ref item_bytes))
+		in items.iter ().enumerate () {
+
+			let item_start =
+				METADUMP_HEADER_SIZE + item_index * METADUMP_ITEM_SIZE;
+
+			set_le_u64 (& mut bytes, item_start, bytenr);
+			set_le_u32 (& mut bytes, item_start + 8, item_bytes.len () as u32);
+
+		}
+
+		for & (_, ref item_bytes) in items.iter () {
+			bytes.extend_from_slice (item_bytes);
+		}
+
+		File::create (
+			path,
+		).unwrap ().write_all (
+			& bytes,
+		).unwrap ();
+
+	}
+
+	fn temp_path (
+		name: & str,
+	) -> PathBuf {
+
+		env::temp_dir ().join (
+			format! (
+				"btrfs-fuse-metadump-{}-{}",
+				name,
+				process::id ()))
+
+	}
+
+	#[ test ]
+	fn compressed_item_lengths_are_learned_when_read () {
+
+		let path = temp_path ("lengths");
+
+		write_dump (& path, & [
+			(BTRFS_SUPERBLOCK_OFFSETS [0], zlib (& [1; 0x1000])),
+			(0x10_0000, zlib (& [0xaa; 0x2000])),
+			(0x10_2000, vec! [0; 0x10]),
+			(0x10_4000, zlib (& vec! [0xbb; METADUMP_MAX_ITEM_SIZE as usize + 1])),
+		]);
+
+		let file =
+			File::open (& path).unwrap ();
+
+		let (superblock_item, items) =
+			metadump_read_index (
+				& path,
+				& file,
+			).unwrap ();
+
+		assert_eq! (
+			metadump_item_read (& file, & superblock_item.unwrap ()).unwrap (),
+			vec! [1; 0x1000]);
+
+		assert_eq! (items.len (), 3);
+
+		let lengths =
+			MetadumpLengths::new (0x1000);
+
+		let item = items [& 0x10_0000];
+
+		assert_eq! (lengths.get (0x10_0000, & item), None);
+
+		let mut buffer: Vec <u8> =
+			vec! [0xff; 0x2000];
+
+		metadump_read_logical (
+			& file,
+			& items,
+			& lengths,
+			0xf_f000,
+			& mut buffer,
+		).unwrap ();
+
+		assert! (buffer [0 .. 0x1000].iter ().all (|byte| * byte == 0xff));
+		assert! (buffer [0x1000 .. 0x2000].iter ().all (|byte| * byte == 0xaa));
+
+		assert_eq! (lengths.get (0x10_0000, & item), Some (0x2000));
+
+		// an item which won't inflate is unreadable, and a node long
+
+		let error =
+			metadump_read_logical (
+				& file,
+				& items,
+				& lengths,
+				0x10_2000,
+				& mut buffer [0 .. 0x1000],
+			).err ().unwrap ();
+
+		assert_eq! (error.kind (), io::ErrorKind::InvalidData);
+
+		assert! (
+			metadump_read_logical (
+				& file,
+				& items,
+				& lengths,
+				0x10_3000,
+				& mut buffer [0 .. 0x1000],
+			).is_ok ());
+
+		// as is one which inflates to more than an item can hold
+
+		let error =
+			metadump_read_logical (
+				& file,
+				& items,
+				& lengths,
+				0x10_4000,
+				& mut buffer [0 .. 0x1000],
+			).err ().unwrap ();
+
+		assert_eq! (error.kind (), io::ErrorKind::InvalidData);
+
+		fs::remove_file (path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn dumps_must_start_with_the_magic_number () {
+
+		let path = temp_path ("magic");
+
+		File::create (
+			& path,
+		).unwrap ().write_all (
+			& [0; METADUMP_CLUSTER_ALIGNMENT as usize],
+		).unwrap ();
+
+		let file =
+			File::open (& path).unwrap ();
+
+		assert! (metadump_read_index (& path, & file).is_err ());
+
+		fs::remove_file (path).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,851 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str;
+
+use bytes::*;
+use devices::*;
+use images::*;
+
+// qcow2 images, versions 2 and 3. positions are looked up through a two
+// level table of clusters. clusters which aren't allocated come from the
+// backing file if there is one, and compressed clusters are raw deflate
+// streams. encryption, external data files and extended L2 entries aren't
+// supported.
+
+pub const QCOW2_MAGIC: & 'static [u8] = b"QFI\xfb";
+
+const QCOW2_HEADER_SIZE: usize = 0x70;
+
+const QCOW2_INCOMPATIBLE_DIRTY: u64 = 0x1;
+const QCOW2_INCOMPATIBLE_CORRUPT: u64 = 0x2;
+const QCOW2_INCOMPATIBLE_COMPRESSION_TYPE: u64 = 0x8;
+
+const QCOW2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const QCOW2_L2_COMPRESSED: u64 = 1 << 62;
+const QCOW2_L2_ZERO: u64 = 0x1;
+
+const QCOW2_MAX_BACKING_NAME: u32 = 0x400;
+const QCOW2_MAX_L1_SIZE: u32 = 0x200_0000;
+
+pub struct Qcow2Image {
+	file: File,
+	file_size: u64,
+	size: u64,
+	cluster_bits: u32,
+	l1_table: Vec <u64>,
+	backing_path: Option <PathBuf>,
+	backing: Option <Box <Image>>,
+	corrupt: bool,
+}
+
+enum Qcow2Cluster {
+	Unallocated,
+	Zero,
+	Data (u64),
+	Compressed (u64, u64),
+}
+
+impl Qcow2Image {
+
+	pub fn open (
+		path: & Path,
+		backing_chain: & [PathBuf],
+	) -> Result <Qcow2Image, String> {
+
+		let file =
+			image_file_open (
+				path,
+			) ?;
+
+		let file_size =
+			image_file_size (
+				path,
+				& file,
+			) ?;
+
+		let header =
+			image_file_read (
+				path,
+				& file,
+				0,
+				QCOW2_HEADER_SIZE,
+			) ?;
+
+		let version = be_u32 (& header, 0x04);
+		let backing_name_offset = be_u64 (& header, 0x08);
+		let backing_name_size = be_u32 (& header, 0x10);
+		let cluster_bits = be_u32 (& header, 0x14);
+		let size = be_u64 (& header, 0x18);
+		let crypt_method = be_u32 (& header, 0x20);
+		let l1_size = be_u32 (& header, 0x24);
+		let l1_table_offset = be_u64 (& header, 0x28);
+
+		if version != 2 && version != 3 {
+
+			return Err (
+				image_invalid (
+					path,
+					"qcow2",
+					& format! ("unsupported version {}", version)));
+
+		}
+
+		if cluster_bits < 9 || cluster_bits > 21 {
+
+			return Err (
+				image_invalid (
+					path,
+					"qcow2",
+					& format! ("invalid cluster bits {}", cluster_bits)));
+
+		}
+
+		if crypt_method != 0 {
+
+			return Err (
+				image_invalid (
+					path,
+					"qcow2",
+					"encrypted images are not supported"));
+
+		}
+
+		if l1_size > QCOW2_MAX_L1_SIZE {
+
+			return Err (
+				image_invalid (
+					path,
+					"qcow2",
+					& format! ("L1 table too large: {} entries", l1_size)));
+
+		}
+
+		// version 3 adds feature bits, and a compression type if the header
+		// is long enough
+
+		let incompatible_features =
+			if version >= 3 {
+				be_u64 (& header, 0x48)
+			} else {
+				0
+			};
+
+		let header_length =
+			if version >= 3 {
+				be_u32 (& header, 0x64) as usize
+			} else {
+				0
+			};
+
+		let unsupported_features =
+			incompatible_features & ! (
+				QCOW2_INCOMPATIBLE_DIRTY
+				| QCOW2_INCOMPATIBLE_CORRUPT
+				| QCOW2_INCOMPATIBLE_COMPRESSION_TYPE);
+
+		if unsupported_features != 0 {
+
+			return Err (
+				image_invalid (
+					path,
+					"qcow2",
+					& format! (
+						"unsupported incompatible features 0x{:x}",
+						unsupported_features)));
+
+		}
+
+		if (
+			incompatible_features & QCOW2_INCOMPATIBLE_COMPRESSION_TYPE != 0
+			&& header_length > 0x68
+			&& header [0x68] != 0
+		) {
+
+			return Err (
+				image_invalid (
+					path,
+					"qcow2",
+					"only deflate compression is supported"));
+
+		}
+
+		// load the L1 table, which is small enough to keep in memory
+
+		let l1_bytes =
+			image_file_read (
+				path,
+				& file,
+				l1_table_offset,
+				l1_size as usize * 8,
+			) ?;
+
+		let l1_table: Vec <u64> =
+			(0 .. l1_size as usize).map (
+				|l1_index|
+
+				be_u64 (& l1_bytes, l1_index * 8)
+
+			).collect ();
+
+		// open the backing file
+
+		let backing_path =
+			if backing_name_offset != 0 && backing_name_size != 0 {
+
+			if backing_name_size > QCOW2_MAX_BACKING_NAME {
+
+				return Err (
+					image_invalid (
+						path,
+						"qcow2",
+						"backing file name too long"));
+
+			}
+
+			let backing_name_bytes =
+				image_file_read (
+					path,
+					& file,
+					backing_name_offset,
+					backing_name_size as usize,
+				) ?;
+
+			let backing_name =
+				str::from_utf8 (
+					& backing_name_bytes,
+				).map_err (
+					|_|
+
+					image_invalid (
+						path,
+						"qcow2",
+						"backing file name is not valid UTF-8")
+
+				) ?;
+
+			Some (
+				image_relative_path (
+					path,
+					backing_name))
+
+		} else {
+			None
+		};
+
+		let backing =
+			match backing_path {
+
+			Some (ref backing_path) =>
+				Some (
+					image_open_backing (
+						backing_path,
+						backing_chain,
+					).map_err (
+						|error|
+
+						format! (
+							"Error opening backing file of {}: {}",
+							path.to_string_lossy (),
+							error)
+
+					) ?),
+
+			None => None,
+
+		};
+
+		Ok (Qcow2Image {
+			file: file,
+			file_size: file_size,
+			size: size,
+			cluster_bits: cluster_bits,
+			l1_table: l1_table,
+			backing_path: backing_path,
+			backing: backing,
+			corrupt: incompatible_features & QCOW2_INCOMPATIBLE_CORRUPT != 0,
+		})
+
+	}
+
+	fn cluster_size (& self) -> u64 {
+		1 << self.cluster_bits
+	}
+
+	// number of bits of a cluster index used to find its L2 entry
+
+	fn l2_bits (& self) -> u32 {
+		self.cluster_bits - 3
+	}
+
+	fn l2_table_offset (
+		& self,
+		l1_index: u64,
+	) -> u64 {
+
+		if l1_index >= self.l1_table.len () as u64 {
+			return 0;
+		}
+
+		self.l1_table [l1_index as usize] & QCOW2_OFFSET_MASK
+
+	}
+
+	fn l2_entry (
+		& self,
+		cluster_index: u64,
+	) -> io::Result <u64> {
+
+		let l2_table_offset =
+			self.l2_table_offset (
+				cluster_index >> self.l2_bits ());
+
+		if l2_table_offset == 0 {
+			return Ok (0);
+		}
+
+		let l2_index =
+			cluster_index & ((1 << self.l2_bits ()) - 1);
+
+		let mut entry_bytes: [u8; 8] = [0; 8];
+
+		file_read_at (
+			& self.file,
+			l2_table_offset + l2_index * 8,
+			& mut entry_bytes,
+		) ?;
+
+		Ok (be_u64 (& entry_bytes, 0))
+
+	}
+
+	fn l2_table (
+		& self,
+		l2_table_offset: u64,
+	) -> io::Result <Vec <u64>> {
+
+		let mut table_bytes: Vec <u8> =
+			vec! [0; self.cluster_size () as usize];
+
+		file_read_at (
+			& self.file,
+			l2_table_offset,
+			& mut table_bytes,
+		) ?;
+
+		Ok (
+			(0 .. table_bytes.len () / 8).map (
+				|l2_index|
+
+				be_u64 (& table_bytes, l2_index * 8)
+
+			).collect ())
+
+	}
+
+	fn cluster (
+		& self,
+		l2_entry: u64,
+	) -> Qcow2Cluster {
+
+		if l2_entry & QCOW2_L2_COMPRESSED != 0 {
+
+			// the split between offset and size depends on the cluster size
+
+			let offset_bits =
+				62 - (self.cluster_bits - 8);
+
+			let offset =
+				l2_entry & ((1 << offset_bits) - 1);
+
+			let num_sectors =
+				((l2_entry >> offset_bits)
+					& ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+
+			return Qcow2Cluster::Compressed (
+				offset,
+				num_sectors * 0x200 - (offset & 0x1ff));
+
+		}
+
+		if l2_entry & QCOW2_L2_ZERO != 0 {
+			return Qcow2Cluster::Zero;
+		}
+
+		match l2_entry & QCOW2_OFFSET_MASK {
+			0 => Qcow2Cluster::Unallocated,
+			offset => Qcow2Cluster::Data (offset),
+		}
+
+	}
+
+	fn read_cluster (
+		& self,
+		cluster_index: u64,
+		cluster_offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		match self.cluster (
+			self.l2_entry (
+				cluster_index,
+			) ?) {
+
+			Qcow2Cluster::Unallocated =>
+				image_read_backing (
+					& self.backing,
+					(cluster_index << self.cluster_bits) + cluster_offset,
+					buffer),
+
+			Qcow2Cluster::Zero => {
+
+				for byte in buffer.iter_mut () {
+					* byte = 0;
+				}
+
+				Ok (())
+
+			},
+
+			Qcow2Cluster::Data (offset) =>
+				file_read_at (
+					& self.file,
+					offset + cluster_offset,
+					buffer),
+
+			Qcow2Cluster::Compressed (offset, compressed_size) => {
+
+				// the last compressed cluster can end before the sectors
+				// it claims do
+
+				let mut compressed: Vec <u8> =
+					vec! [
+						0;
+						cmp::min (
+							compressed_size,
+							self.file_size.saturating_sub (offset),
+						) as usize
+					];
+
+				file_read_at (
+					& self.file,
+					offset,
+					& mut compressed,
+				) ?;
+
+				let mut cluster: Vec <u8> =
+					vec! [0; self.cluster_size () as usize];
+
+				image_inflate (
+					& compressed,
+					& mut cluster,
+					false,
+				) ?;
+
+				buffer.copy_from_slice (
+					& cluster [
+						cluster_offset as usize
+					..
+						cluster_offset as usize + buffer.len ()
+					]);
+
+				Ok (())
+
+			},
+
+		}
+
+	}
+
+}
+
+impl Image for Qcow2Image {
+
+	fn description (& self) -> String {
+
+		let mut description =
+			"qcow2 image".to_owned ();
+
+		if let Some (ref backing_path) = self.backing_path {
+
+			description.push_str (
+				& format! (
+					", backing file {}",
+					backing_path.to_string_lossy ()));
+
+		}
+
+		if self.corrupt {
+
+			description.push_str (
+				", marked corrupt");
+
+		}
+
+		description
+
+	}
+
+	fn size (& self) -> u64 {
+		self.size
+	}
+
+	fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		if offset + buffer.len () as u64 > self.size {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::UnexpectedEof,
+					"Read past end of device"));
+
+		}
+
+		let mut done: usize = 0;
+
+		while done < buffer.len () {
+
+			let position =
+				offset + done as u64;
+
+			let cluster_offset =
+				position & (self.cluster_size () - 1);
+
+			let length =
+				cmp::min (
+					self.cluster_size () - cluster_offset,
+					(buffer.len () - done) as u64,
+				) as usize;
+
+			self.read_cluster (
+				position >> self.cluster_bits,
+				cluster_offset,
+				& mut buffer [done .. done + length],
+			) ?;
+
+			done += length;
+
+		}
+
+		Ok (())
+
+	}
+
+	fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> io::Result <Vec <DeviceRange>> {
+
+		let mut ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		let end =
+			cmp::min (
+				end,
+				self.size);
+
+		let table_bits =
+			self.l2_bits () + self.cluster_bits;
+
+		let mut position = start;
+
+		while position < end {
+
+			let table_end =
+				cmp::min (
+					((position >> table_bits) + 1) << table_bits,
+					end);
+
+			let l2_table_offset =
+				self.l2_table_offset (
+					position >> table_bits);
+
+			// a missing L2 table means none of its clusters are allocated
+
+			if l2_table_offset == 0 {
+
+				image_backing_ranges (
+					& mut ranges,
+					& self.backing,
+					position,
+					table_end,
+				) ?;
+
+				position = table_end;
+
+				continue;
+
+			}
+
+			let l2_table =
+				self.l2_table (
+					l2_table_offset,
+				) ?;
+
+			while position < table_end {
+
+				let cluster_end =
+					cmp::min (
+						((position >> self.cluster_bits) + 1)
+							<< self.cluster_bits,
+						table_end);
+
+				let l2_index =
+					((position >> self.cluster_bits)
+						& ((1 << self.l2_bits ()) - 1)) as usize;
+
+				match self.cluster (l2_table [l2_index]) {
+
+					Qcow2Cluster::Unallocated =>
+						image_backing_ranges (
+							& mut ranges,
+							& self.backing,
+							position,
+							cluster_end,
+						) ?,
+
+					Qcow2Cluster::Zero =>
+						(),
+
+					Qcow2Cluster::Data (_) | Qcow2Cluster::Compressed (_, _) =>
+						image_add_range (
+							& mut ranges,
+							position,
+							cluster_end),
+
+				}
+
+				position = cluster_end;
+
+			}
+
+		}
+
+		Ok (ranges)
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::io::Write;
+	use std::process;
+
+	use super::*;
+
+	fn put_be (
+		bytes: & mut [u8],
+		offset: usize,
+		size: usize,
+		value: u64,
+	) {
+
+		for index in 0 .. size {
+			bytes [offset + index] = (value >> ((size - index - 1) * 8)) as u8;
+		}
+
+	}
+
+	// a version 2 image with 0x200 byte clusters, the backing file name
+	// straight after the header, and no L1 table unless the caller adds one
+
+	fn header (
+		backing_name: Option <& str>,
+	) -> Vec <u8> {
+
+		let mut bytes: Vec <u8> =
+			vec! [0; 0x200];
+
+		bytes [0 .. 4].copy_from_slice (QCOW2_MAGIC);
+
+		put_be (& mut bytes, 0x04, 4, 2);
+		put_be (& mut bytes, 0x14, 4, 9);
+		put_be (& mut bytes, 0x18, 8, 0x800);
+
+		if let Some (backing_name) = backing_name {
+
+			put_be (& mut bytes, 0x08, 8, 0x70);
+			put_be (& mut bytes, 0x10, 4, backing_name.len () as u64);
+
+			bytes [0x70 .. 0x70 + backing_name.len ()].copy_from_slice (
+				backing_name.as_bytes ());
+
+		}
+
+		bytes
+
+	}
+
+	fn write_file (
+		path: & Path,
+		bytes: & [u8],
+	) {
+
+		File::create (
+			path,
+		).unwrap ().write_all (
+			bytes,
+		).unwrap ();
+
+	}
+
+	fn temp_dir (
+		name: & str,
+	) -> PathBuf {
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"btrfs-fuse-qcow2-{}-{}",
+					name,
+					process::id ()));
+
+		fs::create_dir_all (& path).unwrap ();
+
+		path
+
+	}
+
+	#[ test ]
+	fn reads_each_kind_of_cluster () {
+
+		let dir = temp_dir ("clusters");
+
+		write_file (& dir.join ("backing"), & [0xcc; 0x800]);
+
+		let mut bytes =
+			header (Some ("backing"));
+
+		bytes.resize (0x800, 0);
+
+		put_be (& mut bytes, 0x24, 4, 1);
+		put_be (& mut bytes, 0x28, 8, 0x200);
+
+		// one L2 table with a data, a zero, an unallocated and a compressed
+		// cluster, which is a stored deflate block spanning two sectors
+
+		put_be (& mut bytes, 0x200, 8, 0x400);
+
+		put_be (& mut bytes, 0x400, 8, 0x600);
+		put_be (& mut bytes, 0x408, 8, QCOW2_L2_ZERO);
+		put_be (& mut bytes, 0x418, 8, QCOW2_L2_COMPRESSED | 1 << 61 | 0x800);
+
+		for byte in bytes [0x600 .. 0x800].iter_mut () {
+			* byte = 0xaa;
+		}
+
+		bytes.extend_from_slice (& [0x01, 0x00, 0x02, 0xff, 0xfd]);
+		bytes.extend_from_slice (& [0xbb; 0x200]);
+
+		write_file (& dir.join ("image"), & bytes);
+
+		let image =
+			Qcow2Image::open (
+				& dir.join ("image"),
+				& [],
+			).unwrap ();
+
+		let mut buffer: Vec <u8> =
+			vec! [0; 0x800];
+
+		image.read_at (0, & mut buffer).unwrap ();
+
+		assert! (buffer [0x000 .. 0x200].iter ().all (|byte| * byte == 0xaa));
+		assert! (buffer [0x200 .. 0x400].iter ().all (|byte| * byte == 0x00));
+		assert! (buffer [0x400 .. 0x600].iter ().all (|byte| * byte == 0xcc));
+		assert! (buffer [0x600 .. 0x800].iter ().all (|byte| * byte == 0xbb));
+
+		assert_eq! (
+			image.data_ranges (0, 0x800).unwrap (),
+			vec! [
+				DeviceRange { start: 0x000, end: 0x200 },
+				DeviceRange { start: 0x400, end: 0x800 },
+			]);
+
+		assert! (image.read_at (0x700, & mut buffer [0 .. 0x200]).is_err ());
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+	#[ test ]
+	fn backing_loops_are_rejected () {
+
+		let dir = temp_dir ("loop");
+
+		write_file (& dir.join ("a"), & header (Some ("b")));
+		write_file (& dir.join ("b"), & header (Some ("a")));
+
+		let error =
+			Qcow2Image::open (
+				& dir.join ("a"),
+				& [],
+			).err ().unwrap ();
+
+		assert! (error.contains ("Backing file loop"), error);
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+	#[ test ]
+	fn backing_chains_are_limited () {
+
+		let dir = temp_dir ("chain");
+
+		for index in 0 .. 17 {
+
+			write_file (
+				& dir.join (format! ("{}", index)),
+				& header (Some (& format! ("{}", index + 1))));
+
+		}
+
+		write_file (& dir.join ("17"), & header (None));
+
+		assert! (Qcow2Image::open (& dir.join ("1"), & []).is_ok ());
+
+		let error =
+			Qcow2Image::open (
+				& dir.join ("0"),
+				& [],
+			).err ().unwrap ();
+
+		assert! (error.contains ("More than 16 backing files"), error);
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+	#[ test ]
+	fn unsupported_versions_are_rejected () {
+
+		let dir = temp_dir ("version");
+
+		let mut bytes =
+			header (None);
+
+		put_be (& mut bytes, 0x04, 4, 4);
+
+		write_file (& dir.join ("image"), & bytes);
+
+		assert! (Qcow2Image::open (& dir.join ("image"), & []).is_err ());
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
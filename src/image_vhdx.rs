@@ -0,0 +1,749 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use crc::crc32;
+
+use bytes::*;
+use devices::*;
+use images::*;
+
+// VHDX images. two copies of the header and region table are checked by
+// crc32c, and the newest valid header wins. the region table locates the
+// block allocation table and the metadata, which gives the block and
+// sector size. data blocks are interleaved in the allocation table with
+// sector bitmap entries, which only matter for differencing disks, and
+// those aren't supported. the log isn't replayed, so an image which wasn't
+// closed cleanly may be missing its last writes.
+
+pub const VHDX_MAGIC: & 'static [u8] = b"vhdxfile";
+
+const VHDX_HEADER_OFFSETS: [u64; 2] = [0x1_0000, 0x2_0000];
+const VHDX_HEADER_SIZE: usize = 0x1000;
+const VHDX_HEADER_SIGNATURE: & 'static [u8] = b"head";
+
+const VHDX_REGION_TABLE_OFFSETS: [u64; 2] = [0x3_0000, 0x4_0000];
+const VHDX_REGION_TABLE_SIZE: usize = 0x1_0000;
+const VHDX_REGION_TABLE_SIGNATURE: & 'static [u8] = b"regi";
+const VHDX_REGION_MAX_ENTRIES: usize = 2047;
+
+const VHDX_METADATA_SIGNATURE: & 'static [u8] = b"metadata";
+const VHDX_METADATA_TABLE_SIZE: usize = 0x1_0000;
+const VHDX_METADATA_MAX_ENTRIES: usize = 2047;
+
+// guids, in the mixed endian form they are stored in
+
+const VHDX_BAT_GUID: [u8; 16] = [
+	0x66, 0x77, 0xc2, 0x2d, 0x23, 0xf6, 0x00, 0x42,
+	0x9d, 0x64, 0x11, 0x5e, 0x9b, 0xfd, 0x4a, 0x08,
+];
+
+const VHDX_METADATA_GUID: [u8; 16] = [
+	0x06, 0xa2, 0x7c, 0x8b, 0x90, 0x47, 0x9a, 0x4b,
+	0xb8, 0xfe, 0x57, 0x5f, 0x05, 0x0f, 0x88, 0x6e,
+];
+
+const VHDX_FILE_PARAMETERS_GUID: [u8; 16] = [
+	0x37, 0x67, 0xa1, 0xca, 0x36, 0xfa, 0x43, 0x4d,
+	0xb3, 0xb6, 0x33, 0xf0, 0xaa, 0x44, 0xe7, 0x6b,
+];
+
+const VHDX_VIRTUAL_DISK_SIZE_GUID: [u8; 16] = [
+	0x24, 0x42, 0xa5, 0x2f, 0x1b, 0xcd, 0x76, 0x48,
+	0xb2, 0x11, 0x5d, 0xbe, 0xd8, 0x3b, 0xf4, 0xb8,
+];
+
+const VHDX_LOGICAL_SECTOR_SIZE_GUID: [u8; 16] = [
+	0x1d, 0xbf, 0x41, 0x81, 0x6f, 0xa9, 0x09, 0x47,
+	0xba, 0x47, 0xf2, 0x33, 0xa8, 0xfa, 0xab, 0x5f,
+];
+
+const VHDX_FILE_PARAMETERS_HAS_PARENT: u32 = 0x2;
+
+const VHDX_BAT_STATE_MASK: u64 = 0x7;
+const VHDX_BAT_OFFSET_MASK: u64 = ! 0xf_ffff;
+const VHDX_BAT_FULLY_PRESENT: u64 = 6;
+const VHDX_BAT_PARTIALLY_PRESENT: u64 = 7;
+
+const VHDX_CHUNK_SECTORS: u64 = 1 << 23;
+
+pub struct VhdxImage {
+	file: File,
+	size: u64,
+	block_size: u64,
+	chunk_ratio: u64,
+	bat_offset: u64,
+	bat_entries: u64,
+	log_pending: bool,
+}
+
+impl VhdxImage {
+
+	pub fn open (
+		path: & Path,
+	) -> Result <VhdxImage, String> {
+
+		let file =
+			image_file_open (
+				path,
+			) ?;
+
+		// find the current header
+
+		let mut header: Option <Vec <u8>> = None;
+
+		for header_offset in VHDX_HEADER_OFFSETS.iter () {
+
+			let candidate =
+				image_file_read (
+					path,
+					& file,
+					* header_offset,
+					VHDX_HEADER_SIZE,
+				) ?;
+
+			if ! vhdx_checksum_valid (& candidate, VHDX_HEADER_SIGNATURE) {
+				continue;
+			}
+
+			let newer =
+				match header {
+					Some (ref header) => le_u64 (& candidate, 8) > le_u64 (header, 8),
+					None => true,
+				};
+
+			if newer {
+				header = Some (candidate);
+			}
+
+		}
+
+		let header =
+			header.ok_or_else (
+				|| image_invalid (path, "VHDX", "no valid header")
+			) ?;
+
+		let log_pending =
+			header [0x30 .. 0x40].iter ().any (
+				|byte| * byte != 0);
+
+		// find the regions
+
+		let region_table =
+			VHDX_REGION_TABLE_OFFSETS.iter ().filter_map (
+				|region_table_offset|
+
+				image_file_read (
+					path,
+					& file,
+					* region_table_offset,
+					VHDX_REGION_TABLE_SIZE,
+				).ok ().and_then (
+					|region_table|
+
+					if vhdx_checksum_valid (
+						& region_table,
+						VHDX_REGION_TABLE_SIGNATURE,
+					) {
+						Some (region_table)
+					} else {
+						None
+					}
+
+				)
+
+			).next ().ok_or_else (
+				|| image_invalid (path, "VHDX", "no valid region table")
+			) ?;
+
+		let num_regions =
+			cmp::min (
+				le_u32 (& region_table, 8) as usize,
+				VHDX_REGION_MAX_ENTRIES);
+
+		let mut bat_region: Option <(u64, u64)> = None;
+		let mut metadata_region: Option <(u64, u64)> = None;
+
+		for region_index in 0 .. num_regions {
+
+			let entry =
+				& region_table [
+					0x10 + region_index * 0x20
+				..
+					0x30 + region_index * 0x20
+				];
+
+			let region =
+				Some ((le_u64 (entry, 0x10), le_u32 (entry, 0x18) as u64));
+
+			if & entry [0 .. 16] == & VHDX_BAT_GUID [..] {
+				bat_region = region;
+			} else if & entry [0 .. 16] == & VHDX_METADATA_GUID [..] {
+				metadata_region = region;
+			}
+
+		}
+
+		let (bat_offset, bat_length) =
+			bat_region.ok_or_else (
+				|| image_invalid (path, "VHDX", "no block allocation table")
+			) ?;
+
+		let (metadata_offset, metadata_length) =
+			metadata_region.ok_or_else (
+				|| image_invalid (path, "VHDX", "no metadata region")
+			) ?;
+
+		// read the metadata we need
+
+		let metadata_table =
+			image_file_read (
+				path,
+				& file,
+				metadata_offset,
+				VHDX_METADATA_TABLE_SIZE,
+			) ?;
+
+		if ! metadata_table.starts_with (VHDX_METADATA_SIGNATURE) {
+
+			return Err (
+				image_invalid (
+					path,
+					"VHDX",
+					"invalid metadata table"));
+
+		}
+
+		let (file_parameters, size, logical_sector_size) = {
+
+			let metadata_item = |guid: & [u8; 16], size: usize| {
+
+				let num_items =
+					cmp::min (
+						le_u16 (& metadata_table, 0x0a) as usize,
+						VHDX_METADATA_MAX_ENTRIES);
+
+				for item_index in 0 .. num_items {
+
+					let entry =
+						& metadata_table [
+							0x20 + item_index * 0x20
+						..
+							0x40 + item_index * 0x20
+						];
+
+					if & entry [0 .. 16] != & guid [..] {
+						continue;
+					}
+
+					let item_offset = le_u32 (entry, 0x10) as u64;
+					let item_length = le_u32 (entry, 0x14) as u64;
+
+					if (
+						(item_length as usize) < size
+						|| item_offset + size as u64 > metadata_length
+					) {
+						break;
+					}
+
+					return image_file_read (
+						path,
+						& file,
+						metadata_offset + item_offset,
+						size);
+
+				}
+
+				Err (
+					image_invalid (
+						path,
+						"VHDX",
+						"missing metadata item"))
+
+			};
+
+			let file_parameters =
+				metadata_item (
+					& VHDX_FILE_PARAMETERS_GUID,
+					8,
+				) ?;
+
+			let size =
+				le_u64 (
+					& metadata_item (
+						& VHDX_VIRTUAL_DISK_SIZE_GUID,
+						8,
+					) ?,
+					0);
+
+			let logical_sector_size =
+				le_u32 (
+					& metadata_item (
+						& VHDX_LOGICAL_SECTOR_SIZE_GUID,
+						4,
+					) ?,
+					0) as u64;
+
+			(file_parameters, size, logical_sector_size)
+
+		};
+
+		let block_size =
+			le_u32 (& file_parameters, 0) as u64;
+
+		if le_u32 (& file_parameters, 4) & VHDX_FILE_PARAMETERS_HAS_PARENT != 0 {
+
+			return Err (
+				image_invalid (
+					path,
+					"VHDX",
+					"differencing disks are not supported"));
+
+		}
+
+		if (
+			! block_size.is_power_of_two ()
+			|| block_size < 0x10_0000
+			|| (logical_sector_size != 0x200 && logical_sector_size != 0x1000)
+		) {
+
+			return Err (
+				image_invalid (
+					path,
+					"VHDX",
+					"invalid block or sector size"));
+
+		}
+
+		Ok (VhdxImage {
+			file: file,
+			size: size,
+			block_size: block_size,
+			chunk_ratio: VHDX_CHUNK_SECTORS * logical_sector_size / block_size,
+			bat_offset: bat_offset,
+			bat_entries: bat_length / 8,
+			log_pending: log_pending,
+		})
+
+	}
+
+	// every chunk ratio data blocks are followed by a sector bitmap block
+
+	fn bat_entry (
+		& self,
+		block_index: u64,
+	) -> io::Result <u64> {
+
+		let bat_index =
+			block_index + block_index / self.chunk_ratio;
+
+		if bat_index >= self.bat_entries {
+			return Ok (0);
+		}
+
+		let mut entry_bytes: [u8; 8] = [0; 8];
+
+		file_read_at (
+			& self.file,
+			self.bat_offset + bat_index * 8,
+			& mut entry_bytes,
+		) ?;
+
+		Ok (le_u64 (& entry_bytes, 0))
+
+	}
+
+	// returns the position of a block in the file, if it is present
+
+	fn block_position (
+		& self,
+		block_index: u64,
+	) -> io::Result <Option <u64>> {
+
+		let entry =
+			self.bat_entry (
+				block_index,
+			) ?;
+
+		match entry & VHDX_BAT_STATE_MASK {
+
+			VHDX_BAT_FULLY_PRESENT | VHDX_BAT_PARTIALLY_PRESENT =>
+				Ok (Some (entry & VHDX_BAT_OFFSET_MASK)),
+
+			_ =>
+				Ok (None),
+
+		}
+
+	}
+
+}
+
+impl Image for VhdxImage {
+
+	fn description (& self) -> String {
+
+		if self.log_pending {
+			"VHDX image, log not replayed".to_owned ()
+		} else {
+			"VHDX image".to_owned ()
+		}
+
+	}
+
+	fn size (& self) -> u64 {
+		self.size
+	}
+
+	fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		if offset + buffer.len () as u64 > self.size {
+
+			return Err (
+				io::Error::new (
+					io::ErrorKind::UnexpectedEof,
+					"Read past end of device"));
+
+		}
+
+		let mut done: usize = 0;
+
+		while done < buffer.len () {
+
+			let position =
+				offset + done as u64;
+
+			let block_offset =
+				position % self.block_size;
+
+			let length =
+				cmp::min (
+					self.block_size - block_offset,
+					(buffer.len () - done) as u64,
+				) as usize;
+
+			let part =
+				& mut buffer [done .. done + length];
+
+			match self.block_position (
+				position / self.block_size,
+			) ? {
+
+				Some (block_position) =>
+					file_read_at (
+						& self.file,
+						block_position + block_offset,
+						part,
+					) ?,
+
+				None => {
+
+					for byte in part.iter_mut () {
+						* byte = 0;
+					}
+
+				},
+
+			}
+
+			done += length;
+
+		}
+
+		Ok (())
+
+	}
+
+	fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> io::Result <Vec <DeviceRange>> {
+
+		let mut ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		let end =
+			cmp::min (
+				end,
+				self.size);
+
+		let mut position = start;
+
+		while position < end {
+
+			let block_end =
+				cmp::min (
+					(position / self.block_size + 1) * self.block_size,
+					end);
+
+			if self.block_position (
+				position / self.block_size,
+			) ?.is_some () {
+
+				image_add_range (
+					& mut ranges,
+					position,
+					block_end);
+
+			}
+
+			position = block_end;
+
+		}
+
+		Ok (ranges)
+
+	}
+
+}
+
+// headers and region tables have a signature and a crc32c of the whole
+// structure, calculated with the checksum field zeroed
+
+fn vhdx_checksum_valid (
+	bytes: & [u8],
+	signature: & [u8],
+) -> bool {
+
+	if ! bytes.starts_with (signature) {
+		return false;
+	}
+
+	let mut zeroed =
+		bytes.to_vec ();
+
+	for byte in zeroed [4 .. 8].iter_mut () {
+		* byte = 0;
+	}
+
+	crc32::checksum_castagnoli (& zeroed) == le_u32 (bytes, 4)
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::os::unix::fs::FileExt;
+	use std::path::PathBuf;
+	use std::process;
+
+	use super::*;
+
+	fn put_le (
+		bytes: & mut [u8],
+		offset: usize,
+		size: usize,
+		value: u64,
+	) {
+
+		for index in 0 .. size {
+			bytes [offset + index] = (value >> (index * 8)) as u8;
+		}
+
+	}
+
+	fn put_checksum (
+		bytes: & mut [u8],
+	) {
+
+		let checksum =
+			crc32::checksum_castagnoli (bytes);
+
+		put_le (bytes, 4, 4, checksum as u64);
+
+	}
+
+	// a 3M disk with 1M blocks, of which the first and last are present.
+	// the second header is newer, and names a log if asked to.
+
+	fn write_image (
+		path: & Path,
+		log_pending: bool,
+		file_flags: u32,
+	) {
+
+		let file =
+			File::create (path).unwrap ();
+
+		file.set_len (0x50_0000).unwrap ();
+
+		file.write_at (VHDX_MAGIC, 0).unwrap ();
+
+		for (sequence, header_offset)
+		in VHDX_HEADER_OFFSETS.iter ().enumerate () {
+
+			let mut header: Vec <u8> =
+				vec! [0; VHDX_HEADER_SIZE];
+
+			header [0 .. 4].copy_from_slice (VHDX_HEADER_SIGNATURE);
+
+			put_le (& mut header, 8, 8, sequence as u64 + 1);
+
+			if log_pending && sequence == 1 {
+				header [0x30] = 1;
+			}
+
+			put_checksum (& mut header);
+
+			file.write_at (& header, * header_offset).unwrap ();
+
+		}
+
+		let mut region_table: Vec <u8> =
+			vec! [0; VHDX_REGION_TABLE_SIZE];
+
+		region_table [0 .. 4].copy_from_slice (VHDX_REGION_TABLE_SIGNATURE);
+
+		put_le (& mut region_table, 8, 4, 2);
+
+		region_table [0x10 .. 0x20].copy_from_slice (& VHDX_BAT_GUID);
+		put_le (& mut region_table, 0x20, 8, 0x10_0000);
+		put_le (& mut region_table, 0x28, 4, 0x10_0000);
+
+		region_table [0x30 .. 0x40].copy_from_slice (& VHDX_METADATA_GUID);
+		put_le (& mut region_table, 0x40, 8, 0x20_0000);
+		put_le (& mut region_table, 0x48, 4, 0x10_0000);
+
+		put_checksum (& mut region_table);
+
+		file.write_at (& region_table, VHDX_REGION_TABLE_OFFSETS [0]).unwrap ();
+
+		let mut metadata: Vec <u8> =
+			vec! [0; 0x1_0100];
+
+		metadata [0 .. 8].copy_from_slice (VHDX_METADATA_SIGNATURE);
+
+		put_le (& mut metadata, 0x0a, 2, 3);
+
+		for (item_index, & (guid, value)) in [
+			(VHDX_FILE_PARAMETERS_GUID, 0x10_0000 | (file_flags as u64) << 32),
+			(VHDX_VIRTUAL_DISK_SIZE_GUID, 0x30_0000),
+			(VHDX_LOGICAL_SECTOR_SIZE_GUID, 0x200),
+		].iter ().enumerate () {
+
+			let entry = 0x20 + item_index * 0x20;
+			let item_offset = 0x1_0000 + item_index * 0x10;
+
+			metadata [entry .. entry + 0x10].copy_from_slice (& guid);
+
+			put_le (& mut metadata, entry + 0x10, 4, item_offset as u64);
+			put_le (& mut metadata, entry + 0x14, 4, 8);
+			put_le (& mut metadata, item_offset, 8, value);
+
+		}
+
+		file.write_at (& metadata, 0x20_0000).unwrap ();
+
+		let mut bat: Vec <u8> =
+			vec! [0; 0x18];
+
+		put_le (& mut bat, 0x00, 8, 0x30_0000 | VHDX_BAT_FULLY_PRESENT);
+		put_le (& mut bat, 0x10, 8, 0x40_0000 | VHDX_BAT_PARTIALLY_PRESENT);
+
+		file.write_at (& bat, 0x10_0000).unwrap ();
+
+		file.write_at (& [0xaa; 0x200], 0x30_0000).unwrap ();
+		file.write_at (& [0xbb; 0x200], 0x40_0000).unwrap ();
+
+	}
+
+	fn temp_path (
+		name: & str,
+	) -> PathBuf {
+
+		env::temp_dir ().join (
+			format! (
+				"btrfs-fuse-vhdx-{}-{}",
+				name,
+				process::id ()))
+
+	}
+
+	#[ test ]
+	fn reads_present_blocks () {
+
+		let path = temp_path ("blocks");
+
+		write_image (& path, true, 0);
+
+		let image =
+			VhdxImage::open (
+				& path,
+			).unwrap ();
+
+		assert_eq! (image.size (), 0x30_0000);
+		assert_eq! (image.description (), "VHDX image, log not replayed");
+
+		let mut buffer: Vec <u8> =
+			vec! [0xff; 0x400];
+
+		image.read_at (0, & mut buffer).unwrap ();
+
+		assert! (buffer [0 .. 0x200].iter ().all (|byte| * byte == 0xaa));
+		assert! (buffer [0x200 .. 0x400].iter ().all (|byte| * byte == 0));
+
+		image.read_at (0x10_0000, & mut buffer).unwrap ();
+
+		assert! (buffer.iter ().all (|byte| * byte == 0));
+
+		image.read_at (0x20_0000, & mut buffer).unwrap ();
+
+		assert! (buffer [0 .. 0x200].iter ().all (|byte| * byte == 0xbb));
+
+		assert_eq! (
+			image.data_ranges (0, 0x30_0000).unwrap (),
+			vec! [
+				DeviceRange { start: 0x00_0000, end: 0x10_0000 },
+				DeviceRange { start: 0x20_0000, end: 0x30_0000 },
+			]);
+
+		assert! (image.read_at (0x2f_ff00, & mut buffer).is_err ());
+
+		fs::remove_file (path).unwrap ();
+
+	}
+
+	#[ test ]
+	fn rejects_damaged_and_differencing_images () {
+
+		let path = temp_path ("invalid");
+
+		write_image (& path, false, VHDX_FILE_PARAMETERS_HAS_PARENT);
+
+		assert! (VhdxImage::open (& path).is_err ());
+
+		write_image (& path, false, 0);
+
+		{
+
+			let file =
+				fs::OpenOptions::new ().write (true).open (& path).unwrap ();
+
+			for header_offset in VHDX_HEADER_OFFSETS.iter () {
+				file.write_at (& [0xff], * header_offset + 0x100).unwrap ();
+			}
+
+		}
+
+		let error =
+			VhdxImage::open (
+				& path,
+			).err ().unwrap ();
+
+		assert! (error.contains ("no valid header"), error);
+
+		fs::remove_file (path).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
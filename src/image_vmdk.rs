@@ -0,0 +1,1111 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use bytes::*;
+use devices::*;
+use images::*;
+
+// VMDK images, either a descriptor file listing extents, or a hosted sparse
+// extent with the descriptor embedded in it. sparse extents look up grains
+// through a grain directory and grain tables, and stream optimized ones
+// hold zlib compressed grains. flat and zero extents are also supported,
+// so split and preallocated disks work, and unallocated grains come from
+// the parent of a snapshot.
+
+pub const VMDK_SPARSE_MAGIC: & 'static [u8] = b"KDMV";
+pub const VMDK_DESCRIPTOR_MAGIC: & 'static [u8] = b"# Disk DescriptorFile";
+
+const VMDK_SECTOR_SIZE: u64 = 0x200;
+const VMDK_HEADER_SIZE: usize = 0x200;
+
+const VMDK_FLAG_ZERO_GRAIN: u32 = 1 << 2;
+const VMDK_FLAG_COMPRESSED: u32 = 1 << 16;
+
+const VMDK_GD_AT_END: u64 = 0xffff_ffff_ffff_ffff;
+const VMDK_GRAIN_MARKER_SIZE: u64 = 12;
+
+const VMDK_MAX_DESCRIPTOR_SIZE: u64 = 0x10_0000;
+const VMDK_MAX_GRAIN_SIZE: u64 = 0x10_0000;
+
+pub struct VmdkImage {
+	size: u64,
+	extents: Vec <VmdkExtent>,
+	parent_path: Option <PathBuf>,
+	parent: Option <Box <Image>>,
+}
+
+struct VmdkExtent {
+	start: u64,
+	size: u64,
+	source: VmdkExtentSource,
+}
+
+enum VmdkExtentSource {
+	Sparse (VmdkSparseExtent),
+	Flat (File, u64),
+	Zero,
+}
+
+struct VmdkSparseExtent {
+	file: File,
+	file_size: u64,
+	grain_size: u64,
+	grain_table_size: u64,
+	grain_directory: Vec <u32>,
+	compressed: bool,
+	zero_grains: bool,
+}
+
+enum VmdkGrain {
+	Unallocated,
+	Zero,
+	Data (u64),
+}
+
+impl VmdkImage {
+
+	pub fn open (
+		path: & Path,
+		backing_chain: & [PathBuf],
+	) -> Result <VmdkImage, String> {
+
+		let file =
+			image_file_open (
+				path,
+			) ?;
+
+		let file_size =
+			image_file_size (
+				path,
+				& file,
+			) ?;
+
+		let start =
+			image_file_read (
+				path,
+				& file,
+				0,
+				cmp::min (VMDK_HEADER_SIZE as u64, file_size) as usize,
+			) ?;
+
+		// a sparse extent on its own may have no descriptor, in which case
+		// it is the whole disk
+
+		let descriptor =
+			if start.starts_with (VMDK_SPARSE_MAGIC) {
+
+			let header =
+				vmdk_sparse_header (
+					path,
+					& file,
+					file_size,
+				) ?;
+
+			let descriptor_offset = le_u64 (& header, 0x1c);
+			let descriptor_size = le_u64 (& header, 0x24);
+
+			if descriptor_offset == 0 || descriptor_size == 0 {
+
+				let extent =
+					VmdkSparseExtent::open (
+						path,
+					) ?;
+
+				let size =
+					le_u64 (& header, 0x0c) * VMDK_SECTOR_SIZE;
+
+				return Ok (VmdkImage {
+					size: size,
+					extents: vec! [
+						VmdkExtent {
+							start: 0,
+							size: size,
+							source: VmdkExtentSource::Sparse (extent),
+						},
+					],
+					parent_path: None,
+					parent: None,
+				});
+
+			}
+
+			image_file_read (
+				path,
+				& file,
+				descriptor_offset * VMDK_SECTOR_SIZE,
+				cmp::min (
+					descriptor_size * VMDK_SECTOR_SIZE,
+					VMDK_MAX_DESCRIPTOR_SIZE,
+				) as usize,
+			) ?
+
+		} else {
+
+			if file_size > VMDK_MAX_DESCRIPTOR_SIZE {
+
+				return Err (
+					image_invalid (
+						path,
+						"VMDK",
+						"descriptor file too large"));
+
+			}
+
+			image_file_read (
+				path,
+				& file,
+				0,
+				file_size as usize,
+			) ?
+
+		};
+
+		Self::open_descriptor (
+			path,
+			& descriptor,
+			backing_chain)
+
+	}
+
+	// the descriptor is a text file with settings as key="value" lines, and
+	// a line for each extent like: RW 2048 SPARSE "disk-s001.vmdk" 0
+
+	fn open_descriptor (
+		path: & Path,
+		descriptor: & [u8],
+		backing_chain: & [PathBuf],
+	) -> Result <VmdkImage, String> {
+
+		let descriptor =
+			String::from_utf8_lossy (
+				descriptor);
+
+		let mut extents: Vec <VmdkExtent> =
+			Vec::new ();
+
+		let mut parent_name: Option <String> = None;
+		let mut has_parent = false;
+		let mut position: u64 = 0;
+
+		for line in descriptor.lines () {
+
+			let line =
+				line.trim_right_matches ('\0').trim ();
+
+			if line.is_empty () || line.starts_with ('#') {
+				continue;
+			}
+
+			if let Some (equals) = line.find ('=') {
+
+				let key = line [0 .. equals].trim ();
+				let value = line [equals + 1 ..].trim ().trim_matches ('"');
+
+				if key == "parentCID" {
+					has_parent = value != "ffffffff";
+				}
+
+				if key == "parentFileNameHint" {
+					parent_name = Some (value.to_owned ());
+				}
+
+				continue;
+
+			}
+
+			let fields: Vec <& str> =
+				line.split_whitespace ().collect ();
+
+			if fields.len () < 3 || ! [
+				"RW", "RDONLY", "NOACCESS",
+			].contains (& fields [0]) {
+				continue;
+			}
+
+			let invalid_extent = || {
+
+				image_invalid (
+					path,
+					"VMDK",
+					& format! ("invalid extent: {}", line))
+
+			};
+
+			let size =
+				u64::from_str_radix (
+					fields [1],
+					10,
+				).map_err (
+					|_| invalid_extent ()
+				) ? * VMDK_SECTOR_SIZE;
+
+			// the file name is quoted, and may contain spaces
+
+			let extent_path =
+				match (line.find ('"'), line.rfind ('"')) {
+
+				(Some (name_start), Some (name_end))
+				if name_end > name_start =>
+					Some ((
+						image_relative_path (
+							path,
+							& line [name_start + 1 .. name_end]),
+						line [name_end + 1 ..].trim (),
+					)),
+
+				_ => None,
+
+			};
+
+			let source =
+				match (fields [2], extent_path) {
+
+				("ZERO", _) =>
+					VmdkExtentSource::Zero,
+
+				("SPARSE", Some ((ref extent_path, _))) =>
+					VmdkExtentSource::Sparse (
+						VmdkSparseExtent::open (
+							extent_path,
+						) ?),
+
+				("FLAT", Some ((ref extent_path, offset)))
+				| ("VMFS", Some ((ref extent_path, offset))) =>
+					VmdkExtentSource::Flat (
+						image_file_open (
+							extent_path,
+						) ?,
+						if offset.is_empty () {
+							0
+						} else {
+							u64::from_str_radix (
+								offset,
+								10,
+							).map_err (
+								|_| invalid_extent ()
+							) ? * VMDK_SECTOR_SIZE
+						}),
+
+				(extent_type, Some (_)) =>
+					return Err (
+						image_invalid (
+							path,
+							"VMDK",
+							& format! (
+								"unsupported extent type {}",
+								extent_type))),
+
+				(_, None) =>
+					return Err (invalid_extent ()),
+
+			};
+
+			extents.push (
+				VmdkExtent {
+					start: position,
+					size: size,
+					source: source,
+				});
+
+			position += size;
+
+		}
+
+		if extents.is_empty () {
+
+			return Err (
+				image_invalid (
+					path,
+					"VMDK",
+					"no extents in descriptor"));
+
+		}
+
+		let parent_path =
+			if has_parent {
+
+			Some (
+				image_relative_path (
+					path,
+					& parent_name.ok_or_else (
+						||
+
+						image_invalid (
+							path,
+							"VMDK",
+							"snapshot has no parent file name")
+
+					) ?))
+
+		} else {
+			None
+		};
+
+		let parent =
+			match parent_path {
+
+			Some (ref parent_path) =>
+				Some (
+					image_open_backing (
+						parent_path,
+						backing_chain,
+					).map_err (
+						|error|
+
+						format! (
+							"Error opening parent of {}: {}",
+							path.to_string_lossy (),
+							error)
+
+					) ?),
+
+			None => None,
+
+		};
+
+		Ok (VmdkImage {
+			size: position,
+			extents: extents,
+			parent_path: parent_path,
+			parent: parent,
+		})
+
+	}
+
+	fn extent_containing (
+		& self,
+		position: u64,
+	) -> Option <& VmdkExtent> {
+
+		self.extents.iter ().find (
+			|extent|
+
+			position >= extent.start
+			&& position < extent.start + extent.size
+
+		)
+
+	}
+
+}
+
+impl Image for VmdkImage {
+
+	fn description (& self) -> String {
+
+		match self.parent_path {
+
+			Some (ref parent_path) =>
+				format! (
+					"VMDK image with {} extents, parent {}",
+					self.extents.len (),
+					parent_path.to_string_lossy ()),
+
+			None =>
+				format! (
+					"VMDK image with {} extents",
+					self.extents.len ()),
+
+		}
+
+	}
+
+	fn size (& self) -> u64 {
+		self.size
+	}
+
+	fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		let mut done: usize = 0;
+
+		while done < buffer.len () {
+
+			let position =
+				offset + done as u64;
+
+			let extent =
+				self.extent_containing (
+					position,
+				).ok_or_else (
+					||
+
+					io::Error::new (
+						io::ErrorKind::UnexpectedEof,
+						"Read past end of device")
+
+				) ?;
+
+			let extent_offset =
+				position - extent.start;
+
+			let mut length =
+				cmp::min (
+					extent.size - extent_offset,
+					(buffer.len () - done) as u64,
+				) as usize;
+
+			let part =
+				& mut buffer [done .. done + length];
+
+			match extent.source {
+
+				VmdkExtentSource::Sparse (ref sparse) => {
+
+					// one grain at a time
+
+					let grain_offset =
+						extent_offset % sparse.grain_size;
+
+					length =
+						cmp::min (
+							length as u64,
+							sparse.grain_size - grain_offset,
+						) as usize;
+
+					let part =
+						& mut part [0 .. length];
+
+					match sparse.grain (extent_offset) ? {
+
+						VmdkGrain::Unallocated =>
+							image_read_backing (
+								& self.parent,
+								position,
+								part,
+							) ?,
+
+						VmdkGrain::Zero => {
+
+							for byte in part.iter_mut () {
+								* byte = 0;
+							}
+
+						},
+
+						VmdkGrain::Data (grain_position) =>
+							sparse.read_grain (
+								grain_position,
+								grain_offset,
+								part,
+							) ?,
+
+					}
+
+				},
+
+				VmdkExtentSource::Flat (ref file, file_offset) =>
+					file_read_at (
+						file,
+						file_offset + extent_offset,
+						part,
+					) ?,
+
+				VmdkExtentSource::Zero => {
+
+					for byte in part.iter_mut () {
+						* byte = 0;
+					}
+
+				},
+
+			}
+
+			done += length;
+
+		}
+
+		Ok (())
+
+	}
+
+	fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> io::Result <Vec <DeviceRange>> {
+
+		let mut ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		for extent in self.extents.iter () {
+
+			let extent_end =
+				extent.start + extent.size;
+
+			if extent_end <= start || extent.start >= end {
+				continue;
+			}
+
+			let range_start = cmp::max (start, extent.start);
+			let range_end = cmp::min (end, extent_end);
+
+			match extent.source {
+
+				VmdkExtentSource::Sparse (ref sparse) =>
+					sparse.data_ranges (
+						& mut ranges,
+						& self.parent,
+						extent.start,
+						range_start,
+						range_end,
+					) ?,
+
+				VmdkExtentSource::Flat (_, _) =>
+					image_add_range (
+						& mut ranges,
+						range_start,
+						range_end),
+
+				VmdkExtentSource::Zero =>
+					(),
+
+			}
+
+		}
+
+		Ok (ranges)
+
+	}
+
+}
+
+impl VmdkSparseExtent {
+
+	fn open (
+		path: & Path,
+	) -> Result <VmdkSparseExtent, String> {
+
+		let file =
+			image_file_open (
+				path,
+			) ?;
+
+		let file_size =
+			image_file_size (
+				path,
+				& file,
+			) ?;
+
+		let header =
+			vmdk_sparse_header (
+				path,
+				& file,
+				file_size,
+			) ?;
+
+		let flags = le_u32 (& header, 0x08);
+		let capacity = le_u64 (& header, 0x0c) * VMDK_SECTOR_SIZE;
+		let grain_size = le_u64 (& header, 0x14) * VMDK_SECTOR_SIZE;
+		let grain_table_size = le_u32 (& header, 0x2c) as u64;
+		let grain_directory_offset = le_u64 (& header, 0x38);
+
+		if (
+			grain_size == 0
+			|| grain_size > VMDK_MAX_GRAIN_SIZE
+			|| ! grain_size.is_power_of_two ()
+			|| grain_table_size == 0
+		) {
+
+			return Err (
+				image_invalid (
+					path,
+					"VMDK",
+					"invalid grain size"));
+
+		}
+
+		let num_grain_tables =
+			(capacity / grain_size + grain_table_size - 1)
+				/ grain_table_size;
+
+		let grain_directory_bytes =
+			image_file_read (
+				path,
+				& file,
+				grain_directory_offset * VMDK_SECTOR_SIZE,
+				num_grain_tables as usize * 4,
+			) ?;
+
+		Ok (VmdkSparseExtent {
+			file: file,
+			file_size: file_size,
+			grain_size: grain_size,
+			grain_table_size: grain_table_size,
+			grain_directory: (0 .. num_grain_tables as usize).map (
+				|grain_table_index|
+
+				le_u32 (& grain_directory_bytes, grain_table_index * 4)
+
+			).collect (),
+			compressed: flags & VMDK_FLAG_COMPRESSED != 0,
+			zero_grains: flags & VMDK_FLAG_ZERO_GRAIN != 0,
+		})
+
+	}
+
+	fn grain_entry (
+		& self,
+		entry: u32,
+	) -> VmdkGrain {
+
+		match entry {
+			0 => VmdkGrain::Unallocated,
+			1 if self.zero_grains => VmdkGrain::Zero,
+			_ => VmdkGrain::Data (entry as u64 * VMDK_SECTOR_SIZE),
+		}
+
+	}
+
+	fn grain (
+		& self,
+		extent_offset: u64,
+	) -> io::Result <VmdkGrain> {
+
+		let grain_index =
+			extent_offset / self.grain_size;
+
+		let grain_table_position =
+			match self.grain_directory.get (
+				(grain_index / self.grain_table_size) as usize) {
+
+			Some (& 0) | None =>
+				return Ok (VmdkGrain::Unallocated),
+
+			Some (& grain_table_sector) =>
+				grain_table_sector as u64 * VMDK_SECTOR_SIZE,
+
+		};
+
+		let mut entry_bytes: [u8; 4] = [0; 4];
+
+		file_read_at (
+			& self.file,
+			grain_table_position
+				+ (grain_index % self.grain_table_size) * 4,
+			& mut entry_bytes,
+		) ?;
+
+		Ok (
+			self.grain_entry (
+				le_u32 (& entry_bytes, 0)))
+
+	}
+
+	// compressed grains start with a marker holding the grain's position
+	// and the size of the compressed data which follows
+
+	fn read_grain (
+		& self,
+		grain_position: u64,
+		grain_offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		if ! self.compressed {
+
+			return file_read_at (
+				& self.file,
+				grain_position + grain_offset,
+				buffer);
+
+		}
+
+		let mut marker: [u8; VMDK_GRAIN_MARKER_SIZE as usize] =
+			[0; VMDK_GRAIN_MARKER_SIZE as usize];
+
+		file_read_at (
+			& self.file,
+			grain_position,
+			& mut marker,
+		) ?;
+
+		let compressed_size =
+			cmp::min (
+				le_u32 (& marker, 8) as u64,
+				self.file_size.saturating_sub (
+					grain_position + VMDK_GRAIN_MARKER_SIZE));
+
+		let mut compressed: Vec <u8> =
+			vec! [0; compressed_size as usize];
+
+		file_read_at (
+			& self.file,
+			grain_position + VMDK_GRAIN_MARKER_SIZE,
+			& mut compressed,
+		) ?;
+
+		let mut grain: Vec <u8> =
+			vec! [0; self.grain_size as usize];
+
+		image_inflate (
+			& compressed,
+			& mut grain,
+			true,
+		) ?;
+
+		buffer.copy_from_slice (
+			& grain [
+				grain_offset as usize
+			..
+				grain_offset as usize + buffer.len ()
+			]);
+
+		Ok (())
+
+	}
+
+	fn data_ranges (
+		& self,
+		ranges: & mut Vec <DeviceRange>,
+		parent: & Option <Box <Image>>,
+		extent_start: u64,
+		start: u64,
+		end: u64,
+	) -> io::Result <()> {
+
+		let table_size =
+			self.grain_size * self.grain_table_size;
+
+		let mut position = start;
+
+		while position < end {
+
+			let extent_offset =
+				position - extent_start;
+
+			let grain_table_index =
+				extent_offset / table_size;
+
+			let table_end =
+				cmp::min (
+					extent_start + (grain_table_index + 1) * table_size,
+					end);
+
+			let grain_table_sector =
+				self.grain_directory.get (
+					grain_table_index as usize,
+				).cloned ().unwrap_or (0);
+
+			if grain_table_sector == 0 {
+
+				image_backing_ranges (
+					ranges,
+					parent,
+					position,
+					table_end,
+				) ?;
+
+				position = table_end;
+
+				continue;
+
+			}
+
+			let mut grain_table_bytes: Vec <u8> =
+				vec! [0; self.grain_table_size as usize * 4];
+
+			file_read_at (
+				& self.file,
+				grain_table_sector as u64 * VMDK_SECTOR_SIZE,
+				& mut grain_table_bytes,
+			) ?;
+
+			while position < table_end {
+
+				let extent_offset =
+					position - extent_start;
+
+				let grain_end =
+					cmp::min (
+						extent_start
+							+ (extent_offset / self.grain_size + 1)
+								* self.grain_size,
+						table_end);
+
+				let entry_index =
+					((extent_offset / self.grain_size)
+						% self.grain_table_size) as usize;
+
+				match self.grain_entry (
+					le_u32 (& grain_table_bytes, entry_index * 4)) {
+
+					VmdkGrain::Unallocated =>
+						image_backing_ranges (
+							ranges,
+							parent,
+							position,
+							grain_end,
+						) ?,
+
+					VmdkGrain::Zero =>
+						(),
+
+					VmdkGrain::Data (_) =>
+						image_add_range (
+							ranges,
+							position,
+							grain_end),
+
+				}
+
+				position = grain_end;
+
+			}
+
+		}
+
+		Ok (())
+
+	}
+
+}
+
+// reads the header of a sparse extent. stream optimized images write the
+// grain directory last, and put a copy of the header with its position in a
+// footer just before the end of stream marker.
+
+fn vmdk_sparse_header (
+	path: & Path,
+	file: & File,
+	file_size: u64,
+) -> Result <Vec <u8>, String> {
+
+	let header =
+		image_file_read (
+			path,
+			file,
+			0,
+			VMDK_HEADER_SIZE,
+		) ?;
+
+	if le_u64 (& header, 0x38) != VMDK_GD_AT_END {
+		return Ok (header);
+	}
+
+	if file_size < 0x400 {
+
+		return Err (
+			image_invalid (
+				path,
+				"VMDK",
+				"missing footer"));
+
+	}
+
+	let footer =
+		image_file_read (
+			path,
+			file,
+			file_size - 0x400,
+			VMDK_HEADER_SIZE,
+		) ?;
+
+	if ! footer.starts_with (VMDK_SPARSE_MAGIC) {
+
+		return Err (
+			image_invalid (
+				path,
+				"VMDK",
+				"invalid footer"));
+
+	}
+
+	Ok (footer)
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::io::Write;
+	use std::process;
+
+	use super::*;
+
+	fn put_le (
+		bytes: & mut [u8],
+		offset: usize,
+		size: usize,
+		value: u64,
+	) {
+
+		for index in 0 .. size {
+			bytes [offset + index] = (value >> (index * 8)) as u8;
+		}
+
+	}
+
+	fn write_file (
+		path: & Path,
+		bytes: & [u8],
+	) {
+
+		File::create (
+			path,
+		).unwrap ().write_all (
+			bytes,
+		).unwrap ();
+
+	}
+
+	fn temp_dir (
+		name: & str,
+	) -> PathBuf {
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"btrfs-fuse-vmdk-{}-{}",
+					name,
+					process::id ()));
+
+		fs::create_dir_all (& path).unwrap ();
+
+		path
+
+	}
+
+	#[ test ]
+	fn reads_flat_and_zero_extents () {
+
+		let dir = temp_dir ("flat");
+
+		write_file (
+			& dir.join ("disk-flat.vmdk"),
+			& (0 .. 0xa00).map (|index| (index / 0x200) as u8 + 1).collect::<Vec <u8>> ());
+
+		write_file (
+			& dir.join ("disk.vmdk"),
+			b"# Disk DescriptorFile\n\
+			version=1\n\
+			parentCID=ffffffff\n\
+			RW 4 FLAT \"disk-flat.vmdk\" 1\n\
+			RW 4 ZERO\n");
+
+		let image =
+			VmdkImage::open (
+				& dir.join ("disk.vmdk"),
+				& [],
+			).unwrap ();
+
+		assert_eq! (image.size (), 0x1000);
+
+		let mut buffer: Vec <u8> =
+			vec! [0xff; 0x1000];
+
+		image.read_at (0, & mut buffer).unwrap ();
+
+		assert! (buffer [0x000 .. 0x200].iter ().all (|byte| * byte == 2));
+		assert! (buffer [0x600 .. 0x800].iter ().all (|byte| * byte == 5));
+		assert! (buffer [0x800 .. 0x1000].iter ().all (|byte| * byte == 0));
+
+		assert_eq! (
+			image.data_ranges (0, 0x1000).unwrap (),
+			vec! [ DeviceRange { start: 0, end: 0x800 } ]);
+
+		assert! (image.read_at (0xe00, & mut buffer [0 .. 0x400]).is_err ());
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+	#[ test ]
+	fn reads_hosted_sparse_extents () {
+
+		let dir = temp_dir ("sparse");
+
+		// eight one sector grains, in two grain tables of four entries, of
+		// which only the first is present. its grains are data, zero,
+		// unallocated and data.
+
+		let mut bytes: Vec <u8> =
+			vec! [0; 0xa00];
+
+		bytes [0 .. 4].copy_from_slice (VMDK_SPARSE_MAGIC);
+
+		put_le (& mut bytes, 0x04, 4, 1);
+		put_le (& mut bytes, 0x08, 4, VMDK_FLAG_ZERO_GRAIN as u64);
+		put_le (& mut bytes, 0x0c, 8, 8);
+		put_le (& mut bytes, 0x14, 8, 1);
+		put_le (& mut bytes, 0x2c, 4, 4);
+		put_le (& mut bytes, 0x38, 8, 1);
+
+		put_le (& mut bytes, 0x200, 4, 2);
+
+		put_le (& mut bytes, 0x400, 4, 3);
+		put_le (& mut bytes, 0x404, 4, 1);
+		put_le (& mut bytes, 0x40c, 4, 4);
+
+		for byte in bytes [0x600 .. 0x800].iter_mut () {
+			* byte = 0xaa;
+		}
+
+		for byte in bytes [0x800 .. 0xa00].iter_mut () {
+			* byte = 0xbb;
+		}
+
+		write_file (& dir.join ("disk.vmdk"), & bytes);
+
+		let image =
+			VmdkImage::open (
+				& dir.join ("disk.vmdk"),
+				& [],
+			).unwrap ();
+
+		let mut buffer: Vec <u8> =
+			vec! [0xff; 0x1000];
+
+		image.read_at (0, & mut buffer).unwrap ();
+
+		assert! (buffer [0x000 .. 0x200].iter ().all (|byte| * byte == 0xaa));
+		assert! (buffer [0x200 .. 0x600].iter ().all (|byte| * byte == 0));
+		assert! (buffer [0x600 .. 0x800].iter ().all (|byte| * byte == 0xbb));
+		assert! (buffer [0x800 .. 0x1000].iter ().all (|byte| * byte == 0));
+
+		assert_eq! (
+			image.data_ranges (0, 0x1000).unwrap (),
+			vec! [
+				DeviceRange { start: 0x000, end: 0x200 },
+				DeviceRange { start: 0x600, end: 0x800 },
+			]);
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+	#[ test ]
+	fn parent_loops_are_rejected () {
+
+		let dir = temp_dir ("loop");
+
+		write_file (
+			& dir.join ("disk.vmdk"),
+			b"# Disk DescriptorFile\n\
+			parentCID=12345678\n\
+			parentFileNameHint=\"disk.vmdk\"\n\
+			RW 4 ZERO\n");
+
+		let error =
+			VmdkImage::open (
+				& dir.join ("disk.vmdk"),
+				& [],
+			).err ().unwrap ();
+
+		assert! (error.contains ("Backing file loop"), error);
+
+		fs::remove_dir_all (dir).unwrap ();
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,619 @@
+use std::cmp;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use flate2::read::DeflateDecoder;
+use flate2::read::ZlibDecoder;
+
+use bytes::*;
+use devices::*;
+use image_metadump::*;
+use image_qcow2::*;
+use image_vhdx::*;
+use image_vmdk::*;
+
+// virtual machine disk images, and btrfs-image metadata dumps. these map
+// positions on the virtual disk to positions in one or more files, with
+// unallocated parts read as zeros or from a backing image. a raw image is
+// used directly, and isn't opened through here unless it is the backing file
+// of another image.
+
+pub trait Image: Send + Sync {
+
+	fn description (& self) -> String;
+
+	fn size (& self) -> u64;
+
+	fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()>;
+
+	// returns the parts of a range which are allocated, either in this
+	// image or in its backing image
+
+	fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> io::Result <Vec <DeviceRange>>;
+
+	// false for metadata dumps, where data extents were never copied
+
+	fn has_file_data (& self) -> bool {
+		true
+	}
+
+}
+
+// a raw file, used as a backing image or an extent of a VMDK
+
+pub struct RawImage {
+	file: File,
+	size: u64,
+}
+
+impl RawImage {
+
+	pub fn open (
+		path: & Path,
+	) -> Result <RawImage, String> {
+
+		let file =
+			image_file_open (
+				path,
+			) ?;
+
+		let size =
+			image_file_size (
+				path,
+				& file,
+			) ?;
+
+		Ok (RawImage {
+			file: file,
+			size: size,
+		})
+
+	}
+
+}
+
+impl Image for RawImage {
+
+	fn description (& self) -> String {
+		"raw image".to_owned ()
+	}
+
+	fn size (& self) -> u64 {
+		self.size
+	}
+
+	fn read_at (
+		& self,
+		offset: u64,
+		buffer: & mut [u8],
+	) -> io::Result <()> {
+
+		file_read_at (
+			& self.file,
+			offset,
+			buffer)
+
+	}
+
+	fn data_ranges (
+		& self,
+		start: u64,
+		end: u64,
+	) -> io::Result <Vec <DeviceRange>> {
+
+		Ok (vec! [
+			DeviceRange {
+				start: start,
+				end: cmp::min (end, self.size),
+			},
+		])
+
+	}
+
+}
+
+// backing chains can loop, or be deep enough to run out of stack or file
+// handles, so we stop at an image seen before or this many backing images
+
+const IMAGE_MAX_BACKING_DEPTH: usize = 16;
+
+// looks at the start of a file to see if it is a virtual disk image, and
+// opens it if so. anything else is treated as raw by the caller. the chain
+// holds the canonical paths of the images this one is backing.
+
+pub fn image_open (
+	path: & Path,
+	file: & File,
+	backing_chain: & [PathBuf],
+) -> Result <Option <Box <Image>>, String> {
+
+	let mut header: [u8; 0x20] = [0; 0x20];
+
+	if file_read_at (file, 0, & mut header).is_err () {
+		return Ok (None);
+	}
+
+	if le_u64 (& header, 0) == METADUMP_MAGIC {
+
+		return Ok (Some (Box::new (
+			MetadumpImage::open (
+				path,
+			) ?)));
+
+	}
+
+	if & header [0 .. 4] == QCOW2_MAGIC {
+
+		return Ok (Some (Box::new (
+			Qcow2Image::open (
+				path,
+				backing_chain,
+			) ?)));
+
+	}
+
+	if (
+		& header [0 .. 4] == VMDK_SPARSE_MAGIC
+		|| header.starts_with (VMDK_DESCRIPTOR_MAGIC)
+	) {
+
+		return Ok (Some (Box::new (
+			VmdkImage::open (
+				path,
+				backing_chain,
+			) ?)));
+
+	}
+
+	if & header [0 .. 8] == VHDX_MAGIC {
+
+		return Ok (Some (Box::new (
+			VhdxImage::open (
+				path,
+			) ?)));
+
+	}
+
+	Ok (None)
+
+}
+
+// opens a backing image, which may itself be any supported format
+
+pub fn image_open_backing (
+	path: & Path,
+	backing_chain: & [PathBuf],
+) -> Result <Box <Image>, String> {
+
+	let canonical_path =
+		fs::canonicalize (
+			path,
+		).map_err (
+			|error|
+
+			format! (
+				"Error opening {}: {}",
+				path.to_string_lossy (),
+				error.description ())
+
+		) ?;
+
+	if backing_chain.contains (& canonical_path) {
+
+		return Err (
+			format! (
+				"Backing file loop at {}",
+				path.to_string_lossy ()));
+
+	}
+
+	if backing_chain.len () >= IMAGE_MAX_BACKING_DEPTH {
+
+		return Err (
+			format! (
+				"More than {} backing files at {}",
+				IMAGE_MAX_BACKING_DEPTH,
+				path.to_string_lossy ()));
+
+	}
+
+	let mut backing_chain =
+		backing_chain.to_vec ();
+
+	backing_chain.push (
+		canonical_path);
+
+	let file =
+		image_file_open (
+			path,
+		) ?;
+
+	match image_open (path, & file, & backing_chain) ? {
+		Some (image) => Ok (image),
+		None => Ok (Box::new (RawImage::open (path) ?)),
+	}
+
+}
+
+// backing and extent file names are relative to the image which names them
+
+pub fn image_relative_path (
+	image_path: & Path,
+	name: & str,
+) -> PathBuf {
+
+	match image_path.parent () {
+		Some (parent) => parent.join (name),
+		None => PathBuf::from (name),
+	}
+
+}
+
+pub fn image_file_open (
+	path: & Path,
+) -> Result <File, String> {
+
+	File::open (
+		path,
+	).map_err (
+		|error|
+
+		format! (
+			"Error opening {}: {}",
+			path.to_string_lossy (),
+			error.description ())
+
+	)
+
+}
+
+pub fn image_file_size (
+	path: & Path,
+	file: & File,
+) -> Result <u64, String> {
+
+	// seek rather than using metadata, which reports zero for block devices
+
+	let mut file = file;
+
+	file.seek (
+		SeekFrom::End (0),
+	).map_err (
+		|error|
+
+		format! (
+			"Error finding size of {}: {}",
+			path.to_string_lossy (),
+			error.description ())
+
+	)
+
+}
+
+// reads bytes from an image file while opening it, where any failure means
+// the image can't be used
+
+pub fn image_file_read (
+	path: & Path,
+	file: & File,
+	offset: u64,
+	size: usize,
+) -> Result <Vec <u8>, String> {
+
+	let mut bytes: Vec <u8> =
+		vec! [0; size];
+
+	file_read_at (
+		file,
+		offset,
+		& mut bytes,
+	).map_err (
+		|error|
+
+		format! (
+			"Error reading {} at 0x{:x}: {}",
+			path.to_string_lossy (),
+			offset,
+			error.description ())
+
+	) ?;
+
+	Ok (bytes)
+
+}
+
+// pread which carries on until the buffer is full
+
+pub fn file_read_at (
+	file: & File,
+	offset: u64,
+	buffer: & mut [u8],
+) -> io::Result <()> {
+
+	let mut done: usize = 0;
+
+	while done < buffer.len () {
+
+		match file.read_at (
+			& mut buffer [done .. ],
+			offset + done as u64,
+		) {
+
+			Ok (0) =>
+				return Err (
+					io::Error::new (
+						io::ErrorKind::UnexpectedEof,
+						"Read past end of device")),
+
+			Ok (bytes_read) =>
+				done += bytes_read,
+
+			Err (ref error)
+			if error.kind () == io::ErrorKind::Interrupted =>
+				(),
+
+			Err (error) =>
+				return Err (error),
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+// decompresses a compressed cluster or grain. the compressed size is often
+// rounded up to a sector, so trailing bytes are ignored, and the output is
+// zero filled if there is less data than expected.
+
+pub fn image_inflate (
+	compressed: & [u8],
+	buffer: & mut [u8],
+	zlib_header: bool,
+) -> io::Result <()> {
+
+	let done =
+		if zlib_header {
+
+			image_read_all (
+				ZlibDecoder::new (compressed),
+				buffer,
+			) ?
+
+		} else {
+
+			image_read_all (
+				DeflateDecoder::new (compressed),
+				buffer,
+			) ?
+
+		};
+
+	for byte in buffer [done ..].iter_mut () {
+		* byte = 0;
+	}
+
+	Ok (())
+
+}
+
+// decompresses a whole zlib stream, when the size isn't known. a stream
+// which inflates to more than the maximum is treated as corrupt, rather than
+// letting it use up memory.
+
+pub fn image_inflate_all (
+	compressed: & [u8],
+	max_size: u64,
+) -> io::Result <Vec <u8>> {
+
+	let mut buffer: Vec <u8> =
+		Vec::new ();
+
+	ZlibDecoder::new (
+		compressed,
+	).take (
+		max_size + 1,
+	).read_to_end (
+		& mut buffer,
+	).map_err (
+		image_inflate_error,
+	) ?;
+
+	if buffer.len () as u64 > max_size {
+
+		return Err (
+			io::Error::new (
+				io::ErrorKind::InvalidData,
+				format! (
+					"Decompressed data is longer than 0x{:x} bytes",
+					max_size)));
+
+	}
+
+	Ok (buffer)
+
+}
+
+fn image_read_all <Source: Read> (
+	mut source: Source,
+	buffer: & mut [u8],
+) -> io::Result <usize> {
+
+	let mut done: usize = 0;
+
+	while done < buffer.len () {
+
+		match source.read (
+			& mut buffer [done ..],
+		).map_err (
+			image_inflate_error,
+		) ? {
+			0 => break,
+			bytes_read => done += bytes_read,
+		}
+
+	}
+
+	Ok (done)
+
+}
+
+// the decoder reports a corrupt stream as invalid input, but to a reader it
+// is invalid data in the image, which is treated like an unreadable sector
+
+fn image_inflate_error (
+	error: io::Error,
+) -> io::Error {
+
+	io::Error::new (
+		io::ErrorKind::InvalidData,
+		format! (
+			"Corrupt compressed data: {}",
+			error.description ()))
+
+}
+
+// reads the part of a range which falls inside a backing image, with zeros
+// for the rest or if there is no backing image
+
+pub fn image_read_backing (
+	backing: & Option <Box <Image>>,
+	offset: u64,
+	buffer: & mut [u8],
+) -> io::Result <()> {
+
+	let backing_size =
+		match * backing {
+
+		Some (ref backing) => {
+
+			let backing_size =
+				cmp::min (
+					backing.size ().saturating_sub (offset),
+					buffer.len () as u64,
+				) as usize;
+
+			if backing_size > 0 {
+
+				backing.read_at (
+					offset,
+					& mut buffer [0 .. backing_size],
+				) ?;
+
+			}
+
+			backing_size
+
+		},
+
+		None => 0,
+
+	};
+
+	for byte in buffer [backing_size ..].iter_mut () {
+		* byte = 0;
+	}
+
+	Ok (())
+
+}
+
+pub fn image_backing_ranges (
+	ranges: & mut Vec <DeviceRange>,
+	backing: & Option <Box <Image>>,
+	start: u64,
+	end: u64,
+) -> io::Result <()> {
+
+	let backing =
+		match * backing {
+			Some (ref backing) => backing,
+			None => return Ok (()),
+		};
+
+	let end =
+		cmp::min (
+			end,
+			backing.size ());
+
+	if start >= end {
+		return Ok (());
+	}
+
+	for range in backing.data_ranges (start, end) ? {
+
+		image_add_range (
+			ranges,
+			range.start,
+			range.end);
+
+	}
+
+	Ok (())
+
+}
+
+// adds a range to a sorted list, merging it with the last one if they touch
+
+pub fn image_add_range (
+	ranges: & mut Vec <DeviceRange>,
+	start: u64,
+	end: u64,
+) {
+
+	if start >= end {
+		return;
+	}
+
+	if let Some (last_range) = ranges.last_mut () {
+
+		if last_range.end == start {
+
+			last_range.end = end;
+
+			return;
+
+		}
+
+	}
+
+	ranges.push (
+		DeviceRange {
+			start: start,
+			end: end,
+		});
+
+}
+
+pub fn image_invalid (
+	path: & Path,
+	format_name: & str,
+	message: & str,
+) -> String {
+
+	format! (
+		"Invalid {} image {}: {}",
+		format_name,
+		path.to_string_lossy (),
+		message)
+
+}
+
+// ex: noet ts=4 filetype=rust
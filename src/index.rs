@@ -1,41 +1,392 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
+use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 use btrfs::diskformat::*;
 
-use memmap::Mmap;
-use memmap::Protection;
+use libc;
 
 use output::Output;
 
 use uuid::Uuid;
 
 use arguments::*;
+use bytes::*;
+use checksum::*;
+use chunks::*;
+use devices::*;
+use mapfile::*;
+use partitions::*;
+use scan_parameters::*;
+use superblock::*;
+
+// index file format, all integers little endian
+//
+// magic (8 bytes), version (u32)
+// fs uuid (16 bytes), node size (u32), sector size (u32), csum type (u16)
+// device count (u32), then for each device:
+//   device id (u64), size (u64), scanned to (u64, version 4+),
+//   path length (u32), path bytes
+// node count (u64), then for each node:
+//   device id (u64, version 2+), position (u64), tree id (u64),
+//   generation (u64), logical address (u64), level (u8),
+//   validity (u8, version 3+), has keys (u8, version 6+), then if it has
+//   keys: first key and last key (object id u64, type u8, offset u64 each),
+//   item types (32 byte bitmap)
+// bad range count (u64, version 5+), then for each unreadable range:
+//   device id (u64), start (u64), end (u64)
+//
+// version 1 positions are offsets into the concatenation of all devices, in
+// header order, and are converted to per-device positions on load
+//
+// nodes from versions before 3 were not verified and load as unchecked
+//
+// keys let commands go straight to the nodes which may hold an object, without
+// reading every node. the item types bitmap has a bit set for the type of each
+// key in the node. nodes from versions before 6, and broken or empty nodes,
+// have no keys.
+//
+// scanned to records how far the scan of a device got, so an interrupted
+// scan can be resumed from a checkpoint. earlier versions were only written
+// on completion, so load as fully scanned.
+//
+// files not starting with the magic are read as the original text format,
+// one hex position per line, with no device information
+
+pub const INDEX_MAGIC: & 'static [u8] = b"OMEGA13I";
+pub const INDEX_VERSION: u32 = 6;
+
+pub const BTRFS_NODE_HEADER_SIZE: usize = 0x65;
+pub const BTRFS_LEAF_ITEM_SIZE: usize = 0x19;
+pub const BTRFS_INTERNAL_ITEM_SIZE: usize = 0x21;
+pub const BTRFS_MAX_LEVEL: u8 = 8;
+
+// limits for nodes from other filesystems, whose sector and node sizes we
+// don't know
+
+const OTHER_FILESYSTEM_SECTOR_SIZE: u32 = 0x1000;
+const OTHER_FILESYSTEM_NODE_SIZE: usize = 0x1_0000;
+
+const SCAN_CHUNK_SIZE: usize = 0x1000_0000;
+const SCAN_BLOCK_SIZE: usize = 0x10_0000;
+
+#[ derive (Clone, Debug) ]
+pub struct Index {
+	pub header: Option <IndexHeader>,
+	pub nodes: Vec <IndexNode>,
+	pub bad_ranges: Vec <IndexBadRange>,
+}
+
+#[ derive (Clone, Debug) ]
+pub struct IndexHeader {
+	pub version: u32,
+	pub fs_uuid: [u8; 16],
+	pub node_size: u32,
+	pub sector_size: u32,
+	pub csum_type: u16,
+	pub devices: Vec <IndexDevice>,
+}
+
+#[ derive (Clone, Debug) ]
+pub struct IndexDevice {
+	pub path: PathBuf,
+	pub device_id: u64,
+	pub size: u64,
+	pub scanned_to: u64,
+}
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct IndexNode {
+	pub device_id: u64,
+	pub position: u64,
+	pub tree_id: u64,
+	pub generation: u64,
+	pub level: u8,
+	pub logical_address: u64,
+	pub validity: IndexNodeValidity,
+	pub keys: Option <IndexNodeKeys>,
+}
+
+#[ derive (Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd) ]
+pub struct IndexKey {
+	pub object_id: u64,
+	pub item_type: u8,
+	pub offset: u64,
+}
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct IndexNodeKeys {
+	pub first_key: IndexKey,
+	pub last_key: IndexKey,
+	pub item_types: [u8; 32],
+}
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct IndexBadRange {
+	pub device_id: u64,
+	pub start: u64,
+	pub end: u64,
+}
+
+#[ derive (Clone, Copy, Debug, Eq, Hash, PartialEq) ]
+pub enum IndexNodeValidity {
+	Valid,
+	BadChecksum,
+	Broken,
+	Unchecked,
+}
+
+impl IndexNodeValidity {
+
+	pub fn from_u8 (
+		value: u8,
+	) -> Result <IndexNodeValidity, String> {
+
+		match value {
+			0 => Ok (IndexNodeValidity::Valid),
+			1 => Ok (IndexNodeValidity::BadChecksum),
+			2 => Ok (IndexNodeValidity::Broken),
+			3 => Ok (IndexNodeValidity::Unchecked),
+			_ => Err (format! ("Invalid node validity: {}", value)),
+		}
+
+	}
+
+	pub fn to_u8 (
+		self,
+	) -> u8 {
+
+		match self {
+			IndexNodeValidity::Valid => 0,
+			IndexNodeValidity::BadChecksum => 1,
+			IndexNodeValidity::Broken => 2,
+			IndexNodeValidity::Unchecked => 3,
+		}
+
+	}
+
+	pub fn name (
+		self,
+	) -> & 'static str {
+
+		match self {
+			IndexNodeValidity::Valid => "valid",
+			IndexNodeValidity::BadChecksum => "bad checksum",
+			IndexNodeValidity::Broken => "broken",
+			IndexNodeValidity::Unchecked => "unchecked",
+		}
+
+	}
+
+	pub fn is_corrupt (
+		self,
+	) -> bool {
+
+		match self {
+			IndexNodeValidity::BadChecksum => true,
+			IndexNodeValidity::Broken => true,
+			_ => false,
+		}
+
+	}
+
+}
+
+impl IndexKey {
+
+	pub fn from_bytes (
+		bytes: & [u8],
+		offset: usize,
+	) -> IndexKey {
+
+		IndexKey {
+			object_id: le_u64 (bytes, offset),
+			item_type: bytes [offset + 8],
+			offset: le_u64 (bytes, offset + 9),
+		}
+
+	}
+
+	pub fn description (
+		& self,
+	) -> String {
+
+		format! (
+			"({} {} 0x{:x})",
+			self.object_id,
+			self.item_type,
+			self.offset)
+
+	}
+
+}
+
+impl IndexNodeKeys {
+
+	pub fn has_item_type (
+		& self,
+		item_type: u8,
+	) -> bool {
+
+		self.item_types [item_type as usize / 8]
+			& (1 << (item_type % 8)) != 0
+
+	}
+
+}
+
+impl IndexNode {
+
+	// true if the node may hold items for an object. a leaf holds exactly
+	// the keys between its first and last, but an internal node's last key
+	// only starts its last child, so there is no upper bound. nodes without
+	// keys may hold anything.
+
+	pub fn may_contain_object (
+		& self,
+		object_id: u64,
+	) -> bool {
+
+		match self.keys {
+
+			Some (ref keys) =>
+				keys.first_key.object_id <= object_id
+				&& (self.level > 0 || object_id <= keys.last_key.object_id),
+
+			None =>
+				true,
+
+		}
+
+	}
+
+	pub fn may_contain_item_type (
+		& self,
+		item_type: u8,
+	) -> bool {
+
+		self.keys.as_ref ().map_or (
+			true,
+			|keys| keys.has_item_type (item_type))
+
+	}
+
+}
+
+impl IndexHeader {
+
+	pub fn device (
+		& self,
+		device_id: u64,
+	) -> Option <& IndexDevice> {
+
+		self.devices.iter ().find (
+			|index_device|
+
+			index_device.device_id == device_id
+
+		)
+
+	}
+
+	pub fn fs_uuid_string (
+		& self,
+	) -> String {
+
+		Uuid::from_bytes (
+			& self.fs_uuid,
+		).unwrap ().hyphenated ().to_string ()
+
+	}
+
+}
 
 pub fn index (
 	output: & Output,
 	command: IndexCommand,
 ) -> Result <(), String> {
 
-	let mut node_positions: Vec <usize> =
-		Vec::new ();
+	let checkpoint_path =
+		index_checkpoint_path (
+			& command.index);
+
+	let mut index =
+		if command.resume {
+
+		output_message! (
+			output,
+			"Resuming from {}",
+			checkpoint_path.to_string_lossy ());
+
+		index_load (
+			& checkpoint_path,
+		) ?
+
+	} else {
+
+		Index {
+			header: None,
+			nodes: Vec::new (),
+			bad_ranges: Vec::new (),
+		}
 
-	let mut offset: usize = 0;
+	};
+
+	let mut checkpoint = IndexCheckpoint {
+		path: checkpoint_path.clone (),
+		interval: command.checkpoint_interval,
+		last_write: Instant::now (),
+	};
+
+	let mapfiles =
+		mapfiles_load (
+			& command.paths,
+			& command.mapfiles,
+		) ?;
 
-	for path in command.paths.iter () {
+	let offsets =
+		device_offsets (
+			output,
+			& command.paths,
+			& command.offsets,
+		) ?;
+
+	for (((path_index, path), offset), mapfile)
+	in command.paths.iter ().enumerate ()
+		.zip (offsets.into_iter ())
+		.zip (mapfiles.into_iter ()) {
 
 		try! (
 			index_scan (
 				output,
-				& mut node_positions,
+				& command,
+				& mut index,
+				& mut checkpoint,
 				& path,
-				& mut offset));
+				offset,
+				mapfile.map (
+					|mapfile| mapfile.rebase (offset)),
+				path_index as u64 + 1));
 
 	}
 
@@ -53,285 +404,2437 @@ pub fn index (
 	);
 
 	index_write (
-		& node_positions,
+		& index,
 		& mut index_file,
 	) ?;
 
-	Ok (())
-
-}
+	output_message! (
+		output,
+		"Wrote {} nodes to {}",
+		index.nodes.len (),
+		command.index.to_string_lossy ());
 
-fn index_scan (
-	output: & Output,
-	node_positions: & mut Vec <usize>,
-	path: & Path,
-	offset: & mut usize,
-) -> Result <(), String> {
+	// remove checkpoint
 
-	// mmap target
+	if checkpoint_path.exists () {
 
-	let file = try! (
-		File::open (
-			path,
+		fs::remove_file (
+			& checkpoint_path,
 		).map_err (
 			|error|
 
 			format! (
-				"Error opening {}: {}",
-				path.to_string_lossy (),
+				"Error removing {}: {}",
+				checkpoint_path.to_string_lossy (),
 				error.description ())
 
-		)
-	);
-
-	let mmap = try! (
-		Mmap::open (
-			& file,
-			Protection::Read,
-		).map_err (
-			|error|
+		) ?;
 
-			format! (
-				"Error mmaping {}: {}",
-				path.to_string_lossy (),
-				error.description ())
+	}
 
-		)
-	);
+	Ok (())
 
-	output.message_format (
-		format_args! (
-			"Scanning {}",
-			path.to_string_lossy ()));
+}
 
-	// read superblock
+struct IndexCheckpoint {
+	path: PathBuf,
+	interval: Option <Duration>,
+	last_write: Instant,
+}
 
-	let superblock: & BtrfsSuperblock = unsafe {
-		& * (
-			mmap.ptr ().offset (0x1_0000)
-			as * const BtrfsSuperblock
-		)
-	};
+impl IndexCheckpoint {
 
-	if superblock.magic () != BTRFS_MAGIC {
+	fn due (
+		& self,
+	) -> bool {
 
-		return Err (
-			"Superblock not found".to_owned ());
+		match self.interval {
+			Some (interval) => self.last_write.elapsed () >= interval,
+			None => false,
+		}
 
 	}
 
-	// print fs information
+	fn write (
+		& mut self,
+		output: & Output,
+		index: & Index,
+	) -> Result <(), String> {
 
-	output.message_format (
-		format_args! (
-			"Filesystem UUID: {}",
-			Uuid::from_bytes (
-				& superblock.fs_uuid ().bytes (),
-			).unwrap ()));
+		// write to a temporary file and rename, so an interruption during
+		// the write leaves the previous checkpoint intact
 
-	output.message_format (
-		format_args! (
-			"Node size: 0x{:x}",
-			superblock.node_size ()));
+		let mut temp_path =
+			self.path.clone ().into_os_string ();
 
-	output.message_format (
-		format_args! (
-			"Leaf size: 0x{:x}",
-			superblock.leaf_size ()));
+		temp_path.push (".tmp");
 
-	if superblock.node_size () != superblock.leaf_size () {
+		let mut temp_file = try! (
+			File::create (
+				& temp_path,
+			).map_err (
+				|error|
 
-		panic! (
-			"TODO - handle node size and leaf size different");
+				format! (
+					"Error creating {}: {}",
+					temp_path.to_string_lossy (),
+					error.description ())
+			)
+		);
 
-	}
+		index_write (
+			index,
+			& mut temp_file,
+		) ?;
 
-	// scan for nodes
+		try! (
+			temp_file.sync_all ().map_err (
+				|error|
 
-	let mut position: usize =
-		0x1_1000;
+				format! (
+					"Error writing {}: {}",
+					temp_path.to_string_lossy (),
+					error.description ())
+			)
+		);
 
-	let max_position: usize =
-		mmap.len () - mmap.len () % superblock.node_size () as usize;
+		try! (
+			fs::rename (
+				& temp_path,
+				& self.path,
+			).map_err (
+				|error|
 
-	let status_size: usize =
-		superblock.sector_size () as usize * 0x1000;
+				format! (
+					"Error renaming {} to {}: {}",
+					temp_path.to_string_lossy (),
+					self.path.to_string_lossy (),
+					error.description ())
+			)
+		);
 
-	let output_job =
-		output_job_start! (
+		output_debug! (
 			output,
-			"Scanning from 0x{:x} to 0x{:x}",
-			position,
-			max_position);
+			"Wrote checkpoint with {} nodes",
+			index.nodes.len ());
 
-	while position < max_position {
+		self.last_write = Instant::now ();
 
-		if position % status_size == 0 {
+		Ok (())
 
-			output_job_update! (
-				output_job,
-				"At position 0x{:x} ({}%)",
-				position,
-				position * 100 / mmap.len ());
+	}
 
-		}
+}
 
-		// skip superblocks
+pub fn index_node_matches (
+	filter: & NodeFilter,
+	index_node: & IndexNode,
+) -> bool {
+
+	(filter.tree_ids.is_empty ()
+		|| filter.tree_ids.contains (& index_node.tree_id))
+	&& (filter.levels.is_empty ()
+		|| filter.levels.contains (& index_node.level))
+	&& filter.min_generation.map_or (
+		true,
+		|min_generation| index_node.generation >= min_generation)
+	&& filter.max_generation.map_or (
+		true,
+		|max_generation| index_node.generation <= max_generation)
+	&& (filter.object_ids.is_empty ()
+		|| filter.object_ids.iter ().any (
+			|object_id| index_node.may_contain_object (* object_id)))
+	&& (filter.item_types.is_empty ()
+		|| filter.item_types.iter ().any (
+			|item_type| index_node.may_contain_item_type (* item_type)))
 
-		if (
-			position == 0x400_0000
-			|| position == 0x40_0000_0000
-			|| position == 0x4_0000_0000_0000
-		) {
+}
 
-			position +=
-				superblock.sector_size () as usize;
+// finds the metadata and system chunks on a device, by reading the chunk tree
+// from the device itself
 
-			continue;
+fn index_metadata_ranges (
+	parameters: & ScanParameters,
+	reader: & DeviceReader,
+	superblock_bytes: Option <& Vec <u8>>,
+) -> Result <Vec <DeviceRange>, String> {
 
-		}
+	let superblock_bytes =
+		superblock_bytes.ok_or_else (
+			|| "No valid superblock".to_owned (),
+		) ?;
 
-		// check for header
+	// after a mkfs, the superblock belongs to the new filesystem, and its
+	// chunks say nothing about where the old one kept its metadata
 
-		let node_header: & BtrfsNodeHeader = unsafe {
-			& * (
-				mmap.ptr ().offset (position as isize)
-				as * const BtrfsNodeHeader
-			)
-		};
+	if superblock_bytes [0x20 .. 0x30] != parameters.fs_uuid [..] {
 
-		if node_header.fs_uuid () != superblock.fs_uuid () {
+		return Err (
+			"Superblock belongs to a different filesystem".to_owned ());
 
-			position +=
-				superblock.sector_size () as usize;
+	}
 
-			continue;
+	let chunk_map =
+		chunks_read_tree (
+			reader,
+			parameters.device_id,
+			parameters.node_size,
+			parameters.csum_type,
+			superblock_bytes,
+		) ?;
 
-		}
+	Ok (
+		chunk_map.metadata_ranges (
+			parameters.device_id))
 
-		// store it
+}
 
-		output_message! (
-			output,
-			"Found node at 0x{:x} in tree {}",
-			position,
-			node_header.tree_id ());
+pub fn index_node_description (
+	index_node: & IndexNode,
+) -> String {
+
+	format! (
+		"{}/0x{:x} tree {} level {} generation {} logical 0x{:x} {}{}",
+		index_node.device_id,
+		index_node.position,
+		index_node.tree_id,
+		index_node.level,
+		index_node.generation,
+		index_node.logical_address,
+		index_node.validity.name (),
+		match index_node.keys {
+			Some (ref keys) => format! (
+				" keys {} to {}",
+				keys.first_key.description (),
+				keys.last_key.description ()),
+			None => "".to_owned (),
+		})
 
-		node_positions.push (
-			* offset + position);
+}
 
-		// continue
+pub fn index_checkpoint_path (
+	index_path: & Path,
+) -> PathBuf {
 
-		position +=
-			superblock.sector_size () as usize;
+	let mut checkpoint_path =
+		index_path.to_owned ().into_os_string ();
 
-	}
+	checkpoint_path.push (".checkpoint");
 
-	// return
+	PathBuf::from (
+		checkpoint_path)
 
-	output_job.complete ();
+}
 
-	* offset += mmap.len ();
+fn index_scan (
+	output: & Output,
+	command: & IndexCommand,
+	index: & mut Index,
+	checkpoint: & mut IndexCheckpoint,
+	path: & Path,
+	offset: u64,
+	mapfile: Option <Mapfile>,
+	default_device_id: u64,
+) -> Result <(), String> {
 
-	Ok (())
+	// open target
 
-}
+	let reader =
+		Arc::new (
+			DeviceReader::open_at (
+				path,
+				offset,
+			) ?);
 
-fn index_write (
-	node_positions: & Vec <usize>,
-	index_writer: & mut Write,
-) -> Result <(), String> {
+	let device_size =
+		reader.size () as usize;
 
-	let mut index_writer =
-		BufWriter::new (
-			index_writer);
+	output.message_format (
+		format_args! (
+			"Scanning {}",
+			path.to_string_lossy ()));
 
-	for position in node_positions.iter () {
+	if let Some (image) = reader.image () {
 
-		try! (
-			index_writer.write_all (
+		if mapfile.is_some () {
+
+			return Err (
 				format! (
-					"{:x}\n",
-					position,
-				).as_bytes (),
-			).map_err (
-				|error| error.description ().to_owned (),
-			)
-		);
+					"Can't use a mapfile with {}, which is a {}",
+					path.to_string_lossy (),
+					image.description ()));
 
-	}
+		}
 
-	Ok (())
+		output_message! (
+			output,
+			"Reading {} as a {}",
+			path.to_string_lossy (),
+			image.description ());
 
-}
+	}
 
-pub fn index_load (
-	index_path: & Path,
-) -> Result <Vec <usize>, String> {
+	if offset != 0 {
 
-	let mut index_file = try! (
-		File::open (
-			index_path,
-		).map_err (
-			|error|
+		output_message! (
+			output,
+			"Filesystem starts at 0x{:x}",
+			offset);
 
-			format! (
-				"Error opening {}: {}",
-				index_path.to_string_lossy (),
-				error.description ())
+	}
 
-		)
-	);
+	if let Some (ref mapfile) = mapfile {
 
-	let mut node_positions: Vec <usize> =
-		Vec::new ();
+		output_message! (
+			output,
+			"Using mapfile {}, 0x{:x} bytes rescued, {} ranges not rescued",
+			mapfile.path ().to_string_lossy (),
+			mapfile.rescued_size (),
+			mapfile.unrescued ().len ());
 
-	try! (
-		index_read (
-			& mut node_positions,
-			& mut index_file,
-		).map_err (
-			|error|
+	}
 
-			format! (
-				"Error reading {}: {}",
-				index_path.to_string_lossy (),
-				error)
+	let mapfile =
+		Arc::new (
+			mapfile);
 
-		)
-	);
+	// read superblock and work out parameters
 
-	Ok (node_positions)
+	let superblock_bytes =
+		superblock_find (
+			output,
+			& reader);
 
-}
+	let superblock =
+		superblock_bytes.as_ref ().and_then (
+			|superblock_bytes|
 
-fn index_read (
-	node_positions: & mut Vec <usize>,
-	index_reader: & mut Read,
-) -> Result <(), String> {
+			superblock_at (
+				superblock_bytes,
+				0)
 
-	let index_reader =
-		BufReader::new (
-			index_reader);
+		);
 
-	for line in index_reader.lines () {
+	let parameters =
+		scan_parameters (
+			output,
+			command,
+			& index.header,
+			superblock,
+			& reader,
+			default_device_id,
+		) ?;
 
-		let line = try! (
-			line.map_err (
-				|error| error.description ().to_owned ()
+	// print fs information
+
+	output.message_format (
+		format_args! (
+			"Filesystem UUID: {}",
+			parameters.fs_uuid_string ()));
+
+	output.message_format (
+		format_args! (
+			"Device ID: {}",
+			parameters.device_id));
+
+	output.message_format (
+		format_args! (
+			"Node size: 0x{:x}",
+			parameters.node_size));
+
+	output.message_format (
+		format_args! (
+			"Sector size: 0x{:x}",
+			parameters.sector_size));
+
+	// record device in header
+
+	let resume_position =
+		index_device_prepare (
+			index,
+			path,
+			& parameters,
+			device_size as u64,
+		) ?;
+
+	if ! checksum_supported (parameters.csum_type) {
+
+		output_message! (
+			output,
+			"Checksum type {} is not supported, nodes will not be verified",
+			checksum_type_name (parameters.csum_type));
+
+	}
+
+	// scan for nodes
+
+	let sector_size =
+		parameters.sector_size as usize;
+
+	let start_position: usize =
+		cmp::max (
+			0x1_1000,
+			command.start.unwrap_or (0) as usize);
+
+	let start_position: usize =
+		(start_position + sector_size - 1) / sector_size * sector_size;
+
+	let max_position: usize =
+		cmp::min (
+			device_size,
+			command.end.unwrap_or (device_size as u64) as usize);
+
+	let max_position: usize =
+		max_position - max_position % sector_size;
+
+	let start_position: usize =
+		match resume_position {
+
+		Some (resume_position) => {
+
+			if resume_position as usize >= max_position {
+
+				output_message! (
+					output,
+					"Scan of {} already complete",
+					path.to_string_lossy ());
+
+				return Ok (());
+
+			}
+
+			output_message! (
+				output,
+				"Resuming scan of {} at 0x{:x}",
+				path.to_string_lossy (),
+				resume_position);
+
+			cmp::max (
+				start_position,
+				resume_position as usize)
+
+		},
+
+		None =>
+			start_position,
+
+	};
+
+	let chunk_size: usize =
+		SCAN_CHUNK_SIZE / sector_size * sector_size;
+
+	let num_chunks: usize =
+		if max_position > start_position {
+			(max_position - start_position + chunk_size - 1) / chunk_size
+		} else {
+			0
+		};
+
+	// find data, so we can skip holes in sparse images
+
+	let data_ranges =
+		reader.data_ranges (
+			start_position as u64,
+			max_position as u64,
+		) ?;
+
+	let data_size: u64 =
+		data_ranges.iter ().map (
+			|data_range|
+
+			data_range.end - data_range.start
+
+		).sum ();
+
+	let hole_size: u64 =
+		max_position.saturating_sub (start_position) as u64 - data_size;
+
+	if hole_size > 0 {
+
+		output_message! (
+			output,
+			"Found 0x{:x} bytes of data in {} ranges, skipping 0x{:x} bytes \
+			of holes",
+			data_size,
+			data_ranges.len (),
+			hole_size);
+
+	}
+
+	// restrict to metadata chunks if requested
+
+	let data_ranges =
+		if command.metadata_only {
+
+		match index_metadata_ranges (
+			& parameters,
+			& reader,
+			superblock_bytes.as_ref (),
+		) {
+
+			Ok (metadata_ranges) => {
+
+				let metadata_ranges =
+					device_ranges_intersect (
+						& data_ranges,
+						& metadata_ranges);
+
+				output_message! (
+					output,
+					"Scanning 0x{:x} bytes in {} metadata ranges",
+					metadata_ranges.iter ().map (
+						|metadata_range|
+
+						metadata_range.end - metadata_range.start
+
+					).sum::<u64> (),
+					metadata_ranges.len ());
+
+				metadata_ranges
+
+			},
+
+			Err (error) => {
+
+				output_message! (
+					output,
+					"Scanning everything, unable to find metadata chunks: {}",
+					error);
+
+				data_ranges
+
+			},
+
+		}
+
+	} else {
+		data_ranges
+	};
+
+	let data_ranges =
+		Arc::new (
+			data_ranges);
+
+	let num_threads =
+		command.threads.unwrap_or_else (
+			default_num_threads);
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Scanning from 0x{:x} to 0x{:x} with {} threads",
+			start_position,
+			max_position,
+			num_threads);
+
+	// start worker threads
+
+	let (sender, receiver) =
+		mpsc::channel ();
+
+	let next_chunk =
+		Arc::new (
+			AtomicUsize::new (0));
+
+	let progress =
+		Arc::new (
+			AtomicUsize::new (0));
+
+	let mut thread_handles: Vec <thread::JoinHandle <()>> =
+		Vec::new ();
+
+	for _ in 0 .. num_threads {
+
+		let reader = reader.clone ();
+		let mapfile = mapfile.clone ();
+		let data_ranges = data_ranges.clone ();
+		let filter = command.filter.clone ();
+		let next_chunk = next_chunk.clone ();
+		let progress = progress.clone ();
+		let sender = sender.clone ();
+
+		thread_handles.push (
+			thread::spawn (
+				move || {
+
+			loop {
+
+				let chunk_index =
+					next_chunk.fetch_add (
+						1,
+						Ordering::SeqCst);
+
+				if chunk_index >= num_chunks {
+					break;
+				}
+
+				let chunk_start =
+					start_position + chunk_index * chunk_size;
+
+				let chunk_end =
+					cmp::min (
+						chunk_start + chunk_size,
+						max_position);
+
+				let chunk =
+					index_scan_chunk (
+						& parameters,
+						& reader,
+						mapfile.as_ref ().as_ref (),
+						& filter,
+						& data_ranges,
+						chunk_start,
+						chunk_end,
+						max_position,
+						& progress);
+
+				if sender.send (
+					(chunk_index, chunk),
+				).is_err () {
+					break;
+				}
+
+			}
+
+		}));
+
+	}
+
+	drop (sender);
+
+	// merge results in order
+
+	let mut pending_chunks: BTreeMap <usize, Result <IndexScanChunk, String>> =
+		BTreeMap::new ();
+
+	let mut next_merge: usize = 0;
+
+	let mut num_valid: u64 = 0;
+	let mut num_bad_checksum: u64 = 0;
+	let mut num_broken: u64 = 0;
+	let mut num_bad_ranges: u64 = 0;
+	let mut num_unrescued: u64 = 0;
+	let mut num_filtered: u64 = 0;
+
+	let mut other_filesystems: HashMap <[u8; 16], u64> =
+		HashMap::new ();
+
+	let mut merge_error: Option <String> = None;
+
+	loop {
+
+		match receiver.recv_timeout (
+			Duration::from_millis (500)) {
+
+			Ok ((chunk_index, chunk)) => {
+				pending_chunks.insert (
+					chunk_index,
+					chunk);
+			},
+
+			Err (mpsc::RecvTimeoutError::Timeout) =>
+				(),
+
+			Err (mpsc::RecvTimeoutError::Disconnected) =>
+				break,
+
+		}
+
+		while let Some (chunk) =
+			pending_chunks.remove (
+				& next_merge) {
+
+			let chunk =
+				match chunk {
+
+				Ok (chunk) =>
+					chunk,
+
+				Err (error) => {
+
+					merge_error = Some (error);
+
+					break;
+
+				},
+
+			};
+
+			for bad_range in chunk.bad_ranges.iter () {
+
+				output_message! (
+					output,
+					"Unreadable range on {} at 0x{:x} to 0x{:x}, skipped",
+					path.to_string_lossy (),
+					bad_range.start,
+					bad_range.end);
+
+				index.bad_ranges.push (
+					IndexBadRange {
+						device_id: parameters.device_id,
+						start: bad_range.start,
+						end: bad_range.end,
+					});
+
+			}
+
+			for index_node in chunk.nodes.iter () {
+
+				output_message! (
+					output,
+					"Found {} node at {}/0x{:x} in tree {}",
+					index_node.validity.name (),
+					index_node.device_id,
+					index_node.position,
+					index_node.tree_id);
+
+			}
+
+			index.nodes.extend_from_slice (
+				& chunk.nodes);
+
+			num_valid += chunk.num_valid;
+			num_bad_checksum += chunk.num_bad_checksum;
+			num_broken += chunk.num_broken;
+			num_bad_ranges += chunk.bad_ranges.len () as u64;
+			num_unrescued += chunk.num_unrescued;
+			num_filtered += chunk.num_filtered;
+
+			for (fs_uuid, num_nodes) in chunk.other_filesystems.iter () {
+
+				* other_filesystems.entry (
+					* fs_uuid,
+				).or_insert (0) += * num_nodes;
+
+			}
+
+			next_merge += 1;
+
+		}
+
+		if merge_error.is_some () {
+			break;
+		}
+
+		// write checkpoint
+
+		if checkpoint.due () {
+
+			index_device_scanned_to (
+				index,
+				path,
+				cmp::min (
+					start_position + next_merge * chunk_size,
+					max_position) as u64);
+
+			if let Err (error) =
+				checkpoint.write (
+					output,
+					index) {
+
+				merge_error = Some (error);
+
+				break;
+
+			}
+
+		}
+
+		let bytes_scanned =
+			progress.load (
+				Ordering::SeqCst);
+
+		output_job_update! (
+			output_job,
+			"Scanned 0x{:x} bytes ({}%)",
+			bytes_scanned,
+			bytes_scanned * 100 / cmp::max (
+				max_position.saturating_sub (start_position),
+				1));
+
+	}
+
+	// after an error, stop the workers taking more chunks, and wait for
+	// them either way so none are left running
+
+	if merge_error.is_some () {
+
+		next_chunk.store (
+			num_chunks,
+			Ordering::SeqCst);
+
+	}
+
+	let mut panicked = false;
+
+	for thread_handle in thread_handles.into_iter () {
+
+		if thread_handle.join ().is_err () {
+			panicked = true;
+		}
+
+	}
+
+	let scan_error =
+		if merge_error.is_some () {
+			merge_error
+		} else if panicked {
+			Some ("Scan thread panicked".to_owned ())
+		} else if next_merge != num_chunks {
+			Some (
+				format! (
+					"Scan of {} incomplete",
+					path.to_string_lossy ()))
+		} else {
+			None
+		};
+
+	if let Some (scan_error) = scan_error {
+
+		output_job.complete ();
+
+		return Err (scan_error);
+
+	}
+
+	index_device_scanned_to (
+		index,
+		path,
+		max_position as u64);
+
+	// return
+
+	output_job.complete ();
+
+	output_message! (
+		output,
+		"Found {} valid nodes, {} with bad checksums, {} broken",
+		num_valid,
+		num_bad_checksum,
+		num_broken);
+
+	if num_bad_ranges > 0 {
+
+		output_message! (
+			output,
+			"Skipped {} unreadable ranges",
+			num_bad_ranges);
+
+	}
+
+	if hole_size > 0 {
+
+		output_message! (
+			output,
+			"Skipped 0x{:x} bytes of holes",
+			hole_size);
+
+	}
+
+	if num_filtered > 0 {
+
+		output_message! (
+			output,
+			"Left out {} nodes which didn't match the filter",
+			num_filtered);
+
+	}
+
+	if num_unrescued > 0 {
+
+		output_message! (
+			output,
+			"Ignored {} nodes in areas not rescued by ddrescue",
+			num_unrescued);
+
+	}
+
+	index_report_other_filesystems (
+		output,
+		& other_filesystems);
+
+	Ok (())
+
+}
+
+// lists nodes from other filesystems, most often one which was replaced by
+// an accidental mkfs, since these can be indexed instead. their checksums
+// haven't been verified, so some may be stray data which only looks like a
+// node.
+
+fn index_report_other_filesystems (
+	output: & Output,
+	other_filesystems: & HashMap <[u8; 16], u64>,
+) {
+
+	if other_filesystems.is_empty () {
+		return;
+	}
+
+	let mut sorted_filesystems: Vec <(& [u8; 16], & u64)> =
+		other_filesystems.iter ().collect ();
+
+	sorted_filesystems.sort_by_key (
+		|& (_, num_nodes)|
+
+		! * num_nodes
+
+	);
+
+	output_message! (
+		output,
+		"Found unchecked nodes from {} other filesystems, which can be \
+		indexed with --fs-uuid:",
+		sorted_filesystems.len ());
+
+	for & (fs_uuid, num_nodes) in sorted_filesystems.iter () {
+
+		output_message! (
+			output,
+			"  {}: {} nodes",
+			Uuid::from_bytes (
+				fs_uuid,
+			).unwrap ().hyphenated (),
+			num_nodes);
+
+	}
+
+}
+
+// adds the device to the index header, creating it if necessary. if the
+// device is already present, because we are resuming, returns the position
+// the previous scan reached.
+
+fn index_device_prepare (
+	index: & mut Index,
+	path: & Path,
+	parameters: & ScanParameters,
+	size: u64,
+) -> Result <Option <u64>, String> {
+
+	let index_device = IndexDevice {
+		path: path.to_owned (),
+		device_id: parameters.device_id,
+		size: size,
+		scanned_to: 0,
+	};
+
+	if index.header.is_none () {
+
+		index.header = Some (
+			IndexHeader {
+				version: INDEX_VERSION,
+				fs_uuid: parameters.fs_uuid,
+				node_size: parameters.node_size,
+				sector_size: parameters.sector_size,
+				csum_type: parameters.csum_type,
+				devices: vec! [ index_device ],
+			}
+		);
+
+		return Ok (None);
+
+	}
+
+	let index_header =
+		index.header.as_mut ().unwrap ();
+
+	if (
+		index_header.fs_uuid != parameters.fs_uuid
+		|| index_header.node_size != parameters.node_size
+		|| index_header.sector_size != parameters.sector_size
+		|| index_header.csum_type != parameters.csum_type
+	) {
+
+		return Err (
+			format! (
+				"Device {} belongs to a different filesystem",
+				path.to_string_lossy ()));
+
+	}
+
+	if let Some (existing_device) =
+		index_header.devices.iter ().find (
+			|existing_device|
+
+			existing_device.path == path
+
+		) {
+
+		if existing_device.size != size
+			|| existing_device.device_id != parameters.device_id {
+
+			return Err (
+				format! (
+					"Device {} has changed since the checkpoint",
+					path.to_string_lossy ()));
+
+		}
+
+		return Ok (Some (existing_device.scanned_to));
+
+	}
+
+	if index_header.device (
+		index_device.device_id,
+	).is_some () {
+
+		return Err (
+			format! (
+				"Device {} has the same device id as a previous device: {}",
+				path.to_string_lossy (),
+				index_device.device_id));
+
+	}
+
+	index_header.devices.push (
+		index_device);
+
+	Ok (None)
+
+}
+
+fn index_device_scanned_to (
+	index: & mut Index,
+	path: & Path,
+	scanned_to: u64,
+) {
+
+	if let Some (ref mut index_header) = index.header {
+
+		for index_device in index_header.devices.iter_mut () {
+
+			if index_device.path == path {
+				index_device.scanned_to = scanned_to;
+			}
+
+		}
+
+	}
+
+}
+
+struct IndexScanChunk {
+	nodes: Vec <IndexNode>,
+	bad_ranges: Vec <DeviceRange>,
+	num_valid: u64,
+	num_bad_checksum: u64,
+	num_broken: u64,
+	num_unrescued: u64,
+	num_filtered: u64,
+	other_filesystems: HashMap <[u8; 16], u64>,
+}
+
+fn index_scan_chunk (
+	parameters: & ScanParameters,
+	reader: & DeviceReader,
+	mapfile: Option <& Mapfile>,
+	filter: & NodeFilter,
+	data_ranges: & [DeviceRange],
+	chunk_start: usize,
+	chunk_end: usize,
+	max_position: usize,
+	progress: & AtomicUsize,
+) -> Result <IndexScanChunk, String> {
+
+	let node_size =
+		parameters.node_size as usize;
+
+	let sector_size =
+		parameters.sector_size as usize;
+
+	let mut chunk = IndexScanChunk {
+		nodes: Vec::new (),
+		bad_ranges: Vec::new (),
+		num_valid: 0,
+		num_bad_checksum: 0,
+		num_broken: 0,
+		num_unrescued: 0,
+		num_filtered: 0,
+		other_filesystems: HashMap::new (),
+	};
+
+	let mut buffer: Vec <u8> =
+		vec! [0; SCAN_BLOCK_SIZE + node_size];
+
+	// only scan the ranges we were given, which leave out holes, since a
+	// node can't start in one, and anything outside metadata chunks if asked
+
+	let mut scanned_to =
+		chunk_start;
+
+	let mut scanned_bytes: usize = 0;
+
+	for data_range in data_ranges.iter ().filter (
+		|data_range|
+
+		data_range.overlaps (
+			chunk_start as u64,
+			chunk_end as u64)
+
+	) {
+
+		let segment_start =
+			cmp::max (
+				data_range.start as usize / sector_size * sector_size,
+				scanned_to);
+
+		let segment_end =
+			cmp::min (
+				data_range.end as usize,
+				chunk_end);
+
+		if segment_start >= segment_end {
+			continue;
+		}
+
+		let mut block_start =
+			segment_start;
+
+		while block_start < segment_end {
+
+			let block_end =
+				cmp::min (
+					block_start + SCAN_BLOCK_SIZE,
+					segment_end);
+
+			// read the block, plus enough to hold a node starting at its end
+
+			let read_end =
+				cmp::min (
+					block_end + node_size,
+					reader.size () as usize);
+
+			let block_bytes =
+				& mut buffer [0 .. read_end - block_start];
+
+			let bad_ranges =
+				reader.read_tolerant (
+					block_start as u64,
+					block_bytes,
+					sector_size,
+				) ?;
+
+			// record bad ranges in this block, the overlap is recorded by the
+			// next one
+
+			for bad_range in bad_ranges.iter () {
+
+				if bad_range.start >= block_end as u64 {
+					continue;
+				}
+
+				chunk.bad_ranges.push (
+					DeviceRange {
+						start: bad_range.start,
+						end: cmp::min (
+							bad_range.end,
+							block_end as u64),
+					});
+
+			}
+
+			let mut position =
+				block_start;
+
+			while position < block_end
+				&& position + node_size <= max_position {
+
+				let node_start = position;
+				let node_end = position + node_size;
+
+				position +=
+					sector_size;
+
+				// skip superblocks and unreadable ranges
+
+				if superblock_overlaps (
+					node_start as u64,
+					node_size as u64,
+				) {
+					continue;
+				}
+
+				if bad_ranges.iter ().any (
+					|bad_range|
+
+					bad_range.overlaps (
+						node_start as u64,
+						node_end as u64)
+
+				) {
+					continue;
+				}
+
+				// check for header
+
+				let node_bytes =
+					& block_bytes [
+						node_start - block_start
+					..
+						node_end - block_start
+					];
+
+				let node_header: & BtrfsNodeHeader = unsafe {
+					& * (
+						node_bytes.as_ptr ()
+						as * const BtrfsNodeHeader
+					)
+				};
+
+				if node_header.fs_uuid ().bytes () [..]
+					!= parameters.fs_uuid [..] {
+
+					// count nodes from other filesystems. their checksum type
+					// and node size aren't in the header, and may differ from
+					// ours, so only the structure is checked, against the
+					// limits of any filesystem.
+
+					if index_node_structure_valid (
+						OTHER_FILESYSTEM_SECTOR_SIZE,
+						OTHER_FILESYSTEM_NODE_SIZE,
+						node_header,
+					) {
+
+						let mut fs_uuid: [u8; 16] = [0; 16];
+
+						fs_uuid.copy_from_slice (
+							& node_bytes [0x20 .. 0x30]);
+
+						* chunk.other_filesystems.entry (
+							fs_uuid,
+						).or_insert (0) += 1;
+
+					}
+
+					continue;
+
+				}
+
+				// ignore nodes which ddrescue hasn't finished with
+
+				if let Some (mapfile) = mapfile {
+
+					if mapfile.overlaps_unrescued (
+						node_start as u64,
+						node_end as u64,
+					) {
+
+						chunk.num_unrescued += 1;
+
+						continue;
+
+					}
+
+				}
+
+				// verify it
+
+				let validity =
+					index_node_validity (
+						parameters.csum_type,
+						parameters.sector_size,
+						node_bytes,
+						node_header);
+
+				let index_node =
+					IndexNode {
+						device_id: parameters.device_id,
+						position: node_start as u64,
+						tree_id: u64::from (node_header.tree_id ()),
+						generation: node_header.generation (),
+						level: node_header.level (),
+						logical_address: node_header.logical_address (),
+						validity: validity,
+						keys: index_node_keys (
+							node_bytes,
+							node_header,
+							validity),
+					};
+
+				// store it, if it matches the filter
+
+				if ! index_node_matches (filter, & index_node) {
+
+					chunk.num_filtered += 1;
+
+					continue;
+
+				}
+
+				match validity {
+					IndexNodeValidity::Valid => chunk.num_valid += 1,
+					IndexNodeValidity::BadChecksum => chunk.num_bad_checksum += 1,
+					IndexNodeValidity::Broken => chunk.num_broken += 1,
+					IndexNodeValidity::Unchecked => (),
+				}
+
+				chunk.nodes.push (
+					index_node);
+
+			}
+
+			progress.fetch_add (
+				block_end - block_start,
+				Ordering::SeqCst);
+
+			scanned_bytes +=
+				block_end - block_start;
+
+			block_start = block_end;
+
+		}
+
+		scanned_to = segment_end;
+
+	}
+
+	// count holes as progress
+
+	progress.fetch_add (
+		(chunk_end - chunk_start) - scanned_bytes,
+		Ordering::SeqCst);
+
+	Ok (chunk)
+
+}
+
+fn default_num_threads (
+) -> usize {
+
+	let num_cpus = unsafe {
+		libc::sysconf (
+			libc::_SC_NPROCESSORS_ONLN)
+	};
+
+	if num_cpus > 0 {
+		num_cpus as usize
+	} else {
+		1
+	}
+
+}
+
+fn index_node_validity (
+	csum_type: u16,
+	sector_size: u32,
+	node_bytes: & [u8],
+	node_header: & BtrfsNodeHeader,
+) -> IndexNodeValidity {
+
+	// check structure
+
+	if ! index_node_structure_valid (
+		sector_size,
+		node_bytes.len (),
+		node_header,
+	) {
+		return IndexNodeValidity::Broken;
+	}
+
+	// check checksum
+
+	match checksum_verify (
+		csum_type,
+		node_bytes,
+	) {
+		Some (true) => IndexNodeValidity::Valid,
+		Some (false) => IndexNodeValidity::BadChecksum,
+		None => IndexNodeValidity::Unchecked,
+	}
+
+}
+
+// checks the parts of the header which don't depend on the checksum
+
+fn index_node_structure_valid (
+	sector_size: u32,
+	node_size: usize,
+	node_header: & BtrfsNodeHeader,
+) -> bool {
+
+	let item_size =
+		if node_header.level () == 0 {
+			BTRFS_LEAF_ITEM_SIZE
+		} else {
+			BTRFS_INTERNAL_ITEM_SIZE
+		};
+
+	let max_items =
+		(node_size - BTRFS_NODE_HEADER_SIZE) / item_size;
+
+	node_header.level () < BTRFS_MAX_LEVEL
+		&& node_header.logical_address () != 0
+		&& node_header.logical_address () % sector_size as u64 == 0
+		&& node_header.num_items () as usize <= max_items
+
+}
+
+// reads the first and last keys, and the types of all keys, unless the node
+// is too broken to trust its item count
+
+fn index_node_keys (
+	node_bytes: & [u8],
+	node_header: & BtrfsNodeHeader,
+	validity: IndexNodeValidity,
+) -> Option <IndexNodeKeys> {
+
+	let num_items =
+		node_header.num_items () as usize;
+
+	if validity == IndexNodeValidity::Broken || num_items == 0 {
+		return None;
+	}
+
+	let item_size =
+		if node_header.level () == 0 {
+			BTRFS_LEAF_ITEM_SIZE
+		} else {
+			BTRFS_INTERNAL_ITEM_SIZE
+		};
+
+	let mut item_types: [u8; 32] = [0; 32];
+
+	for item_index in 0 .. num_items {
+
+		let item_type =
+			node_bytes [
+				BTRFS_NODE_HEADER_SIZE + item_index * item_size + 8];
+
+		item_types [item_type as usize / 8] |= 1 << (item_type % 8);
+
+	}
+
+	Some (IndexNodeKeys {
+		first_key: IndexKey::from_bytes (
+			node_bytes,
+			BTRFS_NODE_HEADER_SIZE),
+		last_key: IndexKey::from_bytes (
+			node_bytes,
+			BTRFS_NODE_HEADER_SIZE + (num_items - 1) * item_size),
+		item_types: item_types,
+	})
+
+}
+
+pub fn index_write (
+	index: & Index,
+	index_writer: & mut Write,
+) -> Result <(), String> {
+
+	let index_header = try! (
+		index.header.as_ref ().ok_or (
+			"Can't write an index without a header".to_owned ())
+	);
+
+	let mut index_writer =
+		BufWriter::new (
+			index_writer);
+
+	// write header
+
+	write_bytes (& mut index_writer, INDEX_MAGIC) ?;
+	write_u32 (& mut index_writer, INDEX_VERSION) ?;
+
+	write_bytes (& mut index_writer, & index_header.fs_uuid) ?;
+	write_u32 (& mut index_writer, index_header.node_size) ?;
+	write_u32 (& mut index_writer, index_header.sector_size) ?;
+	write_u16 (& mut index_writer, index_header.csum_type) ?;
+
+	write_u32 (
+		& mut index_writer,
+		index_header.devices.len () as u32,
+	) ?;
+
+	for index_device in index_header.devices.iter () {
+
+		let path_bytes =
+			index_device.path.as_os_str ().as_bytes ();
+
+		write_u64 (& mut index_writer, index_device.device_id) ?;
+		write_u64 (& mut index_writer, index_device.size) ?;
+		write_u64 (& mut index_writer, index_device.scanned_to) ?;
+		write_u32 (& mut index_writer, path_bytes.len () as u32) ?;
+		write_bytes (& mut index_writer, path_bytes) ?;
+
+	}
+
+	// write nodes
+
+	write_u64 (
+		& mut index_writer,
+		index.nodes.len () as u64,
+	) ?;
+
+	for index_node in index.nodes.iter () {
+
+		write_u64 (& mut index_writer, index_node.device_id) ?;
+		write_u64 (& mut index_writer, index_node.position) ?;
+		write_u64 (& mut index_writer, index_node.tree_id) ?;
+		write_u64 (& mut index_writer, index_node.generation) ?;
+		write_u64 (& mut index_writer, index_node.logical_address) ?;
+		write_u8 (& mut index_writer, index_node.level) ?;
+		write_u8 (& mut index_writer, index_node.validity.to_u8 ()) ?;
+
+		match index_node.keys {
+
+			Some (ref keys) => {
+
+				write_u8 (& mut index_writer, 1) ?;
+				write_key (& mut index_writer, & keys.first_key) ?;
+				write_key (& mut index_writer, & keys.last_key) ?;
+				write_bytes (& mut index_writer, & keys.item_types) ?;
+
+			},
+
+			None =>
+				write_u8 (& mut index_writer, 0) ?,
+
+		}
+
+	}
+
+	// write bad ranges
+
+	write_u64 (
+		& mut index_writer,
+		index.bad_ranges.len () as u64,
+	) ?;
+
+	for bad_range in index.bad_ranges.iter () {
+
+		write_u64 (& mut index_writer, bad_range.device_id) ?;
+		write_u64 (& mut index_writer, bad_range.start) ?;
+		write_u64 (& mut index_writer, bad_range.end) ?;
+
+	}
+
+	try! (
+		index_writer.flush ().map_err (
+			|error| error.description ().to_owned (),
+		)
+	);
+
+	Ok (())
+
+}
+
+pub fn index_load (
+	index_path: & Path,
+) -> Result <Index, String> {
+
+	let mut index_file = try! (
+		File::open (
+			index_path,
+		).map_err (
+			|error|
+
+			format! (
+				"Error opening {}: {}",
+				index_path.to_string_lossy (),
+				error.description ())
+
+		)
+	);
+
+	let index = try! (
+		index_read (
+			& mut index_file,
+		).map_err (
+			|error|
+
+			format! (
+				"Error reading {}: {}",
+				index_path.to_string_lossy (),
+				error)
+
+		)
+	);
+
+	Ok (index)
+
+}
+
+fn index_read (
+	index_reader: & mut Read,
+) -> Result <Index, String> {
+
+	let mut index_reader =
+		BufReader::new (
+			index_reader);
+
+	let is_binary =
+		try! (
+			index_reader.fill_buf ().map_err (
+				|error| error.description ().to_owned ()
+			)
+		).starts_with (
+			INDEX_MAGIC);
+
+	if is_binary {
+
+		index_read_binary (
+			& mut index_reader)
+
+	} else {
+
+		index_read_text (
+			& mut index_reader)
+
+	}
+
+}
+
+fn index_read_binary (
+	index_reader: & mut Read,
+) -> Result <Index, String> {
+
+	// read header
+
+	let mut magic: [u8; 8] = [0; 8];
+
+	read_bytes (index_reader, & mut magic) ?;
+
+	let version =
+		read_u32 (index_reader) ?;
+
+	if version == 0 || version > INDEX_VERSION {
+
+		return Err (
+			format! (
+				"Unsupported index version {}",
+				version));
+
+	}
+
+	let mut fs_uuid: [u8; 16] = [0; 16];
+
+	read_bytes (index_reader, & mut fs_uuid) ?;
+
+	let node_size = read_u32 (index_reader) ?;
+	let sector_size = read_u32 (index_reader) ?;
+	let csum_type = read_u16 (index_reader) ?;
+
+	let num_devices =
+		read_u32 (index_reader) ?;
+
+	let mut devices: Vec <IndexDevice> =
+		Vec::new ();
+
+	for _ in 0 .. num_devices {
+
+		let device_id = read_u64 (index_reader) ?;
+		let size = read_u64 (index_reader) ?;
+
+		let scanned_to =
+			if version >= 4 {
+				read_u64 (index_reader) ?
+			} else {
+				size
+			};
+
+		let path_len = read_u32 (index_reader) ?;
+
+		let path_bytes =
+			read_bytes_vec (
+				index_reader,
+				path_len as u64,
+			) ?;
+
+		devices.push (
+			IndexDevice {
+				path: PathBuf::from (
+					OsString::from_vec (
+						path_bytes)),
+				device_id: device_id,
+				size: size,
+				scanned_to: scanned_to,
+			}
+		);
+
+	}
+
+	// read nodes, without trusting the count to preallocate
+
+	let num_nodes =
+		read_u64 (index_reader) ?;
+
+	let mut nodes: Vec <IndexNode> =
+		Vec::new ();
+
+	for _ in 0 .. num_nodes {
+
+		let device_id =
+			if version >= 2 {
+				read_u64 (index_reader) ?
+			} else {
+				0
+			};
+
+		let position = read_u64 (index_reader) ?;
+		let tree_id = read_u64 (index_reader) ?;
+		let generation = read_u64 (index_reader) ?;
+		let logical_address = read_u64 (index_reader) ?;
+		let level = read_u8 (index_reader) ?;
+
+		let validity =
+			if version >= 3 {
+				IndexNodeValidity::from_u8 (
+					read_u8 (index_reader) ?,
+				) ?
+			} else {
+				IndexNodeValidity::Unchecked
+			};
+
+		let keys =
+			if version >= 6 && read_u8 (index_reader) ? != 0 {
+
+			let first_key = read_key (index_reader) ?;
+			let last_key = read_key (index_reader) ?;
+
+			let mut item_types: [u8; 32] = [0; 32];
+
+			read_bytes (index_reader, & mut item_types) ?;
+
+			Some (IndexNodeKeys {
+				first_key: first_key,
+				last_key: last_key,
+				item_types: item_types,
+			})
+
+		} else {
+			None
+		};
+
+		nodes.push (
+			IndexNode {
+				device_id: device_id,
+				position: position,
+				tree_id: tree_id,
+				generation: generation,
+				level: level,
+				logical_address: logical_address,
+				validity: validity,
+				keys: keys,
+			}
+		);
+
+	}
+
+	// read bad ranges
+
+	let mut bad_ranges: Vec <IndexBadRange> =
+		Vec::new ();
+
+	if version >= 5 {
+
+		let num_bad_ranges =
+			read_u64 (index_reader) ?;
+
+		for _ in 0 .. num_bad_ranges {
+
+			let device_id = read_u64 (index_reader) ?;
+			let start = read_u64 (index_reader) ?;
+			let end = read_u64 (index_reader) ?;
+
+			bad_ranges.push (
+				IndexBadRange {
+					device_id: device_id,
+					start: start,
+					end: end,
+				}
+			);
+
+		}
+
+	}
+
+	// convert concatenated positions
+
+	if version < 2 {
+
+		index_split_positions (
+			& devices,
+			& mut nodes,
+		) ?;
+
+	}
+
+	Ok (Index {
+		header: Some (
+			IndexHeader {
+				version: version,
+				fs_uuid: fs_uuid,
+				node_size: node_size,
+				sector_size: sector_size,
+				csum_type: csum_type,
+				devices: devices,
+			}
+		),
+		nodes: nodes,
+		bad_ranges: bad_ranges,
+	})
+
+}
+
+fn index_split_positions (
+	devices: & [IndexDevice],
+	nodes: & mut [IndexNode],
+) -> Result <(), String> {
+
+	for node in nodes.iter_mut () {
+
+		let mut device_start: u64 = 0;
+		let mut found = false;
+
+		for device in devices.iter () {
+
+			if node.position < device_start + device.size {
+
+				node.device_id = device.device_id;
+				node.position -= device_start;
+
+				found = true;
+
+				break;
+
+			}
+
+			device_start += device.size;
+
+		}
+
+		if ! found {
+
+			return Err (
+				format! (
+					"Node position 0x{:x} is beyond the end of the last \
+					device",
+					node.position));
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+fn index_read_text (
+	index_reader: & mut BufRead,
+) -> Result <Index, String> {
+
+	let mut nodes: Vec <IndexNode> =
+		Vec::new ();
+
+	for line in index_reader.lines () {
+
+		let line = try! (
+			line.map_err (
+				|error| error.description ().to_owned ()
 			)
 		);
 
-		node_positions.push (
-			usize::from_str_radix (
+		let position = try! (
+			u64::from_str_radix (
 				& line,
 				16,
-			).unwrap ());
+			).map_err (
+				|_|
+
+				format! (
+					"Invalid index line: {}",
+					line)
+
+			)
+		);
+
+		nodes.push (
+			IndexNode {
+				device_id: 0,
+				position: position,
+				tree_id: 0,
+				generation: 0,
+				level: 0,
+				logical_address: 0,
+				validity: IndexNodeValidity::Unchecked,
+				keys: None,
+			}
+		);
 
 	}
 
+	Ok (Index {
+		header: None,
+		nodes: nodes,
+		bad_ranges: Vec::new (),
+	})
+
+}
+
+// binary helpers
+
+fn write_bytes (
+	writer: & mut Write,
+	bytes: & [u8],
+) -> Result <(), String> {
+
+	writer.write_all (
+		bytes,
+	).map_err (
+		|error| error.description ().to_owned (),
+	)
+
+}
+
+fn write_u8 (
+	writer: & mut Write,
+	value: u8,
+) -> Result <(), String> {
+
+	write_bytes (
+		writer,
+		& [ value ])
+
+}
+
+fn write_u16 (
+	writer: & mut Write,
+	value: u16,
+) -> Result <(), String> {
+
+	write_bytes (
+		writer,
+		& [
+			value as u8,
+			(value >> 8) as u8,
+		])
+
+}
+
+fn write_u32 (
+	writer: & mut Write,
+	value: u32,
+) -> Result <(), String> {
+
+	write_bytes (
+		writer,
+		& [
+			value as u8,
+			(value >> 8) as u8,
+			(value >> 16) as u8,
+			(value >> 24) as u8,
+		])
+
+}
+
+fn write_u64 (
+	writer: & mut Write,
+	value: u64,
+) -> Result <(), String> {
+
+	write_u32 (writer, value as u32) ?;
+	write_u32 (writer, (value >> 32) as u32) ?;
+
 	Ok (())
 
 }
 
+fn write_key (
+	writer: & mut Write,
+	key: & IndexKey,
+) -> Result <(), String> {
+
+	write_u64 (writer, key.object_id) ?;
+	write_u8 (writer, key.item_type) ?;
+	write_u64 (writer, key.offset) ?;
+
+	Ok (())
+
+}
+
+fn read_bytes (
+	reader: & mut Read,
+	bytes: & mut [u8],
+) -> Result <(), String> {
+
+	reader.read_exact (
+		bytes,
+	).map_err (
+		|error| error.description ().to_owned (),
+	)
+
+}
+
+fn read_bytes_vec (
+	reader: & mut Read,
+	len: u64,
+) -> Result <Vec <u8>, String> {
+
+	// grow the buffer as data arrives, so a corrupt length can't allocate
+	// more than the file actually holds
+
+	let mut bytes: Vec <u8> =
+		Vec::new ();
+
+	Read::take (
+		& mut * reader,
+		len,
+	).read_to_end (
+		& mut bytes,
+	).map_err (
+		|error| error.description ().to_owned (),
+	) ?;
+
+	if (bytes.len () as u64) < len {
+
+		return Err (
+			"Unexpected end of index file".to_owned ());
+
+	}
+
+	Ok (bytes)
+
+}
+
+fn read_u8 (
+	reader: & mut Read,
+) -> Result <u8, String> {
+
+	let mut bytes: [u8; 1] = [0; 1];
+
+	read_bytes (reader, & mut bytes) ?;
+
+	Ok (bytes [0])
+
+}
+
+fn read_u16 (
+	reader: & mut Read,
+) -> Result <u16, String> {
+
+	let mut bytes: [u8; 2] = [0; 2];
+
+	read_bytes (reader, & mut bytes) ?;
+
+	Ok (
+		bytes [0] as u16
+		| (bytes [1] as u16) << 8
+	)
+
+}
+
+fn read_u32 (
+	reader: & mut Read,
+) -> Result <u32, String> {
+
+	let mut bytes: [u8; 4] = [0; 4];
+
+	read_bytes (reader, & mut bytes) ?;
+
+	Ok (
+		bytes [0] as u32
+		| (bytes [1] as u32) << 8
+		| (bytes [2] as u32) << 16
+		| (bytes [3] as u32) << 24
+	)
+
+}
+
+fn read_u64 (
+	reader: & mut Read,
+) -> Result <u64, String> {
+
+	let low = read_u32 (reader) ? as u64;
+	let high = read_u32 (reader) ? as u64;
+
+	Ok (low | high << 32)
+
+}
+
+fn read_key (
+	reader: & mut Read,
+) -> Result <IndexKey, String> {
+
+	let object_id = read_u64 (reader) ?;
+	let item_type = read_u8 (reader) ?;
+	let offset = read_u64 (reader) ?;
+
+	Ok (IndexKey {
+		object_id: object_id,
+		item_type: item_type,
+		offset: offset,
+	})
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn test_index () -> Index {
+
+		let keys =
+			IndexNodeKeys {
+				first_key: IndexKey {
+					object_id: 256,
+					item_type: 1,
+					offset: 0,
+				},
+				last_key: IndexKey {
+					object_id: 300,
+					item_type: 108,
+					offset: 0x2000,
+				},
+				item_types: [0x5a; 32],
+			};
+
+		Index {
+			header: Some (
+				IndexHeader {
+					version: INDEX_VERSION,
+					fs_uuid: [0x11; 16],
+					node_size: 0x4000,
+					sector_size: 0x1000,
+					csum_type: 0,
+					devices: vec! [
+						IndexDevice {
+							path: PathBuf::from ("/dev/first"),
+							device_id: 1,
+							size: 0x100_0000,
+							scanned_to: 0x80_0000,
+						},
+						IndexDevice {
+							path: PathBuf::from ("/dev/second"),
+							device_id: 2,
+							size: 0x200_0000,
+							scanned_to: 0x200_0000,
+						},
+					],
+				}
+			),
+			nodes: vec! [
+				IndexNode {
+					device_id: 1,
+					position: 0x10_0000,
+					tree_id: 5,
+					generation: 7,
+					level: 0,
+					logical_address: 0x1d0_0000,
+					validity: IndexNodeValidity::Valid,
+					keys: Some (keys),
+				},
+				IndexNode {
+					device_id: 2,
+					position: 0x4000,
+					tree_id: 1,
+					generation: 9,
+					level: 1,
+					logical_address: 0x1d0_4000,
+					validity: IndexNodeValidity::BadChecksum,
+					keys: None,
+				},
+			],
+			bad_ranges: vec! [
+				IndexBadRange {
+					device_id: 2,
+					start: 0x1000,
+					end: 0x3000,
+				},
+			],
+		}
+
+	}
+
+	// writes an index as an older version would have, leaving out whatever
+	// that version didn't record. version 1 had no device ids, and numbered
+	// positions across all the devices, one after another.
+
+	fn write_version (
+		index: & Index,
+		version: u32,
+	) -> Vec <u8> {
+
+		let index_header =
+			index.header.as_ref ().unwrap ();
+
+		let mut bytes: Vec <u8> =
+			Vec::new ();
+
+		{
+
+			let writer: & mut Write =
+				& mut bytes;
+
+			write_bytes (writer, INDEX_MAGIC).unwrap ();
+			write_u32 (writer, version).unwrap ();
+			write_bytes (writer, & index_header.fs_uuid).unwrap ();
+			write_u32 (writer, index_header.node_size).unwrap ();
+			write_u32 (writer, index_header.sector_size).unwrap ();
+			write_u16 (writer, index_header.csum_type).unwrap ();
+
+			write_u32 (writer, index_header.devices.len () as u32).unwrap ();
+
+			for device in index_header.devices.iter () {
+
+				let path_bytes =
+					device.path.as_os_str ().as_bytes ();
+
+				write_u64 (writer, device.device_id).unwrap ();
+				write_u64 (writer, device.size).unwrap ();
+
+				if version >= 4 {
+					write_u64 (writer, device.scanned_to).unwrap ();
+				}
+
+				write_u32 (writer, path_bytes.len () as u32).unwrap ();
+				write_bytes (writer, path_bytes).unwrap ();
+
+			}
+
+			write_u64 (writer, index.nodes.len () as u64).unwrap ();
+
+			for node in index.nodes.iter () {
+
+				if version >= 2 {
+
+					write_u64 (writer, node.device_id).unwrap ();
+					write_u64 (writer, node.position).unwrap ();
+
+				} else {
+
+					let device_start: u64 =
+						index_header.devices.iter ().take_while (
+							|device| device.device_id != node.device_id,
+						).map (
+							|device| device.size,
+						).sum ();
+
+					write_u64 (writer, device_start + node.position).unwrap ();
+
+				}
+
+				write_u64 (writer, node.tree_id).unwrap ();
+				write_u64 (writer, node.generation).unwrap ();
+				write_u64 (writer, node.logical_address).unwrap ();
+				write_u8 (writer, node.level).unwrap ();
+
+				if version >= 3 {
+					write_u8 (writer, node.validity.to_u8 ()).unwrap ();
+				}
+
+				if version >= 6 {
+
+					match node.keys {
+
+						Some (ref keys) => {
+							write_u8 (writer, 1).unwrap ();
+							write_key (writer, & keys.first_key).unwrap ();
+							write_key (writer, & keys.last_key).unwrap ();
+							write_bytes (writer, & keys.item_types).unwrap ();
+						},
+
+						None =>
+							write_u8 (writer, 0).unwrap (),
+
+					}
+
+				}
+
+			}
+
+			if version >= 5 {
+
+				write_u64 (writer, index.bad_ranges.len () as u64).unwrap ();
+
+				for bad_range in index.bad_ranges.iter () {
+					write_u64 (writer, bad_range.device_id).unwrap ();
+					write_u64 (writer, bad_range.start).unwrap ();
+					write_u64 (writer, bad_range.end).unwrap ();
+				}
+
+			}
+
+		}
+
+		bytes
+
+	}
+
+	fn read (
+		bytes: & [u8],
+	) -> Result <Index, String> {
+
+		index_read (
+			& mut & bytes [..])
+
+	}
+
+	#[ test ]
+	fn current_version_round_trips () {
+
+		let index = test_index ();
+
+		let mut bytes: Vec <u8> =
+			Vec::new ();
+
+		index_write (
+			& index,
+			& mut bytes,
+		).unwrap ();
+
+		assert_eq! (
+			bytes,
+			write_version (& index, INDEX_VERSION));
+
+		let read_index =
+			read (& bytes).unwrap ();
+
+		assert_eq! (
+			format! ("{:?}", read_index),
+			format! ("{:?}", index));
+
+	}
+
+	#[ test ]
+	fn older_versions_are_read () {
+
+		let index = test_index ();
+
+		for version in 1 .. INDEX_VERSION + 1 {
+
+			let read_index =
+				read (
+					& write_version (& index, version),
+				).unwrap ();
+
+			let header =
+				read_index.header.as_ref ().unwrap ();
+
+			assert_eq! (header.version, version);
+			assert_eq! (header.fs_uuid, [0x11; 16]);
+			assert_eq! (header.node_size, 0x4000);
+			assert_eq! (header.devices.len (), 2);
+			assert_eq! (header.devices [1].path, PathBuf::from ("/dev/second"));
+
+			assert_eq! (
+				header.devices [0].scanned_to,
+				if version >= 4 { 0x80_0000 } else { 0x100_0000 });
+
+			assert_eq! (read_index.nodes.len (), 2);
+
+			for (read_node, node) in read_index.nodes.iter ().zip (
+				index.nodes.iter ()) {
+
+				assert_eq! (read_node.device_id, node.device_id);
+				assert_eq! (read_node.position, node.position);
+				assert_eq! (read_node.tree_id, node.tree_id);
+				assert_eq! (read_node.generation, node.generation);
+				assert_eq! (read_node.level, node.level);
+				assert_eq! (read_node.logical_address, node.logical_address);
+
+				assert_eq! (
+					read_node.validity,
+					if version >= 3 {
+						node.validity
+					} else {
+						IndexNodeValidity::Unchecked
+					});
+
+				assert_eq! (
+					read_node.keys.map (|keys| keys.last_key),
+					if version >= 6 {
+						node.keys.map (|keys| keys.last_key)
+					} else {
+						None
+					});
+
+			}
+
+			assert_eq! (
+				read_index.bad_ranges.len (),
+				if version >= 5 { 1 } else { 0 });
+
+		}
+
+	}
+
+	#[ test ]
+	fn unsupported_version_is_rejected () {
+
+		let index = test_index ();
+
+		assert! (read (& write_version (& index, 0)).is_err ());
+		assert! (read (& write_version (& index, INDEX_VERSION + 1)).is_err ());
+
+	}
+
+	#[ test ]
+	fn truncated_index_is_an_error () {
+
+		let bytes =
+			write_version (
+				& test_index (),
+				INDEX_VERSION);
+
+		for length in INDEX_MAGIC.len () .. bytes.len () {
+			assert! (read (& bytes [.. length]).is_err ());
+		}
+
+	}
+
+	#[ test ]
+	fn huge_lengths_are_not_trusted () {
+
+		let mut bytes: Vec <u8> =
+			Vec::new ();
+
+		{
+
+			let writer: & mut Write =
+				& mut bytes;
+
+			write_bytes (writer, INDEX_MAGIC).unwrap ();
+			write_u32 (writer, INDEX_VERSION).unwrap ();
+			write_bytes (writer, & [0; 16]).unwrap ();
+			write_u32 (writer, 0x4000).unwrap ();
+			write_u32 (writer, 0x1000).unwrap ();
+			write_u16 (writer, 0).unwrap ();
+
+			// one device, with a path far longer than the file
+
+			write_u32 (writer, 1).unwrap ();
+			write_u64 (writer, 1).unwrap ();
+			write_u64 (writer, 0x1000).unwrap ();
+			write_u64 (writer, 0x1000).unwrap ();
+			write_u32 (writer, 0xffff_ffff).unwrap ();
+
+		}
+
+		assert! (read (& bytes).is_err ());
+
+		// and a node count far larger than the file
+
+		let mut bytes =
+			write_version (
+				& Index {
+					nodes: Vec::new (),
+					bad_ranges: Vec::new (),
+					.. test_index ()
+				},
+				INDEX_VERSION);
+
+		let node_count_position =
+			bytes.len () - 16;
+
+		bytes [node_count_position .. node_count_position + 8]
+			.copy_from_slice (& [0xff; 8]);
+
+		assert! (read (& bytes).is_err ());
+
+	}
+
+}
+
 // ex: noet ts=4 filetype=rust
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use output::Output;
+
+use arguments::*;
+use index::*;
+
+pub fn index_diff (
+	output: & Output,
+	command: IndexDiffCommand,
+) -> Result <(), String> {
+
+	let old_index =
+		index_load (
+			& command.old_index,
+		) ?;
+
+	let new_index =
+		index_load (
+			& command.new_index,
+		) ?;
+
+	// compare headers
+
+	if let (Some (old_header), Some (new_header)) =
+		(old_index.header.as_ref (), new_index.header.as_ref ()) {
+
+		if old_header.fs_uuid != new_header.fs_uuid {
+
+			output_message! (
+				output,
+				"Warning: indexes are for different filesystems, {} and {}",
+				old_header.fs_uuid_string (),
+				new_header.fs_uuid_string ());
+
+		}
+
+	}
+
+	// compare nodes by position
+
+	let old_nodes: BTreeMap <(u64, u64), & IndexNode> =
+		old_index.nodes.iter ().map (
+			|index_node|
+
+			((index_node.device_id, index_node.position), index_node)
+
+		).collect ();
+
+	let new_nodes: BTreeMap <(u64, u64), & IndexNode> =
+		new_index.nodes.iter ().map (
+			|index_node|
+
+			((index_node.device_id, index_node.position), index_node)
+
+		).collect ();
+
+	let mut num_added: u64 = 0;
+	let mut num_removed: u64 = 0;
+	let mut num_changed: u64 = 0;
+
+	for (key, old_node) in old_nodes.iter () {
+
+		match new_nodes.get (key) {
+
+			None => {
+
+				output_message! (
+					output,
+					"- {}",
+					index_node_description (old_node));
+
+				num_removed += 1;
+
+			},
+
+			Some (new_node) => {
+
+				if (
+					old_node.tree_id != new_node.tree_id
+					|| old_node.generation != new_node.generation
+					|| old_node.level != new_node.level
+					|| old_node.logical_address != new_node.logical_address
+				) {
+
+					output_message! (
+						output,
+						"~ {} now {}",
+						index_node_description (old_node),
+						index_node_description (new_node));
+
+					num_changed += 1;
+
+				} else if old_node.validity != new_node.validity {
+
+					output_message! (
+						output,
+						"~ {} now {}",
+						index_node_description (old_node),
+						new_node.validity.name ());
+
+					num_changed += 1;
+
+				}
+
+			},
+
+		}
+
+	}
+
+	for (key, new_node) in new_nodes.iter () {
+
+		if ! old_nodes.contains_key (key) {
+
+			output_message! (
+				output,
+				"+ {}",
+				index_node_description (new_node));
+
+			num_added += 1;
+
+		}
+
+	}
+
+	output_message! (
+		output,
+		"{} nodes added, {} removed, {} changed",
+		num_added,
+		num_removed,
+		num_changed);
+
+	// compare unreadable ranges
+
+	let old_bad_size = bad_ranges_size (& old_index);
+	let new_bad_size = bad_ranges_size (& new_index);
+
+	if old_bad_size != new_bad_size {
+
+		output_message! (
+			output,
+			"Unreadable bytes changed from 0x{:x} to 0x{:x}",
+			old_bad_size,
+			new_bad_size);
+
+	}
+
+	Ok (())
+
+}
+
+fn bad_ranges_size (
+	index: & Index,
+) -> u64 {
+
+	index.bad_ranges.iter ().map (
+		|bad_range|
+
+		bad_range.end - bad_range.start
+
+	).sum ()
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,515 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use output::Output;
+
+use arguments::*;
+use checksum::*;
+use index::*;
+
+const HISTOGRAM_WIDTH: u64 = 40;
+
+#[ derive (Clone, Copy, Default) ]
+struct NodeCounts {
+	total: u64,
+	corrupt: u64,
+	min_generation: u64,
+	max_generation: u64,
+}
+
+impl NodeCounts {
+
+	fn add (
+		& mut self,
+		index_node: & IndexNode,
+	) {
+
+		if self.total == 0 || index_node.generation < self.min_generation {
+			self.min_generation = index_node.generation;
+		}
+
+		if index_node.generation > self.max_generation {
+			self.max_generation = index_node.generation;
+		}
+
+		self.total += 1;
+
+		if index_node.validity.is_corrupt () {
+			self.corrupt += 1;
+		}
+
+	}
+
+}
+
+pub fn index_info (
+	output: & Output,
+	command: IndexInfoCommand,
+) -> Result <(), String> {
+
+	let index =
+		index_load (
+			& command.index,
+		) ?;
+
+	index_info_header (
+		output,
+		& index);
+
+	// filter nodes
+
+	let index_nodes: Vec <& IndexNode> =
+		index.nodes.iter ().filter (
+			|index_node|
+
+			index_node_matches (
+				& command.filter,
+				index_node)
+
+			&& command.device_id.map_or (
+				true,
+				|device_id| index_node.device_id == device_id)
+
+			&& (! command.corrupt
+				|| index_node.validity.is_corrupt ())
+
+		).collect ();
+
+	if index_nodes.len () != index.nodes.len () {
+
+		output_message! (
+			output,
+			"Matched {} of {} nodes",
+			index_nodes.len (),
+			index.nodes.len ());
+
+	}
+
+	if index_nodes.is_empty () {
+		return Ok (());
+	}
+
+	// print statistics
+
+	index_info_totals (
+		output,
+		& index_nodes);
+
+	index_info_trees (
+		output,
+		& index_nodes);
+
+	index_info_levels (
+		output,
+		& index_nodes);
+
+	index_info_generations (
+		output,
+		& index_nodes);
+
+	index_info_histogram (
+		output,
+		& index,
+		& index_nodes,
+		command.histogram_buckets as u64);
+
+	// print nodes
+
+	if command.print_nodes {
+
+		output_message! (
+			output,
+			"Nodes:");
+
+		for index_node in index_nodes.iter () {
+
+			output_message! (
+				output,
+				"  {}",
+				index_node_description (index_node));
+
+		}
+
+	}
+
+	Ok (())
+
+}
+
+fn index_info_header (
+	output: & Output,
+	index: & Index,
+) {
+
+	let index_header =
+		match index.header {
+
+		Some (ref index_header) =>
+			index_header,
+
+		None => {
+
+			output_message! (
+				output,
+				"Text index, node positions only");
+
+			return;
+
+		},
+
+	};
+
+	output_message! (
+		output,
+		"Index version {}",
+		index_header.version);
+
+	output_message! (
+		output,
+		"Filesystem UUID: {}",
+		index_header.fs_uuid_string ());
+
+	output_message! (
+		output,
+		"Node size: 0x{:x}, sector size: 0x{:x}, checksum type: {}",
+		index_header.node_size,
+		index_header.sector_size,
+		checksum_type_name (index_header.csum_type));
+
+	for index_device in index_header.devices.iter () {
+
+		output_message! (
+			output,
+			"Device {}: {}, 0x{:x} bytes, scanned to 0x{:x}",
+			index_device.device_id,
+			index_device.path.to_string_lossy (),
+			index_device.size,
+			index_device.scanned_to);
+
+	}
+
+	if ! index.bad_ranges.is_empty () {
+
+		let bad_size: u64 =
+			index.bad_ranges.iter ().map (
+				|bad_range|
+
+				bad_range.end - bad_range.start
+
+			).sum ();
+
+		output_message! (
+			output,
+			"Unreadable: 0x{:x} bytes in {} ranges",
+			bad_size,
+			index.bad_ranges.len ());
+
+	}
+
+}
+
+fn index_info_totals (
+	output: & Output,
+	index_nodes: & [& IndexNode],
+) {
+
+	let mut counts: NodeCounts =
+		Default::default ();
+
+	let mut validity_counts: BTreeMap <u8, u64> =
+		BTreeMap::new ();
+
+	for index_node in index_nodes.iter () {
+
+		counts.add (
+			index_node);
+
+		* validity_counts.entry (
+			index_node.validity.to_u8 (),
+		).or_insert (0) += 1;
+
+	}
+
+	output_message! (
+		output,
+		"Nodes: {}, corrupt: {}",
+		counts.total,
+		counts.corrupt);
+
+	for (validity, count) in validity_counts.iter () {
+
+		output_message! (
+			output,
+			"  {}: {}",
+			IndexNodeValidity::from_u8 (* validity).unwrap ().name (),
+			count);
+
+	}
+
+	output_message! (
+		output,
+		"Generations: {} to {}",
+		counts.min_generation,
+		counts.max_generation);
+
+}
+
+fn index_info_trees (
+	output: & Output,
+	index_nodes: & [& IndexNode],
+) {
+
+	let mut tree_counts: BTreeMap <u64, NodeCounts> =
+		BTreeMap::new ();
+
+	for index_node in index_nodes.iter () {
+
+		tree_counts.entry (
+			index_node.tree_id,
+		).or_insert_with (
+			Default::default,
+		).add (
+			index_node);
+
+	}
+
+	output_message! (
+		output,
+		"By tree:");
+
+	for (tree_id, counts) in tree_counts.iter () {
+
+		output_message! (
+			output,
+			"  {} ({}): {} nodes, {} corrupt, generations {} to {}",
+			tree_id,
+			tree_name (* tree_id),
+			counts.total,
+			counts.corrupt,
+			counts.min_generation,
+			counts.max_generation);
+
+	}
+
+}
+
+fn index_info_levels (
+	output: & Output,
+	index_nodes: & [& IndexNode],
+) {
+
+	let mut level_counts: BTreeMap <u8, NodeCounts> =
+		BTreeMap::new ();
+
+	for index_node in index_nodes.iter () {
+
+		level_counts.entry (
+			index_node.level,
+		).or_insert_with (
+			Default::default,
+		).add (
+			index_node);
+
+	}
+
+	output_message! (
+		output,
+		"By level:");
+
+	for (level, counts) in level_counts.iter () {
+
+		output_message! (
+			output,
+			"  {}: {} nodes, {} corrupt",
+			level,
+			counts.total,
+			counts.corrupt);
+
+	}
+
+}
+
+fn index_info_generations (
+	output: & Output,
+	index_nodes: & [& IndexNode],
+) {
+
+	let mut generation_counts: BTreeMap <u64, NodeCounts> =
+		BTreeMap::new ();
+
+	for index_node in index_nodes.iter () {
+
+		generation_counts.entry (
+			index_node.generation,
+		).or_insert_with (
+			Default::default,
+		).add (
+			index_node);
+
+	}
+
+	output_message! (
+		output,
+		"By generation:");
+
+	for (generation, counts) in generation_counts.iter () {
+
+		output_message! (
+			output,
+			"  {}: {} nodes, {} corrupt",
+			generation,
+			counts.total,
+			counts.corrupt);
+
+	}
+
+}
+
+// splits each device into equal buckets and shows how many nodes are in
+// each, and their range of generations. old generations clustered in one
+// place can show where damage happened.
+
+fn index_info_histogram (
+	output: & Output,
+	index: & Index,
+	index_nodes: & [& IndexNode],
+	num_buckets: u64,
+) {
+
+	let mut device_nodes: BTreeMap <u64, Vec <& IndexNode>> =
+		BTreeMap::new ();
+
+	for index_node in index_nodes.iter () {
+
+		device_nodes.entry (
+			index_node.device_id,
+		).or_insert_with (
+			Vec::new,
+		).push (
+			index_node);
+
+	}
+
+	for (device_id, nodes) in device_nodes.iter () {
+
+		// use the device size if we know it
+
+		let max_position =
+			nodes.iter ().map (
+				|index_node|
+
+				index_node.position + 1
+
+			).max ().unwrap ();
+
+		let device_size =
+			index.header.as_ref ().and_then (
+				|index_header|
+
+				index_header.device (
+					* device_id)
+
+			).map_or (
+				max_position,
+				|index_device|
+
+				cmp::max (
+					index_device.size,
+					max_position)
+
+			);
+
+		let bucket_size =
+			cmp::max (
+				(device_size + num_buckets - 1) / num_buckets,
+				1);
+
+		let mut buckets: Vec <NodeCounts> =
+			vec! [Default::default (); num_buckets as usize];
+
+		for index_node in nodes.iter () {
+
+			buckets [
+				(index_node.position / bucket_size) as usize
+			].add (
+				index_node);
+
+		}
+
+		let max_count =
+			buckets.iter ().map (
+				|bucket|
+
+				bucket.total
+
+			).max ().unwrap ();
+
+		output_message! (
+			output,
+			"Generations by position on device {}:",
+			device_id);
+
+		for (bucket_index, bucket) in buckets.iter ().enumerate () {
+
+			let bucket_start =
+				bucket_index as u64 * bucket_size;
+
+			let bar: String =
+				(0 .. bucket.total * HISTOGRAM_WIDTH / max_count).map (
+					|_| '#'
+				).collect ();
+
+			if bucket.total == 0 {
+
+				output_message! (
+					output,
+					"  0x{:012x}-0x{:012x} {:>8}",
+					bucket_start,
+					bucket_start + bucket_size,
+					0);
+
+			} else {
+
+				output_message! (
+					output,
+					"  0x{:012x}-0x{:012x} {:>8} {:>10}-{:<10} {}",
+					bucket_start,
+					bucket_start + bucket_size,
+					bucket.total,
+					bucket.min_generation,
+					bucket.max_generation,
+					bar);
+
+			}
+
+		}
+
+	}
+
+}
+
+fn tree_name (
+	tree_id: u64,
+) -> & 'static str {
+
+	match tree_id {
+		1 => "root",
+		2 => "extent",
+		3 => "chunk",
+		4 => "device",
+		5 => "fs",
+		7 => "checksum",
+		8 => "quota",
+		9 => "uuid",
+		10 => "free space",
+		11 => "block group",
+		0xffff_ffff_ffff_fff7 => "data reloc",
+		0xffff_ffff_ffff_fff8 => "tree reloc",
+		0xffff_ffff_ffff_fffa => "tree log",
+		256 ... 0xffff_ffff_ffff_feff => "subvolume",
+		_ => "unknown",
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
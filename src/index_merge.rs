@@ -0,0 +1,362 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+
+use output::Output;
+
+use arguments::*;
+use index::*;
+
+pub fn index_merge (
+	output: & Output,
+	command: IndexMergeCommand,
+) -> Result <(), String> {
+
+	// load indexes
+
+	let mut indexes: Vec <Index> =
+		Vec::new ();
+
+	for input in command.inputs.iter () {
+
+		let index =
+			index_load (
+				input,
+			) ?;
+
+		if index.header.is_none () {
+
+			return Err (
+				format! (
+					"Can't merge {}, text indexes have no filesystem \
+					information",
+					input.to_string_lossy ()));
+
+		}
+
+		output_message! (
+			output,
+			"Loaded {} nodes from {}",
+			index.nodes.len (),
+			input.to_string_lossy ());
+
+		indexes.push (
+			index);
+
+	}
+
+	// check they are for the same filesystem
+
+	let first_header =
+		indexes [0].header.clone ().unwrap ();
+
+	for (index, input) in indexes.iter ().zip (command.inputs.iter ()) {
+
+		let index_header =
+			index.header.as_ref ().unwrap ();
+
+		if (
+			index_header.fs_uuid != first_header.fs_uuid
+			|| index_header.node_size != first_header.node_size
+			|| index_header.sector_size != first_header.sector_size
+			|| index_header.csum_type != first_header.csum_type
+		) {
+
+			return Err (
+				format! (
+					"Index {} is for filesystem {}, not {}, or has \
+					different parameters",
+					input.to_string_lossy (),
+					index_header.fs_uuid_string (),
+					first_header.fs_uuid_string ()));
+
+		}
+
+	}
+
+	// merge devices, the same device may have been scanned under a
+	// different path on another machine
+
+	let mut devices: BTreeMap <u64, IndexDevice> =
+		BTreeMap::new ();
+
+	for index in indexes.iter () {
+
+		for index_device in index.header.as_ref ().unwrap ().devices.iter () {
+
+			let device =
+				devices.entry (
+					index_device.device_id,
+				).or_insert_with (
+					|| index_device.clone ());
+
+			device.size =
+				cmp::max (
+					device.size,
+					index_device.size);
+
+			device.scanned_to =
+				cmp::max (
+					device.scanned_to,
+					index_device.scanned_to);
+
+		}
+
+	}
+
+	// merge nodes, preferring the better copy when a position appears in
+	// several indexes, and the later index when they are equally good
+
+	let mut nodes: BTreeMap <(u64, u64), IndexNode> =
+		BTreeMap::new ();
+
+	let mut num_duplicates: u64 = 0;
+
+	for index in indexes.iter () {
+
+		for index_node in index.nodes.iter () {
+
+			let key =
+				(index_node.device_id, index_node.position);
+
+			let mut index_node =
+				* index_node;
+
+			if let Some (existing_node) = nodes.get (& key) {
+
+				num_duplicates += 1;
+
+				if validity_rank (existing_node.validity)
+					> validity_rank (index_node.validity) {
+
+					continue;
+
+				}
+
+				// an index from before keys were recorded has none, so keep
+				// them from the other copy if it is the same node
+
+				if (
+					index_node.keys.is_none ()
+					&& existing_node.generation == index_node.generation
+					&& existing_node.logical_address
+						== index_node.logical_address
+				) {
+
+					index_node.keys =
+						existing_node.keys;
+
+				}
+
+			}
+
+			nodes.insert (
+				key,
+				index_node);
+
+		}
+
+	}
+
+	let bad_ranges =
+		index_merge_bad_ranges (
+			& indexes);
+
+	let merged_index = Index {
+		header: Some (
+			IndexHeader {
+				version: INDEX_VERSION,
+				devices: devices.into_iter ().map (
+					|(_, index_device)| index_device
+				).collect (),
+				.. first_header
+			}
+		),
+		nodes: nodes.into_iter ().map (
+			|(_, index_node)| index_node
+		).collect (),
+		bad_ranges: bad_ranges,
+	};
+
+	// write merged index
+
+	let mut index_file = try! (
+		File::create (
+			& command.index,
+		).map_err (
+			|error|
+
+			format! (
+				"Error creating {}: {}",
+				command.index.to_string_lossy (),
+				error.description ())
+		)
+	);
+
+	index_write (
+		& merged_index,
+		& mut index_file,
+	) ?;
+
+	output_message! (
+		output,
+		"Wrote {} nodes to {}, ignoring {} duplicates",
+		merged_index.nodes.len (),
+		command.index.to_string_lossy (),
+		num_duplicates);
+
+	Ok (())
+
+}
+
+fn validity_rank (
+	validity: IndexNodeValidity,
+) -> u8 {
+
+	match validity {
+		IndexNodeValidity::Valid => 3,
+		IndexNodeValidity::Unchecked => 2,
+		IndexNodeValidity::BadChecksum => 1,
+		IndexNodeValidity::Broken => 0,
+	}
+
+}
+
+// a range is only unreadable in the merged index if every index which
+// scanned it found it unreadable, since a later scan of a better image may
+// have read it
+
+fn index_merge_bad_ranges (
+	indexes: & [Index],
+) -> Vec <IndexBadRange> {
+
+	let mut merged_ranges: Vec <IndexBadRange> =
+		Vec::new ();
+
+	for (index_index, index) in indexes.iter ().enumerate () {
+
+		for bad_range in index.bad_ranges.iter () {
+
+			let mut pieces: Vec <(u64, u64)> =
+				vec! [ (bad_range.start, bad_range.end) ];
+
+			for (other_index, other) in indexes.iter ().enumerate () {
+
+				if other_index == index_index {
+					continue;
+				}
+
+				let scanned_to =
+					other.header.as_ref ().unwrap ().device (
+						bad_range.device_id,
+					).map_or (
+						0,
+						|index_device| index_device.scanned_to);
+
+				let other_ranges: Vec <& IndexBadRange> =
+					other.bad_ranges.iter ().filter (
+						|other_range|
+
+						other_range.device_id == bad_range.device_id
+
+					).collect ();
+
+				let mut clipped_pieces: Vec <(u64, u64)> =
+					Vec::new ();
+
+				for & (start, end) in pieces.iter () {
+
+					// keep anything the other index didn't scan
+
+					if end > scanned_to {
+
+						clipped_pieces.push (
+							(cmp::max (start, scanned_to), end));
+
+					}
+
+					// and what it scanned but also couldn't read
+
+					let scanned_end =
+						cmp::min (end, scanned_to);
+
+					for other_range in other_ranges.iter () {
+
+						let clipped_start =
+							cmp::max (start, other_range.start);
+
+						let clipped_end =
+							cmp::min (scanned_end, other_range.end);
+
+						if clipped_start < clipped_end {
+
+							clipped_pieces.push (
+								(clipped_start, clipped_end));
+
+						}
+
+					}
+
+				}
+
+				pieces = clipped_pieces;
+
+			}
+
+			for (start, end) in pieces {
+
+				merged_ranges.push (
+					IndexBadRange {
+						device_id: bad_range.device_id,
+						start: start,
+						end: end,
+					});
+
+			}
+
+		}
+
+	}
+
+	// sort and combine overlapping ranges, which will include the same
+	// range found by several indexes
+
+	merged_ranges.sort_by_key (
+		|bad_range|
+
+		(bad_range.device_id, bad_range.start)
+
+	);
+
+	let mut combined_ranges: Vec <IndexBadRange> =
+		Vec::new ();
+
+	for bad_range in merged_ranges {
+
+		if let Some (last_range) = combined_ranges.last_mut () {
+
+			if last_range.device_id == bad_range.device_id
+				&& last_range.end >= bad_range.start {
+
+				last_range.end =
+					cmp::max (
+						last_range.end,
+						bad_range.end);
+
+				continue;
+
+			}
+
+		}
+
+		combined_ranges.push (
+			bad_range);
+
+	}
+
+	combined_ranges
+
+}
+
+// ex: noet ts=4 filetype=rust
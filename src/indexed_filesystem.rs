@@ -1,5 +1,7 @@
 use std::cmp;
+use std::collections::BinaryHeap;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::hash;
 use std::mem;
 use std::path::Path;
@@ -8,17 +10,46 @@ use btrfs::diskformat::*;
 
 use output::Output;
 
+use uuid::Uuid;
+
+use super::bytes::*;
+use super::checksum::*;
+use super::chunks::*;
+use super::devices::*;
 use super::index::*;
+use super::superblock::*;
+use super::trees::*;
+
+pub const INDEXED_INODE_REF_TYPE: u8 = 12;
+pub const INDEXED_INODE_EXTREF_TYPE: u8 = 13;
+
+const INDEXED_ROOT_DIRECTORY_ID: u64 = 256;
+
+// a view of a filesystem built from the nodes in an index, rather than by
+// walking trees from the superblock. node positions and filesystem
+// parameters come from the index, so this works when the superblock is gone
+// or belongs to another filesystem, such as after an accidental mkfs.
 
 pub struct IndexedFilesystem <'a> {
 
-	pub filesystem: & 'a BtrfsFilesystem <'a>,
+	pub device_set: & 'a DeviceSet,
+	pub include_corrupt_nodes: bool,
+	pub max_generation: Option <u64>,
+	pub subvolume_id: Option <u64>,
+
+	pub node_size: u32,
+	pub csum_type: u16,
+	pub device_indexes: HashMap <u64, usize>,
+	pub default_device_id: u64,
+
+	pub chunk_map: ChunkMap,
 
 	pub nodes: Vec <BtrfsNode <'a>>,
 	pub items: Vec <BtrfsLeafItem <'a>>,
 
 	pub internal_nodes: Vec <BtrfsInternalNode <'a>>,
 	pub leaf_nodes: Vec <BtrfsLeafNode <'a>>,
+	pub leaves: Vec <IndexedLeaf>,
 
 	pub internal_items_by_tree:
 		HashMap <BtrfsTreeId, Vec <& 'a BtrfsInternalItem>>,
@@ -26,26 +57,123 @@ pub struct IndexedFilesystem <'a> {
 	pub chunk_items: Vec <BtrfsChunkItem <'a>>,
 	pub chunk_items_index: HashMap <u64, Vec <BtrfsChunkItem <'a>>>,
 
-	pub dir_item_entries: Vec <BtrfsDirItemEntry <'a>>,
-	pub dir_item_entries_index: HashMap <u64, Vec <BtrfsDirItemEntry <'a>>>,
-	pub dir_item_entries_recent: HashMap <u64, BtrfsDirItemEntry <'a>>,
-	pub dir_item_entries_by_parent: HashMap <u64, Vec <u64>>,
+	pub dir_item_entries: Vec <IndexedItem <BtrfsDirItemEntry <'a>>>,
+	pub dir_item_entries_index:
+		HashMap <(u64, u64), Vec <IndexedItem <BtrfsDirItemEntry <'a>>>>,
+	pub dir_item_entries_recent:
+		HashMap <(u64, u64), BtrfsDirItemEntry <'a>>,
+	pub dir_item_entries_by_parent: HashMap <(u64, u64), Vec <u64>>,
 
-	pub extent_datas: Vec <BtrfsExtentData <'a>>,
-	pub extent_datas_index: HashMap <u64, Vec <BtrfsExtentData <'a>>>,
+	pub extent_datas: Vec <IndexedItem <BtrfsExtentData <'a>>>,
+	pub extent_datas_index:
+		HashMap <(u64, u64), Vec <IndexedItem <BtrfsExtentData <'a>>>>,
 
 	pub extent_items: Vec <BtrfsExtentItem <'a>>,
 	pub extent_items_index: HashMap <u64, Vec <BtrfsExtentItem <'a>>>,
 
-	pub inode_items: Vec <BtrfsInodeItem <'a>>,
-	pub inode_items_index: HashMap <u64, Vec <BtrfsInodeItem <'a>>>,
-	pub inode_items_recent: HashMap <u64, BtrfsInodeItem <'a>>,
+	pub inode_items: Vec <IndexedItem <BtrfsInodeItem <'a>>>,
+	pub inode_items_index:
+		HashMap <(u64, u64), Vec <IndexedItem <BtrfsInodeItem <'a>>>>,
+	pub inode_items_recent: HashMap <(u64, u64), BtrfsInodeItem <'a>>,
+
+	pub inode_refs: Vec <IndexedItem <IndexedInodeRef <'a>>>,
 
 	pub root_items: Vec <BtrfsRootItem <'a>>,
 	pub root_items_index: HashMap <u64, Vec <BtrfsRootItem <'a>>>,
 
-	pub root_tree_nodes: Vec <BtrfsNode <'a>>,
+	pub root_tree_nodes: Vec <IndexNode>,
+
+}
+
+// the nodes a filesystem is built from, read from the devices along with the
+// id of the device each was read from. the parsed nodes and items refer to
+// these buffers, so they are read first, and kept for as long as the
+// filesystem is.
+
+pub struct IndexedNodes {
+	nodes: Vec <(IndexNode, u64, Vec <u8>)>,
+	num_missing: u64,
+}
+
+impl IndexedNodes {
+
+	// the nodes of a tree which was walked that couldn't be read, or were
+	// skipped. this is always zero for nodes taken from an index.
+
+	pub fn num_missing (& self) -> u64 {
+		self.num_missing
+	}
+
+}
+
+// an item along with its key, the leaf it was found in, the tree which owns
+// that leaf, and the generation of that leaf, which is when this copy of it
+// was written. items from filesystem trees are indexed by tree id as well as
+// object id, since every subvolume numbers its inodes from 256.
+
+#[ derive (Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd) ]
+pub struct IndexedItem <Item> {
+	pub item: Item,
+	pub key: IndexKey,
+	pub leaf_index: usize,
+	pub tree_id: u64,
+	pub generation: u64,
+}
+
+// the range of keys held by an indexed leaf, and how far it can be trusted.
+// leaves written in different generations of a tree overlap, and each key is
+// taken from the best leaf which covers it, so that an item deleted in a later
+// generation doesn't reappear from an older leaf.
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct IndexedLeaf {
+	pub tree_id: u64,
+	pub generation: u64,
+	pub validity: IndexNodeValidity,
+	pub keys: Option <(IndexKey, IndexKey)>,
+}
+
+// a position between keys, just before the key, or just after it if the flag
+// is set. these sort in key order, so a leaf's keys run from before its first
+// key to after its last key.
+
+pub type IndexKeyBound = (IndexKey, bool);
 
+// the part of a leaf's key range where it is the best leaf, from the start
+// bound up to the end bound
+
+#[ derive (Clone, Copy, Debug, Eq, PartialEq) ]
+pub struct IndexedLeafRange {
+	pub start: IndexKeyBound,
+	pub end: IndexKeyBound,
+	pub leaf_index: usize,
+}
+
+// a name for an inode, from an inode ref or extref item. these are stored
+// next to the inode item, and lead back to the parent directory, so they
+// survive when the directory's own leaves don't.
+
+#[ derive (Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd) ]
+pub struct IndexedInodeRef <'a> {
+	pub object_id: u64,
+	pub parent_object_id: u64,
+	pub dir_index: u64,
+	pub name: & 'a [u8],
+}
+
+// one subvolume or snapshot of an indexed filesystem, stitched together from
+// whichever leaves survive, since its tree may no longer be reachable
+
+pub struct IndexedSubvolume <'a> {
+	filesystem: & 'a IndexedFilesystem <'a>,
+	tree_id: u64,
+	inode_items: HashMap <u64, BtrfsInodeItem <'a>>,
+	dir_item_entries: HashMap <u64, Vec <BtrfsDirItemEntry <'a>>>,
+	dir_indexes: HashMap <u64, Vec <BtrfsDirItemEntry <'a>>>,
+	extent_datas: HashMap <u64, Vec <BtrfsExtentData <'a>>>,
+	inode_refs: HashMap <u64, Vec <IndexedInodeRef <'a>>>,
+	inode_ref_children: HashMap <u64, Vec <IndexedInodeRef <'a>>>,
+	lost_inodes: Vec <u64>,
 }
 
 impl <'a> IndexedFilesystem <'a> {
@@ -55,34 +183,438 @@ impl <'a> IndexedFilesystem <'a> {
 		IndexPath: AsRef <Path>,
 	> (
 		output: & Output,
-		filesystem: & 'a BtrfsFilesystem <'a>,
+		device_set: & 'a DeviceSet,
 		index_path: IndexPath,
-	) -> Result <IndexedFilesystem <'a>, String> {
+		include_corrupt_nodes: bool,
+		max_generation: Option <u64>,
+	) -> Result <(IndexedFilesystem <'a>, IndexedNodes), String> {
 
 		Self::open_real (
 			output,
-			filesystem,
+			device_set,
 			index_path.as_ref (),
+			include_corrupt_nodes,
+			max_generation,
 		)
 
 	}
 
 	pub fn open_real (
 		output: & Output,
-		filesystem: & 'a BtrfsFilesystem <'a>,
+		device_set: & 'a DeviceSet,
+		index_path: & Path,
+		include_corrupt_nodes: bool,
+		max_generation: Option <u64>,
+	) -> Result <(IndexedFilesystem <'a>, IndexedNodes), String> {
+
+		let index =
+			Self::load_index (
+				output,
+				index_path,
+			) ?;
+
+		Self::open_index (
+			output,
+			device_set,
+			& index,
+			include_corrupt_nodes,
+			max_generation,
+			None,
+		)
+
+	}
+
+	pub fn load_index (
+		output: & Output,
 		index_path: & Path,
+	) -> Result <Index, String> {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Loading index from {}",
+				index_path.to_string_lossy ());
+
+		let index =
+			index_load (
+				index_path,
+			) ?;
+
+		output_job.complete ();
+
+		Ok (index)
+
+	}
+
+	// opens an index which has already been loaded, using every node in it,
+	// or only the nodes of one subvolume, as found from a root tree node
+	// chosen with find-root. the nodes are read but not yet added, since they
+	// must outlive the filesystem, which load_nodes then builds from them.
+
+	pub fn open_index (
+		output: & Output,
+		device_set: & 'a DeviceSet,
+		index: & Index,
+		include_corrupt_nodes: bool,
+		max_generation: Option <u64>,
+		root: Option <(u64, u64)>,
+	) -> Result <(IndexedFilesystem <'a>, IndexedNodes), String> {
+
+		let mut indexed_filesystem =
+			Self::new (
+				output,
+				device_set,
+				index,
+				include_corrupt_nodes,
+				max_generation,
+				root.map (
+					|(_, subvolume_id)| subvolume_id),
+			) ?;
+
+		// create indexes
+
+		let subvolume_nodes: Vec <IndexNode>;
+
+		let index_nodes: & [IndexNode] =
+			if let Some ((root_tree_address, subvolume_id)) = root {
+
+			let root_tree_root =
+				tree_root_from_node (
+					& indexed_filesystem.read_node (
+						root_tree_address,
+						None,
+					) ?);
+
+			if root_tree_root.tree_id != BTRFS_ROOT_TREE_OBJECT_ID {
+
+				return Err (
+					format! (
+						"Node at 0x{:x} belongs to tree {}, not the root tree",
+						root_tree_address,
+						root_tree_root.tree_id));
+
+			}
+
+			subvolume_nodes =
+				indexed_filesystem.subvolume_nodes (
+					output,
+					& root_tree_root,
+					subvolume_id,
+				) ?.0;
+
+			& subvolume_nodes
+
+		} else {
+
+			& index.nodes
+
+		};
+
+		let indexed_nodes =
+			indexed_filesystem.read_nodes (
+				output,
+				index_nodes);
+
+		Ok ((
+			indexed_filesystem,
+			indexed_nodes,
+		))
+
+	}
+
+	// opens one subvolume by walking the trees from the superblock, as the
+	// filesystem would normally be read, falling back to the backup roots if
+	// the current root tree can't be read. the nodes are returned to be
+	// loaded, as with open_index.
+
+	pub fn open_trees (
+		output: & Output,
+		device_set: & 'a DeviceSet,
+		index: Option <& Index>,
+		include_corrupt_nodes: bool,
+		subvolume_id: u64,
+	) -> Result <(IndexedFilesystem <'a>, IndexedNodes), String> {
+
+		let (mut indexed_filesystem, root_tree_roots) =
+			Self::open_superblock (
+				output,
+				device_set,
+				index,
+				include_corrupt_nodes,
+				Some (subvolume_id),
+			) ?;
+
+		let mut subvolume_nodes: Option <(Vec <IndexNode>, u64)> =
+			None;
+
+		for root_tree_root in root_tree_roots.iter () {
+
+			match indexed_filesystem.subvolume_nodes (
+				output,
+				root_tree_root,
+				subvolume_id) {
+
+				Ok (nodes) => {
+					subvolume_nodes = Some (nodes);
+					break;
+				},
+
+				Err (error) =>
+					output_message! (
+						output,
+						"Unable to use root tree at 0x{:x} generation {}: {}",
+						root_tree_root.logical_address,
+						root_tree_root.generation,
+						error),
+
+			}
+
+		}
+
+		let subvolume_nodes =
+			subvolume_nodes.ok_or_else (
+				||
+
+				format! (
+					"Subvolume {} not found from any root tree",
+					subvolume_id)
+
+			) ?;
+
+		let (subvolume_nodes, num_missing) =
+			subvolume_nodes;
+
+		let mut indexed_nodes =
+			indexed_filesystem.read_nodes (
+				output,
+				& subvolume_nodes);
+
+		indexed_nodes.num_missing =
+			num_missing
+				+ (subvolume_nodes.len () - indexed_nodes.nodes.len ()) as u64;
+
+		Ok ((
+			indexed_filesystem,
+			indexed_nodes,
+		))
+
+	}
+
+	// opens the filesystem described by the most recent superblock on the
+	// devices, with no nodes loaded yet, and returns the root trees it names,
+	// most recent first. if an index is given, the superblock must be from
+	// the same filesystem, since after a mkfs it will be from the new one.
+
+	pub fn open_superblock (
+		output: & Output,
+		device_set: & 'a DeviceSet,
+		index: Option <& Index>,
+		include_corrupt_nodes: bool,
+		subvolume_id: Option <u64>,
+	) -> Result <(IndexedFilesystem <'a>, Vec <TreeRoot>), String> {
+
+		let (superblock_index, superblock_bytes) =
+			Self::superblock_index (
+				output,
+				device_set,
+			) ?;
+
+		if let (Some (index_header), Some (superblock_header)) = (
+			index.and_then (|index| index.header.as_ref ()),
+			superblock_index.header.as_ref (),
+		) {
+
+			if index_header.fs_uuid != superblock_header.fs_uuid {
+
+				return Err (
+					format! (
+						"Superblock is from filesystem {}, but the index is \
+						from {}",
+						superblock_header.fs_uuid_string (),
+						index_header.fs_uuid_string ()));
+
+			}
+
+		}
+
+		let mut indexed_filesystem =
+			Self::new (
+				output,
+				device_set,
+				& superblock_index,
+				include_corrupt_nodes,
+				None,
+				subvolume_id,
+			) ?;
+
+		// the index may still hold chunk items which the chunk tree has lost
+
+		if let Some (index) = index {
+
+			if ! index.nodes.is_empty () {
+
+				indexed_filesystem.rebuild_chunk_map (
+					output,
+					& index.nodes);
+
+			}
+
+		}
+
+		Ok ((
+			indexed_filesystem,
+			tree_roots_from_superblock (
+				& superblock_bytes),
+		))
+
+	}
+
+	// describes the devices with a superblock from the same filesystem as the
+	// most recent one, as an index with no nodes, and returns that superblock
+
+	fn superblock_index (
+		output: & Output,
+		device_set: & DeviceSet,
+	) -> Result <(Index, Vec <u8>), String> {
+
+		let mut superblocks: Vec <(& DeviceReader, Vec <u8>)> =
+			Vec::new ();
+
+		for reader in device_set.readers () {
+
+			match superblock_find (
+				output,
+				reader) {
+
+				Some (superblock_bytes) =>
+					superblocks.push (
+						(reader, superblock_bytes)),
+
+				None =>
+					output_message! (
+						output,
+						"No valid superblock found on {}",
+						reader.path ().to_string_lossy ()),
+
+			}
+
+		}
+
+		let newest_bytes =
+			superblocks.iter ().map (
+				|& (_, ref superblock_bytes)| superblock_bytes,
+			).max_by_key (
+				|superblock_bytes|
+
+				superblock_at (
+					superblock_bytes,
+					0,
+				).unwrap ().generation ()
+
+			).cloned ().ok_or_else (
+				||
+
+				"No valid superblock found".to_owned ()
+
+			) ?;
+
+		let index = {
+
+			let newest =
+				superblock_at (
+					& newest_bytes,
+					0,
+				).unwrap ();
+
+			let mut fs_uuid: [u8; 16] = [0; 16];
+
+			fs_uuid.copy_from_slice (
+				& newest_bytes [0x20 .. 0x30]);
+
+			let devices: Vec <IndexDevice> =
+				superblocks.iter ().filter (
+					|& & (_, ref superblock_bytes)|
+
+					superblock_bytes [0x20 .. 0x30] == fs_uuid [..]
+
+				).map (
+					|& (reader, ref superblock_bytes)|
+
+					IndexDevice {
+						path: reader.path ().to_owned (),
+						device_id: superblock_at (
+							superblock_bytes,
+							0,
+						).unwrap ().device_id (),
+						size: reader.size (),
+						scanned_to: reader.size (),
+					}
+
+				).collect ();
+
+			Index {
+				header: Some (
+					IndexHeader {
+						version: INDEX_VERSION,
+						fs_uuid: fs_uuid,
+						node_size: newest.node_size (),
+						sector_size: newest.sector_size (),
+						csum_type: newest.csum_type (),
+						devices: devices,
+					}
+				),
+				nodes: Vec::new (),
+				bad_ranges: Vec::new (),
+			}
+
+		};
+
+		Ok ((
+			index,
+			newest_bytes,
+		))
+
+	}
+
+	// sets up the devices and chunk map for an index, with no nodes loaded
+
+	fn new (
+		output: & Output,
+		device_set: & 'a DeviceSet,
+		index: & Index,
+		include_corrupt_nodes: bool,
+		max_generation: Option <u64>,
+		subvolume_id: Option <u64>,
 	) -> Result <IndexedFilesystem <'a>, String> {
 
+		let (node_size, csum_type, device_indexes, default_device_id) =
+			Self::open_devices (
+				output,
+				device_set,
+				index,
+			) ?;
+
 		let mut indexed_filesystem =
 			IndexedFilesystem {
 
-			filesystem: filesystem,
+			device_set: device_set,
+			include_corrupt_nodes: include_corrupt_nodes,
+			max_generation: max_generation,
+			subvolume_id: subvolume_id,
+
+			node_size: node_size,
+			csum_type: csum_type,
+			device_indexes: device_indexes,
+			default_device_id: default_device_id,
+
+			chunk_map: ChunkMap::new (),
 
 			nodes: Vec::new (),
 			items: Vec::new (),
 
 			internal_nodes: Vec::new (),
 			leaf_nodes: Vec::new (),
+			leaves: Vec::new (),
 
 			internal_items_by_tree: HashMap::new (),
 
@@ -104,6 +636,8 @@ impl <'a> IndexedFilesystem <'a> {
 			inode_items_index: HashMap::new (),
 			inode_items_recent: HashMap::new (),
 
+			inode_refs: Vec::new (),
+
 			root_items: Vec::new (),
 			root_items_index: HashMap::new (),
 
@@ -127,68 +661,61 @@ impl <'a> IndexedFilesystem <'a> {
 
 		}
 
-		// load index
+		// find chunks
 
-		let output_job =
-			output_job_start! (
+		indexed_filesystem.chunk_map =
+			Self::read_chunk_map (
 				output,
-				"Loading index from {}",
-				index_path.to_string_lossy ());
+				device_set,
+				index);
 
-		let node_positions =
-			index_load (
-				index_path,
-			) ?;
+		if ! index.nodes.is_empty () {
 
-		output_job.complete ();
+			indexed_filesystem.rebuild_chunk_map (
+				output,
+				& index.nodes);
 
-/*
-		let btrfs_device =
-			filesystem.device (
-				devices.superblock ().device_id (),
-			).unwrap ();
+		}
 
-		// create indexes
+		Ok (indexed_filesystem)
 
-		let mut indexes =
-			FilesystemIndexes::new (
-				devices.superblock (),
-			);
+	}
 
-		indexes.add_nodes (
-			output,
-			& btrfs_device,
-			& node_positions);
+	// adds nodes which have been read, builds the indexes over their items,
+	// and reports what was found
 
-		indexes.build_inode_items_index (
-			output,
-			& btrfs_device);
+	pub fn load_nodes (
+		mut self,
+		output: & Output,
+		indexed_nodes: & 'a IndexedNodes,
+	) -> IndexedFilesystem <'a> {
 
-		indexes.build_dir_items_index (
+		self.add_nodes (
 			output,
-			& btrfs_device);
+			indexed_nodes);
+
+		self.build_inode_items_index (
+			output);
+
+		self.build_dir_items_index (
+			output);
 
 		// output statistics
 
 		output_message! (
 			output,
 			"Nodes: {}",
-			indexes.nodes.len ());
+			self.nodes.len ());
 
 		output_message! (
 			output,
 			"  Internal: {}",
-			indexes.internal_nodes.len ());
+			self.internal_nodes.len ());
 
 		output_message! (
 			output,
 			"  Leaf: {}",
-			indexes.leaf_nodes.len ());
-
-		output_message! (
-			output,
-			"Items: {}",
-			indexes.nodes.len ());
+			self.leaf_nodes.len ());
 
 		output_message! (
 			output,
@@ -197,638 +724,2656 @@ impl <'a> IndexedFilesystem <'a> {
 		output_message! (
 			output,
 			"  Nodes: {}",
-			indexes.root_tree_nodes.len ());
+			self.root_tree_nodes.len ());
 
 		output_message! (
 			output,
 			"  Items: {}",
-			indexes.root_items.len ());
+			self.root_items.len ());
 
-		// find root nodes
+		self
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Looking for root trees");
+	}
 
-		let mut root_node_addresses: HashSet <BtrfsPhysicalAddress> =
-			indexes.root_tree_nodes.iter ().map (
-				|node| node.physical_address ()
-			).collect ();
+	// finds the devices named in the index header, by path or else by their
+	// position on the command line, and checks they are the ones indexed.
+	// returns the node size, checksum type, the position of each device in
+	// the set by id, and the device id to use for text format indexes.
 
-		output_message! (
-			output,
-			"  All root nodes: {}",
-			root_node_addresses.len ());;
+	fn open_devices (
+		output: & Output,
+		device_set: & 'a DeviceSet,
+		index: & Index,
+	) -> Result <(u32, u16, HashMap <u64, usize>, u64), String> {
 
-		for root_internal_item
-		in indexes.root_tree_internal_items () {
+		let readers =
+			device_set.readers ();
 
-			if let Some (physical_address) =
-				chunk_tree.logical_to_physical_address (
-					root_internal_item.block_number ()) {
+		let mut device_indexes: HashMap <u64, usize> =
+			HashMap::new ();
 
-				root_node_addresses.remove (
-					& physical_address);
+		let index_header =
+			match index.header {
 
-			}
+			Some (ref index_header) =>
+				index_header,
 
-		}
+			None =>
+				return Self::open_devices_text (
+					output,
+					device_set),
+
+		};
 
 		output_message! (
 			output,
-			"  Unreferenced root nodes: {}",
-			root_node_addresses.len ());
+			"Index is for filesystem {}",
+			index_header.fs_uuid_string ());
 
-		let root_nodes =
-			root_node_addresses.iter ().map (
-				|physical_address|
+		for (device_index, index_device)
+		in index_header.devices.iter ().enumerate () {
 
-			devices.node_at_physical_address (
-				* physical_address,
-			).unwrap ()
+			let reader_index =
+				readers.iter ().position (
+					|reader|
 
-		);
+					reader.path () == index_device.path.as_path ()
 
-		let mut root_node_generations: HashSet <u64> =
-			HashSet::new ();
+				).or_else (
+					||
 
-		let mut duplicated_root_node_generations: HashSet <u64> =
-			HashSet::new ();
+					if device_index < readers.len () {
+						Some (device_index)
+					} else {
+						None
+					}
 
-		for root_node in root_nodes {
+				).ok_or_else (
+					||
 
-			if BtrfsTree::read_tree_physical_address (
-				devices,
-				& chunk_tree,
-				root_node.physical_address (),
-			).is_err () {
-				continue;
-			}
+					format! (
+						"Index device {} ({}) not found",
+						index_device.device_id,
+						index_device.path.to_string_lossy ())
 
-			if root_node_generations.contains (
-				& root_node.generation ()) {
+				) ?;
 
-				duplicated_root_node_generations.insert (
-					root_node.generation ());
+			let device_size =
+				device_set.device_size (
+					reader_index);
 
-			} else {
+			if device_size != index_device.size {
 
-				root_node_generations.insert (
-					root_node.generation ());
+				return Err (
+					format! (
+						"Index device {} ({}) has size 0x{:x} but opened \
+						device has size 0x{:x}",
+						index_device.device_id,
+						index_device.path.to_string_lossy (),
+						index_device.size,
+						device_size));
 
 			}
 
-		}
+			// a device with a readable superblock must be the one which was
+			// indexed. a damaged one can only be matched as above.
 
-		output_message! (
-			output,
-			"  Duplicated root node generations: {}",
-			duplicated_root_node_generations.len ());
+			if let Some (superblock_bytes) =
+				superblock_find (
+					output,
+					readers [reader_index]) {
+
+				let superblock =
+					superblock_at (
+						& superblock_bytes,
+						0,
+					).unwrap ();
+
+				if superblock.fs_uuid ().bytes () [..]
+					!= index_header.fs_uuid [..] {
+
+					return Err (
+						format! (
+							"Index device {} ({}) is from filesystem {} but \
+							opened device {} has a superblock from {}",
+							index_device.device_id,
+							index_device.path.to_string_lossy (),
+							index_header.fs_uuid_string (),
+							readers [reader_index].path ().to_string_lossy (),
+							Uuid::from_bytes (
+								& superblock.fs_uuid ().bytes (),
+							).unwrap ()));
+
+				}
+
+				if superblock.device_id () != index_device.device_id {
+
+					return Err (
+						format! (
+							"Index device {} ({}) was opened as {} which is \
+							device {} in its superblock",
+							index_device.device_id,
+							index_device.path.to_string_lossy (),
+							readers [reader_index].path ().to_string_lossy (),
+							superblock.device_id ()));
+
+				}
 
-		output_job.complete ();
+			}
 
-		for root_item
-		in indexes.root_items.iter ().filter (
-			|item|
+			device_indexes.insert (
+				index_device.device_id,
+				reader_index);
 
-			item.object_id () == BTRFS_ROOT_TREE_OBJECT_ID
+		}
 
-		) {
+		let default_device_id =
+			index_header.devices.first ().map_or (
+				0,
+				|index_device| index_device.device_id);
 
-			println! (
-				"Root item: {:?}",
-				root_item);
+		Ok ((
+			index_header.node_size,
+			index_header.csum_type,
+			device_indexes,
+			default_device_id,
+		))
 
-		}
+	}
+
+	// text format indexes have no header, so the node size, checksum type
+	// and device id have to come from the superblock
+
+	fn open_devices_text (
+		output: & Output,
+		device_set: & 'a DeviceSet,
+	) -> Result <(u32, u16, HashMap <u64, usize>, u64), String> {
 
-		// find root nodes
+		let readers =
+			device_set.readers ();
 
-		for root_node
-		in indexes.root_nodes.iter ().filter (
-			|node| node.level () == sup {
+		if readers.len () != 1 {
 
-			println! (
-				"Root node: {:?}",
-				root_node);
+			return Err (
+				"Text format indexes can only be used with single device \
+				filesystems".to_owned ());
 
 		}
 
-		// find recent extent tree
+		output_message! (
+			output,
+			"Index has no filesystem identity, unable to verify it matches \
+			the devices");
 
-		{
+		let superblock_bytes =
+			superblock_find (
+				output,
+				readers [0],
+			).ok_or_else (
+				||
 
-			let extent_tree_node =
-				indexes.internal_items_by_tree.get (
-					& 1,
-				).unwrap ().iter ().filter (
-					|item|
+				"Text format indexes need a valid superblock, to find the \
+				node size".to_owned ()
 
-					item.key ().object_id () == 2
-					&& item.key ().item_type () == 132
+			) ?;
 
-				).max_by_key (
-					|item|
+		let superblock =
+			superblock_at (
+				& superblock_bytes,
+				0,
+			).unwrap ();
 
-					item.generation ()
+		let mut device_indexes: HashMap <u64, usize> =
+			HashMap::new ();
 
-				).unwrap ();
+		device_indexes.insert (
+			superblock.device_id (),
+			0);
 
-			println! (
-				"Extent tree node: {:?}",
-				extent_tree_node);
+		Ok ((
+			superblock.node_size (),
+			superblock.csum_type (),
+			device_indexes,
+			superblock.device_id (),
+		))
 
-		}
+	}
 
-		// iterate root items
+	// reads the chunk tree the usual way, from the superblock of each device
+	// which belongs to the indexed filesystem
 
-		for root_item in indexes.root_items.iter () {
+	fn read_chunk_map (
+		output: & Output,
+		device_set: & DeviceSet,
+		index: & Index,
+	) -> ChunkMap {
 
-			println! (
-				"Root item: {:?}",
-				root_item);
+		let mut chunk_map =
+			ChunkMap::new ();
 
-		}
-*/
+		for reader in device_set.readers () {
 
-		// return
+			let superblock_bytes =
+				match superblock_find (
+					output,
+					reader) {
 
-		Ok (indexed_filesystem)
+				Some (superblock_bytes) => superblock_bytes,
+				None => continue,
 
-	}
+			};
 
-	pub fn add_nodes (
-		& mut self,
-		output: & Output,
-		btrfs_device: & 'a BtrfsDevice <'a>,
-		node_positions: & [usize],
-	) {
+			let superblock =
+				superblock_at (
+					& superblock_bytes,
+					0,
+				).unwrap ();
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Adding nodes from index");
+			if let Some (ref index_header) = index.header {
 
-		let node_position_total = node_positions.len () as u64;
-		let mut node_position_count: u64 = 0;
+				if superblock.fs_uuid ().bytes () [..]
+					!= index_header.fs_uuid [..] {
 
-		for node_position in node_positions.iter () {
+					continue;
 
-			output_job.progress (
-				node_position_count,
-				node_position_total);
+				}
 
-			let node_position = * node_position;
+			}
 
-			let node_bytes =
-				btrfs_device.slice_at (
-					node_position as usize,
-					self.filesystem.superblock ().node_size () as usize,
-				).unwrap ();
+			match chunks_read_tree (
+				reader,
+				superblock.device_id (),
+				superblock.node_size (),
+				superblock.csum_type (),
+				& superblock_bytes,
+			) {
 
-			let node_result =
-				BtrfsNode::from_bytes (
-					BtrfsPhysicalAddress::new (
-						btrfs_device.device_id (),
-						node_position as u64),
-					node_bytes);
+				Ok (device_chunk_map) => {
 
-			if node_result.is_err () {
+					for chunk in device_chunk_map.chunks () {
 
-				output_message! (
-					output,
-					"Error reading node at 0x{:x}: {}",
-					node_position,
-					node_result.err ().unwrap ());
+						chunk_map.insert (
+							chunk.clone ());
+
+					}
+
+				},
+
+				Err (error) =>
+					output_message! (
+						output,
+						"Unable to read chunk tree from {}: {}",
+						reader.path ().to_string_lossy (),
+						error),
+
+			}
+
+		}
+
+		chunk_map
+
+	}
+
+	// fills in any chunks which couldn't be read from the chunk tree, using
+	// the chunk and device extent items in the indexed leaves. leaves with
+	// bad checksums are included, ranked below good ones, since a damaged
+	// chunk tree is the reason for doing this.
+
+	fn rebuild_chunk_map (
+		& mut self,
+		output: & Output,
+		index_nodes: & [IndexNode],
+	) {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Rebuilding chunk map from indexed chunk items");
+
+		let mut chunk_rebuild =
+			ChunkRebuild::new ();
+
+		for index_node in index_nodes.iter () {
 
+			if ! ChunkRebuild::wants_node (index_node) {
 				continue;
+			}
+
+			if let Ok ((_, node_bytes)) =
+				self.index_node_bytes (
+					index_node) {
+
+				chunk_rebuild.add_leaf (
+					& node_bytes,
+					index_node.generation,
+					index_node.validity != IndexNodeValidity::BadChecksum);
 
 			}
 
-			let node =
-				node_result.unwrap ();
+		}
 
-			self.nodes.push (
-				node.clone ());
+		let chunk_rebuild_result =
+			chunk_rebuild.build ();
 
-			output_debug! (
+		output_job.complete ();
+
+		output_message! (
+			output,
+			"Found {} chunk items and {} device extent items",
+			chunk_rebuild_result.num_chunk_items,
+			chunk_rebuild_result.num_dev_extent_items);
+
+		output_message! (
+			output,
+			"Rebuilt {} chunks, {} confirmed by device extents, {} \
+			unconfirmed, {} contradicted, {} stale copies discarded",
+			chunk_rebuild_result.chunk_map.len (),
+			chunk_rebuild_result.num_confirmed,
+			chunk_rebuild_result.num_unconfirmed,
+			chunk_rebuild_result.num_contradicted,
+			chunk_rebuild_result.num_discarded);
+
+		// chunks read from the chunk tree are current, so rebuilt ones only
+		// fill the gaps
+
+		let mut num_added: u64 = 0;
+
+		for chunk in chunk_rebuild_result.chunk_map.chunks () {
+
+			if self.chunk_map.overlaps (
+				chunk.logical_address,
+				chunk.length) {
+
+				continue;
+
+			}
+
+			self.chunk_map.insert (
+				chunk.clone ());
+
+			num_added += 1;
+
+		}
+
+		if num_added > 0 {
+
+			output_message! (
 				output,
-				"Node: {:?}",
-				node);
+				"Added {} rebuilt chunks missing from the chunk tree",
+				num_added);
 
-			if node.tree_id ().is_root () {
+		}
 
-				self.root_tree_nodes.push (
-					node.clone ());
+		if self.chunk_map.len () == 0 {
+
+			output_message! (
+				output,
+				"No chunks found, file data can't be located");
+
+		}
+
+	}
+
+	// walks the root tree to find a subvolume, then walks the subvolume's
+	// tree, and returns the nodes which it reached, as the copies read, and
+	// how many it couldn't
+
+	fn subvolume_nodes (
+		& self,
+		output: & Output,
+		root_tree_root: & TreeRoot,
+		subvolume_id: u64,
+	) -> Result <(Vec <IndexNode>, u64), String> {
+
+		let mut root_items: Vec <TreeRoot> =
+			Vec::new ();
+
+		let (root_nodes_reached, _) =
+			tree_walk (
+				root_tree_root,
+				|logical_address, generation|
+
+				self.read_node (
+					logical_address,
+					Some (generation)),
+
+				|leaf_bytes| root_items.extend (
+					tree_root_items (leaf_bytes)),
+			);
+
+		if root_nodes_reached.is_empty () {
+
+			return Err (
+				format! (
+					"Unable to read root tree node at 0x{:x}",
+					root_tree_root.logical_address));
+
+		}
+
+		let subvolume_root =
+			root_items.into_iter ().filter (
+				|tree_root| tree_root.tree_id == subvolume_id,
+			).max_by_key (
+				|tree_root| tree_root.generation,
+			).ok_or_else (
+				||
+
+				format! (
+					"Subvolume {} not found in root tree at 0x{:x}",
+					subvolume_id,
+					root_tree_root.logical_address)
+
+			) ?;
+
+		let (nodes_reached, mut num_missing) =
+			tree_walk (
+				& subvolume_root,
+				|logical_address, generation|
+
+				self.read_node (
+					logical_address,
+					Some (generation)),
+
+				|_| (),
+			);
+
+		output_message! (
+			output,
+			"Using subvolume {} from root tree at 0x{:x} generation {}, tree \
+			at 0x{:x} generation {}, {} nodes reached, {} missing",
+			subvolume_id,
+			root_tree_root.logical_address,
+			root_tree_root.generation,
+			subvolume_root.logical_address,
+			subvolume_root.generation,
+			nodes_reached.len (),
+			num_missing);
+
+		let mut index_nodes: Vec <IndexNode> =
+			Vec::new ();
+
+		for & (logical_address, generation) in nodes_reached.iter () {
+
+			let (device_id, position, node_bytes) =
+				match self.read_node_copy (
+					logical_address,
+					Some (generation)) {
+
+					Ok (node_copy) => node_copy,
+
+					Err (_) => {
+						num_missing += 1;
+						continue;
+					},
+
+				};
+
+			index_nodes.push (
+				IndexNode {
+					device_id: device_id,
+					position: position,
+					tree_id: le_u64 (& node_bytes, 0x58),
+					generation: generation,
+					level: node_bytes [0x64],
+					logical_address: logical_address,
+					validity: match checksum_verify (
+						self.csum_type,
+						& node_bytes) {
+						Some (true) => IndexNodeValidity::Valid,
+						Some (false) => IndexNodeValidity::BadChecksum,
+						None => IndexNodeValidity::Unchecked,
+					},
+					keys: None,
+				});
+
+		}
+
+		index_nodes.sort_by_key (
+			|index_node| (index_node.device_id, index_node.position));
+
+		Ok ((
+			index_nodes,
+			num_missing,
+		))
+
+	}
+
+	// reads a node from wherever the chunk map puts it. the generation is
+	// the one its parent expects, if known.
+
+	pub fn read_node (
+		& self,
+		logical_address: u64,
+		generation: Option <u64>,
+	) -> Result <Vec <u8>, String> {
+
+		self.read_node_copy (
+			logical_address,
+			generation,
+		).map (
+			|(_, _, node_bytes)| node_bytes,
+		)
+
+	}
 
+	// reads a node, returning the device id and position of the copy used.
+	// each copy is tried in turn, since one may be stale or damaged, and the
+	// first whose header matches and whose checksum verifies is used. a copy
+	// with a bad checksum is only used if there is no good one, and corrupt
+	// nodes were asked for.
+
+	fn read_node_copy (
+		& self,
+		logical_address: u64,
+		generation: Option <u64>,
+	) -> Result <(u64, u64, Vec <u8>), String> {
+
+		let node_size =
+			self.node_size as usize;
+
+		let mut corrupt_copy: Option <(u64, u64, Vec <u8>)> =
+			None;
+
+		for (device_id, physical_address)
+		in self.logical_copies (
+			logical_address,
+			node_size,
+		) ? {
+
+			let device_index =
+				match self.device_indexes.get (& device_id) {
+					Some (& device_index) => device_index,
+					None => continue,
+				};
+
+			let node_bytes =
+				match self.device_set.read (
+					device_index,
+					physical_address,
+					node_size) {
+
+				Ok (node_bytes) => node_bytes,
+				Err (_) => continue,
+
+			};
+
+			if le_u64 (& node_bytes, 0x30) != logical_address {
+				continue;
 			}
 
-			match node {
+			if let Some (generation) = generation {
 
-				BtrfsNode::Leaf (leaf_node) =>
-					self.store_leaf_node (
-						leaf_node),
+				if le_u64 (& node_bytes, 0x50) != generation {
+					continue;
+				}
 
-				BtrfsNode::Internal (internal_node) =>
-					self.store_internal_node (
-						internal_node),
+			}
+
+			if checksum_verify (
+				self.csum_type,
+				& node_bytes,
+			) != Some (false) {
+
+				return Ok ((
+					device_id,
+					physical_address,
+					node_bytes,
+				));
+
+			}
+
+			if corrupt_copy.is_none () {
+
+				corrupt_copy =
+					Some ((
+						device_id,
+						physical_address,
+						node_bytes,
+					));
+
+			}
+
+		}
+
+		match corrupt_copy {
+
+			Some (corrupt_copy) if self.include_corrupt_nodes =>
+				Ok (corrupt_copy),
+
+			Some (_) =>
+				Err (
+					format! (
+						"Every copy of node at 0x{:x} has a bad checksum",
+						logical_address)),
 
+			None =>
+				Err (
+					format! (
+						"No readable copy of node at 0x{:x}",
+						logical_address)),
+
+		}
+
+	}
+
+	// finds the bytes of an indexed node, returning the device id it was
+	// read from
+
+	pub fn index_node_bytes (
+		& self,
+		index_node: & IndexNode,
+	) -> Result <(u64, Vec <u8>), String> {
+
+		// text format indexes have no device ids, and are only accepted for
+		// single device filesystems
+
+		let device_id =
+			if index_node.device_id != 0 {
+				index_node.device_id
+			} else {
+				self.default_device_id
 			};
 
+		let device_index =
+			* self.device_indexes.get (
+				& device_id,
+			).ok_or_else (
+				||
+
+				format! (
+					"Device {} not found for node at 0x{:x}",
+					device_id,
+					index_node.position)
+
+			) ?;
+
+		let node_bytes =
+			self.device_set.read (
+				device_index,
+				index_node.position,
+				self.node_size as usize,
+			).map_err (
+				|error|
+
+				format! (
+					"Unable to read node at {}/0x{:x}: {}",
+					device_id,
+					index_node.position,
+					error)
+
+			) ?;
+
+		Ok ((
+			device_id,
+			node_bytes,
+		))
+
+	}
+
+	// reads file data at a logical address, from any device which holds a
+	// copy, returning the index of that device in the set and the position
+	// on it
+
+	pub fn read_logical (
+		& self,
+		logical_address: u64,
+		size: usize,
+	) -> Result <(usize, u64, Vec <u8>), String> {
+
+		for (device_id, physical_address)
+		in self.logical_copies (
+			logical_address,
+			size,
+		) ? {
+
+			let device_index =
+				match self.device_indexes.get (& device_id) {
+					Some (& device_index) => device_index,
+					None => continue,
+				};
+
+			if physical_address.checked_add (size as u64).map_or (
+				true,
+				|end| end > self.device_set.device_size (device_index),
+			) {
+				continue;
+			}
+
+			let data =
+				self.device_set.read (
+					device_index,
+					physical_address,
+					size,
+				) ?;
+
+			return Ok ((
+				device_index,
+				physical_address,
+				data,
+			));
+
+		}
+
+		Err (
+			format! (
+				"No device holds logical address 0x{:x}",
+				logical_address))
+
+	}
+
+	// returns the device id and position of every copy of a range of
+	// logical addresses
+
+	fn logical_copies (
+		& self,
+		logical_address: u64,
+		size: usize,
+	) -> Result <Vec <(u64, u64)>, String> {
+
+		let chunk =
+			self.chunk_map.chunk (
+				logical_address,
+			).ok_or_else (
+				||
+
+				format! (
+					"No chunk for logical address 0x{:x}",
+					logical_address)
+
+			) ?;
+
+		// striped profiles only keep a stripe length together
+
+		if (
+			chunk.device_length () != chunk.length
+			&& size as u64 > chunk.stripe_length
+				- (logical_address - chunk.logical_address)
+					% chunk.stripe_length
+		) {
+
+			return Err (
+				format! (
+					"Extent at 0x{:x} crosses a stripe boundary",
+					logical_address));
+
+		}
+
+		Ok (
+			chunk.map (
+				logical_address))
+
+	}
+
+	// reads the bytes of the nodes to be added, skipping any which are
+	// corrupt, unless they were asked for, or newer than the maximum
+	// generation
+
+	pub fn read_nodes (
+		& self,
+		output: & Output,
+		index_nodes: & [IndexNode],
+	) -> IndexedNodes {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Reading nodes from index");
+
+		let node_position_total = index_nodes.len () as u64;
+		let mut node_position_count: u64 = 0;
+
+		let mut num_corrupt_skipped: u64 = 0;
+		let mut num_newer_skipped: u64 = 0;
+
+		let mut indexed_nodes: Vec <(IndexNode, u64, Vec <u8>)> =
+			Vec::new ();
+
+		for index_node in index_nodes.iter () {
+
+			output_job.progress (
+				node_position_count,
+				node_position_total);
+
 			node_position_count += 1;
 
+			if index_node.validity.is_corrupt ()
+				&& ! self.include_corrupt_nodes {
+
+				num_corrupt_skipped += 1;
+
+				continue;
+
+			}
+
+			let (device_id, node_bytes) =
+				match self.index_node_bytes (
+					index_node) {
+
+				Ok (device_and_bytes) =>
+					device_and_bytes,
+
+				Err (error) => {
+
+					output_message! (
+						output,
+						"{}",
+						error);
+
+					continue;
+
+				},
+
+			};
+
+			// read the generation from the node itself, since text format
+			// indexes don't record it
+
+			let generation =
+				le_u64 (& node_bytes, 0x50);
+
+			if self.max_generation.map_or (
+				false,
+				|max_generation| generation > max_generation) {
+
+				num_newer_skipped += 1;
+
+				continue;
+
+			}
+
+			indexed_nodes.push (
+				(* index_node, device_id, node_bytes));
+
+		}
+
+		output_job.complete ();
+
+		if num_corrupt_skipped > 0 {
+
+			output_message! (
+				output,
+				"Skipped {} corrupt nodes",
+				num_corrupt_skipped);
+
+		}
+
+		if let Some (max_generation) = self.max_generation {
+
+			output_message! (
+				output,
+				"Skipped {} nodes newer than generation {}",
+				num_newer_skipped,
+				max_generation);
+
 		}
 
-		output_job.complete ();
+		IndexedNodes {
+			nodes: indexed_nodes,
+			num_missing: 0,
+		}
+
+	}
+
+	pub fn add_nodes (
+		& mut self,
+		output: & Output,
+		indexed_nodes: & 'a IndexedNodes,
+	) {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Adding nodes from index");
+
+		let node_position_total = indexed_nodes.nodes.len () as u64;
+		let mut node_position_count: u64 = 0;
+
+		for & (ref index_node, device_id, ref node_bytes)
+		in indexed_nodes.nodes.iter () {
+
+			output_job.progress (
+				node_position_count,
+				node_position_total);
+
+			node_position_count += 1;
+
+			let node_position = index_node.position;
+
+			// read the owner and generation from the node itself, since text
+			// format indexes don't record them. when opened at a root, every
+			// node came from one subvolume's tree, including those shared with
+			// a snapshot, which name the other subvolume as their owner.
+
+			let tree_id =
+				self.subvolume_id.unwrap_or (
+					le_u64 (node_bytes, 0x58));
+
+			let generation =
+				le_u64 (node_bytes, 0x50);
+
+			let node_result =
+				BtrfsNode::from_bytes (
+					BtrfsPhysicalAddress::new (
+						device_id,
+						node_position),
+					node_bytes);
+
+			if node_result.is_err () {
+
+				output_message! (
+					output,
+					"Error reading node at {}/0x{:x}: {}",
+					device_id,
+					node_position,
+					node_result.err ().unwrap ());
+
+				continue;
+
+			}
+
+			let node =
+				node_result.unwrap ();
+
+			self.nodes.push (
+				node.clone ());
+
+			output_debug! (
+				output,
+				"Node: {:?}",
+				node);
+
+			if node.tree_id ().is_root () {
+
+				self.root_tree_nodes.push (
+					* index_node);
+
+			}
+
+			match node {
+
+				BtrfsNode::Leaf (leaf_node) =>
+					self.store_leaf_node (
+						leaf_node,
+						node_bytes,
+						tree_id,
+						generation,
+						index_node.validity),
+
+				BtrfsNode::Internal (internal_node) =>
+					self.store_internal_node (
+						internal_node),
+
+			};
+
+		}
+
+		output_job.complete ();
+
+		// sort leaves
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Sorting nodes");
+
+		Self::sort_leaves (
+			& mut self.chunk_items_index);
+
+		Self::sort_leaves (
+			& mut self.dir_item_entries_index);
+
+		Self::sort_leaves (
+			& mut self.inode_items_index);
+
+		Self::sort_leaves (
+			& mut self.extent_datas_index);
+
+		output_job.complete ();
+
+		// output and return
+
+		output_message! (
+			output,
+			"Found {} dir entries, {} inodes, {} extents",
+			self.dir_item_entries.len (),
+			self.inode_items.len (),
+			self.extent_datas.len ());
+
+	}
+
+	fn store_leaf_node (
+		& mut self,
+		leaf_node: BtrfsLeafNode <'a>,
+		node_bytes: & 'a [u8],
+		tree_id: u64,
+		generation: u64,
+		validity: IndexNodeValidity,
+	) {
+
+		// keys are read from the node, since the parsed items don't all
+		// carry one
+
+		let leaf_index =
+			self.leaves.len ();
+
+		let num_items =
+			cmp::min (
+				le_u32 (node_bytes, 0x60) as usize,
+				(node_bytes.len () - BTRFS_NODE_HEADER_SIZE)
+					/ BTRFS_LEAF_ITEM_SIZE);
+
+		let item_key =
+			|item_index: usize|
+
+			IndexKey::from_bytes (
+				node_bytes,
+				BTRFS_NODE_HEADER_SIZE + item_index * BTRFS_LEAF_ITEM_SIZE);
+
+		self.leaves.push (
+			IndexedLeaf {
+				tree_id: tree_id,
+				generation: generation,
+				validity: validity,
+				keys: if num_items > 0 {
+					Some ((item_key (0), item_key (num_items - 1)))
+				} else {
+					None
+				},
+			});
+
+		self.leaf_nodes.push (
+			leaf_node);
+
+		for (item_index, item)
+		in leaf_node.items ().into_iter ().enumerate () {
+
+			if item_index >= num_items {
+				break;
+			}
+
+			let key =
+				item_key (
+					item_index);
+
+			match item {
+
+				BtrfsLeafItem::ChunkItem (chunk_item) =>
+					self.store_chunk_item (
+						chunk_item),
+
+				BtrfsLeafItem::DirItem (dir_item) =>
+					self.store_dir_item (
+						dir_item,
+						leaf_index,
+						key),
+
+				BtrfsLeafItem::ExtentData (extent_data) =>
+					self.store_extent_data (
+						extent_data,
+						leaf_index,
+						key),
+
+				BtrfsLeafItem::ExtentItem (extent_item) =>
+					self.store_extent_item (
+						extent_item),
+
+				BtrfsLeafItem::InodeItem (inode_item) =>
+					self.store_inode_item (
+						inode_item,
+						leaf_index,
+						key),
+
+				BtrfsLeafItem::RootItem (root_item) =>
+					self.store_root_item (
+						root_item),
+
+				_ => (),
+
+			}
+
+		}
+
+		// inode refs aren't parsed by the btrfs crate, so are read from the
+		// node directly
+
+		for (key, data) in chunks_leaf_items (node_bytes) {
+
+			if key.item_type != INDEXED_INODE_REF_TYPE
+				&& key.item_type != INDEXED_INODE_EXTREF_TYPE {
+
+				continue;
+
+			}
+
+			for inode_ref in indexed_inode_refs (key, data) {
+
+				let indexed_inode_ref =
+					self.indexed_item (
+						inode_ref,
+						leaf_index,
+						key);
+
+				self.inode_refs.push (
+					indexed_inode_ref);
+
+			}
+
+		}
+
+	}
+
+	fn store_internal_node (
+		& mut self,
+		internal_node: BtrfsInternalNode <'a>,
+	) {
+
+		self.internal_nodes.push (
+			internal_node);
+
+		let tree_items =
+			self.internal_items_by_tree.entry (
+				internal_node.header ().tree_id (),
+			).or_insert (
+				Vec::new (),
+			);
+
+		for item in internal_node.items () {
+
+			tree_items.push (
+				item);
+
+		}
+
+	}
+
+	fn store_chunk_item (
+		& mut self,
+		chunk_item: BtrfsChunkItem <'a>,
+	) {
+
+		self.chunk_items.push (
+			chunk_item);
+
+		self.chunk_items_index.entry (
+			chunk_item.key ().object_id (),
+		).or_insert (
+			Vec::new (),
+		).push (
+			chunk_item
+		);
+
+	}
+
+	fn store_dir_item (
+		& mut self,
+		dir_item: BtrfsDirItem <'a>,
+		leaf_index: usize,
+		key: IndexKey,
+	) {
+
+		for dir_item_entry in dir_item.entries () {
+
+			self.store_dir_item_entry (
+				dir_item_entry,
+				leaf_index,
+				key);
+
+		}
+
+	}
+
+	fn store_dir_item_entry (
+		& mut self,
+		dir_item_entry: BtrfsDirItemEntry <'a>,
+		leaf_index: usize,
+		key: IndexKey,
+	) {
+
+		let indexed_dir_item_entry =
+			self.indexed_item (
+				dir_item_entry,
+				leaf_index,
+				key);
+
+		self.dir_item_entries.push (
+			indexed_dir_item_entry);
+
+		self.dir_item_entries_index.entry (
+			(indexed_dir_item_entry.tree_id, dir_item_entry.key ().object_id ()),
+		).or_insert (
+			Vec::new (),
+		).push (
+			indexed_dir_item_entry
+		);
+
+	}
+
+	fn store_extent_data (
+		& mut self,
+		extent_data: BtrfsExtentData <'a>,
+		leaf_index: usize,
+		key: IndexKey,
+	) {
+
+		let indexed_extent_data =
+			self.indexed_item (
+				extent_data,
+				leaf_index,
+				key);
+
+		self.extent_datas.push (
+			indexed_extent_data);
+
+		self.extent_datas_index.entry (
+			(indexed_extent_data.tree_id, extent_data.object_id ()),
+		).or_insert (
+			Vec::new (),
+		).push (
+			indexed_extent_data
+		);
+
+	}
+
+	fn store_extent_item (
+		& mut self,
+		extent_item: BtrfsExtentItem <'a>,
+	) {
+
+		self.extent_items.push (
+			extent_item);
+
+		self.extent_items_index.entry (
+			extent_item.object_id (),
+		).or_insert (
+			Vec::new (),
+		).push (
+			extent_item
+		);
+
+	}
+
+	fn store_inode_item (
+		& mut self,
+		inode_item: BtrfsInodeItem <'a>,
+		leaf_index: usize,
+		key: IndexKey,
+	) {
+
+		let indexed_inode_item =
+			self.indexed_item (
+				inode_item,
+				leaf_index,
+				key);
+
+		self.inode_items.push (
+			indexed_inode_item);
+
+		self.inode_items_index.entry (
+			(indexed_inode_item.tree_id, inode_item.object_id ()),
+		).or_insert (
+			Vec::new (),
+		).push (
+			indexed_inode_item
+		)
+
+	}
+
+	fn indexed_item <Item> (
+		& self,
+		item: Item,
+		leaf_index: usize,
+		key: IndexKey,
+	) -> IndexedItem <Item> {
+
+		let leaf =
+			& self.leaves [leaf_index];
+
+		IndexedItem {
+			item: item,
+			key: key,
+			leaf_index: leaf_index,
+			tree_id: leaf.tree_id,
+			generation: leaf.generation,
+		}
+
+	}
+
+	fn store_root_item (
+		& mut self,
+		root_item: BtrfsRootItem <'a>,
+	) {
+
+		self.root_items.push (
+			root_item);
+
+		self.root_items_index.entry (
+			root_item.object_id (),
+		).or_insert (
+			Vec::new (),
+		).push (
+			root_item
+		)
+
+	}
+
+	pub fn build_inode_items_index (
+		& mut self,
+		output: & Output,
+	) {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Selecting most recent inode items");
+
+		let inode_items_recent =
+			Self::select_recent (
+				self.inode_items.iter (),
+				|inode_item| (
+					inode_item.tree_id,
+					inode_item.item.object_id (),
+				));
+
+		self.inode_items_recent =
+			inode_items_recent;
+
+		output_job.complete ();
+
+	}
+
+	pub fn build_dir_items_index (
+		& mut self,
+		output: & Output,
+	) {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Selecting most recent directory item entries");
+
+		let dir_item_entries_recent =
+			Self::select_recent (
+				self.dir_item_entries.iter (),
+				|dir_item_entry| (
+					dir_item_entry.tree_id,
+					dir_item_entry.item.child_object_id (),
+				));
+
+		self.dir_item_entries_recent =
+			dir_item_entries_recent;
+
+		output_job.complete ();
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Grouping directory item entries by parent");
+
+		for (& (tree_id, _), dir_item_entry)
+		in self.dir_item_entries_recent.iter () {
+
+			self.dir_item_entries_by_parent.entry (
+				(tree_id, dir_item_entry.object_id ()),
+			).or_insert (
+				Vec::new (),
+			).push (
+				dir_item_entry.child_object_id (),
+			);
+
+		}
+
+		output_job.complete ();
+
+	}
+
+	pub fn root_tree_internal_items (
+		& 'a self,
+	) -> & [& 'a BtrfsInternalItem] {
+
+		self.internal_items_by_tree.get (
+			& BTRFS_ROOT_TREE_ID,
+		).unwrap ()
+
+	}
+
+	// property accessors
+
+	pub fn dir_item_entries_recent (
+		& 'a self,
+	) -> & HashMap <(u64, u64), BtrfsDirItemEntry <'a>> {
+		& self.dir_item_entries_recent
+	}
+
+	pub fn dir_item_entries_by_parent (
+		& 'a self,
+	) -> & HashMap <(u64, u64), Vec <u64>> {
+		& self.dir_item_entries_by_parent
+	}
+
+	pub fn extent_datas_index (
+		& 'a self,
+	) -> & HashMap <(u64, u64), Vec <IndexedItem <BtrfsExtentData>>> {
+		& self.extent_datas_index
+	}
+
+	// the most recent copy of each of a file's extents, by file position
+
+	pub fn extent_datas_recent (
+		& self,
+		tree_id: u64,
+		object_id: u64,
+	) -> Vec <BtrfsExtentData <'a>> {
+
+		let extent_datas_recent =
+			Self::select_recent (
+				self.extent_datas_index.get (
+					& (tree_id, object_id),
+				).into_iter ().flat_map (
+					|extent_datas| extent_datas.iter ()),
+				|extent_data| extent_data.item.offset ());
+
+		let mut extent_datas: Vec <BtrfsExtentData <'a>> =
+			extent_datas_recent.into_iter ().map (
+				|(_, extent_data)| extent_data,
+			).collect ();
+
+		extent_datas.sort_by_key (
+			|extent_data| extent_data.offset ());
+
+		extent_datas
+
+	}
+
+	pub fn extent_items_index (
+		& 'a self,
+	) -> & HashMap <u64, Vec <BtrfsExtentItem>> {
+		& self.extent_items_index
+	}
+
+	pub fn inode_items_recent (
+		& 'a self,
+	) -> & HashMap <(u64, u64), BtrfsInodeItem <'a>> {
+		& self.inode_items_recent
+	}
+
+	// subvolumes
+
+	pub fn subvolume (
+		& 'a self,
+		output: & Output,
+		tree_id: u64,
+	) -> IndexedSubvolume <'a> {
+
+		let output_job =
+			output_job_start! (
+				output,
+				"Building tree {} from indexed leaves",
+				tree_id);
+
+		let inode_items =
+			self.select_best_leaf (
+				tree_id,
+				self.inode_items.iter ());
+
+		let dir_item_entries =
+			self.select_best_leaf (
+				tree_id,
+				self.dir_item_entries.iter ());
+
+		let extent_datas =
+			self.select_best_leaf (
+				tree_id,
+				self.extent_datas.iter ());
+
+		let inode_refs =
+			self.select_best_leaf (
+				tree_id,
+				self.inode_refs.iter ());
+
+		let mut leaves_used: HashSet <usize> =
+			HashSet::new ();
+
+		leaves_used.extend (
+			inode_items.iter ().map (
+				|inode_item| inode_item.leaf_index));
+
+		leaves_used.extend (
+			dir_item_entries.iter ().map (
+				|dir_item_entry| dir_item_entry.leaf_index));
+
+		leaves_used.extend (
+			extent_datas.iter ().map (
+				|extent_data| extent_data.leaf_index));
+
+		leaves_used.extend (
+			inode_refs.iter ().map (
+				|inode_ref| inode_ref.leaf_index));
+
+		let mut indexed_subvolume =
+			IndexedSubvolume {
+				filesystem: self,
+				tree_id: tree_id,
+				inode_items: HashMap::new (),
+				dir_item_entries: HashMap::new (),
+				dir_indexes: HashMap::new (),
+				extent_datas: HashMap::new (),
+				inode_refs: HashMap::new (),
+				inode_ref_children: HashMap::new (),
+				lost_inodes: Vec::new (),
+			};
+
+		for inode_item in inode_items.iter () {
+
+			indexed_subvolume.inode_items.insert (
+				inode_item.item.object_id (),
+				inode_item.item);
+
+		}
+
+		// each name is in both a directory's item and its index, and may have
+		// been reused, so the most recent entry for each name is used. an
+		// inode keeps every name, since it may be hard linked.
+
+		indexed_subvolume.dir_item_entries =
+			Self::select_recent_links (
+				dir_item_entries.iter (),
+				|dir_item_entry| (
+					dir_item_entry.object_id (),
+					dir_item_entry.name (),
+					dir_item_entry.child_object_id (),
+				));
+
+		for dir_item_entry
+		in indexed_subvolume.dir_item_entries.values ().flat_map (
+			|dir_item_entries| dir_item_entries.iter ()) {
+
+			indexed_subvolume.dir_indexes.entry (
+				dir_item_entry.object_id (),
+			).or_insert (
+				Vec::new (),
+			).push (
+				* dir_item_entry,
+			);
+
+		}
+
+		for extent_data in extent_datas.iter () {
+
+			indexed_subvolume.extent_datas.entry (
+				extent_data.item.object_id (),
+			).or_insert (
+				Vec::new (),
+			).push (
+				extent_data.item,
+			);
+
+		}
+
+		for file_extent_datas in indexed_subvolume.extent_datas.values_mut () {
+
+			file_extent_datas.sort_by_key (
+				|extent_data| extent_data.offset ());
+
+		}
+
+		for inode_ref in inode_refs.iter () {
+
+			indexed_subvolume.inode_refs.entry (
+				inode_ref.item.object_id,
+			).or_insert (
+				Vec::new (),
+			).push (
+				inode_ref.item,
+			);
+
+		}
+
+		for object_inode_refs in indexed_subvolume.inode_refs.values_mut () {
+			object_inode_refs.sort ();
+		}
+
+		// inodes which no surviving directory entry names are placed by their
+		// own refs instead. the root directory's ref names itself as parent.
+
+		for (object_id, object_inode_refs)
+		in indexed_subvolume.inode_refs.iter () {
+
+			let inode_ref =
+				object_inode_refs [0];
+
+			if (
+				indexed_subvolume.dir_item_entries.contains_key (object_id)
+				|| ! indexed_subvolume.inode_items.contains_key (object_id)
+				|| inode_ref.parent_object_id == * object_id
+			) {
+				continue;
+			}
+
+			indexed_subvolume.inode_ref_children.entry (
+				inode_ref.parent_object_id,
+			).or_insert (
+				Vec::new (),
+			).push (
+				inode_ref,
+			);
+
+		}
+
+		let lost_inodes =
+			indexed_subvolume.find_lost_inodes ();
+
+		indexed_subvolume.lost_inodes =
+			lost_inodes;
+
+		output_job.complete ();
+
+		output_message! (
+			output,
+			"Tree {}: used {} of {} leaves, found {} inodes, {} directory \
+			entries, {} extents",
+			tree_id,
+			leaves_used.len (),
+			self.leaves.iter ().filter (
+				|leaf| leaf.tree_id == tree_id && leaf.keys.is_some (),
+			).count (),
+			indexed_subvolume.inode_items.len (),
+			indexed_subvolume.dir_item_entries.values ().map (
+				|dir_item_entries| dir_item_entries.len (),
+			).sum::<usize> (),
+			extent_datas.len ());
+
+		output_message! (
+			output,
+			"Placed {} inodes using inode refs, {} inodes can't be reached \
+			from the root directory",
+			indexed_subvolume.inode_ref_children.values ().map (
+				|inode_refs| inode_refs.len (),
+			).sum::<usize> (),
+			indexed_subvolume.lost_inodes.len ());
+
+		indexed_subvolume
+
+	}
+
+	// the subvolumes and snapshots which have any leaves in the index
+
+	pub fn subvolume_ids (
+		& self,
+	) -> Vec <u64> {
+
+		let mut subvolume_ids: Vec <u64> =
+			self.leaves.iter ().map (
+				|leaf| leaf.tree_id,
+			).filter (
+				|& tree_id| tree_is_subvolume (tree_id),
+			).collect ();
+
+		subvolume_ids.sort ();
+		subvolume_ids.dedup ();
+
+		subvolume_ids
+
+	}
+
+	// utility functions
+
+	// picks the item from the most recent leaf for each key. nodes newer than
+	// the maximum generation were never added, so this is the filesystem as
+	// it was then, rather than a mix of items from different transactions.
+
+	fn select_recent <
+		'b,
+		Item: Copy + 'b,
+		Key: hash::Hash + cmp::Eq,
+		Items: Iterator <Item = & 'b IndexedItem <Item>>,
+		GetKey: Fn (& IndexedItem <Item>) -> Key,
+	> (
+		items: Items,
+		get_key: GetKey,
+	) -> HashMap <Key, Item> {
+
+		let mut items_recent: HashMap <Key, IndexedItem <Item>> =
+			HashMap::new ();
+
+		for indexed_item in items {
+
+			let item_recent =
+				items_recent.entry (
+					get_key (indexed_item),
+				).or_insert (
+					* indexed_item);
+
+			if item_recent.generation < indexed_item.generation {
+
+				* item_recent =
+					* indexed_item;
+
+			}
+
+		}
+
+		items_recent.into_iter ().map (
+			|(key, indexed_item)| (key, indexed_item.item)
+		).collect ()
+
+	}
+
+	// picks the most recent entry for each name in each directory, and
+	// groups them by the inode they link to, in order of directory and name
+
+	fn select_recent_links <
+		'b,
+		Item: Copy + 'b,
+		Name: hash::Hash + cmp::Eq + cmp::Ord,
+		Items: Iterator <Item = & 'b IndexedItem <Item>>,
+		GetLink: Fn (& Item) -> (u64, Name, u64),
+	> (
+		items: Items,
+		get_link: GetLink,
+	) -> HashMap <u64, Vec <Item>> {
+
+		let mut links: Vec <((u64, Name), Item)> =
+			Self::select_recent (
+				items,
+				|indexed_item| {
+
+				let (parent_object_id, name, _) =
+					get_link (& indexed_item.item);
+
+				(parent_object_id, name)
+
+			}).into_iter ().collect ();
+
+		links.sort_by (
+			|left, right|
+
+			left.0.cmp (& right.0)
+
+		);
+
+		let mut links_by_child: HashMap <u64, Vec <Item>> =
+			HashMap::new ();
+
+		for (_, item) in links {
+
+			let (_, _, child_object_id) =
+				get_link (& item);
+
+			links_by_child.entry (
+				child_object_id,
+			).or_insert (
+				Vec::new (),
+			).push (
+				item,
+			);
+
+		}
+
+		links_by_child
+
+	}
+
+	// keeps the items of a tree which come from the best leaf covering their
+	// key, so that each leaf only supplies the keys no better leaf covers
+
+	fn select_best_leaf <
+		'b,
+		Item: Copy + 'b,
+		Items: Iterator <Item = & 'b IndexedItem <Item>>,
+	> (
+		& self,
+		tree_id: u64,
+		items: Items,
+	) -> Vec <IndexedItem <Item>> {
+
+		let leaf_ranges =
+			Self::clip_leaves (
+				& self.leaves,
+				tree_id);
+
+		let mut items_selected: Vec <IndexedItem <Item>> =
+			items.filter (
+				|indexed_item|
+
+				indexed_item.tree_id == tree_id
+				&& Self::leaf_range_owner (
+					& leaf_ranges,
+					indexed_item.key,
+				) == Some (indexed_item.leaf_index)
+
+			).cloned ().collect ();
+
+		items_selected.sort_by_key (
+			|indexed_item| indexed_item.key);
+
+		items_selected
+
+	}
+
+	// clips the leaves of a tree to the key ranges where each is the best
+	// leaf, in key order. the bounds of every leaf are swept in order, with
+	// the leaves covering each stretch between two bounds kept in a heap, and
+	// dropped from the top of it once the sweep has passed their last key.
+
+	pub fn clip_leaves (
+		leaves: & [IndexedLeaf],
+		tree_id: u64,
+	) -> Vec <IndexedLeafRange> {
+
+		let mut leaf_bounds: Vec <(IndexKeyBound, IndexKeyBound, usize)> =
+			leaves.iter ().enumerate ().filter_map (
+				|(leaf_index, leaf)|
+
+				match leaf.keys {
+					Some ((first_key, last_key)) if leaf.tree_id == tree_id =>
+						Some (((first_key, false), (last_key, true), leaf_index)),
+					_ => None,
+				}
+
+			).collect ();
+
+		leaf_bounds.sort ();
+
+		let mut bounds: Vec <IndexKeyBound> =
+			leaf_bounds.iter ().flat_map (
+				|& (start, end, _)| vec! [ start, end ],
+			).collect ();
+
+		bounds.sort ();
+		bounds.dedup ();
+
+		let mut leaves_active: BinaryHeap <((u8, u64), usize)> =
+			BinaryHeap::new ();
+
+		let mut next_leaf: usize = 0;
+
+		let mut leaf_ranges: Vec <IndexedLeafRange> =
+			Vec::new ();
+
+		for bound_index in 1 .. bounds.len () {
+
+			let start = bounds [bound_index - 1];
+			let end = bounds [bound_index];
+
+			while next_leaf < leaf_bounds.len ()
+				&& leaf_bounds [next_leaf].0 <= start {
+
+				let leaf_index =
+					leaf_bounds [next_leaf].2;
+
+				leaves_active.push ((
+					Self::leaf_rank (& leaves [leaf_index]),
+					leaf_index,
+				));
+
+				next_leaf += 1;
+
+			}
+
+			let best_leaf_index =
+				loop {
+
+				let (leaf_index, leaf_expired) =
+					match leaves_active.peek () {
+
+					Some (& (_, leaf_index)) => (
+						leaf_index,
+						leaves [leaf_index].keys.map_or (
+							true,
+							|(_, last_key)| (last_key, true) <= start),
+					),
+
+					None => break None,
+
+				};
+
+				if ! leaf_expired {
+					break Some (leaf_index);
+				}
+
+				leaves_active.pop ();
+
+			};
+
+			let leaf_index =
+				match best_leaf_index {
+					Some (leaf_index) => leaf_index,
+					None => continue,
+				};
+
+			// extend the previous range if the same leaf carries on
+
+			if let Some (last_range) = leaf_ranges.last_mut () {
+
+				if last_range.leaf_index == leaf_index
+					&& last_range.end == start {
+
+					last_range.end = end;
+
+					continue;
+
+				}
+
+			}
+
+			leaf_ranges.push (
+				IndexedLeafRange {
+					start: start,
+					end: end,
+					leaf_index: leaf_index,
+				});
+
+		}
+
+		leaf_ranges
+
+	}
+
+	// finds the leaf whose clipped range holds a key
+
+	fn leaf_range_owner (
+		leaf_ranges: & [IndexedLeafRange],
+		key: IndexKey,
+	) -> Option <usize> {
+
+		let position =
+			match leaf_ranges.binary_search_by_key (
+				& (key, false),
+				|leaf_range| leaf_range.start) {
+
+			Ok (position) => position,
+			Err (0) => return None,
+			Err (position) => position - 1,
+
+		};
+
+		let leaf_range =
+			& leaf_ranges [position];
+
+		if (key, false) < leaf_range.end {
+			Some (leaf_range.leaf_index)
+		} else {
+			None
+		}
+
+	}
+
+	// leaves with good checksums beat unchecked ones, which beat damaged
+	// ones, then the most recent wins
+
+	fn leaf_rank (
+		leaf: & IndexedLeaf,
+	) -> (u8, u64) {
+
+		let validity_rank =
+			match leaf.validity {
+				IndexNodeValidity::Valid => 2,
+				IndexNodeValidity::Unchecked => 1,
+				IndexNodeValidity::BadChecksum => 0,
+				IndexNodeValidity::Broken => 0,
+			};
+
+		(validity_rank, leaf.generation)
+
+	}
+
+	fn sort_leaves <
+		Key: hash::Hash + cmp::Eq,
+		Value: cmp::Ord,
+	> (
+		map: & mut HashMap <Key, Vec <Value>>,
+	) {
+
+		for (ref key, ref mut values)
+		in map.iter_mut () {
+
+			values.sort ();
+
+		}
+
+	}
+
+}
+
+impl <'a> IndexedSubvolume <'a> {
+
+	pub fn filesystem (
+		& self,
+	) -> & 'a IndexedFilesystem <'a> {
+		self.filesystem
+	}
+
+	pub fn tree_id (
+		& self,
+	) -> u64 {
+		self.tree_id
+	}
+
+	pub fn inode_item (
+		& self,
+		object_id: u64,
+	) -> Option <BtrfsInodeItem <'a>> {
+
+		self.inode_items.get (
+			& object_id,
+		).cloned ()
+
+	}
+
+	// the first directory entry which links to an inode, of any hard links
+
+	pub fn dir_item_entry (
+		& self,
+		child_object_id: u64,
+	) -> Option <BtrfsDirItemEntry <'a>> {
+
+		self.dir_item_entries.get (
+			& child_object_id,
+		).map (
+			|dir_item_entries| dir_item_entries [0],
+		)
+
+	}
+
+	pub fn dir_item_entries (
+		& self,
+	) -> Vec <BtrfsDirItemEntry <'a>> {
+
+		self.dir_item_entries.values ().flat_map (
+			|dir_item_entries| dir_item_entries.iter ().cloned (),
+		).collect ()
+
+	}
+
+	// the entries in a directory, one for each child
+
+	pub fn dir_indexes (
+		& self,
+		directory_id: u64,
+	) -> Vec <BtrfsDirItemEntry <'a>> {
+
+		self.dir_indexes.get (
+			& directory_id,
+		).cloned ().unwrap_or (
+			Vec::new ())
+
+	}
+
+	// a file's extents, by file position
+
+	pub fn extent_datas (
+		& self,
+		object_id: u64,
+	) -> Vec <BtrfsExtentData <'a>> {
+
+		self.extent_datas.get (
+			& object_id,
+		).cloned ().unwrap_or (
+			Vec::new ())
+
+	}
+
+	// the names of an inode from its own refs, in any directory
+
+	pub fn inode_refs (
+		& self,
+		object_id: u64,
+	) -> Vec <IndexedInodeRef <'a>> {
+
+		self.inode_refs.get (
+			& object_id,
+		).cloned ().unwrap_or (
+			Vec::new ())
 
-		// sort leaves
+	}
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Sorting nodes");
+	// the children of a directory which no directory entry names, and which
+	// are only found from their own inode refs
 
-		Self::sort_leaves (
-			& mut self.chunk_items_index);
+	pub fn inode_ref_children (
+		& self,
+		directory_id: u64,
+	) -> Vec <IndexedInodeRef <'a>> {
 
-		Self::sort_leaves (
-			& mut self.dir_item_entries_index);
+		self.inode_ref_children.get (
+			& directory_id,
+		).cloned ().unwrap_or (
+			Vec::new ())
 
-		Self::sort_leaves (
-			& mut self.inode_items_index);
+	}
 
-		Self::sort_leaves (
-			& mut self.extent_datas_index);
+	// the inodes which can't be reached from the root directory, apart from
+	// those beneath another such inode
 
-		output_job.complete ();
+	pub fn lost_inodes (
+		& self,
+	) -> & [u64] {
+		& self.lost_inodes
+	}
 
-		// try and construct chunk tree
+	fn parent_object_id (
+		& self,
+		object_id: u64,
+	) -> Option <u64> {
 
-		let mut num_unique: u64 = 0;
-		let mut num_duplicated: u64 = 0;
+		match self.dir_item_entries.get (& object_id) {
 
-		for (object_id, chunk_items) in & self.chunk_items_index {
+			Some (dir_item_entries) =>
+				Some (dir_item_entries [0].object_id ()),
 
-			if chunk_items.len () == 1 {
-				num_unique += 1;
-			} else {
-				num_duplicated += 1;
-			}
+			None =>
+				self.inode_refs.get (
+					& object_id,
+				).map (
+					|inode_refs| inode_refs [0].parent_object_id,
+				),
 
 		}
 
-		output_message! (
-			output,
-			"CHUNK ITEMS: {} unique, {} duplicated",
-			num_unique,
-			num_duplicated);
+	}
 
-		// output and return
+	// an inode whose parent is also lost will be restored beneath it, so only
+	// the tops are returned. inodes in a loop have no top, so the first of
+	// them is used.
 
-		output_message! (
-			output,
-			"Found {} dir entries, {} inodes, {} extents",
-			self.dir_item_entries.len (),
-			self.inode_items.len (),
-			self.extent_datas.len ());
+	fn find_lost_inodes (
+		& self,
+	) -> Vec <u64> {
 
-	}
+		let mut reached: HashSet <u64> =
+			HashSet::new ();
 
-	fn store_leaf_node (
-		& mut self,
-		leaf_node: BtrfsLeafNode <'a>,
-	) {
+		if self.inode_items.contains_key (& INDEXED_ROOT_DIRECTORY_ID) {
 
-		self.leaf_nodes.push (
-			leaf_node);
+			self.reach_from (
+				& mut reached,
+				INDEXED_ROOT_DIRECTORY_ID);
 
-		for item in leaf_node.items () {
+		}
 
-			match item {
+		let mut object_ids: Vec <u64> =
+			self.inode_items.keys ().cloned ().collect ();
 
-				BtrfsLeafItem::ChunkItem (chunk_item) =>
-					self.store_chunk_item (
-						chunk_item),
+		object_ids.sort ();
 
-				BtrfsLeafItem::DirItem (dir_item) =>
-					self.store_dir_item (
-						dir_item),
+		let mut lost_inodes: Vec <u64> =
+			Vec::new ();
 
-				BtrfsLeafItem::ExtentData (extent_data) =>
-					self.store_extent_data (
-						extent_data),
+		for & tops_only in [true, false].iter () {
 
-				BtrfsLeafItem::ExtentItem (extent_item) =>
-					self.store_extent_item (
-						extent_item),
+			for & object_id in object_ids.iter () {
 
-				BtrfsLeafItem::InodeItem (inode_item) =>
-					self.store_inode_item (
-						inode_item),
+				if reached.contains (& object_id) {
+					continue;
+				}
 
-				BtrfsLeafItem::RootItem (root_item) =>
-					self.store_root_item (
-						root_item),
+				if tops_only && self.parent_object_id (
+					object_id,
+				).map_or (
+					false,
+					|parent_object_id|
+						self.inode_items.contains_key (& parent_object_id),
+				) {
+					continue;
+				}
 
-				_ => (),
+				lost_inodes.push (
+					object_id);
+
+				self.reach_from (
+					& mut reached,
+					object_id);
 
 			}
 
 		}
 
+		lost_inodes
+
 	}
 
-	fn store_internal_node (
-		& mut self,
-		internal_node: BtrfsInternalNode <'a>,
+	// marks an inode, and everything beneath it which restore would find, as
+	// reached
+
+	fn reach_from (
+		& self,
+		reached: & mut HashSet <u64>,
+		object_id: u64,
 	) {
 
-		self.internal_nodes.push (
-			internal_node);
+		reached.insert (
+			object_id);
 
-		let tree_items =
-			self.internal_items_by_tree.entry (
-				internal_node.header ().tree_id (),
-			).or_insert (
-				Vec::new (),
-			);
+		let mut pending: Vec <u64> =
+			vec! [ object_id ];
 
-		for item in internal_node.items () {
+		while let Some (directory_id) = pending.pop () {
 
-			tree_items.push (
-				item);
+			let child_object_ids: Vec <u64> =
+				self.dir_indexes (
+					directory_id,
+				).iter ().map (
+					|dir_item_entry| dir_item_entry.child_object_id (),
+				).chain (
+					self.inode_ref_children (
+						directory_id,
+					).iter ().map (
+						|inode_ref| inode_ref.object_id,
+					),
+				).collect ();
 
-		}
+			for child_object_id in child_object_ids {
 
-	}
+				if self.inode_items.contains_key (& child_object_id)
+					&& reached.insert (child_object_id) {
 
-	fn store_chunk_item (
-		& mut self,
-		chunk_item: BtrfsChunkItem <'a>,
-	) {
+					pending.push (
+						child_object_id);
 
-		self.chunk_items.push (
-			chunk_item);
+				}
 
-		self.chunk_items_index.entry (
-			chunk_item.key ().object_id (),
-		).or_insert (
-			Vec::new (),
-		).push (
-			chunk_item
-		);
+			}
+
+		}
 
 	}
 
-	fn store_dir_item (
-		& mut self,
-		dir_item: BtrfsDirItem <'a>,
-	) {
+}
 
-		for dir_item_entry in dir_item.entries () {
+// reads the names from an inode ref or extref item. a ref's key holds the
+// parent directory, and each name in it is preceded by the index in that
+// directory. an extref's key holds a hash of the name, so each name is also
+// preceded by the parent.
 
-			self.store_dir_item_entry (
-				dir_item_entry);
+pub fn indexed_inode_refs <'a> (
+	key: IndexKey,
+	data: & 'a [u8],
+) -> Vec <IndexedInodeRef <'a>> {
 
-		}
+	let mut inode_refs: Vec <IndexedInodeRef <'a>> =
+		Vec::new ();
 
-	}
+	let header_size =
+		if key.item_type == INDEXED_INODE_REF_TYPE {
+			10
+		} else {
+			18
+		};
 
-	fn store_dir_item_entry (
-		& mut self,
-		dir_item_entry: BtrfsDirItemEntry <'a>,
-	) {
+	let mut position: usize = 0;
 
-		self.dir_item_entries.push (
-			dir_item_entry);
+	while position + header_size <= data.len () {
 
-		self.dir_item_entries_index.entry (
-			dir_item_entry.key ().object_id (),
-		).or_insert (
-			Vec::new (),
-		).push (
-			dir_item_entry
-		);
+		let name_start =
+			position + header_size;
 
-	}
+		let name_end =
+			name_start + le_u16 (data, name_start - 2) as usize;
 
-	fn store_extent_data (
-		& mut self,
-		extent_data: BtrfsExtentData <'a>,
-	) {
+		if name_end > data.len () {
+			break;
+		}
 
-		self.extent_datas.push (
-			extent_data);
+		let parent_object_id =
+			if key.item_type == INDEXED_INODE_REF_TYPE {
+				key.offset
+			} else {
+				le_u64 (data, position)
+			};
 
-		self.extent_datas_index.entry (
-			extent_data.object_id (),
-		).or_insert (
-			Vec::new (),
-		).push (
-			extent_data
-		);
+		inode_refs.push (
+			IndexedInodeRef {
+				object_id: key.object_id,
+				parent_object_id: parent_object_id,
+				dir_index: le_u64 (data, name_start - 10),
+				name: & data [name_start .. name_end],
+			});
+
+		position = name_end;
 
 	}
 
-	fn store_extent_item (
-		& mut self,
-		extent_item: BtrfsExtentItem <'a>,
-	) {
+	inode_refs
 
-		self.extent_items.push (
-			extent_item);
+}
 
-		self.extent_items_index.entry (
-			extent_item.object_id (),
-		).or_insert (
-			Vec::new (),
-		).push (
-			extent_item
-		);
+#[ cfg (test) ]
+mod tests {
 
-	}
+	use super::*;
 
-	fn store_inode_item (
-		& mut self,
-		inode_item: BtrfsInodeItem <'a>,
-	) {
+	fn key (
+		object_id: u64,
+	) -> IndexKey {
 
-		self.inode_items.push (
-			inode_item);
+		IndexKey {
+			object_id: object_id,
+			item_type: 1,
+			offset: 0,
+		}
 
-		self.inode_items_index.entry (
-			inode_item.object_id (),
-		).or_insert (
-			Vec::new (),
-		).push (
-			inode_item
-		)
+	}
+
+	fn leaf (
+		generation: u64,
+		validity: IndexNodeValidity,
+		first_object_id: u64,
+		last_object_id: u64,
+	) -> IndexedLeaf {
+
+		IndexedLeaf {
+			tree_id: 5,
+			generation: generation,
+			validity: validity,
+			keys: Some ((key (first_object_id), key (last_object_id))),
+		}
 
 	}
 
-	fn store_root_item (
-		& mut self,
-		root_item: BtrfsRootItem <'a>,
-	) {
+	fn owner (
+		leaf_ranges: & [IndexedLeafRange],
+		object_id: u64,
+	) -> Option <usize> {
 
-		self.root_items.push (
-			root_item);
+		IndexedFilesystem::leaf_range_owner (
+			leaf_ranges,
+			key (object_id))
 
-		self.root_items_index.entry (
-			root_item.object_id (),
-		).or_insert (
-			Vec::new (),
-		).push (
-			root_item
-		)
+	}
+
+	#[ test ]
+	fn split_leaf_replaces_older_leaf () {
+
+		// an old leaf, which was later split in two
+
+		let leaves = vec! [
+			leaf (10, IndexNodeValidity::Valid, 1, 100),
+			leaf (20, IndexNodeValidity::Valid, 1, 50),
+			leaf (20, IndexNodeValidity::Valid, 51, 100),
+		];
+
+		let leaf_ranges =
+			IndexedFilesystem::clip_leaves (
+				& leaves,
+				5);
+
+		assert_eq! (owner (& leaf_ranges, 1), Some (1));
+		assert_eq! (owner (& leaf_ranges, 50), Some (1));
+		assert_eq! (owner (& leaf_ranges, 51), Some (2));
+		assert_eq! (owner (& leaf_ranges, 100), Some (2));
+		assert_eq! (owner (& leaf_ranges, 101), None);
+
+		// only keys falling between the two halves come from the old leaf
+
+		assert_eq! (
+			IndexedFilesystem::leaf_range_owner (
+				& leaf_ranges,
+				IndexKey {
+					object_id: 50,
+					item_type: 1,
+					offset: 1,
+				}),
+			Some (0));
+
+		assert_eq! (
+			leaf_ranges.iter ().filter (
+				|leaf_range| leaf_range.leaf_index == 0,
+			).collect::<Vec <_>> (),
+			vec! [
+				& IndexedLeafRange {
+					start: (key (50), true),
+					end: (key (51), false),
+					leaf_index: 0,
+				},
+			]);
 
 	}
 
-	pub fn build_inode_items_index (
-		& mut self,
-		output: & Output,
-		btrfs_device: & 'a BtrfsDevice <'a>,
-	) {
+	#[ test ]
+	fn older_leaf_is_clipped_around_newer_leaf () {
+
+		let leaves = vec! [
+			leaf (10, IndexNodeValidity::Valid, 1, 100),
+			leaf (20, IndexNodeValidity::Valid, 40, 60),
+		];
+
+		let leaf_ranges =
+			IndexedFilesystem::clip_leaves (
+				& leaves,
+				5);
+
+		assert_eq! (
+			leaf_ranges,
+			vec! [
+				IndexedLeafRange {
+					start: (key (1), false),
+					end: (key (40), false),
+					leaf_index: 0,
+				},
+				IndexedLeafRange {
+					start: (key (40), false),
+					end: (key (60), true),
+					leaf_index: 1,
+				},
+				IndexedLeafRange {
+					start: (key (60), true),
+					end: (key (100), true),
+					leaf_index: 0,
+				},
+			]);
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Selecting most recent inode items");
+	}
 
-		for inode_item
-		in self.inode_items.iter () {
+	#[ test ]
+	fn damaged_leaf_loses_to_older_good_leaf () {
 
-			let map_inode_item =
-				self.inode_items_recent.entry (
-					inode_item.object_id (),
-				).or_insert (
-					* inode_item
-				);
+		let leaves = vec! [
+			leaf (10, IndexNodeValidity::Valid, 1, 100),
+			leaf (20, IndexNodeValidity::BadChecksum, 1, 100),
+			IndexedLeaf {
+				tree_id: 6,
+				.. leaf (30, IndexNodeValidity::Valid, 1, 100)
+			},
+		];
 
-			if map_inode_item.transaction_id ()
-				< inode_item.transaction_id () {
+		let leaf_ranges =
+			IndexedFilesystem::clip_leaves (
+				& leaves,
+				5);
 
-				* map_inode_item =
-					* inode_item;
+		assert_eq! (owner (& leaf_ranges, 1), Some (0));
+		assert_eq! (owner (& leaf_ranges, 100), Some (0));
+		assert_eq! (leaf_ranges.len (), 1);
 
-			}
+	}
 
-		}
 
-		output_job.complete ();
+	fn link (
+		generation: u64,
+		item_type: u8,
+		link: (u64, & 'static [u8], u64),
+	) -> IndexedItem <(u64, & 'static [u8], u64)> {
+
+		IndexedItem {
+			item: link,
+			key: IndexKey {
+				object_id: link.0,
+				item_type: item_type,
+				offset: 0,
+			},
+			leaf_index: 0,
+			tree_id: 5,
+			generation: generation,
+		}
 
 	}
 
-	pub fn build_dir_items_index (
-		& mut self,
-		output: & Output,
-		btrfs_device: & 'a BtrfsDevice <'a>,
-	) {
+	#[ test ]
+	fn hard_linked_inode_keeps_every_name () {
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Selecting most recent directory item entries");
+		// inode 257 is linked into directories 256 and 258, and the name "old"
+		// in 256 was reused for inode 260 after 259 was deleted
 
-		for dir_item_entry in self.dir_item_entries.iter () {
+		let links = vec! [
+			link (10, 84, (256, b"a", 257)),
+			link (10, 96, (256, b"a", 257)),
+			link (12, 84, (258, b"b", 257)),
+			link (12, 96, (258, b"b", 257)),
+			link (10, 96, (256, b"old", 259)),
+			link (12, 96, (256, b"old", 260)),
+		];
 
-			let map_dir_item_entry =
-				self.dir_item_entries_recent.entry (
-					dir_item_entry.child_object_id (),
-				).or_insert (
-					* dir_item_entry
-				);
+		let links_by_child =
+			IndexedFilesystem::select_recent_links (
+				links.iter (),
+				|& link| link);
 
-			if map_dir_item_entry.transaction_id ()
-				< dir_item_entry.transaction_id () {
+		assert_eq! (
+			links_by_child [& 257],
+			vec! [ (256, & b"a" [..], 257), (258, & b"b" [..], 257) ]);
 
-				* map_dir_item_entry =
-					* dir_item_entry;
+		assert_eq! (
+			links_by_child [& 260],
+			vec! [ (256, & b"old" [..], 260) ]);
 
-			}
+		assert! (! links_by_child.contains_key (& 259));
+		assert_eq! (links_by_child.len (), 2);
 
-		}
+	}
 
-		output_job.complete ();
+	fn inode_ref_entry (
+		data: & mut Vec <u8>,
+		parent_object_id: Option <u64>,
+		dir_index: u64,
+		name: & [u8],
+	) {
 
-		let output_job =
-			output_job_start! (
-				output,
-				"Grouping directory item entries by parent");
+		if let Some (parent_object_id) = parent_object_id {
 
-		for dir_item_entry in self.dir_item_entries_recent.values () {
+			for shift in 0 .. 8 {
+				data.push ((parent_object_id >> (shift * 8)) as u8);
+			}
 
-			self.dir_item_entries_by_parent.entry (
-				dir_item_entry.object_id (),
-			).or_insert (
-				Vec::new (),
-			).push (
-				dir_item_entry.child_object_id (),
-			);
+		}
 
+		for shift in 0 .. 8 {
+			data.push ((dir_index >> (shift * 8)) as u8);
 		}
 
-		output_job.complete ();
+		data.push (name.len () as u8);
+		data.push ((name.len () >> 8) as u8);
 
-	}
+		data.extend_from_slice (name);
 
-	pub fn root_tree_internal_items (
-		& 'a self,
-	) -> & [& 'a BtrfsInternalItem] {
+	}
 
-		self.internal_items_by_tree.get (
-			& BTRFS_ROOT_TREE_ID,
-		).unwrap ()
+	#[ test ]
+	fn inode_refs_are_parsed () {
+
+		let mut data: Vec <u8> =
+			Vec::new ();
+
+		inode_ref_entry (& mut data, None, 2, b"first");
+		inode_ref_entry (& mut data, None, 0x1234_5678_9abc, b"second name");
+
+		let inode_refs =
+			indexed_inode_refs (
+				IndexKey {
+					object_id: 257,
+					item_type: INDEXED_INODE_REF_TYPE,
+					offset: 256,
+				},
+				& data);
+
+		assert_eq! (
+			inode_refs,
+			vec! [
+				IndexedInodeRef {
+					object_id: 257,
+					parent_object_id: 256,
+					dir_index: 2,
+					name: b"first",
+				},
+				IndexedInodeRef {
+					object_id: 257,
+					parent_object_id: 256,
+					dir_index: 0x1234_5678_9abc,
+					name: b"second name",
+				},
+			]);
 
 	}
 
-	// property accessors
+	#[ test ]
+	fn inode_extrefs_are_parsed () {
+
+		let mut data: Vec <u8> =
+			Vec::new ();
+
+		inode_ref_entry (& mut data, Some (256), 3, b"one");
+		inode_ref_entry (& mut data, Some (300), 4, b"");
+
+		let inode_refs =
+			indexed_inode_refs (
+				IndexKey {
+					object_id: 258,
+					item_type: INDEXED_INODE_EXTREF_TYPE,
+					offset: 0xdead_beef,
+				},
+				& data);
+
+		assert_eq! (
+			inode_refs,
+			vec! [
+				IndexedInodeRef {
+					object_id: 258,
+					parent_object_id: 256,
+					dir_index: 3,
+					name: b"one",
+				},
+				IndexedInodeRef {
+					object_id: 258,
+					parent_object_id: 300,
+					dir_index: 4,
+					name: b"",
+				},
+			]);
 
-	pub fn dir_item_entries_recent (
-		& 'a self,
-	) -> & HashMap <u64, BtrfsDirItemEntry <'a>> {
-		& self.dir_item_entries_recent
 	}
 
-	pub fn dir_item_entries_by_parent (
-		& 'a self,
-	) -> & HashMap <u64, Vec <u64>> {
-		& self.dir_item_entries_by_parent
-	}
+	#[ test ]
+	fn truncated_inode_refs_are_dropped () {
 
-	pub fn extent_datas_index (
-		& 'a self,
-	) -> & HashMap <u64, Vec <BtrfsExtentData>> {
-		& self.extent_datas_index
-	}
+		let mut data: Vec <u8> =
+			Vec::new ();
 
-	pub fn extent_items_index (
-		& 'a self,
-	) -> & HashMap <u64, Vec <BtrfsExtentItem>> {
-		& self.extent_items_index
-	}
+		inode_ref_entry (& mut data, None, 2, b"whole");
+		inode_ref_entry (& mut data, None, 3, b"truncated");
 
-	pub fn inode_items_recent (
-		& 'a self,
-	) -> & HashMap <u64, BtrfsInodeItem <'a>> {
-		& self.inode_items_recent
-	}
+		let key =
+			IndexKey {
+				object_id: 257,
+				item_type: INDEXED_INODE_REF_TYPE,
+				offset: 256,
+			};
 
-	// utility functions
+		// a name running past the end of the item
 
-	fn sort_leaves <
-		Key: hash::Hash + cmp::Eq,
-		Value: cmp::Ord,
-	> (
-		map: & mut HashMap <Key, Vec <Value>>,
-	) {
+		let data_length = data.len ();
 
-		for (ref key, ref mut values)
-		in map.iter_mut () {
+		assert_eq! (
+			indexed_inode_refs (key, & data [0 .. data_length - 1]).len (),
+			1);
 
-			values.sort ();
+		// a header running past the end of the item
 
-		}
+		assert_eq! (
+			indexed_inode_refs (key, & data [0 .. 10 + 5 + 9]).len (),
+			1);
+
+		assert_eq! (
+			indexed_inode_refs (key, & data [0 .. 9]).len (),
+			0);
 
 	}
 
@@ -10,18 +10,37 @@
 extern crate btrfs;
 extern crate clap;
 extern crate crc;
+extern crate flate2;
 extern crate libc;
-extern crate memmap;
 extern crate uuid;
 
 #[ macro_use ]
 extern crate output;
 
 mod arguments;
+mod bytes;
+mod checksum;
+mod chunks;
+mod devices;
+mod find_root;
+mod find_superblock;
+mod image_metadump;
+mod image_qcow2;
+mod image_vhdx;
+mod image_vmdk;
+mod images;
 mod indexed_filesystem;
 mod index;
+mod index_diff;
+mod index_info;
+mod index_merge;
+mod mapfile;
+mod partitions;
 mod restore;
 mod scan;
+mod scan_parameters;
+mod superblock;
+mod trees;
 
 use std::error::Error;
 use std::panic;
@@ -30,7 +49,12 @@ use std::process;
 use output::*;
 
 use arguments::*;
+use find_root::*;
+use find_superblock::*;
 use index::*;
+use index_diff::*;
+use index_info::*;
+use index_merge::*;
 use restore::*;
 use scan::*;
 
@@ -80,6 +104,31 @@ fn main_real (
 						& output,
 						index_command),
 
+				Command::IndexInfo (index_info_command) =>
+					index_info (
+						& output,
+						index_info_command),
+
+				Command::IndexMerge (index_merge_command) =>
+					index_merge (
+						& output,
+						index_merge_command),
+
+				Command::IndexDiff (index_diff_command) =>
+					index_diff (
+						& output,
+						index_diff_command),
+
+				Command::FindRoot (find_root_command) =>
+					find_root (
+						& output,
+						find_root_command),
+
+				Command::FindSuperblock (find_superblock_command) =>
+					find_superblock (
+						& output,
+						find_superblock_command),
+
 				Command::Restore (restore_command) =>
 					restore (
 						& output,
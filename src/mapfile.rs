@@ -0,0 +1,547 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+
+use devices::*;
+
+// a GNU ddrescue mapfile. the first non-comment line holds the current
+// position and status, which we ignore, and the rest describe blocks of the
+// image as "position size status". only blocks with status '+' have been
+// rescued, everything else in the image is zero filled by ddrescue, and so
+// anything found there can't be trusted.
+
+pub struct Mapfile {
+	path: PathBuf,
+	rescued_size: u64,
+	unrescued: Vec <DeviceRange>,
+	end: u64,
+}
+
+impl Mapfile {
+
+	pub fn load (
+		path: & Path,
+	) -> Result <Mapfile, String> {
+
+		let file =
+			File::open (
+				path,
+			).map_err (
+				|error|
+
+				format! (
+					"Error opening mapfile {}: {}",
+					path.to_string_lossy (),
+					error.description ())
+
+			) ?;
+
+		let reader =
+			BufReader::new (
+				file);
+
+		let mut unrescued: Vec <DeviceRange> =
+			Vec::new ();
+
+		let mut rescued_size: u64 = 0;
+		let mut end: u64 = 0;
+		let mut seen_status = false;
+
+		for (line_index, line) in reader.lines ().enumerate () {
+
+			let line =
+				line.map_err (
+					|error|
+
+					format! (
+						"Error reading mapfile {}: {}",
+						path.to_string_lossy (),
+						error.description ())
+
+				) ?;
+
+			let line =
+				match line.find ('#') {
+					Some (comment_start) => & line [0 .. comment_start],
+					None => & line [..],
+				};
+
+			let fields: Vec <& str> =
+				line.split_whitespace ().collect ();
+
+			if fields.is_empty () {
+				continue;
+			}
+
+			let invalid_line = || {
+
+				format! (
+					"Invalid line {} in mapfile {}",
+					line_index + 1,
+					path.to_string_lossy ())
+
+			};
+
+			// skip the status line
+
+			if ! seen_status {
+
+				seen_status = true;
+
+				continue;
+
+			}
+
+			if fields.len () != 3 || fields [2].len () != 1 {
+				return Err (invalid_line ());
+			}
+
+			let block_start =
+				mapfile_number (
+					fields [0],
+				).ok_or_else (
+					& invalid_line,
+				) ?;
+
+			let block_size =
+				mapfile_number (
+					fields [1],
+				).ok_or_else (
+					& invalid_line,
+				) ?;
+
+			if block_start != end {
+
+				return Err (
+					format! (
+						"Mapfile {} has a gap or overlap at 0x{:x}",
+						path.to_string_lossy (),
+						block_start));
+
+			}
+
+			end =
+				block_start + block_size;
+
+			match fields [2] {
+
+				"+" =>
+					rescued_size += block_size,
+
+				"?" | "*" | "/" | "-" =>
+					mapfile_add_range (
+						& mut unrescued,
+						block_start,
+						end),
+
+				_ =>
+					return Err (invalid_line ()),
+
+			}
+
+		}
+
+		Ok (Mapfile {
+			path: path.to_owned (),
+			rescued_size: rescued_size,
+			unrescued: unrescued,
+			end: end,
+		})
+
+	}
+
+	// moves positions to be relative to a filesystem which starts at an
+	// offset in the image
+
+	pub fn rebase (
+		self,
+		offset: u64,
+	) -> Mapfile {
+
+		Mapfile {
+			unrescued: self.unrescued.into_iter ().filter (
+				|unrescued_range|
+
+				unrescued_range.end > offset
+
+			).map (
+				|unrescued_range|
+
+				DeviceRange {
+					start: unrescued_range.start.saturating_sub (offset),
+					end: unrescued_range.end - offset,
+				}
+
+			).collect (),
+			end: self.end.saturating_sub (offset),
+			.. self
+		}
+
+	}
+
+	pub fn path (& self) -> & Path {
+		& self.path
+	}
+
+	pub fn rescued_size (& self) -> u64 {
+		self.rescued_size
+	}
+
+	pub fn unrescued (& self) -> & [DeviceRange] {
+		& self.unrescued
+	}
+
+	pub fn end (& self) -> u64 {
+		self.end
+	}
+
+	// returns true if any part of the range was not rescued. anything past
+	// the end of the mapfile has not been tried.
+
+	pub fn overlaps_unrescued (
+		& self,
+		start: u64,
+		end: u64,
+	) -> bool {
+
+		if end > self.end {
+			return true;
+		}
+
+		let index =
+			self.first_overlap (
+				start);
+
+		index < self.unrescued.len ()
+			&& self.unrescued [index].overlaps (start, end)
+
+	}
+
+	// returns the parts of the range which were not rescued
+
+	pub fn unrescued_in (
+		& self,
+		start: u64,
+		end: u64,
+	) -> Vec <DeviceRange> {
+
+		let mut ranges: Vec <DeviceRange> =
+			Vec::new ();
+
+		for unrescued_range in self.unrescued [
+			self.first_overlap (start) ..
+		].iter () {
+
+			if unrescued_range.start >= end {
+				break;
+			}
+
+			mapfile_add_range (
+				& mut ranges,
+				if unrescued_range.start > start {
+					unrescued_range.start
+				} else {
+					start
+				},
+				if unrescued_range.end < end {
+					unrescued_range.end
+				} else {
+					end
+				});
+
+		}
+
+		if end > self.end {
+
+			mapfile_add_range (
+				& mut ranges,
+				if self.end > start { self.end } else { start },
+				end);
+
+		}
+
+		ranges
+
+	}
+
+	// index of the first unrescued range which ends after the position
+
+	fn first_overlap (
+		& self,
+		position: u64,
+	) -> usize {
+
+		match self.unrescued.binary_search_by_key (
+			& position,
+			|unrescued_range| unrescued_range.end,
+		) {
+			Ok (index) => index + 1,
+			Err (index) => index,
+		}
+
+	}
+
+}
+
+// loads the mapfiles given on the command line, which must match up with the
+// device paths
+
+pub fn mapfiles_load (
+	paths: & [PathBuf],
+	mapfile_paths: & [PathBuf],
+) -> Result <Vec <Option <Mapfile>>, String> {
+
+	if mapfile_paths.is_empty () {
+
+		return Ok (
+			paths.iter ().map (|_| None).collect ());
+
+	}
+
+	if mapfile_paths.len () != paths.len () {
+
+		return Err (
+			format! (
+				"Got {} mapfiles for {} devices, please give one for each \
+				device",
+				mapfile_paths.len (),
+				paths.len ()));
+
+	}
+
+	let mut mapfiles: Vec <Option <Mapfile>> =
+		Vec::new ();
+
+	for mapfile_path in mapfile_paths.iter () {
+
+		mapfiles.push (
+			Some (
+				Mapfile::load (
+					mapfile_path,
+				) ?));
+
+	}
+
+	Ok (mapfiles)
+
+}
+
+fn mapfile_add_range (
+	ranges: & mut Vec <DeviceRange>,
+	start: u64,
+	end: u64,
+) {
+
+	if start >= end {
+		return;
+	}
+
+	if let Some (last_range) = ranges.last_mut () {
+
+		if last_range.end == start {
+
+			last_range.end = end;
+
+			return;
+
+		}
+
+	}
+
+	ranges.push (
+		DeviceRange {
+			start: start,
+			end: end,
+		});
+
+}
+
+fn mapfile_number (
+	value: & str,
+) -> Option <u64> {
+
+	if value.starts_with ("0x") || value.starts_with ("0X") {
+
+		u64::from_str_radix (
+			& value [2 ..],
+			16,
+		).ok ()
+
+	} else {
+
+		u64::from_str_radix (
+			value,
+			10,
+		).ok ()
+
+	}
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::io::Write;
+	use std::process;
+
+	use super::*;
+
+	fn load (
+		name: & str,
+		contents: & str,
+	) -> Result <Mapfile, String> {
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"btrfs-fuse-mapfile-{}-{}",
+					process::id (),
+					name));
+
+		{
+
+			let mut file =
+				File::create (
+					& path,
+				).unwrap ();
+
+			file.write_all (
+				contents.as_bytes (),
+			).unwrap ();
+
+		}
+
+		let result =
+			Mapfile::load (
+				& path);
+
+		fs::remove_file (
+			& path,
+		).unwrap ();
+
+		result
+
+	}
+
+	fn range (
+		start: u64,
+		end: u64,
+	) -> DeviceRange {
+
+		DeviceRange {
+			start: start,
+			end: end,
+		}
+
+	}
+
+	#[ test ]
+	fn blocks_are_parsed () {
+
+		let mapfile =
+			load (
+				"blocks",
+				"# Mapfile. Created by GNU ddrescue version 1.23\n\
+				# current_pos  current_status  current_pass\n\
+				0x00020000     ?               1\n\
+				#      pos        size  status\n\
+				0x00000000  0x00010000  +\n\
+				0x00010000  0x00001000  -\n\
+				0x00011000  0x00001000  *  # trimmed\n\
+				0x00012000  0x0000E000  +\n\
+				0x00020000  4096  /\n\
+				0x00021000  0x00001000  ?\n\
+				\n\
+				0x00022000  0x00002000  +\n",
+			).unwrap ();
+
+		assert_eq! (mapfile.rescued_size (), 0x1_0000 + 0xe000 + 0x2000);
+		assert_eq! (mapfile.end (), 0x2_4000);
+
+		assert_eq! (
+			mapfile.unrescued (),
+			& [ range (0x1_0000, 0x1_2000), range (0x2_0000, 0x2_2000) ]);
+
+	}
+
+	#[ test ]
+	fn unrescued_ranges_are_found () {
+
+		let mapfile =
+			load (
+				"ranges",
+				"0 +\n\
+				0x0000 0x1000 +\n\
+				0x1000 0x1000 -\n\
+				0x2000 0x2000 +\n\
+				0x4000 0x1000 ?\n\
+				0x5000 0x1000 +\n",
+			).unwrap ();
+
+		assert! (! mapfile.overlaps_unrescued (0x0000, 0x1000));
+		assert! (mapfile.overlaps_unrescued (0x0800, 0x1800));
+		assert! (! mapfile.overlaps_unrescued (0x2000, 0x4000));
+		assert! (mapfile.overlaps_unrescued (0x3fff, 0x4001));
+		assert! (! mapfile.overlaps_unrescued (0x5000, 0x6000));
+		assert! (mapfile.overlaps_unrescued (0x5000, 0x6001));
+
+		assert_eq! (
+			mapfile.unrescued_in (0x0800, 0x7000),
+			vec! [
+				range (0x1000, 0x2000),
+				range (0x4000, 0x5000),
+				range (0x6000, 0x7000),
+			]);
+
+		assert_eq! (
+			mapfile.unrescued_in (0x1800, 0x4800),
+			vec! [ range (0x1800, 0x2000), range (0x4000, 0x4800) ]);
+
+		let rebased =
+			mapfile.rebase (0x1800);
+
+		assert_eq! (rebased.end (), 0x4800);
+
+		assert_eq! (
+			rebased.unrescued (),
+			& [ range (0x0000, 0x0800), range (0x2800, 0x3800) ]);
+
+	}
+
+	#[ test ]
+	fn gaps_and_bad_lines_are_errors () {
+
+		assert! (
+			load (
+				"gap",
+				"0 +\n0x0000 0x1000 +\n0x2000 0x1000 +\n",
+			).err ().unwrap ().contains ("gap or overlap at 0x2000"));
+
+		assert! (
+			load (
+				"status",
+				"0 +\n0x0000 0x1000 x\n",
+			).err ().unwrap ().contains ("Invalid line 2"));
+
+		assert! (
+			load (
+				"fields",
+				"0 +\n0x0000 0x1000\n",
+			).err ().unwrap ().contains ("Invalid line 2"));
+
+		assert! (
+			load (
+				"number",
+				"0 +\n0x0000 0xzz +\n",
+			).err ().unwrap ().contains ("Invalid line 2"));
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
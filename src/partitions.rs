@@ -0,0 +1,670 @@
+use std::path::PathBuf;
+
+use output::Output;
+
+use bytes::*;
+use devices::*;
+use superblock::*;
+
+// finds where a btrfs filesystem starts in a whole disk image, by looking
+// through GPT and MBR partition tables, and for bcache and md headers in
+// front of the filesystem.
+
+const MBR_SIGNATURE_OFFSET: u64 = 0x1fe;
+const MBR_ENTRIES_OFFSET: u64 = 0x1be;
+const MBR_ENTRY_SIZE: u64 = 0x10;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+const GPT_SIGNATURE: & 'static [u8] = b"EFI PART";
+const GPT_MAX_ENTRIES: u32 = 0x400;
+
+const BCACHE_SUPERBLOCK_OFFSET: u64 = 0x1000;
+const BCACHE_MAGIC: [u8; 16] = [
+	0xc6, 0x85, 0x73, 0xf6, 0x4e, 0x1a, 0x45, 0xca,
+	0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba, 0x6d, 0x81,
+];
+const BCACHE_VERSION_BACKING: u64 = 1;
+const BCACHE_VERSION_BACKING_WITH_OFFSET: u64 = 4;
+const BCACHE_DEFAULT_DATA_OFFSET: u64 = 16;
+
+const MD_MAGIC: u32 = 0xa92b4efc;
+const MD_SUPERBLOCK_OFFSETS: [(u64, & 'static str); 2] = [
+	(0x0, "1.1"),
+	(0x1000, "1.2"),
+];
+
+const SECTOR_SIZE: u64 = 0x200;
+
+#[ derive (Clone, Debug) ]
+pub struct DeviceOffset {
+	pub offset: u64,
+	pub description: String,
+}
+
+// works out the offset of the filesystem in each path, using the ones given
+// on the command line if there are any
+
+pub fn device_offsets (
+	output: & Output,
+	paths: & [PathBuf],
+	command_offsets: & [u64],
+) -> Result <Vec <u64>, String> {
+
+	if command_offsets.len () == 1 {
+
+		return Ok (
+			paths.iter ().map (
+				|_| command_offsets [0]
+			).collect ());
+
+	}
+
+	if ! command_offsets.is_empty () {
+
+		if command_offsets.len () != paths.len () {
+
+			return Err (
+				format! (
+					"Got {} offsets for {} devices, please give one for all \
+					devices or one for each",
+					command_offsets.len (),
+					paths.len ()));
+
+		}
+
+		return Ok (
+			command_offsets.to_vec ());
+
+	}
+
+	let mut offsets: Vec <u64> =
+		Vec::new ();
+
+	for path in paths.iter () {
+
+		offsets.push (
+			device_offset_find (
+				output,
+				& DeviceReader::open (
+					path,
+				) ?,
+			) ?);
+
+	}
+
+	Ok (offsets)
+
+}
+
+pub fn device_offset_find (
+	output: & Output,
+	reader: & DeviceReader,
+) -> Result <u64, String> {
+
+	// the normal case
+
+	if device_offset_has_btrfs (reader, 0) {
+		return Ok (0);
+	}
+
+	// look for partitions and containers
+
+	let mut candidates: Vec <DeviceOffset> =
+		Vec::new ();
+
+	for partition in partitions_find (reader) {
+
+		candidates.push (
+			partition.clone ());
+
+		for container in containers_find (reader, partition.offset) {
+
+			candidates.push (
+				DeviceOffset {
+					offset: container.offset,
+					description: format! (
+						"{} in {}",
+						container.description,
+						partition.description),
+				});
+
+		}
+
+	}
+
+	candidates.extend (
+		containers_find (
+			reader,
+			0));
+
+	if candidates.is_empty () {
+		return Ok (0);
+	}
+
+	let btrfs_candidates: Vec <& DeviceOffset> =
+		candidates.iter ().filter (
+			|candidate|
+
+			device_offset_has_btrfs (
+				reader,
+				candidate.offset)
+
+		).collect ();
+
+	match btrfs_candidates.len () {
+
+		0 => {
+
+			output_message! (
+				output,
+				"No btrfs filesystem found in {}, which contains:",
+				reader.path ().to_string_lossy ());
+
+			for candidate in candidates.iter () {
+
+				output_message! (
+					output,
+					"  {} at 0x{:x}",
+					candidate.description,
+					candidate.offset);
+
+			}
+
+			Ok (0)
+
+		},
+
+		1 => {
+
+			output_message! (
+				output,
+				"Found btrfs filesystem in {} at 0x{:x} in {}",
+				reader.path ().to_string_lossy (),
+				btrfs_candidates [0].offset,
+				btrfs_candidates [0].description);
+
+			Ok (btrfs_candidates [0].offset)
+
+		},
+
+		_ => {
+
+			output_message! (
+				output,
+				"Found several btrfs filesystems in {}:",
+				reader.path ().to_string_lossy ());
+
+			for candidate in btrfs_candidates.iter () {
+
+				output_message! (
+					output,
+					"  {} at 0x{:x}",
+					candidate.description,
+					candidate.offset);
+
+			}
+
+			Err (
+				"Please choose a filesystem with --offset".to_owned ())
+
+		},
+
+	}
+
+}
+
+// checks for the magic number in any of the superblock copies. the
+// checksum is checked later, this just tells us where to look.
+
+fn device_offset_has_btrfs (
+	reader: & DeviceReader,
+	offset: u64,
+) -> bool {
+
+	BTRFS_SUPERBLOCK_OFFSETS.iter ().any (
+		|superblock_offset| {
+
+		let mut magic: [u8; 8] = [0; 8];
+
+		reader.read_at (
+			offset + superblock_offset + 0x40,
+			& mut magic,
+		).is_ok () && & magic == b"_BHRfS_M"
+
+	})
+
+}
+
+fn read_bytes (
+	reader: & DeviceReader,
+	offset: u64,
+	size: usize,
+) -> Option <Vec <u8>> {
+
+	let mut bytes: Vec <u8> =
+		vec! [0; size];
+
+	if offset + size as u64 > reader.size () {
+		return None;
+	}
+
+	reader.read_at (
+		offset,
+		& mut bytes,
+	).ok ().map (
+		|_| bytes
+	)
+
+}
+
+pub fn partitions_find (
+	reader: & DeviceReader,
+) -> Vec <DeviceOffset> {
+
+	let mbr =
+		match read_bytes (reader, 0, SECTOR_SIZE as usize) {
+			Some (mbr) => mbr,
+			None => return Vec::new (),
+		};
+
+	if (
+		mbr [MBR_SIGNATURE_OFFSET as usize] != 0x55
+		|| mbr [MBR_SIGNATURE_OFFSET as usize + 1] != 0xaa
+	) {
+		return Vec::new ();
+	}
+
+	let mut partitions: Vec <DeviceOffset> =
+		Vec::new ();
+
+	for entry_index in 0 .. 4 {
+
+		let entry_start =
+			(MBR_ENTRIES_OFFSET + entry_index * MBR_ENTRY_SIZE) as usize;
+
+		let partition_type =
+			mbr [entry_start + 4];
+
+		let start_sector =
+			le_u32 (& mbr, entry_start + 8) as u64;
+
+		if partition_type == 0 || start_sector == 0 {
+			continue;
+		}
+
+		if partition_type == MBR_TYPE_GPT_PROTECTIVE {
+
+			return gpt_partitions_find (
+				reader);
+
+		}
+
+		// logical partitions inside an extended partition aren't supported
+
+		if MBR_TYPES_EXTENDED.contains (& partition_type) {
+			continue;
+		}
+
+		partitions.push (
+			DeviceOffset {
+				offset: start_sector * SECTOR_SIZE,
+				description: format! (
+					"MBR partition {} (type 0x{:02x})",
+					entry_index + 1,
+					partition_type),
+			});
+
+	}
+
+	partitions
+
+}
+
+fn gpt_partitions_find (
+	reader: & DeviceReader,
+) -> Vec <DeviceOffset> {
+
+	// the header is in the second logical block, which depends on the
+	// logical sector size of the disk it was made on
+
+	for block_size in [0x200, 0x1000].iter () {
+
+		let header =
+			match read_bytes (reader, * block_size, 0x5c) {
+				Some (header) => header,
+				None => continue,
+			};
+
+		if & header [0 .. 8] != GPT_SIGNATURE {
+			continue;
+		}
+
+		let entries_block = le_u64 (& header, 0x48);
+		let num_entries = le_u32 (& header, 0x50);
+		let entry_size = le_u32 (& header, 0x54) as u64;
+
+		if num_entries > GPT_MAX_ENTRIES || entry_size < 0x80 {
+			return Vec::new ();
+		}
+
+		let entries =
+			match read_bytes (
+				reader,
+				entries_block * block_size,
+				(num_entries as u64 * entry_size) as usize) {
+
+			Some (entries) => entries,
+			None => return Vec::new (),
+
+		};
+
+		let mut partitions: Vec <DeviceOffset> =
+			Vec::new ();
+
+		for entry_index in 0 .. num_entries as u64 {
+
+			let entry =
+				& entries [
+					(entry_index * entry_size) as usize
+				..
+					((entry_index + 1) * entry_size) as usize
+				];
+
+			if entry [0 .. 16].iter ().all (|byte| * byte == 0) {
+				continue;
+			}
+
+			let first_block =
+				le_u64 (entry, 0x20);
+
+			let name: String =
+				String::from_utf16_lossy (
+					& (0 .. 36).map (
+						|char_index|
+
+						le_u16 (entry, 0x38 + char_index * 2)
+
+					).take_while (
+						|character| * character != 0
+					).collect::<Vec <u16>> ());
+
+			partitions.push (
+				DeviceOffset {
+					offset: first_block * block_size,
+					description: if name.is_empty () {
+						format! (
+							"GPT partition {}",
+							entry_index + 1)
+					} else {
+						format! (
+							"GPT partition {} ({})",
+							entry_index + 1,
+							name)
+					},
+				});
+
+		}
+
+		return partitions;
+
+	}
+
+	Vec::new ()
+
+}
+
+// looks for bcache backing device and md raid headers, which put the data
+// they contain at an offset
+
+pub fn containers_find (
+	reader: & DeviceReader,
+	offset: u64,
+) -> Vec <DeviceOffset> {
+
+	let mut containers: Vec <DeviceOffset> =
+		Vec::new ();
+
+	if let Some (bcache) =
+		read_bytes (
+			reader,
+			offset + BCACHE_SUPERBLOCK_OFFSET,
+			0x80) {
+
+		let version =
+			le_u64 (& bcache, 0x10);
+
+		if & bcache [0x18 .. 0x28] == & BCACHE_MAGIC [..] {
+
+			let data_offset =
+				match version {
+					BCACHE_VERSION_BACKING => Some (BCACHE_DEFAULT_DATA_OFFSET),
+					BCACHE_VERSION_BACKING_WITH_OFFSET => Some (le_u64 (& bcache, 0x78)),
+					_ => None,
+				};
+
+			if let Some (data_offset) = data_offset {
+
+				containers.push (
+					DeviceOffset {
+						offset: offset + data_offset * SECTOR_SIZE,
+						description: "bcache backing device".to_owned (),
+					});
+
+			}
+
+		}
+
+	}
+
+	for & (superblock_offset, version_name) in MD_SUPERBLOCK_OFFSETS.iter () {
+
+		if let Some (md) =
+			read_bytes (
+				reader,
+				offset + superblock_offset,
+				0x100) {
+
+			if le_u32 (& md, 0x00) == MD_MAGIC && le_u32 (& md, 0x04) == 1 {
+
+				containers.push (
+					DeviceOffset {
+						offset: offset + le_u64 (& md, 0x80) * SECTOR_SIZE,
+						description: format! (
+							"md raid {} member",
+							version_name),
+					});
+
+			}
+
+		}
+
+	}
+
+	containers
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use std::env;
+	use std::fs;
+	use std::fs::File;
+	use std::io::Write;
+	use std::process;
+
+	use super::*;
+
+	fn put_le (
+		bytes: & mut [u8],
+		offset: usize,
+		size: usize,
+		value: u64,
+	) {
+
+		for index in 0 .. size {
+			bytes [offset + index] = (value >> (index * 8)) as u8;
+		}
+
+	}
+
+	fn offsets (
+		name: & str,
+		bytes: & [u8],
+		find: fn (& DeviceReader) -> Vec <DeviceOffset>,
+	) -> Vec <(u64, String)> {
+
+		let path =
+			env::temp_dir ().join (
+				format! (
+					"btrfs-fuse-partitions-{}-{}",
+					name,
+					process::id ()));
+
+		File::create (
+			& path,
+		).unwrap ().write_all (
+			bytes,
+		).unwrap ();
+
+		let offsets =
+			find (
+				& DeviceReader::open_at (& path, 0).unwrap (),
+			).into_iter ().map (
+				|device_offset|
+
+				(device_offset.offset, device_offset.description)
+
+			).collect ();
+
+		fs::remove_file (path).unwrap ();
+
+		offsets
+
+	}
+
+	fn mbr (
+		entries: & [(u8, u32)],
+	) -> Vec <u8> {
+
+		let mut bytes: Vec <u8> =
+			vec! [0; SECTOR_SIZE as usize];
+
+		for (entry_index, & (partition_type, start_sector))
+		in entries.iter ().enumerate () {
+
+			let entry_start =
+				MBR_ENTRIES_OFFSET as usize
+					+ entry_index * MBR_ENTRY_SIZE as usize;
+
+			bytes [entry_start + 4] = partition_type;
+
+			put_le (& mut bytes, entry_start + 8, 4, start_sector as u64);
+
+		}
+
+		bytes [MBR_SIGNATURE_OFFSET as usize] = 0x55;
+		bytes [MBR_SIGNATURE_OFFSET as usize + 1] = 0xaa;
+
+		bytes
+
+	}
+
+	fn find_containers (
+		reader: & DeviceReader,
+	) -> Vec <DeviceOffset> {
+
+		containers_find (reader, 0)
+
+	}
+
+	#[ test ]
+	fn finds_mbr_partitions () {
+
+		assert_eq! (
+			offsets (
+				"mbr",
+				& mbr (& [ (0x83, 0x800), (0x05, 0x1000), (0x83, 0x1_0000) ]),
+				partitions_find),
+			vec! [
+				(0x10_0000, "MBR partition 1 (type 0x83)".to_owned ()),
+				(0x200_0000, "MBR partition 3 (type 0x83)".to_owned ()),
+			]);
+
+		let mut unsigned =
+			mbr (& [ (0x83, 0x800) ]);
+
+		unsigned [MBR_SIGNATURE_OFFSET as usize] = 0;
+
+		assert_eq! (
+			offsets ("unsigned", & unsigned, partitions_find),
+			vec! []);
+
+	}
+
+	#[ test ]
+	fn finds_gpt_partitions () {
+
+		let mut bytes =
+			mbr (& [ (MBR_TYPE_GPT_PROTECTIVE, 1) ]);
+
+		bytes.resize (0x600, 0);
+
+		bytes [0x200 .. 0x208].copy_from_slice (GPT_SIGNATURE);
+
+		put_le (& mut bytes, 0x248, 8, 2);
+		put_le (& mut bytes, 0x250, 4, 4);
+		put_le (& mut bytes, 0x254, 4, 0x80);
+
+		bytes [0x400] = 1;
+
+		put_le (& mut bytes, 0x420, 8, 0x800);
+
+		for (char_index, character) in "data".bytes ().enumerate () {
+			bytes [0x438 + char_index * 2] = character;
+		}
+
+		bytes [0x500] = 1;
+
+		put_le (& mut bytes, 0x520, 8, 0x1_0000);
+
+		assert_eq! (
+			offsets ("gpt", & bytes, partitions_find),
+			vec! [
+				(0x10_0000, "GPT partition 1 (data)".to_owned ()),
+				(0x200_0000, "GPT partition 3".to_owned ()),
+			]);
+
+	}
+
+	#[ test ]
+	fn finds_bcache_and_md_containers () {
+
+		let mut bytes: Vec <u8> =
+			vec! [0; 0x1100];
+
+		put_le (& mut bytes, 0x1010, 8, BCACHE_VERSION_BACKING);
+
+		bytes [0x1018 .. 0x1028].copy_from_slice (& BCACHE_MAGIC);
+
+		assert_eq! (
+			offsets ("bcache", & bytes, find_containers),
+			vec! [ (0x2000, "bcache backing device".to_owned ()) ]);
+
+		let mut bytes: Vec <u8> =
+			vec! [0; 0x1100];
+
+		put_le (& mut bytes, 0x1000, 4, MD_MAGIC as u64);
+		put_le (& mut bytes, 0x1004, 4, 1);
+		put_le (& mut bytes, 0x1080, 8, 0x800);
+
+		assert_eq! (
+			offsets ("md", & bytes, find_containers),
+			vec! [ (0x10_0000, "md raid 1.2 member".to_owned ()) ]);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -1,10 +1,13 @@
+use std::cmp;
 use std::collections::HashSet;
 use std::default::Default;
 use std::error::Error;
 use std::ffi::CString;
 use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Cursor;
 use std::io::Seek;
 use std::io::SeekFrom;
@@ -22,7 +25,10 @@ use libc;
 use output::Output;
 
 use super::arguments::*;
+use super::devices::*;
+use super::index::*;
 use super::indexed_filesystem::*;
+use super::partitions::*;
 
 pub fn restore (
 	output: & Output,
@@ -31,48 +37,275 @@ pub fn restore (
 
 	// open filesystem
 
-	let mmap_devices =
-		BtrfsMmapDeviceSet::open (
+	let offsets =
+		device_offsets (
+			output,
+			& command.paths,
+			& command.offsets,
+		) ?;
+
+	let device_set =
+		DeviceSet::open (
 			& command.paths,
+			& offsets,
+			& command.mapfiles,
 		) ?;
 
-	let mut devices =
-		mmap_devices.devices () ?;
+	for reader in device_set.readers () {
+
+		if let Some (image) = reader.image () {
+
+			output_message! (
+				output,
+				"Reading {} as a {}",
+				reader.path ().to_string_lossy (),
+				image.description ());
+
+			if ! image.has_file_data () {
+
+				return Err (
+					format! (
+						"Can't restore from {}, which holds metadata but no \
+						file data",
+						reader.path ().to_string_lossy ()));
+
+			}
+
+		}
+
+	}
+
+	for mapfile in device_set.mapfiles () {
+
+		output_message! (
+			output,
+			"Using mapfile {}, 0x{:x} bytes rescued, {} ranges not rescued",
+			mapfile.path ().to_string_lossy (),
+			mapfile.rescued_size (),
+			mapfile.unrescued ().len ());
+
+	}
+
+	let index =
+		match command.index {
+
+		Some (ref index_path) =>
+			Some (
+				IndexedFilesystem::load_index (
+					output,
+					index_path,
+				) ?),
+
+		None =>
+			None,
+
+	};
+
+	// restore from the index if asked to, or if a root tree or generation was
+	// chosen, which the filesystem trees can't do. otherwise restore from the
+	// filesystem trees if they can be read and are from the same filesystem
+	// as the index, falling back to the index.
+
+	if let Some (ref index) = index {
+
+		if command.from_index
+			|| command.root.is_some ()
+			|| command.max_generation.is_some () {
+
+			return restore_from_index (
+				output,
+				& command,
+				& device_set,
+				index);
+
+		}
+
+	}
+
+	let error =
+		match restore_from_filesystem (
+			output,
+			& command,
+			& device_set,
+			index.as_ref ()) {
+
+		Ok (()) =>
+			return Ok (()),
+
+		Err (error) =>
+			error,
+
+	};
+
+	let index =
+		match index {
+			Some (ref index) => index,
+			None => return Err (error),
+		};
+
+	output_message! (
+		output,
+		"Unable to restore from filesystem trees, using index instead: {}",
+		error);
+
+	restore_from_index (
+		output,
+		& command,
+		& device_set,
+		index)
+
+}
+
+fn restore_from_filesystem <'a> (
+	output: & Output,
+	command: & RestoreCommand,
+	device_set: & 'a DeviceSet,
+	index: Option <& Index>,
+) -> Result <(), String> {
 
-	let filesystem =
-		BtrfsFilesystem::open_try_backups (
+	let (indexed_filesystem, indexed_nodes) =
+		IndexedFilesystem::open_trees (
 			output,
-			& devices,
+			device_set,
+			index,
+			command.include_corrupt,
+			command.subvolume_id,
 		) ?;
 
-	// find subvolume
+	let indexed_filesystem =
+		indexed_filesystem.load_nodes (
+			output,
+			& indexed_nodes);
+
+	// a damaged tree is stitched together from the leaves which were read,
+	// like an index, so that what they hold can still be placed
+
+	if indexed_nodes.num_missing () > 0 {
 
-	let root_item =
-		filesystem.root_item (
+		output_message! (
+			output,
+			"Subvolume {} has {} nodes missing, restoring from the leaves \
+			which could be read",
 			command.subvolume_id,
-		).ok_or (
+			indexed_nodes.num_missing ());
+
+		return restore_subvolume (
+			output,
+			command,
+			device_set,
+			& indexed_filesystem);
+
+	}
 
+	let restore_tree =
+		FilesystemRestoreTree {
+			filesystem: & indexed_filesystem,
+			tree_id: command.subvolume_id,
+		};
+
+	if restore_tree.inode_item (
+		RESTORE_ROOT_DIRECTORY_ID,
+	).is_none () {
+
+		return Err (
 			format! (
-				"Subvolume not found: {}",
-				command.subvolume_id)
+				"Root directory of subvolume {} not found",
+				command.subvolume_id));
+
+	}
+
+	restore_run (
+		output,
+		command,
+		device_set,
+		& restore_tree,
+		RESTORE_ROOT_DIRECTORY_ID)
+
+}
+
+fn restore_from_index <'a> (
+	output: & Output,
+	command: & RestoreCommand,
+	device_set: & 'a DeviceSet,
+	index: & Index,
+) -> Result <(), String> {
 
+	let (indexed_filesystem, indexed_nodes) =
+		IndexedFilesystem::open_index (
+			output,
+			device_set,
+			index,
+			command.include_corrupt,
+			command.max_generation,
+			command.root.map (
+				|root_tree_address|
+
+				(root_tree_address, command.subvolume_id)
+
+			),
 		) ?;
 
-	let filesystem_tree =
-		filesystem.filesystem_tree (
-			command.subvolume_id,
-		).ok_or (
+	let indexed_filesystem =
+		indexed_filesystem.load_nodes (
+			output,
+			& indexed_nodes);
+
+	restore_subvolume (
+		output,
+		command,
+		device_set,
+		& indexed_filesystem)
+
+}
+
+fn restore_subvolume <'a> (
+	output: & Output,
+	command: & RestoreCommand,
+	device_set: & 'a DeviceSet,
+	indexed_filesystem: & 'a IndexedFilesystem <'a>,
+) -> Result <(), String> {
 
+	let indexed_subvolume =
+		indexed_filesystem.subvolume (
+			output,
+			command.subvolume_id);
+
+	if indexed_subvolume.inode_item (
+		RESTORE_ROOT_DIRECTORY_ID,
+	).is_none () {
+
+		return Err (
 			format! (
-				"Subvolume not found: {}",
-				command.subvolume_id)
+				"Root directory of subvolume {} not found, subvolumes found: \
+				{}",
+				command.subvolume_id,
+				indexed_filesystem.subvolume_ids ().iter ().map (
+					|subvolume_id| subvolume_id.to_string (),
+				).collect::<Vec <String>> ().join (", ")));
 
-		) ?;
+	}
+
+	restore_run (
+		output,
+		command,
+		device_set,
+		& indexed_subvolume,
+		RESTORE_ROOT_DIRECTORY_ID)
+
+}
+
+fn restore_run <'a> (
+	output: & Output,
+	command: & RestoreCommand,
+	device_set: & 'a DeviceSet,
+	restore_tree: & 'a (RestoreTree <'a> + 'a),
+	root_object_id: u64,
+) -> Result <(), String> {
 
 	// find object
 
 	let mut child_object_id =
-		root_item.root_object_id ();
+		root_object_id;
 
 	let mut child_type =
 		BTRFS_FT_DIR;
@@ -83,8 +316,8 @@ pub fn restore (
 			continue;
 		}
 
-		let dir_item_entry =
-			filesystem_tree.dir_item_entry (
+		let dir_entry =
+			restore_tree.dir_entry (
 				child_object_id,
 				path_part.as_bytes (),
 			).ok_or (
@@ -96,10 +329,10 @@ pub fn restore (
 			) ?;
 
 		child_object_id =
-			dir_item_entry.child_object_id ();
+			dir_entry.child_object_id;
 
 		child_type =
-			dir_item_entry.child_type ();
+			dir_entry.child_type;
 
 	}
 
@@ -107,13 +340,17 @@ pub fn restore (
 
 	let mut restore_job = RestoreJob {
 
-		filesystem: & filesystem,
-		filesystem_tree: & filesystem_tree,
+		device_set: device_set,
+		restore_tree: restore_tree,
 
 		log: Default::default (),
 
 	};
 
+	restore_log_unreadable_metadata (
+		output,
+		& mut restore_job);
+
 	let output_job =
 		output_job_start! (
 			output,
@@ -128,6 +365,23 @@ pub fn restore (
 		& command.target,
 	);
 
+	// inodes which couldn't be placed are only restored with the whole
+	// subvolume
+
+	let lost_inodes =
+		restore_tree.lost_inodes ();
+
+	if child_object_id == root_object_id && ! lost_inodes.is_empty () {
+
+		restore_lost_inodes (
+			output,
+			& mut restore_job,
+			& lost_inodes,
+			& command.source,
+			& command.target);
+
+	}
+
 	output_job.complete ();
 
 	// print summary
@@ -142,6 +396,128 @@ pub fn restore (
 
 }
 
+// restores each inode which can't be reached from the root directory into
+// lost+found, named by its inode number. the subvolume may have its own
+// lost+found, so the directory is given the first name which isn't taken.
+
+fn restore_lost_inodes <'a> (
+	output: & Output,
+	restore_job: & mut RestoreJob,
+	lost_inodes: & [u64],
+	source: & Path,
+	target: & Path,
+) {
+
+	let (source, target) =
+		match restore_lost_found (
+			source,
+			target) {
+
+		Ok (source_and_target) =>
+			source_and_target,
+
+		Err (error) => {
+
+			restore_log_error (
+				output,
+				restore_job,
+				error,
+				source,
+				target);
+
+			return;
+
+		},
+
+	};
+
+	output_message! (
+		output,
+		"Restoring {} inodes which can't be reached from the root directory \
+		into {}",
+		lost_inodes.len (),
+		target.to_string_lossy ());
+
+	for & object_id in lost_inodes.iter () {
+
+		let child_type =
+			match restore_job.restore_tree.inode_item (
+				object_id) {
+
+			Some (inode_item) =>
+				restore_file_type (
+					inode_item.st_mode ()),
+
+			None =>
+				continue,
+
+		};
+
+		restore_item (
+			output,
+			restore_job,
+			child_type,
+			object_id,
+			& source.join (object_id.to_string ()),
+			& target.join (object_id.to_string ()),
+		);
+
+	}
+
+}
+
+// creates the first of lost+found, lost+found.1 and so on which doesn't
+// exist yet, and returns its source and target paths
+
+fn restore_lost_found (
+	source: & Path,
+	target: & Path,
+) -> Result <(PathBuf, PathBuf), String> {
+
+	for suffix in 0 .. RESTORE_LOST_FOUND_MAX_SUFFIX {
+
+		let name =
+			if suffix == 0 {
+				"lost+found".to_owned ()
+			} else {
+				format! ("lost+found.{}", suffix)
+			};
+
+		let lost_found_target =
+			target.join (
+				& name);
+
+		match fs::create_dir (
+			& lost_found_target) {
+
+			Ok (()) =>
+				return Ok ((
+					source.join (& name),
+					lost_found_target,
+				)),
+
+			Err (ref error)
+			if error.kind () == io::ErrorKind::AlreadyExists =>
+				continue,
+
+			Err (error) =>
+				return Err (
+					format! (
+						"Error creating directory {}: {}",
+						lost_found_target.to_string_lossy (),
+						error.description ())),
+
+		}
+
+	}
+
+	Err (
+		format! (
+			"Unable to find an unused name for lost+found in {}",
+			target.to_string_lossy ()))
+
+}
+
 fn restore_summary (
 	output: & Output,
 	log: & RestoreLog,
@@ -177,6 +553,62 @@ fn restore_summary (
 
 	}
 
+	if ! log.unreadable_metadata.is_empty () {
+
+		output_message! (
+			output,
+			"Read zeros from {} unreadable ranges of metadata, so some \
+			directories, symlinks and extended attributes may be missing or \
+			incomplete",
+			log.unreadable_metadata.len ());
+
+	}
+
+	if ! log.unreadable.is_empty () {
+
+		output_message! (
+			output,
+			"Read zeros from unreadable ranges in {} files:",
+			log.unreadable.len ());
+
+		for restore_unreadable in log.unreadable.iter () {
+
+			output_message! (
+				output,
+				"  {}",
+				restore_unreadable.target.to_string_lossy ());
+
+		}
+
+	}
+
+	if ! log.unrescued.is_empty () {
+
+		output_message! (
+			output,
+			"Restored {} files with data ddrescue has not rescued:",
+			log.unrescued.len ());
+
+		for restore_unrescued in log.unrescued.iter () {
+
+			output_message! (
+				output,
+				"  {} ({})",
+				restore_unrescued.target.to_string_lossy (),
+				restore_unrescued.file_ranges.iter ().map (
+					|& (start, end)|
+
+					format! (
+						"0x{:x}-0x{:x}",
+						start,
+						end)
+
+				).collect::<Vec <String>> ().join (", "));
+
+		}
+
+	}
+
 	if ! log.errors.is_empty () {
 
 		output_message! (
@@ -216,6 +648,14 @@ fn restore_item <'a> (
 
 	}
 
+	// anything read since the last item was logged, such as a directory's
+	// own metadata after its children, belongs to this item
+
+	restore_log_unreadable (
+		output,
+		restore_job,
+		target);
+
 }
 
 fn restore_item_real <'a> (
@@ -311,26 +751,29 @@ fn restore_children <'a> (
 
 	// iterate children
 
-	for dir_index
-	in restore_job.filesystem_tree.dir_indexes (
+	let restore_tree =
+		restore_job.restore_tree;
+
+	for dir_entry
+	in restore_tree.dir_entries (
 		directory_id,
 	) {
 
 		let source =
 			source.join (
 				OsString::from_vec (
-					dir_index.name ().to_vec ()));
+					dir_entry.name.clone ()));
 
 		let target =
 			target.join (
 				OsString::from_vec (
-					dir_index.name ().to_vec ()));
+					dir_entry.name));
 
 		restore_item (
 			output,
 			restore_job,
-			dir_index.child_type (),
-			dir_index.child_object_id (),
+			dir_entry.child_type,
+			dir_entry.child_object_id,
 			& source,
 			& target,
 		);
@@ -355,7 +798,7 @@ fn restore_regular_file <'a> (
 	restore_job.log.num_files += 1;
 
 	let inode_item =
-		restore_job.filesystem_tree.inode_item (
+		restore_job.restore_tree.inode_item (
 			object_id,
 		).ok_or (
 
@@ -391,6 +834,11 @@ fn restore_regular_file <'a> (
 		target,
 	) ?;
 
+	restore_log_unreadable (
+		output,
+		restore_job,
+		target);
+
 	// set metadata
 
 	restore_metadata (
@@ -419,8 +867,11 @@ fn restore_file_contents <'a, FileType: Write + Seek> (
 
 	let mut file_position: u64 = 0;
 
+	let restore_tree =
+		restore_job.restore_tree;
+
 	for extent_data
-	in restore_job.filesystem_tree.extent_datas (
+	in restore_tree.extent_datas (
 		inode_item.object_id (),
 	) {
 
@@ -440,6 +891,7 @@ fn restore_file_contents <'a, FileType: Write + Seek> (
 			output,
 			restore_job,
 			& extent_data,
+			file_position,
 			inode_item.st_size () - file_position,
 			file,
 			source,
@@ -479,6 +931,7 @@ fn restore_extent_data <'a, FileType: Write + Seek> (
 	output: & Output,
 	restore_job: & mut RestoreJob,
 	extent_data: & BtrfsExtentData,
+	file_position: u64,
 	file_size_remaining: u64,
 	file: & mut FileType,
 	source: & Path,
@@ -524,16 +977,27 @@ fn restore_extent_data <'a, FileType: Write + Seek> (
 
 			if extent_data.extent_logical_address () != 0 {
 
-				let raw_data =
-					restore_job.filesystem.slice_at_logical_address (
+				let (device_index, raw_start, raw_data) =
+					restore_job.restore_tree.read_logical (
 						extent_data.extent_logical_address (),
 						extent_data.extent_size () as usize,
 					) ?;
 
+				restore_log_unrescued (
+					output,
+					restore_job,
+					extent_data,
+					device_index,
+					raw_start,
+					raw_data.len () as u64,
+					file_position,
+					expected_data_size,
+					target);
+
 				let uncompressed_data =
 					btrfs_decompress_pages (
 						extent_data.compression (),
-						raw_data,
+						& raw_data,
 						extent_data.extent_data_size (),
 					) ?;
 
@@ -632,7 +1096,7 @@ fn restore_directory <'a> (
 	restore_job.log.num_directories += 1;
 
 	let inode_item =
-		restore_job.filesystem_tree.inode_item (
+		restore_job.restore_tree.inode_item (
 			object_id,
 		).ok_or (
 
@@ -787,7 +1251,7 @@ fn restore_symlink <'a> (
 	restore_job.log.num_symlinks += 1;
 
 	let inode_item =
-		restore_job.filesystem_tree.inode_item (
+		restore_job.restore_tree.inode_item (
 			object_id,
 		).ok_or (
 
@@ -910,7 +1374,7 @@ fn restore_device <'a> (
 ) -> Result <(), String> {
 
 	let inode_item =
-		restore_job.filesystem_tree.inode_item (
+		restore_job.restore_tree.inode_item (
 			object_id,
 		).ok_or (
 
@@ -976,7 +1440,7 @@ fn restore_socket <'a> (
 	restore_job.log.num_sockets += 1;
 
 	let inode_item =
-		restore_job.filesystem_tree.inode_item (
+		restore_job.restore_tree.inode_item (
 			object_id,
 		).ok_or (
 
@@ -1032,6 +1496,182 @@ fn restore_log_error (
 
 }
 
+// records any unreadable ranges which were read, as zeros, while restoring
+// a file, directory or other item
+
+fn restore_log_unreadable (
+	output: & Output,
+	restore_job: & mut RestoreJob,
+	target: & Path,
+) {
+
+	let unreadable_ranges =
+		restore_job.device_set.take_unreadable_ranges ();
+
+	if unreadable_ranges.is_empty () {
+		return;
+	}
+
+	for unreadable_range in unreadable_ranges.iter () {
+
+		output_message! (
+			output,
+			"Unreadable range on {} at 0x{:x} to 0x{:x} in {}, restored as \
+			zeros",
+			unreadable_range.path.to_string_lossy (),
+			unreadable_range.range.start,
+			unreadable_range.range.end,
+			target.to_string_lossy ());
+
+	}
+
+	restore_job.log.unreadable.push (
+		RestoreUnreadable {
+			target: target.to_owned (),
+			ranges: unreadable_ranges,
+		}
+	);
+
+}
+
+// records any unreadable ranges which were read, as zeros, while opening the
+// filesystem. these were tree nodes, so the directories, symlinks and
+// extended attributes which were stored in them may be missing or incomplete.
+
+fn restore_log_unreadable_metadata (
+	output: & Output,
+	restore_job: & mut RestoreJob,
+) {
+
+	let unreadable_ranges =
+		restore_job.device_set.take_unreadable_ranges ();
+
+	for unreadable_range in unreadable_ranges.iter () {
+
+		output_message! (
+			output,
+			"Unreadable range on {} at 0x{:x} to 0x{:x} in filesystem \
+			metadata, read as zeros",
+			unreadable_range.path.to_string_lossy (),
+			unreadable_range.range.start,
+			unreadable_range.range.end);
+
+	}
+
+	restore_job.log.unreadable_metadata =
+		unreadable_ranges;
+
+}
+
+// records any part of an extent which ddrescue has not rescued, as a range
+// in the restored file. for compressed extents we can't tell which part of
+// the file is affected, so we record all of it.
+
+fn restore_log_unrescued (
+	output: & Output,
+	restore_job: & mut RestoreJob,
+	extent_data: & BtrfsExtentData,
+	device_index: usize,
+	raw_start: u64,
+	raw_size: u64,
+	file_position: u64,
+	file_size: u64,
+	target: & Path,
+) {
+
+	let device_ranges =
+		restore_job.device_set.unrescued_ranges (
+			device_index,
+			raw_start,
+			raw_start + raw_size);
+
+	if device_ranges.is_empty () {
+		return;
+	}
+
+	let file_end =
+		file_position + file_size;
+
+	let mut file_ranges: Vec <(u64, u64)> =
+		Vec::new ();
+
+	if extent_data.compression () == 0 {
+
+		// map each range through the extent, then clip it to the part of the
+		// extent which is in the file
+
+		let file_base =
+			file_position as i64
+				- extent_data.extent_data_offset () as i64;
+
+		for device_range in device_ranges.iter () {
+
+			let start =
+				file_base + (device_range.range.start - raw_start) as i64;
+
+			let end =
+				file_base + (device_range.range.end - raw_start) as i64;
+
+			let start = cmp::max (start, file_position as i64) as u64;
+			let end = cmp::min (end, file_end as i64) as u64;
+
+			if start < end {
+				file_ranges.push ((start, end));
+			}
+
+		}
+
+	} else {
+
+		file_ranges.push (
+			(file_position, file_end));
+
+	}
+
+	if file_ranges.is_empty () {
+		return;
+	}
+
+	for & (start, end) in file_ranges.iter () {
+
+		output_message! (
+			output,
+			"Data in {} at 0x{:x} to 0x{:x} was not rescued by ddrescue",
+			target.to_string_lossy (),
+			start,
+			end);
+
+	}
+
+	// add to the entry for this file, if there is one
+
+	if let Some (restore_unrescued) =
+		restore_job.log.unrescued.last_mut () {
+
+		if restore_unrescued.target.as_path () == target {
+
+			restore_unrescued.file_ranges.extend (
+				file_ranges);
+
+			restore_unrescued.device_ranges.extend (
+				device_ranges);
+
+			return;
+
+		}
+
+	}
+
+	restore_job.log.unrescued.push (
+		RestoreUnrescued {
+			target: target.to_owned (),
+			file_ranges: file_ranges,
+			device_ranges: device_ranges,
+		}
+	);
+
+}
+
 fn c_string (
 	bytes: & [u8],
 ) -> CString {
@@ -1044,17 +1684,337 @@ fn c_string (
 
 struct RestoreJob <'a> {
 
-	filesystem: & 'a BtrfsFilesystem <'a>,
-	filesystem_tree: & 'a BtrfsFilesystemTree <'a>,
+	device_set: & 'a DeviceSet,
+	restore_tree: & 'a (RestoreTree <'a> + 'a),
 
 	log: RestoreLog,
 
 }
 
+// the root directory of a subvolume, which is also the first inode
+
+const RESTORE_ROOT_DIRECTORY_ID: u64 = 256;
+const RESTORE_DIR_ITEM_TYPE: u8 = 84;
+const RESTORE_DIR_INDEX_TYPE: u8 = 96;
+const RESTORE_LOST_FOUND_MAX_SUFFIX: u64 = 1000;
+
+// where files are restored from. this is either a subvolume whose tree was
+// walked from the superblock, or one stitched together from the leaves of a
+// damaged tree or an index.
+
+trait RestoreTree <'a> {
+
+	fn inode_item (
+		& self,
+		object_id: u64,
+	) -> Option <BtrfsInodeItem <'a>>;
+
+	fn dir_entries (
+		& self,
+		directory_id: u64,
+	) -> Vec <RestoreDirEntry>;
+
+	fn dir_entry (
+		& self,
+		directory_id: u64,
+		name: & [u8],
+	) -> Option <RestoreDirEntry> {
+
+		self.dir_entries (
+			directory_id,
+		).into_iter ().find (
+			|dir_entry|
+
+			dir_entry.name.as_slice () == name
+
+		)
+
+	}
+
+	fn extent_datas (
+		& self,
+		object_id: u64,
+	) -> Vec <BtrfsExtentData <'a>>;
+
+	// reads file data, returning the index of the device it was read from
+	// in the set, and the position on that device
+
+	fn read_logical (
+		& self,
+		logical_address: u64,
+		size: usize,
+	) -> Result <(usize, u64, Vec <u8>), String>;
+
+	// inodes which can't be reached from the root directory, to be restored
+	// into lost+found
+
+	fn lost_inodes (
+		& self,
+	) -> Vec <u64> {
+		Vec::new ()
+	}
+
+}
+
+struct RestoreDirEntry {
+	name: Vec <u8>,
+	child_type: u8,
+	child_object_id: u64,
+}
+
+// the directory entry type for an inode's mode, for inodes found without a
+// directory entry
+
+fn restore_file_type (
+	mode: libc::mode_t,
+) -> u8 {
+
+	match mode & libc::S_IFMT {
+		libc::S_IFREG => BTRFS_FT_REG_FILE,
+		libc::S_IFDIR => BTRFS_FT_DIR,
+		libc::S_IFLNK => BTRFS_FT_SYMLINK,
+		libc::S_IFCHR => BTRFS_FT_CHRDEV,
+		libc::S_IFBLK => BTRFS_FT_BLKDEV,
+		libc::S_IFSOCK => BTRFS_FT_SOCK,
+		_ => 0,
+	}
+
+}
+
+// a subvolume whose whole tree was walked from the superblock, so each key
+// is held once, and items are looked up by key as the filesystem itself
+// would, without choosing between copies
+
+struct FilesystemRestoreTree <'a> {
+	filesystem: & 'a IndexedFilesystem <'a>,
+	tree_id: u64,
+}
+
+impl <'a> RestoreTree <'a> for FilesystemRestoreTree <'a> {
+
+	fn inode_item (
+		& self,
+		object_id: u64,
+	) -> Option <BtrfsInodeItem <'a>> {
+
+		self.filesystem.inode_items_index.get (
+			& (self.tree_id, object_id),
+		).and_then (
+			|inode_items| inode_items.first (),
+		).map (
+			|inode_item| inode_item.item,
+		)
+
+	}
+
+	fn dir_entries (
+		& self,
+		directory_id: u64,
+	) -> Vec <RestoreDirEntry> {
+
+		let mut dir_indexes: Vec <& IndexedItem <BtrfsDirItemEntry <'a>>> =
+			self.filesystem.dir_item_entries_index.get (
+				& (self.tree_id, directory_id),
+			).into_iter ().flat_map (
+				|dir_item_entries| dir_item_entries.iter (),
+			).filter (
+				|dir_item_entry|
+
+				dir_item_entry.key.item_type == RESTORE_DIR_INDEX_TYPE
+
+			).collect ();
+
+		dir_indexes.sort_by_key (
+			|dir_index| dir_index.key.offset);
+
+		dir_indexes.into_iter ().map (
+			|dir_index|
+
+			RestoreDirEntry {
+				name: dir_index.item.name ().to_vec (),
+				child_type: dir_index.item.child_type (),
+				child_object_id: dir_index.item.child_object_id (),
+			}
+
+		).collect ()
+
+	}
+
+	fn dir_entry (
+		& self,
+		directory_id: u64,
+		name: & [u8],
+	) -> Option <RestoreDirEntry> {
+
+		self.filesystem.dir_item_entries_index.get (
+			& (self.tree_id, directory_id),
+		).into_iter ().flat_map (
+			|dir_item_entries| dir_item_entries.iter (),
+		).find (
+			|dir_item_entry|
+
+			dir_item_entry.key.item_type == RESTORE_DIR_ITEM_TYPE
+				&& dir_item_entry.item.name () == name
+
+		).map (
+			|dir_item_entry|
+
+			RestoreDirEntry {
+				name: name.to_vec (),
+				child_type: dir_item_entry.item.child_type (),
+				child_object_id: dir_item_entry.item.child_object_id (),
+			}
+
+		)
+
+	}
+
+	fn extent_datas (
+		& self,
+		object_id: u64,
+	) -> Vec <BtrfsExtentData <'a>> {
+
+		let mut extent_datas: Vec <BtrfsExtentData <'a>> =
+			self.filesystem.extent_datas_index.get (
+				& (self.tree_id, object_id),
+			).into_iter ().flat_map (
+				|extent_datas| extent_datas.iter (),
+			).map (
+				|extent_data| extent_data.item,
+			).collect ();
+
+		extent_datas.sort_by_key (
+			|extent_data| extent_data.offset ());
+
+		extent_datas
+
+	}
+
+	fn read_logical (
+		& self,
+		logical_address: u64,
+		size: usize,
+	) -> Result <(usize, u64, Vec <u8>), String> {
+
+		self.filesystem.read_logical (
+			logical_address,
+			size)
+
+	}
+
+}
+
+// an index keeps every copy of every item, so the subvolume is stitched
+// together from the best leaf holding each key. inodes whose directory
+// entries were lost are listed in their parent using their own inode refs.
+
+impl <'a> RestoreTree <'a> for IndexedSubvolume <'a> {
+
+	fn inode_item (
+		& self,
+		object_id: u64,
+	) -> Option <BtrfsInodeItem <'a>> {
+
+		IndexedSubvolume::inode_item (
+			self,
+			object_id)
+
+	}
+
+	fn dir_entries (
+		& self,
+		directory_id: u64,
+	) -> Vec <RestoreDirEntry> {
+
+		let mut dir_entries: Vec <RestoreDirEntry> =
+			self.dir_indexes (
+				directory_id,
+			).into_iter ().map (
+				|dir_item_entry|
+
+				RestoreDirEntry {
+					name: dir_item_entry.name ().to_vec (),
+					child_type: dir_item_entry.child_type (),
+					child_object_id: dir_item_entry.child_object_id (),
+				}
+
+			).chain (
+				self.inode_ref_children (
+					directory_id,
+				).into_iter ().filter_map (
+					|inode_ref|
+
+					IndexedSubvolume::inode_item (
+						self,
+						inode_ref.object_id,
+					).map (
+						|inode_item|
+
+						RestoreDirEntry {
+							name: inode_ref.name.to_vec (),
+							child_type: restore_file_type (
+								inode_item.st_mode ()),
+							child_object_id: inode_ref.object_id,
+						}
+
+					)
+
+				),
+			).collect ();
+
+		dir_entries.sort_by (
+			|left, right|
+
+			left.name.cmp (& right.name)
+
+		);
+
+		dir_entries
+
+	}
+
+	fn extent_datas (
+		& self,
+		object_id: u64,
+	) -> Vec <BtrfsExtentData <'a>> {
+
+		IndexedSubvolume::extent_datas (
+			self,
+			object_id)
+
+	}
+
+	fn read_logical (
+		& self,
+		logical_address: u64,
+		size: usize,
+	) -> Result <(usize, u64, Vec <u8>), String> {
+
+		self.filesystem ().read_logical (
+			logical_address,
+			size)
+
+	}
+
+	fn lost_inodes (
+		& self,
+	) -> Vec <u64> {
+
+		IndexedSubvolume::lost_inodes (
+			self,
+		).to_vec ()
+
+	}
+
+}
+
 #[ derive (Default) ]
 struct RestoreLog {
 
 	errors: Vec <RestoreError>,
+	unreadable: Vec <RestoreUnreadable>,
+	unreadable_metadata: Vec <DeviceUnreadableRange>,
+	unrescued: Vec <RestoreUnrescued>,
 
 	num_files: u64,
 	num_directories: u64,
@@ -1079,4 +2039,21 @@ struct RestoreError {
 
 }
 
+struct RestoreUnreadable {
+
+	target: PathBuf,
+
+	ranges: Vec <DeviceUnreadableRange>,
+
+}
+
+struct RestoreUnrescued {
+
+	target: PathBuf,
+
+	file_ranges: Vec <(u64, u64)>,
+	device_ranges: Vec <DeviceUnreadableRange>,
+
+}
+
 // ex: noet ts=4 filetype=rust
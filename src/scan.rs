@@ -1,11 +1,18 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::collections::HashSet;
-
-use btrfs::diskformat::*;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
 
 use output::Output;
 
 use super::arguments::*;
+use super::chunks::*;
+use super::devices::*;
 use super::indexed_filesystem::*;
+use super::partitions::*;
+use super::trees::*;
 
 pub fn scan (
 	output: & Output,
@@ -14,26 +21,84 @@ pub fn scan (
 
 	// open filesystem
 
-	let mmap_devices =
-		BtrfsMmapDeviceSet::open (
+	let offsets =
+		device_offsets (
+			output,
+			& command.paths,
+			& command.offsets,
+		) ?;
+
+	let device_set =
+		DeviceSet::open (
 			& command.paths,
+			& offsets,
+			& command.mapfiles,
 		) ?;
 
-	let devices =
-		mmap_devices.devices () ?;
+	for reader in device_set.readers () {
+
+		if let Some (image) = reader.image () {
+
+			output_message! (
+				output,
+				"Reading {} as a {}",
+				reader.path ().to_string_lossy (),
+				image.description ());
+
+		}
+
+	}
+
+	for mapfile in device_set.mapfiles () {
+
+		output_message! (
+			output,
+			"Using mapfile {}, 0x{:x} bytes rescued, {} ranges not rescued",
+			mapfile.path ().to_string_lossy (),
+			mapfile.rescued_size (),
+			mapfile.unrescued ().len ());
+
+	}
+
+	let index =
+		match command.index {
 
-	let filesystem =
-		BtrfsFilesystem::open_try_backups (
+		Some (ref index_path) =>
+			Some (
+				IndexedFilesystem::load_index (
+					output,
+					index_path,
+				) ?),
+
+		None =>
+			None,
+
+	};
+
+	let (filesystem, root_tree_roots) =
+		IndexedFilesystem::open_superblock (
 			output,
-			& devices,
+			& device_set,
+			index.as_ref (),
+			command.include_corrupt,
+			None,
+		) ?;
+
+	let (subvolume_roots, root_backrefs) =
+		scan_root_tree (
+			output,
+			& filesystem,
+			& root_tree_roots,
 		) ?;
 
 	// print out subvolumes
 
-	let default_subvolume_root_item =
-		filesystem.default_subvolume_root_item ().ok_or (
-			"No default subvolume root item"
-		) ?;
+	if ! subvolume_roots.contains_key (& TREE_FS_TREE_ID) {
+
+		return Err (
+			"No default subvolume root item".to_owned ());
+
+	}
 
 	output_message! (
 		output,
@@ -43,18 +108,39 @@ pub fn scan (
 		output,
 		"  ROOT (5)");
 
-	for root_backref in filesystem.subvolume_root_backrefs () {
+	let mut subvolume_paths =
+		ScanSubvolumePaths {
+			filesystem: & filesystem,
+			subvolume_roots: & subvolume_roots,
+			root_backrefs: & root_backrefs,
+			inode_refs: HashMap::new (),
+		};
+
+	for root_backref in root_backrefs.values () {
 
 		let path =
-			filesystem.subvolume_path (
-				& root_backref,
+			subvolume_paths.path (
+				root_backref.tree_id,
 			) ?;
 
 		output_message! (
 			output,
 			"  {} ({})",
 			path.to_string_lossy (),
-			root_backref.object_id ());
+			root_backref.tree_id);
+
+	}
+
+	// report unreadable ranges
+
+	for unreadable_range in device_set.take_unreadable_ranges () {
+
+		output_message! (
+			output,
+			"Unreadable range on {} at 0x{:x} to 0x{:x}, read as zeros",
+			unreadable_range.path.to_string_lossy (),
+			unreadable_range.range.start,
+			unreadable_range.range.end);
 
 	}
 
@@ -64,22 +150,289 @@ pub fn scan (
 
 }
 
+// walks the most recent root tree which can be read, and returns the root of
+// each subvolume, and the backref which links each one into its parent
+
+fn scan_root_tree (
+	output: & Output,
+	filesystem: & IndexedFilesystem,
+	root_tree_roots: & [TreeRoot],
+) -> Result <(HashMap <u64, TreeRoot>, BTreeMap <u64, TreeRootBackref>), String> {
+
+	for root_tree_root in root_tree_roots.iter () {
+
+		let mut subvolume_roots: HashMap <u64, TreeRoot> =
+			HashMap::new ();
+
+		let mut root_backrefs: BTreeMap <u64, TreeRootBackref> =
+			BTreeMap::new ();
+
+		let (nodes_reached, num_missing) =
+			tree_walk (
+				root_tree_root,
+				|logical_address, generation|
+
+				filesystem.read_node (
+					logical_address,
+					Some (generation)),
+
+				|leaf_bytes| {
+
+				for tree_root in tree_root_items (leaf_bytes) {
+
+					if tree_is_subvolume (tree_root.tree_id) {
+
+						subvolume_roots.insert (
+							tree_root.tree_id,
+							tree_root);
+
+					}
+
+				}
+
+				for root_backref in tree_root_backrefs (leaf_bytes) {
+
+					root_backrefs.insert (
+						root_backref.tree_id,
+						root_backref);
+
+				}
+
+			});
+
+		if nodes_reached.is_empty () {
+
+			output_message! (
+				output,
+				"Unable to read root tree at 0x{:x} generation {}",
+				root_tree_root.logical_address,
+				root_tree_root.generation);
+
+			continue;
+
+		}
+
+		if num_missing > 0 {
+
+			output_message! (
+				output,
+				"Root tree at 0x{:x} generation {} has {} missing nodes, some \
+				subvolumes may not be listed",
+				root_tree_root.logical_address,
+				root_tree_root.generation,
+				num_missing);
+
+		}
+
+		return Ok ((
+			subvolume_roots,
+			root_backrefs,
+		));
+
+	}
+
+	Err (
+		"Unable to read any root tree".to_owned ())
+
+}
+
+// works out where each subvolume is, from the backref linking it into its
+// parent and the inode refs of the directories leading to it there. the inode
+// refs of each parent subvolume are read once, when first needed.
+
+struct ScanSubvolumePaths <'a, 'b: 'a> {
+	filesystem: & 'a IndexedFilesystem <'b>,
+	subvolume_roots: & 'a HashMap <u64, TreeRoot>,
+	root_backrefs: & 'a BTreeMap <u64, TreeRootBackref>,
+	inode_refs: HashMap <u64, HashMap <u64, (u64, Vec <u8>)>>,
+}
+
+impl <'a, 'b> ScanSubvolumePaths <'a, 'b> {
+
+	fn path (
+		& mut self,
+		tree_id: u64,
+	) -> Result <PathBuf, String> {
+
+		let root_backrefs =
+			self.root_backrefs;
+
+		let mut parts: Vec <Vec <u8>> =
+			Vec::new ();
+
+		let mut tree_id =
+			tree_id;
+
+		while tree_id != TREE_FS_TREE_ID {
+
+			if parts.len () > SCAN_MAX_PATH_PARTS {
+
+				return Err (
+					format! (
+						"Subvolume {} is nested too deeply",
+						tree_id));
+
+			}
+
+			let root_backref =
+				root_backrefs.get (
+					& tree_id,
+				).ok_or_else (
+					||
+
+					format! (
+						"No root backref for subvolume {}",
+						tree_id)
+
+				) ?;
+
+			parts.push (
+				root_backref.name.clone ());
+
+			let mut dir_id =
+				root_backref.dir_id;
+
+			let tree_inode_refs =
+				self.tree_inode_refs (
+					root_backref.parent_tree_id,
+				) ?;
+
+			while dir_id != SCAN_ROOT_DIRECTORY_ID {
+
+				if parts.len () > SCAN_MAX_PATH_PARTS {
+
+					return Err (
+						format! (
+							"Directory {} in subvolume {} is nested too deeply",
+							dir_id,
+							root_backref.parent_tree_id));
+
+				}
+
+				let & (parent_dir_id, ref name) =
+					tree_inode_refs.get (
+						& dir_id,
+					).ok_or_else (
+						||
+
+						format! (
+							"Unable to find directory {} in subvolume {}",
+							dir_id,
+							root_backref.parent_tree_id)
+
+					) ?;
+
+				parts.push (
+					name.clone ());
+
+				dir_id =
+					parent_dir_id;
+
+			}
+
+			tree_id =
+				root_backref.parent_tree_id;
+
+		}
+
+		Ok (
+			parts.into_iter ().rev ().map (
+				|part| PathBuf::from (OsString::from_vec (part)),
+			).collect ())
+
+	}
+
+	// the parent and name of every inode in a subvolume, from its inode refs
+
+	fn tree_inode_refs (
+		& mut self,
+		tree_id: u64,
+	) -> Result <& HashMap <u64, (u64, Vec <u8>)>, String> {
+
+		if ! self.inode_refs.contains_key (& tree_id) {
+
+			let tree_root =
+				* self.subvolume_roots.get (
+					& tree_id,
+				).ok_or_else (
+					||
+
+					format! (
+						"No root item for subvolume {}",
+						tree_id)
+
+				) ?;
+
+			let mut tree_inode_refs: HashMap <u64, (u64, Vec <u8>)> =
+				HashMap::new ();
+
+			let filesystem =
+				self.filesystem;
+
+			tree_walk (
+				& tree_root,
+				|logical_address, generation|
+
+				filesystem.read_node (
+					logical_address,
+					Some (generation)),
+
+				|leaf_bytes| {
+
+				for (key, data) in chunks_leaf_items (leaf_bytes) {
+
+					if key.item_type != INDEXED_INODE_REF_TYPE
+						&& key.item_type != INDEXED_INODE_EXTREF_TYPE {
+
+						continue;
+
+					}
+
+					for inode_ref in indexed_inode_refs (key, data) {
+
+						tree_inode_refs.entry (
+							inode_ref.object_id,
+						).or_insert (
+							(inode_ref.parent_object_id, inode_ref.name.to_vec ()));
+
+					}
+
+				}
+
+			});
+
+			self.inode_refs.insert (
+				tree_id,
+				tree_inode_refs);
+
+		}
+
+		Ok (& self.inode_refs [& tree_id])
+
+	}
+
+}
+
+const SCAN_ROOT_DIRECTORY_ID: u64 = 256;
+const SCAN_MAX_PATH_PARTS: usize = 0x1000;
+
 fn print_roots (
-	indexed_filesystem: & IndexedFilesystem,
+	indexed_subvolume: & IndexedSubvolume,
 	output: & Output,
 ) {
 
 	// find parent dir entries
 
 	let root_object_ids: HashSet <u64> =
-		indexed_filesystem.dir_item_entries_recent ().values ().filter (
-			|&& dir_item_entry|
+		indexed_subvolume.dir_item_entries ().iter ().filter (
+			|dir_item_entry|
 
-			! indexed_filesystem.dir_item_entries_recent ().contains_key (
-				& dir_item_entry.object_id ())
+			indexed_subvolume.dir_item_entry (
+				dir_item_entry.object_id (),
+			).is_none ()
 
 		).map (
-			|& dir_item_entry|
+			|dir_item_entry|
 
 			dir_item_entry.object_id ()
 
@@ -95,7 +448,7 @@ fn print_roots (
 				root_object_id));
 
 		print_tree (
-			indexed_filesystem,
+			indexed_subvolume,
 			output,
 			"  ",
 			root_object_id,
@@ -106,46 +459,36 @@ fn print_roots (
 }
 
 fn print_tree (
-	indexed_filesystem: & IndexedFilesystem,
+	indexed_subvolume: & IndexedSubvolume,
 	output: & Output,
 	indent: & str,
 	object_id: u64,
 	max_depth: u64,
 ) {
 
-	if let Some (child_object_ids) =
-		indexed_filesystem.dir_item_entries_by_parent ().get (
-			& object_id) {
+	let next_indent =
+		format! (
+			"{}  ",
+			indent);
 
-		let next_indent =
-			format! (
-				"{}  ",
-				indent);
+	for child_dir_item_entry
+	in indexed_subvolume.dir_indexes (object_id) {
 
-		for child_object_id in child_object_ids {
-
-			let child_dir_item_entry =
-				indexed_filesystem.dir_item_entries_recent ().get (
-					child_object_id,
-				).unwrap ();
-
-			output.message_format (
-				format_args! (
-					"{}{}",
-					indent,
-					String::from_utf8_lossy (
-						child_dir_item_entry.name ())));
-
-			if max_depth > 0 {
-
-				print_tree (
-					indexed_filesystem,
-					output,
-					& next_indent,
-					* child_object_id,
-					max_depth - 1);
-
-			}
+		output.message_format (
+			format_args! (
+				"{}{}",
+				indent,
+				String::from_utf8_lossy (
+					child_dir_item_entry.name ())));
+
+		if max_depth > 0 {
+
+			print_tree (
+				indexed_subvolume,
+				output,
+				& next_indent,
+				child_dir_item_entry.child_object_id (),
+				max_depth - 1);
 
 		}
 
@@ -0,0 +1,530 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use btrfs::diskformat::*;
+
+use crc::Hasher32;
+use crc::crc32;
+
+use output::Output;
+
+use uuid::Uuid;
+
+use arguments::*;
+use bytes::*;
+use checksum::*;
+use devices::*;
+use index::*;
+
+const INFER_WINDOWS: u64 = 64;
+const INFER_WINDOW_SIZE: u64 = 0x100_0000;
+const INFER_MAX_CANDIDATES: u64 = 0x1000;
+
+const INFER_NODE_SIZES: [u32; 5] = [
+	0x1000,
+	0x2000,
+	0x4000,
+	0x8000,
+	0x1_0000,
+];
+
+// the sector size can't be seen in a node header, and every node is aligned
+// to at least 4K, so that is what we scan with. a smaller step than the real
+// sector size only makes the scan slower.
+
+const INFER_SECTOR_SIZE: u32 = 0x1000;
+
+#[ derive (Clone, Copy, Debug) ]
+pub struct ScanParameters {
+	pub fs_uuid: [u8; 16],
+	pub node_size: u32,
+	pub sector_size: u32,
+	pub csum_type: u16,
+	pub device_id: u64,
+}
+
+impl ScanParameters {
+
+	pub fn fs_uuid_string (
+		& self,
+	) -> String {
+
+		Uuid::from_bytes (
+			& self.fs_uuid,
+		).unwrap ().hyphenated ().to_string ()
+
+	}
+
+}
+
+// works out filesystem parameters for a device. values on the command line
+// always win, then the superblock, then an index header from a previous
+// device, and finally statistics gathered from candidate node headers.
+
+pub fn scan_parameters (
+	output: & Output,
+	command: & IndexCommand,
+	index_header: & Option <IndexHeader>,
+	superblock: Option <& BtrfsSuperblock>,
+	reader: & DeviceReader,
+	default_device_id: u64,
+) -> Result <ScanParameters, String> {
+
+	let mut parameters =
+		if let Some (superblock) = superblock {
+
+		let mut fs_uuid: [u8; 16] = [0; 16];
+
+		fs_uuid.copy_from_slice (
+			& superblock.fs_uuid ().bytes ());
+
+		ScanParameters {
+			fs_uuid: fs_uuid,
+			node_size: superblock.node_size (),
+			sector_size: superblock.sector_size (),
+			csum_type: superblock.csum_type (),
+			device_id: superblock.device_id (),
+		}
+
+	} else if let Some (ref index_header) = * index_header {
+
+		output_message! (
+			output,
+			"Using filesystem parameters from previous device");
+
+		ScanParameters {
+			fs_uuid: index_header.fs_uuid,
+			node_size: index_header.node_size,
+			sector_size: index_header.sector_size,
+			csum_type: index_header.csum_type,
+			device_id: default_device_id,
+		}
+
+	} else if (
+		command.fs_uuid.is_some ()
+		&& command.node_size.is_some ()
+		&& command.sector_size.is_some ()
+		&& command.csum_type.is_some ()
+	) {
+
+		ScanParameters {
+			fs_uuid: command.fs_uuid.unwrap (),
+			node_size: command.node_size.unwrap (),
+			sector_size: command.sector_size.unwrap (),
+			csum_type: command.csum_type.unwrap (),
+			device_id: default_device_id,
+		}
+
+	} else {
+
+		output_message! (
+			output,
+			"No valid superblock, inferring filesystem parameters from \
+			node headers");
+
+		scan_parameters_infer (
+			output,
+			command.fs_uuid,
+			reader,
+			default_device_id,
+		) ?
+
+	};
+
+	// apply overrides
+
+	if let Some (fs_uuid) = command.fs_uuid {
+		parameters.fs_uuid = fs_uuid;
+	}
+
+	if let Some (node_size) = command.node_size {
+		parameters.node_size = node_size;
+	}
+
+	if let Some (sector_size) = command.sector_size {
+		parameters.sector_size = sector_size;
+	}
+
+	if let Some (csum_type) = command.csum_type {
+		parameters.csum_type = csum_type;
+	}
+
+	scan_parameters_check (
+		& parameters,
+	) ?;
+
+	Ok (parameters)
+
+}
+
+// the sizes are checked whichever source they came from, since everything
+// downstream divides by them
+
+fn scan_parameters_check (
+	parameters: & ScanParameters,
+) -> Result <(), String> {
+
+	check_block_size (
+		"sector size",
+		parameters.sector_size as u64,
+		MIN_BLOCK_SIZE,
+	) ?;
+
+	check_block_size (
+		"node size",
+		parameters.node_size as u64,
+		parameters.sector_size,
+	) ?;
+
+	Ok (())
+
+}
+
+fn scan_parameters_infer (
+	output: & Output,
+	fs_uuid_filter: Option <[u8; 16]>,
+	reader: & DeviceReader,
+	device_id: u64,
+) -> Result <ScanParameters, String> {
+
+	// gather statistics from windows spread over the device
+
+	let mut node_counts: HashMap <([u8; 16], u32), u64> =
+		HashMap::new ();
+
+	let mut num_candidates: u64 = 0;
+
+	let bytes_len =
+		reader.size ();
+
+	let mut window_bytes: Vec <u8> =
+		Vec::new ();
+
+	let window_step =
+		bytes_len / INFER_WINDOWS / 0x1000 * 0x1000;
+
+	let window_step =
+		if window_step < INFER_WINDOW_SIZE {
+			INFER_WINDOW_SIZE
+		} else {
+			window_step
+		};
+
+	let output_job =
+		output_job_start! (
+			output,
+			"Sampling node headers");
+
+	let mut window_start: u64 = 0;
+
+	while window_start < bytes_len
+		&& num_candidates < INFER_MAX_CANDIDATES {
+
+		output_job.progress (
+			window_start,
+			bytes_len);
+
+		// read the window, plus enough to check the largest node size at
+		// its end
+
+		let window_end =
+			cmp::min (
+				window_start + INFER_WINDOW_SIZE + 0x1_0000,
+				bytes_len);
+
+		window_bytes.resize (
+			(window_end - window_start) as usize,
+			0);
+
+		let bad_ranges =
+			reader.read_tolerant (
+				window_start,
+				& mut window_bytes,
+				0x1000,
+			) ?;
+
+		let mut position = window_start;
+
+		while position < window_start + INFER_WINDOW_SIZE
+			&& position + 0x1000 <= bytes_len
+			&& num_candidates < INFER_MAX_CANDIDATES {
+
+			let end =
+				cmp::min (
+					position + 0x1_0000,
+					bytes_len);
+
+			if bad_ranges.iter ().any (
+				|bad_range|
+
+				bad_range.overlaps (
+					position,
+					position + 0x1000)
+
+			) {
+
+				position += 0x1000;
+
+				continue;
+
+			}
+
+			let candidate_bytes =
+				& window_bytes [
+					(position - window_start) as usize
+				..
+					(end - window_start) as usize
+				];
+
+			if let Some (node_size) =
+				infer_node_size (
+					candidate_bytes) {
+
+				let mut fs_uuid: [u8; 16] = [0; 16];
+
+				fs_uuid.copy_from_slice (
+					& candidate_bytes [0x20 .. 0x30]);
+
+				let matches_filter =
+					match fs_uuid_filter {
+						Some (ref filter) => * filter == fs_uuid,
+						None => true,
+					};
+
+				if matches_filter {
+
+					* node_counts.entry (
+						(fs_uuid, node_size),
+					).or_insert (0) += 1;
+
+					num_candidates += 1;
+
+				}
+
+			}
+
+			position += 0x1000;
+
+		}
+
+		window_start += window_step;
+
+	}
+
+	output_job.complete ();
+
+	// report statistics
+
+	let mut sorted_counts: Vec <(([u8; 16], u32), u64)> =
+		node_counts.into_iter ().collect ();
+
+	sorted_counts.sort_by_key (
+		|& (_, count)|
+
+		! count
+
+	);
+
+	for & ((ref fs_uuid, node_size), count)
+	in sorted_counts.iter ().take (5) {
+
+		output_message! (
+			output,
+			"  {} with node size 0x{:x}: {} nodes",
+			Uuid::from_bytes (fs_uuid).unwrap (),
+			node_size,
+			count);
+
+	}
+
+	let & ((fs_uuid, node_size), _) =
+		sorted_counts.first ().ok_or (
+			"Unable to infer filesystem parameters, please specify \
+			--fs-uuid, --node-size, --sector-size and --csum-type".to_owned ()
+		) ?;
+
+	let parameters =
+		ScanParameters {
+			fs_uuid: fs_uuid,
+			node_size: node_size,
+			sector_size: INFER_SECTOR_SIZE,
+			csum_type: BTRFS_CSUM_TYPE_CRC32,
+			device_id: device_id,
+		};
+
+	output_message! (
+		output,
+		"Inferred filesystem {}, node size 0x{:x}, checksum type {}, \
+		assuming sector size 0x{:x}",
+		parameters.fs_uuid_string (),
+		parameters.node_size,
+		checksum_type_name (parameters.csum_type),
+		parameters.sector_size);
+
+	Ok (parameters)
+
+}
+
+// checks if the bytes look like a node header, and if the crc32c checksum
+// matches for one of the possible node sizes. other checksum types can't be
+// inferred and must be given on the command line.
+
+fn infer_node_size (
+	bytes: & [u8],
+) -> Option <u32> {
+
+	let logical_address =
+		le_u64 (bytes, 0x30);
+
+	let generation =
+		le_u64 (bytes, 0x50);
+
+	let level =
+		bytes [0x64];
+
+	if (
+		logical_address == 0
+		|| logical_address % 0x1000 != 0
+		|| generation == 0
+		|| level >= BTRFS_MAX_LEVEL
+	) {
+		return None;
+	}
+
+	let stored_checksum =
+		le_u32 (bytes, 0);
+
+	let mut digest =
+		crc32::Digest::new (
+			crc32::CASTAGNOLI);
+
+	let mut checked_size: usize =
+		BTRFS_CSUM_SIZE;
+
+	for node_size in INFER_NODE_SIZES.iter () {
+
+		let node_size = * node_size as usize;
+
+		if node_size > bytes.len () {
+			break;
+		}
+
+		digest.write (
+			& bytes [checked_size .. node_size]);
+
+		checked_size = node_size;
+
+		if digest.sum32 () == stored_checksum {
+			return Some (node_size as u32);
+		}
+
+	}
+
+	None
+
+}
+
+#[ cfg (test) ]
+mod tests {
+
+	use super::*;
+
+	fn parameters (
+		node_size: u32,
+		sector_size: u32,
+	) -> ScanParameters {
+
+		ScanParameters {
+			fs_uuid: [0; 16],
+			node_size: node_size,
+			sector_size: sector_size,
+			csum_type: BTRFS_CSUM_TYPE_CRC32,
+			device_id: 1,
+		}
+
+	}
+
+	fn node (
+		node_size: usize,
+		logical_address: u64,
+	) -> Vec <u8> {
+
+		let mut bytes: Vec <u8> =
+			vec! [0; node_size];
+
+		for index in 0 .. 8 {
+			bytes [0x30 + index] = (logical_address >> (index * 8)) as u8;
+		}
+
+		bytes [0x50] = 1;
+
+		for index in 0x65 .. node_size {
+			bytes [index] = index as u8;
+		}
+
+		let checksum =
+			crc32::checksum_castagnoli (
+				& bytes [BTRFS_CSUM_SIZE .. ]);
+
+		for index in 0 .. 4 {
+			bytes [index] = (checksum >> (index * 8)) as u8;
+		}
+
+		bytes
+
+	}
+
+	#[ test ]
+	fn sizes_must_be_powers_of_two_in_range () {
+
+		assert! (scan_parameters_check (& parameters (0x4000, 0x1000)).is_ok ());
+		assert! (scan_parameters_check (& parameters (0x1_0000, 0x1_0000)).is_ok ());
+
+		assert! (scan_parameters_check (& parameters (0, 0)).is_err ());
+		assert! (scan_parameters_check (& parameters (0x4000, 0x800)).is_err ());
+		assert! (scan_parameters_check (& parameters (0x3000, 0x1000)).is_err ());
+		assert! (scan_parameters_check (& parameters (0x2_0000, 0x1000)).is_err ());
+		assert! (scan_parameters_check (& parameters (0x1000, 0x4000)).is_err ());
+
+	}
+
+	#[ test ]
+	fn node_size_is_inferred_from_the_checksum () {
+
+		for & node_size in INFER_NODE_SIZES.iter () {
+
+			let mut bytes =
+				node (node_size as usize, 0x10_0000);
+
+			bytes.resize (0x1_0000, 0xff);
+
+			assert_eq! (infer_node_size (& bytes), Some (node_size));
+
+		}
+
+	}
+
+	#[ test ]
+	fn unlikely_node_headers_are_ignored () {
+
+		let mut bytes =
+			node (0x4000, 0x10_0000);
+
+		bytes [0x1000] ^= 1;
+
+		assert_eq! (infer_node_size (& bytes), None);
+
+		assert_eq! (infer_node_size (& node (0x4000, 0x10_0800)), None);
+		assert_eq! (infer_node_size (& node (0x4000, 0)), None);
+
+		// too short for the node size the checksum covers
+
+		assert_eq! (
+			infer_node_size (& node (0x4000, 0x10_0000) [0 .. 0x2000]),
+			None);
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust
@@ -0,0 +1,246 @@
+use btrfs::diskformat::*;
+
+use output::Output;
+
+use checksum::*;
+use devices::*;
+
+pub const BTRFS_SUPERBLOCK_OFFSETS: [u64; 3] = [
+	0x1_0000,
+	0x400_0000,
+	0x40_0000_0000,
+];
+
+// the fourth mirror is never written by current kernels, but old ones did,
+// so we still avoid treating it as a node
+
+pub const BTRFS_SUPERBLOCK_SKIP_OFFSETS: [u64; 4] = [
+	0x1_0000,
+	0x400_0000,
+	0x40_0000_0000,
+	0x4_0000_0000_0000,
+];
+
+pub const BTRFS_SUPERBLOCK_SIZE: u64 = 0x1000;
+
+pub fn superblock_at <'a> (
+	bytes: & 'a [u8],
+	offset: u64,
+) -> Option <& 'a BtrfsSuperblock> {
+
+	if offset + BTRFS_SUPERBLOCK_SIZE > bytes.len () as u64 {
+		return None;
+	}
+
+	let superblock: & BtrfsSuperblock = unsafe {
+		& * (
+			bytes [offset as usize ..].as_ptr ()
+			as * const BtrfsSuperblock
+		)
+	};
+
+	if superblock.magic () != BTRFS_MAGIC {
+		return None;
+	}
+
+	Some (superblock)
+
+}
+
+pub fn superblock_verify (
+	bytes: & [u8],
+	offset: u64,
+	superblock: & BtrfsSuperblock,
+) -> Result <(), String> {
+
+	let superblock_bytes =
+		& bytes [
+			offset as usize
+		..
+			(offset + BTRFS_SUPERBLOCK_SIZE) as usize
+		];
+
+	if checksum_verify (
+		superblock.csum_type (),
+		superblock_bytes,
+	) == Some (false) {
+
+		return Err (
+			"Checksum mismatch".to_owned ());
+
+	}
+
+	if ! parameter_size_valid (superblock.sector_size (), 0x1000) {
+
+		return Err (
+			format! (
+				"Invalid sector size 0x{:x}",
+				superblock.sector_size ()));
+
+	}
+
+	if ! parameter_size_valid (superblock.node_size (), superblock.sector_size ()) {
+
+		return Err (
+			format! (
+				"Invalid node size 0x{:x}",
+				superblock.node_size ()));
+
+	}
+
+	if superblock.node_size () != superblock.leaf_size () {
+
+		return Err (
+			format! (
+				"Node size 0x{:x} and leaf size 0x{:x} differ",
+				superblock.node_size (),
+				superblock.leaf_size ()));
+
+	}
+
+	Ok (())
+
+}
+
+pub fn superblock_read (
+	reader: & DeviceReader,
+	offset: u64,
+) -> Option <Vec <u8>> {
+
+	if offset + BTRFS_SUPERBLOCK_SIZE > reader.size () {
+		return None;
+	}
+
+	let mut bytes: Vec <u8> =
+		vec! [0; BTRFS_SUPERBLOCK_SIZE as usize];
+
+	if reader.read_at (offset, & mut bytes).is_err () {
+		return None;
+	}
+
+	Some (bytes)
+
+}
+
+// finds the most recent valid superblock from the primary and its mirrors,
+// returning a copy of its bytes
+
+pub fn superblock_find (
+	output: & Output,
+	reader: & DeviceReader,
+) -> Option <Vec <u8>> {
+
+	let mut found: Option <(u64, Vec <u8>)> =
+		None;
+
+	for offset in BTRFS_SUPERBLOCK_OFFSETS.iter () {
+
+		let bytes =
+			match superblock_read (
+				reader,
+				* offset) {
+
+			Some (bytes) =>
+				bytes,
+
+			None => {
+
+				output_message! (
+					output,
+					"Unable to read superblock at 0x{:x}",
+					offset);
+
+				continue;
+
+			},
+
+		};
+
+		let generation = {
+
+			let superblock =
+				match superblock_at (
+					& bytes,
+					0) {
+
+				Some (superblock) =>
+					superblock,
+
+				None => {
+
+					output_message! (
+						output,
+						"No superblock at 0x{:x}",
+						offset);
+
+					continue;
+
+				},
+
+			};
+
+			if let Err (error) =
+				superblock_verify (
+					& bytes,
+					0,
+					superblock) {
+
+				output_message! (
+					output,
+					"Ignoring superblock at 0x{:x}: {}",
+					offset,
+					error);
+
+				continue;
+
+			}
+
+			superblock.generation ()
+
+		};
+
+		let newer =
+			match found {
+				Some ((found_generation, _)) => generation > found_generation,
+				None => true,
+			};
+
+		if newer {
+			found = Some ((generation, bytes));
+		}
+
+	}
+
+	found.map (
+		|(_, bytes)| bytes
+	)
+
+}
+
+pub fn superblock_overlaps (
+	position: u64,
+	size: u64,
+) -> bool {
+
+	BTRFS_SUPERBLOCK_SKIP_OFFSETS.iter ().any (
+		|offset|
+
+		position < offset + BTRFS_SUPERBLOCK_SIZE
+		&& * offset < position + size
+
+	)
+
+}
+
+pub fn parameter_size_valid (
+	size: u32,
+	minimum: u32,
+) -> bool {
+
+	size.is_power_of_two ()
+	&& size >= minimum
+	&& size <= 0x1_0000
+
+}
+
+// ex: noet ts=4 filetype=rust
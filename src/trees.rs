@@ -0,0 +1,479 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use bytes::*;
+use chunks::*;
+use index::*;
+
+// raw tree walking, for following trees from nodes found in an index rather
+// than from the superblock. as with the chunk tree, we parse nodes ourselves,
+// so that a missing or overwritten node is counted and skipped, instead of
+// failing the whole tree.
+
+const TREE_INODE_ITEM_TYPE: u8 = 1;
+const TREE_EXTENT_DATA_TYPE: u8 = 108;
+const TREE_ROOT_ITEM_TYPE: u8 = 132;
+const TREE_ROOT_BACKREF_TYPE: u8 = 144;
+
+const TREE_ROOT_ITEM_GENERATION_OFFSET: usize = 0xa0;
+const TREE_ROOT_ITEM_BYTENR_OFFSET: usize = 0xb0;
+const TREE_ROOT_ITEM_LEVEL_OFFSET: usize = 0xee;
+const TREE_ROOT_ITEM_MIN_SIZE: usize = 0xef;
+
+const TREE_SUPERBLOCK_GENERATION_OFFSET: usize = 0x48;
+const TREE_SUPERBLOCK_ROOT_OFFSET: usize = 0x50;
+const TREE_SUPERBLOCK_ROOT_LEVEL_OFFSET: usize = 0xc6;
+const TREE_SUPERBLOCK_BACKUP_ROOTS_OFFSET: usize = 0xb2b;
+const TREE_SUPERBLOCK_BACKUP_ROOT_SIZE: usize = 0xa8;
+const TREE_SUPERBLOCK_NUM_BACKUP_ROOTS: usize = 4;
+const TREE_BACKUP_ROOT_GENERATION_OFFSET: usize = 0x08;
+const TREE_BACKUP_ROOT_LEVEL_OFFSET: usize = 0x98;
+
+pub const TREE_ROOT_TREE_ID: u64 = 1;
+pub const TREE_FS_TREE_ID: u64 = 5;
+pub const TREE_FIRST_FREE_ID: u64 = 256;
+pub const TREE_LAST_FREE_ID: u64 = -256i64 as u64;
+
+// the top of a tree, from a root item or a node header
+
+#[ derive (Clone, Copy, Debug, Eq, Hash, PartialEq) ]
+pub struct TreeRoot {
+	pub tree_id: u64,
+	pub logical_address: u64,
+	pub generation: u64,
+	pub level: u8,
+}
+
+// where a subvolume is linked into its parent, from a root backref item
+
+#[ derive (Clone, Debug, Eq, PartialEq) ]
+pub struct TreeRootBackref {
+	pub tree_id: u64,
+	pub parent_tree_id: u64,
+	pub dir_id: u64,
+	pub name: Vec <u8>,
+}
+
+// what was found by walking a tree
+
+#[ derive (Clone, Copy, Debug, Default) ]
+pub struct TreeCounts {
+	pub num_nodes: u64,
+	pub num_missing: u64,
+	pub num_inodes: u64,
+	pub num_extent_datas: u64,
+}
+
+impl TreeCounts {
+
+	pub fn add (
+		& mut self,
+		other: & TreeCounts,
+	) {
+
+		self.num_nodes += other.num_nodes;
+		self.num_missing += other.num_missing;
+		self.num_inodes += other.num_inodes;
+		self.num_extent_datas += other.num_extent_datas;
+
+	}
+
+}
+
+pub fn tree_is_subvolume (
+	tree_id: u64,
+) -> bool {
+
+	tree_id == TREE_FS_TREE_ID
+	|| (tree_id >= TREE_FIRST_FREE_ID && tree_id <= TREE_LAST_FREE_ID)
+
+}
+
+// reads the top of a tree from the header of its root node
+
+pub fn tree_root_from_node (
+	node_bytes: & [u8],
+) -> TreeRoot {
+
+	TreeRoot {
+		tree_id: le_u64 (node_bytes, 0x58),
+		logical_address: le_u64 (node_bytes, 0x30),
+		generation: le_u64 (node_bytes, 0x50),
+		level: node_bytes [0x64],
+	}
+
+}
+
+// returns the root tree named by a superblock, followed by its backup roots,
+// most recent first, for when the current one can't be read
+
+pub fn tree_roots_from_superblock (
+	superblock_bytes: & [u8],
+) -> Vec <TreeRoot> {
+
+	let mut tree_roots: Vec <TreeRoot> =
+		vec! [
+			TreeRoot {
+				tree_id: TREE_ROOT_TREE_ID,
+				logical_address: le_u64 (
+					superblock_bytes,
+					TREE_SUPERBLOCK_ROOT_OFFSET),
+				generation: le_u64 (
+					superblock_bytes,
+					TREE_SUPERBLOCK_GENERATION_OFFSET),
+				level: superblock_bytes [TREE_SUPERBLOCK_ROOT_LEVEL_OFFSET],
+			},
+		];
+
+	let mut backup_roots: Vec <TreeRoot> =
+		(0 .. TREE_SUPERBLOCK_NUM_BACKUP_ROOTS).map (
+			|backup_index| {
+
+			let backup_start =
+				TREE_SUPERBLOCK_BACKUP_ROOTS_OFFSET
+					+ backup_index * TREE_SUPERBLOCK_BACKUP_ROOT_SIZE;
+
+			TreeRoot {
+				tree_id: TREE_ROOT_TREE_ID,
+				logical_address: le_u64 (
+					superblock_bytes,
+					backup_start),
+				generation: le_u64 (
+					superblock_bytes,
+					backup_start + TREE_BACKUP_ROOT_GENERATION_OFFSET),
+				level: superblock_bytes [
+					backup_start + TREE_BACKUP_ROOT_LEVEL_OFFSET],
+			}
+
+		}).filter (
+			|backup_root|
+
+			backup_root.logical_address != 0
+			&& backup_root.generation < tree_roots [0].generation
+
+		).collect ();
+
+	backup_roots.sort_by (
+		|left, right|
+
+		right.generation.cmp (
+			& left.generation)
+
+	);
+
+	tree_roots.extend (
+		backup_roots);
+
+	tree_roots
+
+}
+
+// returns the trees named by the root items in a leaf of the root tree
+
+pub fn tree_root_items (
+	leaf_bytes: & [u8],
+) -> Vec <TreeRoot> {
+
+	chunks_leaf_items (
+		leaf_bytes,
+	).into_iter ().filter (
+		|& (ref key, data)|
+
+		key.item_type == TREE_ROOT_ITEM_TYPE
+		&& data.len () >= TREE_ROOT_ITEM_MIN_SIZE
+
+	).map (
+		|(key, data)|
+
+		TreeRoot {
+			tree_id: key.object_id,
+			logical_address: le_u64 (data, TREE_ROOT_ITEM_BYTENR_OFFSET),
+			generation: le_u64 (data, TREE_ROOT_ITEM_GENERATION_OFFSET),
+			level: data [TREE_ROOT_ITEM_LEVEL_OFFSET],
+		}
+
+	).collect ()
+
+}
+
+// returns the root backrefs in a leaf of the root tree. the key holds the
+// subvolume and its parent, and the data the directory in the parent and the
+// name it has there.
+
+pub fn tree_root_backrefs (
+	leaf_bytes: & [u8],
+) -> Vec <TreeRootBackref> {
+
+	chunks_leaf_items (
+		leaf_bytes,
+	).into_iter ().filter_map (
+		|(key, data)| {
+
+		if key.item_type != TREE_ROOT_BACKREF_TYPE || data.len () < 0x12 {
+			return None;
+		}
+
+		let name_end =
+			0x12 + le_u16 (data, 0x10) as usize;
+
+		if name_end > data.len () {
+			return None;
+		}
+
+		Some (TreeRootBackref {
+			tree_id: key.object_id,
+			parent_tree_id: key.offset,
+			dir_id: le_u64 (data, 0x00),
+			name: data [0x12 .. name_end].to_vec (),
+		})
+
+	}).collect ()
+
+}
+
+// a node is only accepted if it is the one its parent expects, since the
+// same logical address is reused once a node is freed
+
+fn tree_node_matches (
+	node_bytes: & [u8],
+	logical_address: u64,
+	generation: u64,
+	level: u8,
+) -> bool {
+
+	node_bytes.len () > BTRFS_NODE_HEADER_SIZE
+	&& le_u64 (node_bytes, 0x30) == logical_address
+	&& le_u64 (node_bytes, 0x50) == generation
+	&& node_bytes [0x64] == level
+
+}
+
+// returns the logical address and generation of each child of an internal
+// node
+
+pub fn tree_node_children (
+	node_bytes: & [u8],
+) -> Vec <(u64, u64)> {
+
+	let num_items =
+		le_u32 (node_bytes, 0x60) as usize;
+
+	let max_items =
+		(node_bytes.len () - BTRFS_NODE_HEADER_SIZE)
+			/ BTRFS_INTERNAL_ITEM_SIZE;
+
+	(0 .. cmp::min (num_items, max_items)).map (
+		|item_index| {
+
+		let item_start =
+			BTRFS_NODE_HEADER_SIZE + item_index * BTRFS_INTERNAL_ITEM_SIZE;
+
+		(
+			le_u64 (node_bytes, item_start + 0x11),
+			le_u64 (node_bytes, item_start + 0x19),
+		)
+
+	}).collect ()
+
+}
+
+// visits every leaf of a tree which can be read. nodes are read with the
+// generation their parent expects, so the right copy can be chosen. returns
+// the logical address and generation of every node reached, and how many
+// couldn't be.
+
+pub fn tree_walk <ReadNode, VisitLeaf> (
+	tree_root: & TreeRoot,
+	mut read_node: ReadNode,
+	mut visit_leaf: VisitLeaf,
+) -> (HashSet <(u64, u64)>, u64)
+where
+	ReadNode: FnMut (u64, u64) -> Result <Vec <u8>, String>,
+	VisitLeaf: FnMut (& [u8]) {
+
+	let mut nodes_reached: HashSet <(u64, u64)> =
+		HashSet::new ();
+
+	let mut num_missing: u64 = 0;
+
+	let mut pending: Vec <(u64, u64, u8)> =
+		vec! [ (
+			tree_root.logical_address,
+			tree_root.generation,
+			tree_root.level,
+		) ];
+
+	while let Some ((logical_address, generation, level)) = pending.pop () {
+
+		let node_bytes =
+			match read_node (logical_address, generation) {
+
+			Ok (node_bytes) =>
+				node_bytes,
+
+			Err (_) => {
+				num_missing += 1;
+				continue;
+			},
+
+		};
+
+		if ! tree_node_matches (
+			& node_bytes,
+			logical_address,
+			generation,
+			level) {
+
+			num_missing += 1;
+
+			continue;
+
+		}
+
+		if ! nodes_reached.insert (
+			(logical_address, generation)) {
+
+			continue;
+
+		}
+
+		if level == 0 {
+
+			visit_leaf (
+				& node_bytes);
+
+			continue;
+
+		}
+
+		for (child_address, child_generation)
+		in tree_node_children (& node_bytes) {
+
+			pending.push ((
+				child_address,
+				child_generation,
+				level - 1,
+			));
+
+		}
+
+	}
+
+	(nodes_reached, num_missing)
+
+}
+
+// counts the nodes, inodes and file extents in a tree. counts for each node
+// are remembered, so trees from different generations, which share most of
+// their nodes, can be counted quickly one after another.
+
+pub struct TreeCounter <ReadNode>
+where ReadNode: FnMut (u64, u64) -> Result <Vec <u8>, String> {
+	read_node: ReadNode,
+	node_counts: HashMap <(u64, u64), TreeCounts>,
+}
+
+impl <ReadNode> TreeCounter <ReadNode>
+where ReadNode: FnMut (u64, u64) -> Result <Vec <u8>, String> {
+
+	pub fn new (
+		read_node: ReadNode,
+	) -> TreeCounter <ReadNode> {
+
+		TreeCounter {
+			read_node: read_node,
+			node_counts: HashMap::new (),
+		}
+
+	}
+
+	pub fn count (
+		& mut self,
+		tree_root: & TreeRoot,
+	) -> TreeCounts {
+
+		self.count_node (
+			tree_root.logical_address,
+			tree_root.generation,
+			tree_root.level)
+
+	}
+
+	fn count_node (
+		& mut self,
+		logical_address: u64,
+		generation: u64,
+		level: u8,
+	) -> TreeCounts {
+
+		if let Some (node_counts) =
+			self.node_counts.get (
+				& (logical_address, generation)) {
+
+			return * node_counts;
+
+		}
+
+		let mut node_counts: TreeCounts =
+			Default::default ();
+
+		let node_bytes: Vec <u8> =
+			match (self.read_node) (logical_address, generation) {
+				Ok (node_bytes) => node_bytes,
+				Err (_) => Vec::new (),
+			};
+
+		if ! tree_node_matches (
+			& node_bytes,
+			logical_address,
+			generation,
+			level) {
+
+			node_counts.num_missing = 1;
+
+		} else if level == 0 {
+
+			node_counts.num_nodes = 1;
+
+			for (key, _) in chunks_leaf_items (& node_bytes) {
+
+				if key.item_type == TREE_INODE_ITEM_TYPE {
+					node_counts.num_inodes += 1;
+				} else if key.item_type == TREE_EXTENT_DATA_TYPE {
+					node_counts.num_extent_datas += 1;
+				}
+
+			}
+
+		} else {
+
+			node_counts.num_nodes = 1;
+
+			for (child_address, child_generation)
+			in tree_node_children (& node_bytes) {
+
+				let child_counts =
+					self.count_node (
+						child_address,
+						child_generation,
+						level - 1);
+
+				node_counts.add (
+					& child_counts);
+
+			}
+
+		}
+
+		self.node_counts.insert (
+			(logical_address, generation),
+			node_counts);
+
+		node_counts
+
+	}
+
+}
+
+// ex: noet ts=4 filetype=rust